@@ -0,0 +1,21 @@
+#![no_main]
+
+use base32::Alphabet;
+use giggleshitter_common::{
+    proxy::util::{decode_url, encode_url},
+    state::{Config, UrlEncodingAlgorithm},
+};
+use libfuzzer_sys::fuzz_target;
+
+// Arbitrary URLs as they appear in rewritten pages and Location headers
+fuzz_target!(|url: &str| {
+    let config = Config {
+        url_encoding_algorithm: UrlEncodingAlgorithm::Base32Xor(Alphabet::Z, b"fuzz".to_vec()),
+        public_host: "proxy.local".to_string(),
+        ..Default::default()
+    };
+
+    let encoded = encode_url(&config, url);
+    let _ = decode_url(&config, &encoded);
+    let _ = decode_url(&config, url);
+});
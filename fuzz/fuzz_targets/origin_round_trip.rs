@@ -0,0 +1,34 @@
+#![no_main]
+
+use base32::Alphabet;
+use giggleshitter_common::{
+    proxy::util::proxied_origin,
+    state::{Config, UrlEncodingAlgorithm},
+};
+use libfuzzer_sys::fuzz_target;
+
+// Labels that decode to arbitrary bytes, including ones that aren't UTF-8 or an origin at all,
+// in whatever case the browser sends them
+fuzz_target!(|data: (&[u8], bool)| {
+    let (origin, uppercase) = data;
+
+    let config = Config {
+        url_encoding_algorithm: UrlEncodingAlgorithm::Base32(Alphabet::Z),
+        public_host: "proxy.local".to_string(),
+        ..Default::default()
+    };
+
+    let label = base32::encode(Alphabet::Z, origin);
+    let label = match uppercase {
+        true => label.to_ascii_uppercase(),
+        false => label,
+    };
+
+    let Ok(proxied) = proxied_origin(&config, &format!("{}.proxy.local", label)) else {
+        return;
+    };
+
+    // Whatever decodes has to be the origin that was encoded
+    let origin = std::str::from_utf8(origin).unwrap();
+    assert!(origin.contains(proxied.host()));
+});
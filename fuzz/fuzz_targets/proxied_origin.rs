@@ -0,0 +1,20 @@
+#![no_main]
+
+use base32::Alphabet;
+use giggleshitter_common::{
+    proxy::util::proxied_origin,
+    state::{Config, UrlEncodingAlgorithm},
+};
+use libfuzzer_sys::fuzz_target;
+
+// Arbitrary Host headers, which may carry any label, port or suffix
+fuzz_target!(|host: &str| {
+    let config = Config {
+        url_encoding_algorithm: UrlEncodingAlgorithm::Base32(Alphabet::Z),
+        public_host: "proxy.local".to_string(),
+        ..Default::default()
+    };
+
+    let _ = proxied_origin(&config, host);
+    let _ = proxied_origin(&config, &format!("{}.proxy.local", host));
+});
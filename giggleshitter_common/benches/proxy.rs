@@ -0,0 +1,128 @@
+use base32::Alphabet;
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use giggleshitter_common::{
+    proxy::util::{encode_url, proxied_origin, Origin},
+    rewriting::{
+        html::html_rewriter::HtmlRewriter,
+        rewriter::{RewriteContext, Rewriter},
+    },
+    state::{Config, UrlEncodingAlgorithm},
+};
+
+fn config() -> Config {
+    Config {
+        url_encoding_algorithm: UrlEncodingAlgorithm::Base32(Alphabet::Z),
+        public_host: "proxy.local".to_string(),
+        ..Default::default()
+    }
+}
+
+fn xor_config() -> Config {
+    Config {
+        url_encoding_algorithm: UrlEncodingAlgorithm::Base32Xor(
+            Alphabet::Z,
+            b"0123456789abcdef".to_vec(),
+        ),
+        ..config()
+    }
+}
+
+/// A page with the mix of links, scripts, images and forms of a typical article, repeated until
+/// it is roughly `bytes` long
+fn page(bytes: usize) -> Vec<u8> {
+    let block = r#"
+<article>
+  <h2><a href="https://news.example.com/2024/05/article">A headline</a></h2>
+  <img src="https://cdn.example.com/images/photo.jpg" srcset="https://cdn.example.com/images/photo@2x.jpg 2x">
+  <p>Some text with <a href="/relative/link">a relative link</a> and
+     <a href="https://other.example.org/page?query=1#fragment">an absolute one</a>.</p>
+  <form action="https://news.example.com/search" method="get"><input name="q"></form>
+  <script src="https://cdn.example.com/js/app.js"></script>
+</article>"#;
+
+    let mut page = String::from(
+        r#"<!doctype html><html><head><title>Bench</title>
+<link rel="stylesheet" href="https://cdn.example.com/css/site.css"></head><body>"#,
+    );
+    while page.len() < bytes {
+        page.push_str(block);
+    }
+    page.push_str("</body></html>");
+
+    page.into_bytes()
+}
+
+fn bench_encode_url(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode_url");
+
+    for (name, config) in [("base32", config()), ("base32_xor", xor_config())] {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                encode_url(
+                    black_box(&config),
+                    black_box("https://www.example.com:8443/some/path?query=1"),
+                )
+            })
+        });
+    }
+
+    group.finish();
+}
+
+fn bench_proxied_origin(c: &mut Criterion) {
+    let mut group = c.benchmark_group("proxied_origin");
+
+    for (name, config) in [("base32", config()), ("base32_xor", xor_config())] {
+        let encoded = encode_url(&config, "https://www.example.com:8443/");
+        let host = encoded
+            .trim_start_matches("https://")
+            .trim_end_matches('/')
+            .to_string();
+
+        group.bench_function(name, |b| {
+            b.iter(|| proxied_origin(black_box(&config), black_box(&host)).unwrap())
+        });
+    }
+
+    group.finish();
+}
+
+fn bench_html_rewriting(c: &mut Criterion) {
+    let mut group = c.benchmark_group("html_rewriting");
+    let config = config();
+    let rewriter = HtmlRewriter::new();
+    let origin = "https://www.example.com".parse::<Origin>().unwrap();
+    let context = RewriteContext {
+        origin: &origin,
+        request_path: "/article",
+        charset: None,
+        content_type: Some("text/html"),
+    };
+
+    for (name, bytes) in [
+        ("small", 4 * 1024),
+        ("medium", 64 * 1024),
+        ("large", 1024 * 1024),
+    ] {
+        let page = page(bytes);
+        group.throughput(Throughput::Bytes(page.len() as u64));
+
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || page.clone(),
+                |page| rewriter.rewrite(&config, &context, page).unwrap(),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_encode_url,
+    bench_proxied_origin,
+    bench_html_rewriting
+);
+criterion_main!(benches);
@@ -0,0 +1,150 @@
+use std::{
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use scorched::{logf, LogData, LogImportance};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{error::Result, state::Config};
+
+/// Why a client was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Denial {
+    /// The client's address is on the deny list, or not on the allow list
+    Address,
+    /// The client's country is denied, or not allowed
+    Country,
+}
+
+#[derive(Serialize, ToSchema)]
+/// How many proxied requests the access rules let through or refused since the server started
+pub struct AccessStats {
+    pub allowed: u64,
+    pub denied_address: u64,
+    pub denied_country: u64,
+}
+
+#[derive(Clone)]
+/// Decides which clients may use the proxy from their address and, with the `geoip` feature, the
+/// country it is located in
+pub struct AccessControl(Arc<Inner>);
+
+struct Inner {
+    #[cfg(feature = "geoip")]
+    geoip: Option<maxminddb::Reader<Vec<u8>>>,
+    allowed: AtomicU64,
+    denied_address: AtomicU64,
+    denied_country: AtomicU64,
+}
+
+impl AccessControl {
+    /// Open the GeoIP database if one is configured. The database is only read at startup.
+    pub fn load(config: &Config) -> Result<Self> {
+        #[cfg(feature = "geoip")]
+        let geoip = match &config.access.geoip_database {
+            Some(path) => {
+                let reader = maxminddb::Reader::open_readfile(path)?;
+                logf!(Info, "Loaded GeoIP database {}", path.display());
+                Some(reader)
+            }
+            None => None,
+        };
+
+        #[cfg(not(feature = "geoip"))]
+        if let Some(path) = &config.access.geoip_database {
+            logf!(
+                Warning,
+                "Not loading GeoIP database {}, this build does not include the geoip feature",
+                path.display()
+            );
+        }
+
+        Ok(Self(Arc::new(Inner {
+            #[cfg(feature = "geoip")]
+            geoip,
+            allowed: AtomicU64::new(0),
+            denied_address: AtomicU64::new(0),
+            denied_country: AtomicU64::new(0),
+        })))
+    }
+
+    /// Check a client against the access rules, counting the outcome
+    pub fn check(&self, config: &Config, ip: IpAddr) -> Option<Denial> {
+        let denial = self.evaluate(config, ip);
+
+        let counter = match denial {
+            None => &self.0.allowed,
+            Some(Denial::Address) => &self.0.denied_address,
+            Some(Denial::Country) => &self.0.denied_country,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        denial
+    }
+
+    fn evaluate(&self, config: &Config, ip: IpAddr) -> Option<Denial> {
+        let access = &config.access;
+        // IPv4 clients of a dual stack listener show up as mapped IPv6 addresses
+        let ip = ip.to_canonical();
+
+        if access.deny.iter().any(|net| net.contains(&ip)) {
+            return Some(Denial::Address);
+        }
+
+        if !access.allow.is_empty() && !access.allow.iter().any(|net| net.contains(&ip)) {
+            return Some(Denial::Address);
+        }
+
+        if access.deny_countries.is_empty() && access.allow_countries.is_empty() {
+            return None;
+        }
+
+        let country = self.country(ip);
+        let listed = |countries: &[String]| {
+            country.as_deref().is_some_and(|country| {
+                countries
+                    .iter()
+                    .any(|listed| listed.eq_ignore_ascii_case(country))
+            })
+        };
+
+        // Clients whose country is unknown are only let in when no countries are allow-listed
+        if listed(&access.deny_countries)
+            || (!access.allow_countries.is_empty() && !listed(&access.allow_countries))
+        {
+            return Some(Denial::Country);
+        }
+
+        None
+    }
+
+    /// The ISO code of the country `ip` is located in, if the GeoIP database knows it
+    #[cfg(feature = "geoip")]
+    fn country(&self, ip: IpAddr) -> Option<String> {
+        let reader = self.0.geoip.as_ref()?;
+        let country: maxminddb::geoip2::Country = reader.lookup(ip).ok()?;
+
+        country
+            .country
+            .and_then(|country| country.iso_code)
+            .map(str::to_string)
+    }
+
+    #[cfg(not(feature = "geoip"))]
+    fn country(&self, _ip: IpAddr) -> Option<String> {
+        None
+    }
+
+    pub fn stats(&self) -> AccessStats {
+        AccessStats {
+            allowed: self.0.allowed.load(Ordering::Relaxed),
+            denied_address: self.0.denied_address.load(Ordering::Relaxed),
+            denied_country: self.0.denied_country.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use std::sync::Arc;
+
+use axum::{debug_handler, extract::State, Json};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    access::AccessStats,
+    state::{APIState, AccessConfig},
+};
+
+#[utoipa::path(
+    get, path = "/access", tag = "admin", security(("api_key" = [])),
+    responses((status = 200, body = AccessStats))
+)]
+#[debug_handler]
+/// How many requests the client access rules let through and refused
+pub async fn get_access_stats(State(state): State<Arc<APIState>>) -> Json<AccessStats> {
+    Json(state.access.stats())
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct AccessRules {
+    #[schema(value_type = Vec<String>, example = json!(["10.0.0.0/8"]))]
+    pub allow: Vec<IpNet>,
+    #[schema(value_type = Vec<String>)]
+    pub deny: Vec<IpNet>,
+}
+
+impl From<&AccessConfig> for AccessRules {
+    fn from(access: &AccessConfig) -> Self {
+        Self {
+            allow: access.allow.clone(),
+            deny: access.deny.clone(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessList {
+    Allow,
+    Deny,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct AccessRuleChange {
+    pub list: AccessList,
+    #[schema(value_type = String, example = "192.0.2.0/24")]
+    pub network: IpNet,
+    /// Whether the network should be on the list
+    pub enabled: bool,
+}
+
+#[utoipa::path(
+    get, path = "/access/rules", tag = "admin", security(("api_key" = [])),
+    responses((status = 200, body = AccessRules))
+)]
+#[debug_handler]
+/// The networks clients are allowed in from and refused from
+pub async fn get_access_rules(State(state): State<Arc<APIState>>) -> Json<AccessRules> {
+    Json(AccessRules::from(&state.config.load().access))
+}
+
+#[utoipa::path(
+    post, path = "/access/rules", tag = "admin", security(("api_key" = [])),
+    request_body = AccessRuleChange,
+    responses((status = 200, body = AccessRules))
+)]
+#[debug_handler]
+/// Add a network to or remove it from the allow or deny list. The change applies to every tenant,
+/// and lasts until the configuration is replaced, e.g. by reloading it from its file.
+pub async fn post_access_rule(
+    State(state): State<Arc<APIState>>,
+    Json(change): Json<AccessRuleChange>,
+) -> Json<AccessRules> {
+    state.config.rcu(|config| {
+        let mut config = (**config).clone();
+        let networks = match change.list {
+            AccessList::Allow => &mut config.access.allow,
+            AccessList::Deny => &mut config.access.deny,
+        };
+
+        networks.retain(|network| *network != change.network);
+        if change.enabled {
+            networks.push(change.network);
+        }
+
+        config
+    });
+
+    Json(AccessRules::from(&state.config.load().access))
+}
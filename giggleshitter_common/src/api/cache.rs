@@ -0,0 +1,13 @@
+use axum::{debug_handler, Json};
+
+use crate::proxy::util::{origin_cache_stats, OriginCacheStats};
+
+#[utoipa::path(
+    get, path = "/cache", tag = "admin", security(("api_key" = [])),
+    responses((status = 200, body = OriginCacheStats))
+)]
+#[debug_handler]
+/// How often proxied hosts were found in the origin cache
+pub async fn get_origin_cache_stats() -> Json<OriginCacheStats> {
+    Json(origin_cache_stats())
+}
@@ -0,0 +1,18 @@
+use std::sync::Arc;
+
+use axum::{debug_handler, extract::State, Extension, Json};
+
+use crate::{breaker::OriginCircuit, state::APIState, tenant::TenantConfig};
+
+#[utoipa::path(
+    get, path = "/circuits", tag = "admin", security(("api_key" = [])),
+    responses((status = 200, body = Vec<OriginCircuit>))
+)]
+#[debug_handler]
+/// The circuits of the upstream origins that failed since they last succeeded
+pub async fn get_circuits(
+    State(state): State<Arc<APIState>>,
+    Extension(TenantConfig(config)): Extension<TenantConfig>,
+) -> Json<Vec<OriginCircuit>> {
+    Json(state.breaker.snapshot(&config.circuit_breaker))
+}
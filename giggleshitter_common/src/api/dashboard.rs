@@ -0,0 +1,55 @@
+use std::{cmp::Reverse, sync::Arc};
+
+use axum::{debug_handler, extract::State, response::Html, Json};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{
+    access::AccessStats,
+    metrics::{OriginLatency, Traffic},
+    pages::admin_page,
+    proxy::util::{origin_cache_stats, OriginCacheStats},
+    state::APIState,
+};
+
+/// How many origins the dashboard lists
+const TOP_ORIGINS: usize = 10;
+
+#[derive(Serialize, ToSchema)]
+pub struct Dashboard {
+    pub traffic: Traffic,
+    /// The origins with the most requests, busiest first
+    pub top_origins: Vec<OriginLatency>,
+    pub cache: OriginCacheStats,
+    pub access: AccessStats,
+}
+
+#[utoipa::path(
+    get, path = "/dashboard", tag = "admin", security(("api_key" = [])),
+    responses((status = 200, body = Dashboard))
+)]
+#[debug_handler]
+/// Everything the admin dashboard shows, in one request so it can be polled
+pub async fn get_dashboard(State(state): State<Arc<APIState>>) -> Json<Dashboard> {
+    let mut origins = state.metrics.snapshot().origins;
+    origins.sort_by_key(|origin| Reverse(origin.requests));
+    origins.truncate(TOP_ORIGINS);
+
+    Json(Dashboard {
+        traffic: state.metrics.traffic(),
+        top_origins: origins,
+        cache: origin_cache_stats(),
+        access: state.access.stats(),
+    })
+}
+
+#[utoipa::path(
+    get, path = "/admin", tag = "admin",
+    responses((status = 200, content_type = "text/html", body = String))
+)]
+#[debug_handler]
+/// The admin dashboard. The page itself holds no data, it asks for an API key and fetches
+/// everything from the admin endpoints with it.
+pub async fn get_admin() -> Html<String> {
+    Html(admin_page())
+}
@@ -0,0 +1,30 @@
+use std::sync::Arc;
+
+use axum::{
+    debug_handler,
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use hyper::StatusCode;
+
+use crate::{diagnostics::Refusal, state::APIState, tenant::TenantConfig};
+
+#[utoipa::path(
+    get, path = "/diagnostics/{id}", tag = "admin", security(("api_key" = [])),
+    params(("id" = String, Path, description = "The correlation ID shown on the refusal page")),
+    responses((status = 200, body = Refusal), (status = 404))
+)]
+#[debug_handler]
+/// Why a request was refused and how long refusing it took, by the correlation ID its user was
+/// shown. Only the most recent refusals are kept.
+pub async fn get_diagnostics(
+    State(state): State<Arc<APIState>>,
+    Extension(TenantConfig(config)): Extension<TenantConfig>,
+    Path(id): Path<String>,
+) -> Response {
+    match state.diagnostics.get(&config.public_host, &id) {
+        Some(refusal) => Json(refusal).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
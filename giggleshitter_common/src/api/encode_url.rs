@@ -1,29 +1,78 @@
-use std::sync::Arc;
-
 use axum::debug_handler;
-use axum::{extract::State, Json};
+use axum::{
+    extract::Query,
+    http::{header::LOCATION, StatusCode},
+    response::IntoResponse,
+    Extension, Json,
+};
 use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
 
 use crate::error::Result;
 use crate::proxy::util::encode_url;
-use crate::APIState;
+use crate::tenant::TenantConfig;
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema, IntoParams)]
 pub struct EncodeUrlRequest {
     pub url: String,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub struct EncodeUrlResponse {
     pub encoded_url: String,
 }
 
+#[utoipa::path(
+    post, path = "/encode", tag = "encode", security(("api_key" = [])),
+    request_body = EncodeUrlRequest,
+    responses((status = 200, body = EncodeUrlResponse))
+)]
 #[debug_handler]
 pub async fn post_encode(
-    State(state): State<Arc<APIState>>,
+    Extension(TenantConfig(config)): Extension<TenantConfig>,
     Json(EncodeUrlRequest { url }): Json<EncodeUrlRequest>,
 ) -> Result<Json<EncodeUrlResponse>> {
     Ok(Json(EncodeUrlResponse {
-        encoded_url: encode_url(&state.config, &url),
+        encoded_url: encode_url(&config, &url),
+    }))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct EncodeUrlBatchRequest {
+    pub urls: Vec<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct EncodeUrlBatchResponse {
+    /// The encoded URLs, in the same order as the request
+    pub encoded_urls: Vec<String>,
+}
+
+#[utoipa::path(
+    post, path = "/encode/batch", tag = "encode", security(("api_key" = [])),
+    request_body = EncodeUrlBatchRequest,
+    responses((status = 200, body = EncodeUrlBatchResponse))
+)]
+#[debug_handler]
+pub async fn post_encode_batch(
+    Extension(TenantConfig(config)): Extension<TenantConfig>,
+    Json(EncodeUrlBatchRequest { urls }): Json<EncodeUrlBatchRequest>,
+) -> Result<Json<EncodeUrlBatchResponse>> {
+    Ok(Json(EncodeUrlBatchResponse {
+        encoded_urls: urls.iter().map(|url| encode_url(&config, url)).collect(),
     }))
 }
+
+#[utoipa::path(
+    get, path = "/encode", tag = "encode", security(("api_key" = [])),
+    params(EncodeUrlRequest),
+    responses((status = 302, description = "Redirects to the URL through the proxy"))
+)]
+#[debug_handler]
+/// Redirect to the URL through the proxy, for "open through proxy" links and bookmarklets
+pub async fn get_encode(
+    Extension(TenantConfig(config)): Extension<TenantConfig>,
+    Query(EncodeUrlRequest { url }): Query<EncodeUrlRequest>,
+) -> impl IntoResponse {
+    (StatusCode::FOUND, [(LOCATION, encode_url(&config, &url))])
+}
@@ -0,0 +1,19 @@
+use axum::{debug_handler, Extension, Json};
+
+use crate::{
+    proxy::util::{key_stats, KeyStats},
+    tenant::TenantConfig,
+};
+
+#[utoipa::path(
+    get, path = "/encoding-keys", tag = "admin", security(("api_key" = [])),
+    responses((status = 200, body = Vec<KeyStats>))
+)]
+#[debug_handler]
+/// Which of the current, previous and legacy encoding keys proxied hosts were decoded with, so
+/// that old keys can be retired once nothing matches them anymore
+pub async fn get_encoding_keys(
+    Extension(TenantConfig(config)): Extension<TenantConfig>,
+) -> Json<Vec<KeyStats>> {
+    Json(key_stats(&config))
+}
@@ -0,0 +1,73 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use hyper::{
+    header::{HeaderName, AUTHORIZATION},
+    HeaderMap,
+};
+use sha2::{Digest, Sha256};
+
+use crate::state::{ApiKey, Config};
+
+/// The header API keys can be sent in, for clients that can't set `Authorization`
+static API_KEY_HEADER: HeaderName = HeaderName::from_static("x-gs-api-key");
+
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+/// The configured key a request was made with, if it carries one
+pub fn find_key<'a>(config: &'a Config, headers: &HeaderMap) -> Option<&'a ApiKey> {
+    let presented = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| {
+            headers
+                .get(&API_KEY_HEADER)
+                .and_then(|value| value.to_str().ok())
+        })?
+        .trim();
+
+    let hash: String = Sha256::digest(presented.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+
+    config
+        .api_keys
+        .iter()
+        .find(|key| key.sha256.eq_ignore_ascii_case(&hash))
+}
+
+#[derive(Clone, Default)]
+/// Counts the requests of each API key in one minute windows
+pub struct RateLimiter {
+    windows: Arc<Mutex<HashMap<String, (Instant, u32)>>>,
+}
+
+impl RateLimiter {
+    /// Count a request made with `key`, returning whether it is within the key's limit
+    pub fn check(&self, key: &ApiKey) -> bool {
+        let Some(limit) = key.requests_per_minute else {
+            return true;
+        };
+
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        let (start, count) = windows.entry(key.name.clone()).or_insert((now, 0));
+
+        if now.duration_since(*start) >= RATE_LIMIT_WINDOW {
+            *start = now;
+            *count = 0;
+        }
+
+        if *count >= limit {
+            return false;
+        }
+
+        *count += 1;
+        true
+    }
+}
@@ -1,2 +1,19 @@
+pub mod access;
+pub mod cache;
+pub mod circuits;
+pub mod dashboard;
+pub mod diagnostics;
 pub mod encode_url;
+pub mod encoding_keys;
+pub mod keys;
+pub mod onboarding;
+pub mod openapi;
+pub mod qr;
+pub mod recordings;
 pub mod service;
+pub mod session;
+pub mod shorten;
+pub mod snapshot;
+pub mod stats;
+pub mod upstream;
+pub mod usage;
@@ -0,0 +1,102 @@
+use axum::{
+    debug_handler,
+    http::{header::LOCATION, StatusCode},
+    response::{Html, IntoResponse},
+    Extension, Form, Json,
+};
+use hyper::header::CONTENT_TYPE;
+use serde_json::{json, Value};
+
+use crate::{
+    pages::bookmarklet_page, proxy::util::encode_url, state::Config, tenant::TenantConfig,
+};
+
+use super::encode_url::EncodeUrlRequest;
+
+/// Where the bookmarklet and the extension send URLs, the API host serves every tenant's API
+/// whether or not it is also served under a path prefix
+fn api_base(config: &Config) -> String {
+    format!("https://api.{}", config.public_host)
+}
+
+/// A `javascript:` URL that opens the current page through the proxy. It posts the page's URL to
+/// `/open` instead of putting it in a query string, so it doesn't end up in the history.
+fn bookmarklet(config: &Config) -> String {
+    let action = serde_json::to_string(&format!("{}/open", api_base(config))).unwrap();
+
+    format!(
+        "javascript:(()=>{{const f=document.createElement('form');f.method='post';f.action={};\
+         const i=document.createElement('input');i.type='hidden';i.name='url';\
+         i.value=location.href;f.append(i);document.documentElement.append(f);f.submit()}})()",
+        action
+    )
+}
+
+#[utoipa::path(
+    get, path = "/bookmarklet", tag = "onboarding",
+    responses((status = 200, content_type = "text/html", body = String))
+)]
+#[debug_handler]
+/// A page with the bookmarklet, to drag to the bookmarks bar
+pub async fn get_bookmarklet(
+    Extension(TenantConfig(config)): Extension<TenantConfig>,
+) -> Html<String> {
+    Html(bookmarklet_page(&config.public_host, &bookmarklet(&config)))
+}
+
+#[utoipa::path(
+    get, path = "/extension/manifest.json", tag = "onboarding",
+    responses((status = 200, body = Object))
+)]
+#[debug_handler]
+/// The manifest of a minimal browser extension, whose button opens the current tab through the
+/// proxy. It is loaded unpacked from a directory with this and `background.js`.
+pub async fn get_extension_manifest(
+    Extension(TenantConfig(config)): Extension<TenantConfig>,
+) -> Json<Value> {
+    Json(json!({
+        "manifest_version": 3,
+        "name": format!("Open through {}", config.public_host),
+        "version": "1.0",
+        "description": format!("Opens the current tab through the proxy at {}", config.public_host),
+        "action": { "default_title": format!("Open through {}", config.public_host) },
+        "background": { "service_worker": "background.js" },
+        "host_permissions": [format!("{}/*", api_base(&config))],
+    }))
+}
+
+#[utoipa::path(
+    get, path = "/extension/background.js", tag = "onboarding",
+    responses((status = 200, content_type = "text/javascript", body = String))
+)]
+#[debug_handler]
+/// The background script of the extension from [`get_extension_manifest`]
+pub async fn get_extension_script(
+    Extension(TenantConfig(config)): Extension<TenantConfig>,
+) -> impl IntoResponse {
+    let encode = serde_json::to_string(&format!("{}/encode?url=", api_base(&config))).unwrap();
+
+    (
+        [(CONTENT_TYPE, "text/javascript")],
+        format!(
+            "chrome.action.onClicked.addListener((tab) => {{\n    \
+             chrome.tabs.update(tab.id, {{ url: {} + encodeURIComponent(tab.url) }});\n}});\n",
+            encode
+        ),
+    )
+}
+
+#[utoipa::path(
+    post, path = "/open", tag = "encode", security(("api_key" = [])),
+    request_body(content = EncodeUrlRequest, content_type = "application/x-www-form-urlencoded"),
+    responses((status = 302, description = "Redirects to the URL through the proxy"))
+)]
+#[debug_handler]
+/// Redirect to the URL through the proxy, like `GET /encode` but for forms, which is what the
+/// bookmarklet submits
+pub async fn post_open(
+    Extension(TenantConfig(config)): Extension<TenantConfig>,
+    Form(EncodeUrlRequest { url }): Form<EncodeUrlRequest>,
+) -> impl IntoResponse {
+    (StatusCode::FOUND, [(LOCATION, encode_url(&config, &url))])
+}
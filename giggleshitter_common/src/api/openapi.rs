@@ -0,0 +1,76 @@
+use utoipa::{
+    openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+    Modify, OpenApi,
+};
+
+use super::{
+    access, cache, circuits, dashboard, diagnostics, encode_url, encoding_keys, onboarding, qr,
+    recordings, session, shorten, snapshot, stats, upstream, usage,
+};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "giggleshitter API",
+        description = "The API served on `api.<public host>`, and under the API path prefix of \
+                       the public host when one is configured"
+    ),
+    paths(
+        encode_url::get_encode,
+        encode_url::post_encode,
+        encode_url::post_encode_batch,
+        qr::get_encode_qr,
+        onboarding::post_open,
+        onboarding::get_bookmarklet,
+        onboarding::get_extension_manifest,
+        onboarding::get_extension_script,
+        shorten::post_shorten,
+        shorten::get_short_link,
+        snapshot::post_snapshot,
+        snapshot::get_snapshot,
+        session::post_session,
+        usage::get_usage,
+        dashboard::get_admin,
+        dashboard::get_dashboard,
+        diagnostics::get_diagnostics,
+        access::get_access_stats,
+        access::get_access_rules,
+        access::post_access_rule,
+        cache::get_origin_cache_stats,
+        circuits::get_circuits,
+        encoding_keys::get_encoding_keys,
+        recordings::get_recordings,
+        recordings::get_recording,
+        stats::get_stats,
+        upstream::get_upstream,
+    ),
+    modifiers(&ApiKey),
+    tags(
+        (name = "encode", description = "Making proxied URLs, needs a key with the Encode scope"),
+        (name = "onboarding", description = "Ways for users to open pages through the proxy"),
+        (name = "sessions", description = "The calling client's session and usage"),
+        (name = "admin", description = "Statistics and settings, needs a key with the Admin scope"),
+    )
+)]
+/// The OpenAPI document of the API, served as `/openapi.json` with Swagger UI at `/docs`
+pub struct ApiDoc;
+
+pub fn openapi_json() -> String {
+    ApiDoc::openapi()
+        .to_pretty_json()
+        .expect("the OpenAPI document serializes")
+}
+
+/// API keys are sent as bearer tokens, or in `X-GS-API-Key` by clients that can't set
+/// `Authorization`. Encoding only needs one when keys are configured, the admin API always does.
+struct ApiKey;
+
+impl Modify for ApiKey {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+    }
+}
@@ -0,0 +1,95 @@
+use axum::{
+    debug_handler,
+    extract::Query,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use hyper::{header::CONTENT_TYPE, StatusCode};
+use qrcode::{render::svg, QrCode};
+use serde::Deserialize;
+use serde_json::json;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{proxy::util::encode_url, tenant::TenantConfig};
+
+/// The smallest width and height of a QR code, in pixels
+const MIN_SIZE: u32 = 256;
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum QrFormat {
+    #[default]
+    Svg,
+    /// Only available when built with the `media` feature
+    Png,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct QrRequest {
+    pub url: String,
+    #[serde(default)]
+    pub format: QrFormat,
+}
+
+#[utoipa::path(
+    get, path = "/encode/qr", tag = "encode", security(("api_key" = [])),
+    params(QrRequest),
+    responses(
+        (status = 200, content((String = "image/svg+xml"), (Vec<u8> = "image/png"))),
+        (status = 400, description = "The URL is too long, or PNGs aren't available")
+    )
+)]
+#[debug_handler]
+/// A QR code of the URL through the proxy, for opening it on another device
+pub async fn get_encode_qr(
+    Extension(TenantConfig(config)): Extension<TenantConfig>,
+    Query(QrRequest { url, format }): Query<QrRequest>,
+) -> Response {
+    let code = match QrCode::new(encode_url(&config, &url)) {
+        Ok(code) => code,
+        Err(e) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": format!("the URL can't be made into a QR code: {}", e) })),
+            )
+                .into_response()
+        }
+    };
+
+    match format {
+        QrFormat::Svg => {
+            let image = code
+                .render::<svg::Color>()
+                .min_dimensions(MIN_SIZE, MIN_SIZE)
+                .build();
+
+            ([(CONTENT_TYPE, "image/svg+xml")], image).into_response()
+        }
+        QrFormat::Png => png_response(&code),
+    }
+}
+
+#[cfg(feature = "media")]
+fn png_response(code: &QrCode) -> Response {
+    use image::{codecs::png::PngEncoder, DynamicImage, Luma};
+
+    let image = code
+        .render::<Luma<u8>>()
+        .min_dimensions(MIN_SIZE, MIN_SIZE)
+        .build();
+
+    let mut png = vec![];
+    match DynamicImage::ImageLuma8(image).write_with_encoder(PngEncoder::new(&mut png)) {
+        Ok(()) => ([(CONTENT_TYPE, "image/png")], png).into_response(),
+        Err(e) => crate::error::AppError::from(e).into_response(),
+    }
+}
+
+#[cfg(not(feature = "media"))]
+fn png_response(_code: &QrCode) -> Response {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(json!({ "error": "PNG QR codes need the media feature, ask for an SVG instead" })),
+    )
+        .into_response()
+}
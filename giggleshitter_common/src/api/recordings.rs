@@ -0,0 +1,86 @@
+use axum::{
+    body::Body,
+    debug_handler,
+    extract::Path,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use hyper::{
+    header::{CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE},
+    StatusCode,
+};
+use serde::Serialize;
+use tokio_util::io::ReaderStream;
+use utoipa::ToSchema;
+
+use crate::{
+    error::Result,
+    recording::{self, Archive},
+    tenant::TenantConfig,
+};
+
+#[derive(Serialize, ToSchema)]
+pub struct RecordingsResponse {
+    /// The WARC files, newest first. The newest one may still be written to.
+    pub recordings: Vec<Archive>,
+}
+
+#[utoipa::path(
+    get, path = "/recordings", tag = "admin", security(("api_key" = [])),
+    responses((status = 200, body = RecordingsResponse))
+)]
+#[debug_handler]
+/// The WARC files that proxied exchanges were recorded into
+pub async fn get_recordings(
+    Extension(TenantConfig(config)): Extension<TenantConfig>,
+) -> Result<Json<RecordingsResponse>> {
+    let recordings = match &config.recording.dir {
+        Some(dir) => recording::list(dir).await?,
+        None => vec![],
+    };
+
+    Ok(Json(RecordingsResponse { recordings }))
+}
+
+#[utoipa::path(
+    get, path = "/recordings/{name}", tag = "admin", security(("api_key" = [])),
+    params(("name" = String, Path)),
+    responses((status = 200, content_type = "application/warc", body = Vec<u8>), (status = 404))
+)]
+#[debug_handler]
+/// Download a WARC file
+pub async fn get_recording(
+    Extension(TenantConfig(config)): Extension<TenantConfig>,
+    Path(name): Path<String>,
+) -> Result<Response> {
+    let Some(path) = config
+        .recording
+        .dir
+        .as_deref()
+        .and_then(|dir| recording::archive_path(dir, &name))
+    else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+
+    let file = match tokio::fs::File::open(path).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(StatusCode::NOT_FOUND.into_response())
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let length = file.metadata().await?.len();
+
+    Ok((
+        [
+            (CONTENT_TYPE, "application/warc".to_string()),
+            (CONTENT_LENGTH, length.to_string()),
+            (
+                CONTENT_DISPOSITION,
+                format!(r#"attachment; filename="{}""#, name),
+            ),
+        ],
+        Body::from_stream(ReaderStream::new(file)),
+    )
+        .into_response())
+}
@@ -1,35 +1,171 @@
 use std::sync::Arc;
 
 use axum::{
-    extract::State,
-    http::StatusCode,
-    response::IntoResponse,
+    extract::{Request, State},
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Response},
     routing::{get, post},
-    Router,
+    Extension, Json, Router,
 };
-use hyper::Method;
+use hyper::{header::WWW_AUTHENTICATE, Method, StatusCode};
+use serde_json::json;
 use tower_http::cors::{Any, CorsLayer};
+use utoipa::OpenApi;
+use utoipa_swagger_ui::SwaggerUi;
 
-use crate::APIState;
+use crate::{
+    pages::index_page,
+    state::{APIState, ApiScope, Config},
+    tenant::TenantConfig,
+};
 
-use super::encode_url::post_encode;
+use super::{
+    access::{get_access_rules, get_access_stats, post_access_rule},
+    cache::get_origin_cache_stats,
+    circuits::get_circuits,
+    dashboard::{get_admin, get_dashboard},
+    diagnostics::get_diagnostics,
+    encode_url::{get_encode, post_encode, post_encode_batch},
+    encoding_keys::get_encoding_keys,
+    keys::find_key,
+    onboarding::{get_bookmarklet, get_extension_manifest, get_extension_script, post_open},
+    openapi::ApiDoc,
+    qr::get_encode_qr,
+    recordings::{get_recording, get_recordings},
+    session::post_session,
+    shorten::{get_short_link, post_shorten},
+    snapshot::{get_snapshot, post_snapshot},
+    stats::get_stats,
+    upstream::get_upstream,
+    usage::get_usage,
+};
 
 pub fn service(state: Arc<APIState>) -> Router {
+    // Keys are only accepted from headers, which browsers never attach on their own, so letting
+    // any origin call the API can't be used to make requests on someone else's behalf. The admin
+    // API is only used by the dashboard on the API host itself, and isn't opened to other origins.
     let cors = CorsLayer::new()
         .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
         .allow_headers(Any)
         .allow_origin(Any);
 
+    let encode = Router::new()
+        .route("/encode", get(get_encode).post(post_encode))
+        .route("/encode/batch", post(post_encode_batch))
+        .route("/encode/qr", get(get_encode_qr))
+        .route("/open", post(post_open))
+        .route("/shorten", post(post_shorten))
+        .route("/snapshot", post(post_snapshot))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            require_encode_scope,
+        ));
+
+    let admin = Router::new()
+        .route("/access", get(get_access_stats))
+        .route(
+            "/access/rules",
+            get(get_access_rules).post(post_access_rule),
+        )
+        .route("/cache", get(get_origin_cache_stats))
+        .route("/circuits", get(get_circuits))
+        .route("/dashboard", get(get_dashboard))
+        .route("/diagnostics/:id", get(get_diagnostics))
+        .route("/encoding-keys", get(get_encoding_keys))
+        .route("/recordings", get(get_recordings))
+        .route("/recordings/:name", get(get_recording))
+        .route("/stats", get(get_stats))
+        .route("/upstream", get(get_upstream))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            require_admin_scope,
+        ));
+
     Router::new()
         .route("/", get(index))
-        .route("/encode", post(post_encode))
+        .route("/admin", get(get_admin))
+        .route("/bookmarklet", get(get_bookmarklet))
+        .route("/extension/manifest.json", get(get_extension_manifest))
+        .route("/extension/background.js", get(get_extension_script))
+        .route("/usage", get(get_usage))
+        .route("/session", post(post_session))
+        .route("/snapshot/:id", get(get_snapshot))
+        .route("/s/:id", get(get_short_link))
+        .merge(encode)
+        .merge(SwaggerUi::new("/docs").url("/openapi.json", ApiDoc::openapi()))
         .layer(cors)
+        .merge(admin)
         .with_state(state)
 }
 
-async fn index(State(state): State<Arc<APIState>>) -> impl IntoResponse {
-    (
-        StatusCode::OK,
-        format!("Hello, world! Configured host: {}", state.config.host),
-    )
+async fn index(Extension(TenantConfig(config)): Extension<TenantConfig>) -> Html<String> {
+    Html(index_page(&config.public_host))
+}
+
+async fn require_encode_scope(
+    State(state): State<Arc<APIState>>,
+    Extension(TenantConfig(config)): Extension<TenantConfig>,
+    req: Request,
+    next: Next,
+) -> Response {
+    authorize(&state, &config, ApiScope::Encode, req, next).await
+}
+
+async fn require_admin_scope(
+    State(state): State<Arc<APIState>>,
+    Extension(TenantConfig(config)): Extension<TenantConfig>,
+    req: Request,
+    next: Next,
+) -> Response {
+    authorize(&state, &config, ApiScope::Admin, req, next).await
+}
+
+/// Let the request through if it carries an API key with at least `scope` that is within its rate
+/// limit. Without any keys configured only encoding is open to everyone, the admin API stays
+/// closed until an admin key is configured.
+async fn authorize(
+    state: &APIState,
+    config: &Config,
+    scope: ApiScope,
+    req: Request,
+    next: Next,
+) -> Response {
+    if scope == ApiScope::Admin && !config.api_keys.iter().any(|key| key.scope >= scope) {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({ "error": "the admin API is disabled until an admin key is configured" })),
+        )
+            .into_response();
+    }
+
+    if config.api_keys.is_empty() {
+        return next.run(req).await;
+    }
+
+    let Some(key) = find_key(config, req.headers()) else {
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(WWW_AUTHENTICATE, "Bearer")],
+            Json(json!({ "error": "a valid API key is required" })),
+        )
+            .into_response();
+    };
+
+    if key.scope < scope {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({ "error": format!("the API key {} may not do this", key.name) })),
+        )
+            .into_response();
+    }
+
+    if !state.rate_limiter.check(key) {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            Json(json!({ "error": format!("the API key {} is over its rate limit", key.name) })),
+        )
+            .into_response();
+    }
+
+    next.run(req).await
 }
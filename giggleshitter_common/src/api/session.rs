@@ -0,0 +1,60 @@
+use axum::{
+    debug_handler,
+    extract::Query,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use hyper::{header::SET_COOKIE, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+use utoipa::IntoParams;
+
+use crate::{session::Session, tenant::TenantConfig};
+
+#[derive(Deserialize, IntoParams)]
+pub struct SessionRequest {
+    /// Record the session's exchanges, when the configuration lets sessions be recorded
+    #[serde(default)]
+    pub record: bool,
+}
+
+#[utoipa::path(
+    post, path = "/session", tag = "sessions",
+    params(SessionRequest),
+    responses(
+        (status = 204, description = "Sets the session cookie"),
+        (status = 403, description = "The session may not be recorded"),
+        (status = 404, description = "Sessions aren't enabled")
+    )
+)]
+#[debug_handler]
+/// Start a new session, replacing the caller's current one so that the links encoded for it stop
+/// working
+pub async fn post_session(
+    Extension(TenantConfig(config)): Extension<TenantConfig>,
+    Query(SessionRequest { record }): Query<SessionRequest>,
+) -> Response {
+    if !config.sessions.enabled {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let mut session = Session::new();
+
+    if record {
+        if config.recording.dir.is_none() || !config.recording.sessions {
+            return (
+                StatusCode::FORBIDDEN,
+                Json(json!({ "error": "sessions may not be recorded" })),
+            )
+                .into_response();
+        }
+
+        session = session.recorded();
+    }
+
+    (
+        StatusCode::NO_CONTENT,
+        [(SET_COOKIE, session.cookie(&config))],
+    )
+        .into_response()
+}
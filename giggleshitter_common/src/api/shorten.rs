@@ -0,0 +1,96 @@
+use axum::{
+    debug_handler,
+    extract::Path,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use hyper::{header::LOCATION, StatusCode};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use utoipa::ToSchema;
+
+use crate::{error::AppError, proxy::util::encode_url, shortener, tenant::TenantConfig};
+
+#[derive(Deserialize, ToSchema)]
+pub struct ShortenRequest {
+    /// A proxied URL, or the URL of a site, which is proxied first
+    pub url: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ShortenResponse {
+    pub id: String,
+    /// `https://<public host>/s/<id>`
+    pub short_url: String,
+}
+
+#[utoipa::path(
+    post, path = "/shorten", tag = "encode", security(("api_key" = [])),
+    request_body = ShortenRequest,
+    responses(
+        (status = 200, body = ShortenResponse),
+        (status = 400, description = "Short links aren't enabled, or the URL isn't valid")
+    )
+)]
+#[debug_handler]
+/// Make a short link to a proxied URL
+pub async fn post_shorten(
+    Extension(TenantConfig(config)): Extension<TenantConfig>,
+    Json(ShortenRequest { url }): Json<ShortenRequest>,
+) -> Response {
+    let Some(dir) = &config.shortener.dir else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "short links are not enabled" })),
+        )
+            .into_response();
+    };
+
+    let valid = Url::parse(&url)
+        .is_ok_and(|url| matches!(url.scheme(), "http" | "https") && url.host_str().is_some());
+    if !valid {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": format!("{} is not a valid URL", url) })),
+        )
+            .into_response();
+    };
+
+    // Only links through this proxy are stored, so short links can't point anywhere else
+    let proxied = encode_url(&config, &url);
+
+    match shortener::store(dir, &proxied, config.shortener.id_length).await {
+        Ok(id) => Json(ShortenResponse {
+            short_url: format!("https://{}/s/{}", config.public_host, id),
+            id,
+        })
+        .into_response(),
+        Err(e) => AppError::from(e).into_response(),
+    }
+}
+
+#[utoipa::path(
+    get, path = "/s/{id}", tag = "encode",
+    params(("id" = String, Path)),
+    responses(
+        (status = 302, description = "Redirects to the proxied URL"),
+        (status = 404)
+    )
+)]
+#[debug_handler]
+/// Redirect to the proxied URL a short link was made for
+pub async fn get_short_link(
+    Extension(TenantConfig(config)): Extension<TenantConfig>,
+    Path(id): Path<String>,
+) -> Response {
+    let Some(dir) = &config.shortener.dir else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    match shortener::load(dir, &id).await {
+        Ok(Some(url)) => (StatusCode::FOUND, [(LOCATION, url)]).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => AppError::from(e).into_response(),
+    }
+}
@@ -0,0 +1,111 @@
+use std::sync::Arc;
+
+use axum::{
+    debug_handler,
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use hyper::{
+    header::{CONTENT_SECURITY_POLICY, CONTENT_TYPE},
+    StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use utoipa::ToSchema;
+
+use crate::{error::AppError, snapshot, state::APIState, tenant::TenantConfig};
+
+#[derive(Deserialize, ToSchema)]
+pub struct SnapshotRequest {
+    pub url: String,
+    /// Keep the snapshot on the server and respond with its ID, rather than with the snapshot
+    #[serde(default)]
+    pub store: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct StoredSnapshotResponse {
+    /// Where the snapshot can be fetched, under `/snapshot/`
+    pub id: String,
+}
+
+#[utoipa::path(
+    post, path = "/snapshot", tag = "encode", security(("api_key" = [])),
+    request_body = SnapshotRequest,
+    responses(
+        (status = 200, content(
+            (String = "text/html"),
+            (StoredSnapshotResponse = "application/json")
+        ))
+    )
+)]
+#[debug_handler]
+/// Save a page with its stylesheets and images inlined, as a single HTML document
+pub async fn post_snapshot(
+    State(state): State<Arc<APIState>>,
+    Extension(TenantConfig(config)): Extension<TenantConfig>,
+    Json(SnapshotRequest { url, store }): Json<SnapshotRequest>,
+) -> Response {
+    let dir = match (&config.snapshots.dir, store) {
+        (_, false) => None,
+        (Some(dir), true) => Some(dir),
+        (None, true) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": "storing snapshots is not enabled" })),
+            )
+                .into_response()
+        }
+    };
+
+    let snapshot = match snapshot::capture(&state.client, &config, &url).await {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            return (e.status(), Json(json!({ "error": e.to_string() }))).into_response();
+        }
+    };
+
+    match dir {
+        Some(dir) => match snapshot::store(dir, &snapshot).await {
+            Ok(id) => Json(StoredSnapshotResponse { id }).into_response(),
+            Err(e) => AppError::from(e).into_response(),
+        },
+        None => snapshot_response(snapshot),
+    }
+}
+
+#[utoipa::path(
+    get, path = "/snapshot/{id}", tag = "encode",
+    params(("id" = String, Path)),
+    responses((status = 200, content_type = "text/html", body = String), (status = 404))
+)]
+#[debug_handler]
+/// A stored snapshot. IDs can't be guessed, so they are shared without needing an API key.
+pub async fn get_snapshot(
+    Extension(TenantConfig(config)): Extension<TenantConfig>,
+    Path(id): Path<String>,
+) -> Response {
+    let Some(dir) = &config.snapshots.dir else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    match snapshot::load(dir, &id).await {
+        Ok(Some(snapshot)) => snapshot_response(snapshot),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => AppError::from(e).into_response(),
+    }
+}
+
+/// Snapshots are served from the API's origin, so they are sandboxed in case anything that runs
+/// script made it through
+fn snapshot_response(snapshot: String) -> Response {
+    (
+        [
+            (CONTENT_TYPE, "text/html; charset=utf-8"),
+            (CONTENT_SECURITY_POLICY, "sandbox; script-src 'none'"),
+        ],
+        snapshot,
+    )
+        .into_response()
+}
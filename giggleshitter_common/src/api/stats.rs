@@ -0,0 +1,15 @@
+use std::sync::Arc;
+
+use axum::{debug_handler, extract::State, Json};
+
+use crate::{metrics::Stats, state::APIState};
+
+#[utoipa::path(
+    get, path = "/stats", tag = "admin", security(("api_key" = [])),
+    responses((status = 200, body = Stats))
+)]
+#[debug_handler]
+/// The latencies of the requests proxied to each upstream origin, and the recent slow requests
+pub async fn get_stats(State(state): State<Arc<APIState>>) -> Json<Stats> {
+    Json(state.metrics.snapshot())
+}
@@ -0,0 +1,33 @@
+use std::sync::Arc;
+
+use axum::{debug_handler, extract::State, Json};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{
+    state::{APIState, UpstreamConfig},
+    upstream::HostConnectionStats,
+};
+
+#[derive(Serialize, ToSchema)]
+pub struct UpstreamResponse {
+    /// The connection settings the upstream clients were built with
+    #[schema(value_type = Object)]
+    pub settings: UpstreamConfig,
+    pub hosts: Vec<HostConnectionStats>,
+}
+
+#[utoipa::path(
+    get, path = "/upstream", tag = "admin", security(("api_key" = [])),
+    responses((status = 200, body = UpstreamResponse))
+)]
+#[debug_handler]
+/// The upstream connection settings in effect, and how often each upstream host's connections
+/// were reused
+pub async fn get_upstream(State(state): State<Arc<APIState>>) -> Json<UpstreamResponse> {
+    Json(UpstreamResponse {
+        // The clients are built at startup, so later changes to the configuration aren't in effect
+        settings: state.upstream.clone(),
+        hosts: state.connections.snapshot(),
+    })
+}
@@ -0,0 +1,39 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    debug_handler,
+    extract::{ConnectInfo, State},
+    Json,
+};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::state::APIState;
+
+#[derive(Serialize, ToSchema)]
+pub struct UsageResponse {
+    /// Bytes transferred by the calling client today, unset when the client has no address
+    pub bytes: Option<u64>,
+    pub quota: Option<u64>,
+    pub remaining: Option<u64>,
+}
+
+#[utoipa::path(
+    get, path = "/usage", tag = "sessions",
+    responses((status = 200, body = UsageResponse))
+)]
+#[debug_handler]
+/// The bandwidth used by the calling client today
+pub async fn get_usage(
+    State(state): State<Arc<APIState>>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+) -> Json<UsageResponse> {
+    let bytes = connect_info.map(|ConnectInfo(addr)| state.usage.get(addr.ip()));
+    let quota = state.usage.quota();
+
+    Json(UsageResponse {
+        bytes,
+        quota,
+        remaining: quota.map(|quota| quota.saturating_sub(bytes.unwrap_or_default())),
+    })
+}
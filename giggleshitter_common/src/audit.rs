@@ -0,0 +1,218 @@
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use chrono::{SecondsFormat, Utc};
+use scorched::{logf, LogData, LogImportance};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::mpsc,
+};
+
+use crate::state::{AuditConfig, LiveConfig};
+
+#[derive(Serialize)]
+/// A single line of the audit log
+pub struct AuditRecord {
+    /// When the request was made, in RFC 3339 format
+    pub timestamp: String,
+    /// A salted hash of the client IP, unset for clients connected over a Unix socket
+    pub client: Option<String>,
+    /// The decoded upstream origin
+    pub origin: String,
+    /// The path and query of the request
+    pub path: String,
+    pub status: u16,
+    /// The size of the response body sent to the client
+    pub bytes: u64,
+}
+
+#[derive(Clone)]
+/// A handle to the background task that writes the audit log. Records are dropped while the
+/// current configuration has auditing disabled.
+pub struct AuditLog {
+    config: LiveConfig,
+    tx: mpsc::UnboundedSender<AuditRecord>,
+}
+
+impl AuditLog {
+    /// Start the writer task, which follows the audit settings of the live configuration
+    pub fn spawn(config: LiveConfig) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(write_records(config.clone(), rx));
+
+        Self { config, tx }
+    }
+
+    /// Start a record for a response, which is written once the [`PendingRecord`] is dropped so
+    /// that streamed bodies can be counted as they are sent
+    pub fn start(
+        &self,
+        client: Option<IpAddr>,
+        origin: String,
+        path: String,
+        status: u16,
+    ) -> Option<PendingRecord> {
+        let config = self.config.load();
+        let audit = config.audit.as_ref()?;
+
+        Some(PendingRecord {
+            log: self.clone(),
+            record: Some(AuditRecord {
+                timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+                client: client.map(|ip| hash_ip(&audit.ip_salt, ip)),
+                origin,
+                path,
+                status,
+                bytes: 0,
+            }),
+        })
+    }
+}
+
+/// An audit record that is written when dropped
+pub struct PendingRecord {
+    log: AuditLog,
+    record: Option<AuditRecord>,
+}
+
+impl PendingRecord {
+    pub fn add_bytes(&mut self, bytes: usize) {
+        if let Some(record) = self.record.as_mut() {
+            record.bytes += bytes as u64;
+        }
+    }
+}
+
+impl Drop for PendingRecord {
+    fn drop(&mut self) {
+        if let Some(record) = self.record.take() {
+            let _ = self.log.tx.send(record);
+        }
+    }
+}
+
+fn hash_ip(salt: &str, ip: IpAddr) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(ip.to_string().as_bytes());
+
+    // Half of the digest is plenty to tell clients apart
+    hasher.finalize()[..16]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// The open audit file, along with the path it was opened at and its current size
+struct Output {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+async fn write_records(config: LiveConfig, mut rx: mpsc::UnboundedReceiver<AuditRecord>) {
+    let mut output: Option<Output> = None;
+
+    while let Some(record) = rx.recv().await {
+        let Some(audit) = config.load().audit.clone() else {
+            output = None;
+            continue;
+        };
+
+        let mut line = match serde_json::to_vec(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                logf!(Error, "Error serializing audit record: {}", e);
+                continue;
+            }
+        };
+        line.push(b'\n');
+
+        if let Err(e) = write_line(&audit, &mut output, &line).await {
+            logf!(
+                Error,
+                "Error writing audit log {}: {}",
+                audit.path.display(),
+                e
+            );
+            output = None;
+        }
+    }
+}
+
+async fn write_line(
+    audit: &AuditConfig,
+    output: &mut Option<Output>,
+    line: &[u8],
+) -> std::io::Result<()> {
+    // Reopen the file when the configured path changed
+    if output
+        .as_ref()
+        .is_some_and(|output| output.path != audit.path)
+    {
+        *output = None;
+    }
+
+    if output
+        .as_ref()
+        .is_some_and(|output| output.size > 0 && output.size + line.len() as u64 > audit.max_bytes)
+    {
+        *output = None;
+        rotate(&audit.path, audit.max_files).await?;
+    }
+
+    let output = match output {
+        Some(output) => output,
+        None => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&audit.path)
+                .await?;
+            let size = file.metadata().await?.len();
+
+            output.insert(Output {
+                path: audit.path.clone(),
+                file,
+                size,
+            })
+        }
+    };
+
+    output.file.write_all(line).await?;
+    output.size += line.len() as u64;
+
+    Ok(())
+}
+
+/// Shift `path.1` to `path.2` and so on, dropping the files past `max_files`, then move the
+/// current file to `path.1`
+async fn rotate(path: &Path, max_files: usize) -> std::io::Result<()> {
+    let rotated = |index: usize| {
+        let mut rotated = path.as_os_str().to_owned();
+        rotated.push(format!(".{}", index));
+        PathBuf::from(rotated)
+    };
+
+    if max_files == 0 {
+        return fs::remove_file(path).await;
+    }
+
+    let _ = fs::remove_file(rotated(max_files)).await;
+
+    for index in (1..max_files).rev() {
+        let from = rotated(index);
+
+        if fs::try_exists(&from).await? {
+            fs::rename(from, rotated(index + 1)).await?;
+        }
+    }
+
+    fs::rename(path, rotated(1)).await
+}
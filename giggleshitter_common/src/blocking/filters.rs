@@ -0,0 +1,564 @@
+use std::collections::{HashMap, HashSet};
+
+/// The resource types network filters can be restricted to, as named in filter lists
+const RESOURCE_TYPES: &[&str] = &[
+    "document",
+    "font",
+    "image",
+    "media",
+    "object",
+    "other",
+    "ping",
+    "script",
+    "stylesheet",
+    "subdocument",
+    "websocket",
+    "xmlhttprequest",
+];
+
+/// The parsed rules of one or more EasyList/uBlock style filter lists. Only the commonly used
+/// subset of the syntax is understood, rules using anything else are skipped rather than guessed
+/// at.
+#[derive(Default)]
+pub struct FilterSet {
+    /// Block rules anchored to a host (`||example.com^`), keyed by that host
+    host_blocks: HashMap<String, Vec<NetworkRule>>,
+    blocks: Vec<NetworkRule>,
+    exceptions: Vec<NetworkRule>,
+    /// Element hiding selectors that apply to every site unless it is excluded
+    generic_hiding: Vec<CosmeticRule>,
+    /// Element hiding selectors for specific sites, keyed by host
+    specific_hiding: HashMap<String, Vec<String>>,
+    hiding_exceptions: HashSet<String>,
+}
+
+/// A resource request checked against the network rules
+pub struct ResourceRequest<'a> {
+    /// The full upstream URL, lowercased
+    pub url: &'a str,
+    /// The host of the upstream URL
+    pub host: &'a str,
+    /// The host of the page that loaded the resource, if known
+    pub source_host: Option<&'a str>,
+    /// The resource type in filter list terms, if known
+    pub resource_type: Option<&'static str>,
+}
+
+struct NetworkRule {
+    pattern: String,
+    anchor: Anchor,
+    end_anchor: bool,
+    options: RuleOptions,
+}
+
+#[derive(PartialEq, Eq)]
+enum Anchor {
+    None,
+    /// `|`, the pattern matches from the start of the URL
+    Start,
+    /// `||`, the pattern matches from the start of the host or one of its subdomains
+    Host,
+}
+
+#[derive(Default)]
+struct RuleOptions {
+    third_party: Option<bool>,
+    include_domains: Vec<String>,
+    exclude_domains: Vec<String>,
+    include_types: Vec<&'static str>,
+    exclude_types: Vec<&'static str>,
+}
+
+struct CosmeticRule {
+    selector: String,
+    exclude_domains: Vec<String>,
+}
+
+impl FilterSet {
+    /// Parse the text of a filter list, adding its rules to the set
+    pub fn add_list(&mut self, list: &str) {
+        for line in list.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('!') || line.starts_with('[') {
+                continue;
+            }
+
+            if let Some((domains, selector)) = line.split_once("#@#") {
+                if domains.is_empty() {
+                    self.hiding_exceptions.insert(selector.to_string());
+                }
+            } else if let Some((domains, selector)) = line.split_once("##") {
+                self.add_cosmetic(domains, selector);
+            } else if line.contains("#?#") || line.contains("#$#") || line.contains("#%#") {
+                // Procedural filters and scriptlets need a content script
+            } else if let Some(rule) = line.strip_prefix("@@") {
+                if let Some(rule) = NetworkRule::parse(rule) {
+                    self.exceptions.push(rule);
+                }
+            } else if let Some(rule) = NetworkRule::parse(line) {
+                match rule.host_key() {
+                    Some(host) => self.host_blocks.entry(host).or_default().push(rule),
+                    None => self.blocks.push(rule),
+                }
+            }
+        }
+    }
+
+    fn add_cosmetic(&mut self, domains: &str, selector: &str) {
+        // Extended selectors and scriptlets such as `+js(...)` or `:has-text(...)`
+        if selector.is_empty() || selector.starts_with('+') || selector.contains(":-abp-") {
+            return;
+        }
+
+        let (include, exclude): (Vec<_>, Vec<_>) = domains
+            .split(',')
+            .map(str::trim)
+            .filter(|domain| !domain.is_empty())
+            .partition(|domain| !domain.starts_with('~'));
+
+        if include.is_empty() {
+            self.generic_hiding.push(CosmeticRule {
+                selector: selector.to_string(),
+                exclude_domains: exclude
+                    .iter()
+                    .map(|domain| domain[1..].to_ascii_lowercase())
+                    .collect(),
+            });
+        } else {
+            for domain in include {
+                self.specific_hiding
+                    .entry(domain.to_ascii_lowercase())
+                    .or_default()
+                    .push(selector.to_string());
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.host_blocks.is_empty()
+            && self.blocks.is_empty()
+            && self.generic_hiding.is_empty()
+            && self.specific_hiding.is_empty()
+    }
+
+    /// The pattern of the rule blocking the request, unless an exception allows it
+    pub fn blocking_rule(&self, request: &ResourceRequest) -> Option<&str> {
+        let host_rules = parent_domains(request.host)
+            .filter_map(|domain| self.host_blocks.get(domain))
+            .flatten();
+
+        let rule = host_rules
+            .chain(&self.blocks)
+            .find(|rule| rule.matches(request))?;
+
+        if self.exceptions.iter().any(|rule| rule.matches(request)) {
+            return None;
+        }
+
+        Some(&rule.pattern)
+    }
+
+    /// The element hiding selectors that apply to pages on `host`, including the generic ones
+    /// when asked for
+    pub fn hiding_selectors(&self, host: &str, generic: bool) -> Vec<&str> {
+        let specific = parent_domains(host)
+            .filter_map(|domain| self.specific_hiding.get(domain))
+            .flatten()
+            .map(String::as_str);
+
+        let generic = self
+            .generic_hiding
+            .iter()
+            .filter(move |_| generic)
+            .filter(|rule| {
+                !rule
+                    .exclude_domains
+                    .iter()
+                    .any(|domain| is_subdomain_of(host, domain))
+            })
+            .map(|rule| rule.selector.as_str());
+
+        specific
+            .chain(generic)
+            .filter(|selector| !self.hiding_exceptions.contains(*selector))
+            .collect()
+    }
+}
+
+impl NetworkRule {
+    fn parse(rule: &str) -> Option<Self> {
+        // Regular expression rules
+        if rule.starts_with('/') && rule.ends_with('/') && rule.len() > 1 {
+            return None;
+        }
+
+        let (pattern, options) = match rule.rfind('$') {
+            Some(index) => (&rule[..index], RuleOptions::parse(&rule[index + 1..])?),
+            None => (rule, RuleOptions::default()),
+        };
+
+        let (anchor, pattern) = if let Some(pattern) = pattern.strip_prefix("||") {
+            (Anchor::Host, pattern)
+        } else if let Some(pattern) = pattern.strip_prefix('|') {
+            (Anchor::Start, pattern)
+        } else {
+            (Anchor::None, pattern)
+        };
+
+        let (end_anchor, pattern) = match pattern.strip_suffix('|') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+
+        // A rule without a pattern would block everything
+        if pattern.trim_matches('*').is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            pattern: pattern.to_ascii_lowercase(),
+            anchor,
+            end_anchor,
+            options,
+        })
+    }
+
+    /// The host of `||host^` rules, which can be looked up instead of matched one by one
+    fn host_key(&self) -> Option<String> {
+        if self.anchor != Anchor::Host || self.end_anchor {
+            return None;
+        }
+
+        let host = self.pattern.strip_suffix('^')?;
+
+        if host.contains(|c: char| !(c.is_ascii_alphanumeric() || c == '.' || c == '-')) {
+            return None;
+        }
+
+        Some(host.to_string())
+    }
+
+    fn matches(&self, request: &ResourceRequest) -> bool {
+        self.options.applies_to(request) && self.matches_url(request.url)
+    }
+
+    fn matches_url(&self, url: &str) -> bool {
+        let pattern = self.pattern.as_bytes();
+        let url_bytes = url.as_bytes();
+
+        match self.anchor {
+            Anchor::Start => match_here(pattern, url_bytes, self.end_anchor),
+            Anchor::Host => {
+                let host_start = url.find("://").map_or(0, |index| index + 3);
+                let host_end = url[host_start..]
+                    .find(['/', '?', '#', ':'])
+                    .map_or(url.len(), |index| host_start + index);
+
+                std::iter::once(host_start)
+                    .chain(
+                        url[host_start..host_end]
+                            .match_indices('.')
+                            .map(|(index, _)| host_start + index + 1),
+                    )
+                    .any(|start| match_here(pattern, &url_bytes[start..], self.end_anchor))
+            }
+            Anchor::None => (0..url_bytes.len())
+                .any(|start| match_here(pattern, &url_bytes[start..], self.end_anchor)),
+        }
+    }
+}
+
+impl RuleOptions {
+    /// Parse the options after `$`, `None` when one of them isn't supported
+    fn parse(options: &str) -> Option<Self> {
+        let mut parsed = Self::default();
+
+        for option in options.split(',').map(str::trim) {
+            let (negated, name) = match option.strip_prefix('~') {
+                Some(name) => (true, name),
+                None => (false, option),
+            };
+
+            match name {
+                "third-party" | "3p" => parsed.third_party = Some(!negated),
+                "first-party" | "1p" => parsed.third_party = Some(negated),
+                "important" | "match-case" => {}
+                _ if name.starts_with("domain=") => {
+                    for domain in name["domain=".len()..].split('|') {
+                        match domain.strip_prefix('~') {
+                            Some(domain) => {
+                                parsed.exclude_domains.push(domain.to_ascii_lowercase())
+                            }
+                            None => parsed.include_domains.push(domain.to_ascii_lowercase()),
+                        }
+                    }
+                }
+                _ => {
+                    let name = match name {
+                        "xhr" => "xmlhttprequest",
+                        "css" => "stylesheet",
+                        "frame" => "subdocument",
+                        "doc" => "document",
+                        name => name,
+                    };
+                    let resource_type = RESOURCE_TYPES.iter().find(|known| **known == name)?;
+
+                    if negated {
+                        parsed.exclude_types.push(resource_type);
+                    } else {
+                        parsed.include_types.push(resource_type);
+                    }
+                }
+            }
+        }
+
+        Some(parsed)
+    }
+
+    fn applies_to(&self, request: &ResourceRequest) -> bool {
+        if let Some(third_party) = self.third_party {
+            let is_third_party = request
+                .source_host
+                .is_some_and(|source| base_domain(source) != base_domain(request.host));
+
+            if third_party != is_third_party {
+                return false;
+            }
+        }
+
+        if !self.include_domains.is_empty() || !self.exclude_domains.is_empty() {
+            let Some(source) = request.source_host else {
+                return self.include_domains.is_empty();
+            };
+
+            if self
+                .exclude_domains
+                .iter()
+                .any(|domain| is_subdomain_of(source, domain))
+            {
+                return false;
+            }
+
+            if !self.include_domains.is_empty()
+                && !self
+                    .include_domains
+                    .iter()
+                    .any(|domain| is_subdomain_of(source, domain))
+            {
+                return false;
+            }
+        }
+
+        // Requests of an unknown type are matched by every rule
+        match request.resource_type {
+            Some(resource_type) => {
+                (self.include_types.is_empty() || self.include_types.contains(&resource_type))
+                    && !self.exclude_types.contains(&resource_type)
+            }
+            None => true,
+        }
+    }
+}
+
+/// Match a filter pattern against the start of `text`, where `*` matches anything and `^` a
+/// separator or the end of the URL
+fn match_here(pattern: &[u8], text: &[u8], end_anchor: bool) -> bool {
+    match pattern.first() {
+        None => !end_anchor || text.is_empty(),
+        Some(b'*') => {
+            (0..=text.len()).any(|skip| match_here(&pattern[1..], &text[skip..], end_anchor))
+        }
+        Some(b'^') => match text.first() {
+            None => match_here(&pattern[1..], text, end_anchor),
+            Some(c) if is_separator(*c) => match_here(&pattern[1..], &text[1..], end_anchor),
+            Some(_) => false,
+        },
+        Some(c) => text.first().is_some_and(|t| {
+            t.eq_ignore_ascii_case(c) && match_here(&pattern[1..], &text[1..], end_anchor)
+        }),
+    }
+}
+
+fn is_separator(c: u8) -> bool {
+    !(c.is_ascii_alphanumeric() || matches!(c, b'_' | b'-' | b'.' | b'%'))
+}
+
+/// `host` followed by each of its parent domains
+fn parent_domains(host: &str) -> impl Iterator<Item = &str> {
+    std::iter::successors(Some(host), |host| {
+        host.split_once('.').map(|(_, parent)| parent)
+    })
+}
+
+pub(crate) fn is_subdomain_of(host: &str, domain: &str) -> bool {
+    host == domain
+        || host
+            .strip_suffix(domain)
+            .is_some_and(|subdomain| subdomain.ends_with('.'))
+}
+
+/// The last two labels of a host, a rough stand-in for the registrable domain when telling first
+/// and third party requests apart
+fn base_domain(host: &str) -> &str {
+    match host.rmatch_indices('.').nth(1) {
+        Some((index, _)) => &host[index + 1..],
+        None => host,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filters(list: &str) -> FilterSet {
+        let mut filters = FilterSet::default();
+        filters.add_list(list);
+        filters
+    }
+
+    fn request<'a>(
+        url: &'a str,
+        source_host: Option<&'a str>,
+        resource_type: Option<&'static str>,
+    ) -> ResourceRequest<'a> {
+        let host_start = url.find("://").unwrap() + 3;
+        let host = url[host_start..]
+            .split(['/', '?', ':'])
+            .next()
+            .unwrap_or_default();
+
+        ResourceRequest {
+            url,
+            host,
+            source_host,
+            resource_type,
+        }
+    }
+
+    fn blocks(filters: &FilterSet, url: &str) -> bool {
+        filters.blocking_rule(&request(url, None, None)).is_some()
+    }
+
+    #[test]
+    fn anchors_rules_to_hosts() {
+        let filters = filters("||ads.example.com^");
+
+        assert!(blocks(&filters, "https://ads.example.com/banner.png"));
+        assert!(blocks(&filters, "https://cdn.ads.example.com/banner.png"));
+        assert!(blocks(&filters, "https://ads.example.com"));
+        assert!(!blocks(&filters, "https://notads.example.com/banner.png"));
+        assert!(!blocks(&filters, "https://ads.example.com.evil.test/"));
+        assert!(!blocks(&filters, "https://example.com/ads.example.com/"));
+    }
+
+    #[test]
+    fn matches_separators_and_wildcards() {
+        let filters = filters("/banner^\n||tracker.test/*/pixel.gif");
+
+        assert!(blocks(&filters, "https://example.com/banner?size=1"));
+        assert!(blocks(&filters, "https://example.com/banner"));
+        assert!(!blocks(&filters, "https://example.com/banners"));
+        assert!(blocks(&filters, "https://tracker.test/a/b/pixel.gif"));
+        assert!(!blocks(&filters, "https://tracker.test/pixel.png"));
+    }
+
+    #[test]
+    fn anchors_rules_to_the_start_and_end_of_urls() {
+        let filters = filters("|http://plain.test/\n.swf|");
+
+        assert!(blocks(&filters, "http://plain.test/page"));
+        assert!(!blocks(&filters, "https://example.com/?http://plain.test/"));
+        assert!(blocks(&filters, "https://example.com/movie.swf"));
+        assert!(!blocks(&filters, "https://example.com/movie.swf?autoplay"));
+    }
+
+    #[test]
+    fn applies_party_options() {
+        let filters = filters("||widgets.test^$third-party\n||own.test^$first-party");
+
+        let third_party = request("https://widgets.test/w.js", Some("news.test"), None);
+        assert!(filters.blocking_rule(&third_party).is_some());
+        let first_party = request("https://widgets.test/w.js", Some("www.widgets.test"), None);
+        assert!(filters.blocking_rule(&first_party).is_none());
+
+        let first_party = request("https://cdn.own.test/a.js", Some("own.test"), None);
+        assert!(filters.blocking_rule(&first_party).is_some());
+        let third_party = request("https://cdn.own.test/a.js", Some("news.test"), None);
+        assert!(filters.blocking_rule(&third_party).is_none());
+    }
+
+    #[test]
+    fn applies_domain_options() {
+        let filters = filters("/ad.js$domain=news.test|~sports.news.test");
+
+        let on = |source| request("https://cdn.test/ad.js", source, None);
+        assert!(filters.blocking_rule(&on(Some("news.test"))).is_some());
+        assert!(filters.blocking_rule(&on(Some("www.news.test"))).is_some());
+        assert!(filters
+            .blocking_rule(&on(Some("sports.news.test")))
+            .is_none());
+        assert!(filters.blocking_rule(&on(Some("blog.test"))).is_none());
+        // Without a known source page, only rules for any domain apply
+        assert!(filters.blocking_rule(&on(None)).is_none());
+    }
+
+    #[test]
+    fn applies_resource_type_options() {
+        let filters = filters("||media.test^$image,~script\n||frames.test^$frame");
+
+        let typed = |url, resource_type| request(url, None, Some(resource_type));
+        assert!(filters
+            .blocking_rule(&typed("https://media.test/a.png", "image"))
+            .is_some());
+        assert!(filters
+            .blocking_rule(&typed("https://media.test/a.js", "script"))
+            .is_none());
+        assert!(filters
+            .blocking_rule(&typed("https://frames.test/", "subdocument"))
+            .is_some());
+        // Requests of an unknown type are matched by every rule
+        assert!(blocks(&filters, "https://media.test/a.png"));
+    }
+
+    #[test]
+    fn skips_rules_with_unsupported_syntax() {
+        let filters = filters(
+            "! comment\n[Adblock Plus 2.0]\n/ads[0-9]+/\n||popups.test^$popup\n||*$script\n|",
+        );
+
+        assert!(filters.is_empty());
+    }
+
+    #[test]
+    fn lets_exceptions_through() {
+        let filters = filters("||ads.test^\n@@||ads.test/allowed/$image");
+
+        assert!(blocks(&filters, "https://ads.test/banner.png"));
+        let allowed = request("https://ads.test/allowed/a.png", None, Some("image"));
+        assert!(filters.blocking_rule(&allowed).is_none());
+        let other_type = request("https://ads.test/allowed/a.js", None, Some("script"));
+        assert!(filters.blocking_rule(&other_type).is_some());
+    }
+
+    #[test]
+    fn reports_the_blocking_rule() {
+        let filters = filters("||ads.test^");
+
+        let rule = filters.blocking_rule(&request("https://ads.test/", None, None));
+        assert_eq!(rule, Some("ads.test^"));
+    }
+
+    #[test]
+    fn collects_element_hiding_selectors() {
+        let filters = filters(
+            "##.ad-banner\n~quiet.test##.sponsored\nnews.test,blog.test##.promo\n#@#.sponsored\nnews.test##+js(nobab)\nnews.test##div:-abp-has(.ad)\nnews.test#?#div:has-text(Ad)",
+        );
+
+        let mut selectors = filters.hiding_selectors("www.news.test", true);
+        selectors.sort();
+        assert_eq!(selectors, [".ad-banner", ".promo"]);
+
+        assert_eq!(filters.hiding_selectors("www.news.test", false), [".promo"]);
+        assert_eq!(filters.hiding_selectors("quiet.test", true), [".ad-banner"]);
+        assert!(filters.hiding_selectors("quiet.test", false).is_empty());
+    }
+}
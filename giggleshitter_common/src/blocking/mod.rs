@@ -0,0 +1,175 @@
+pub mod filters;
+
+use std::{sync::Arc, time::Duration};
+
+use arc_swap::ArcSwap;
+use scorched::{logf, LogData, LogImportance};
+use tokio::task::AbortHandle;
+
+use crate::{
+    error::Result,
+    state::{Config, LiveConfig},
+};
+
+use self::filters::{is_subdomain_of, FilterSet, ResourceRequest};
+
+#[derive(Clone)]
+/// Ad and tracker blocking with filter lists, which are downloaded when the proxy starts and then
+/// every update interval
+pub struct Blocker {
+    filters: Arc<ArcSwap<FilterSet>>,
+    _updates: Arc<Updates>,
+}
+
+/// The task loading the filter lists, stopped once the last clone of the blocker is dropped along
+/// with the server it was built for
+struct Updates(AbortHandle);
+
+impl Drop for Updates {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+impl Blocker {
+    /// Start loading the configured filter lists in the background, nothing is blocked until the
+    /// first load completes
+    pub fn spawn(config: LiveConfig) -> Self {
+        let filters = Arc::new(ArcSwap::from_pointee(FilterSet::default()));
+        let updates = tokio::spawn(update(config, filters.clone()));
+
+        Self {
+            filters,
+            _updates: Arc::new(Updates(updates.abort_handle())),
+        }
+    }
+
+    /// Whether blocking applies to pages on `page_host`
+    fn is_enabled(config: &Config, page_host: &str) -> bool {
+        config.blocking.enabled
+            && !config
+                .blocking
+                .exempt_hosts
+                .iter()
+                .any(|exempt| is_subdomain_of(page_host, exempt))
+    }
+
+    /// The rule blocking a resource loaded by a page on `source_host`, or by no page at all when
+    /// it is navigated to directly
+    pub fn blocking_rule(
+        &self,
+        config: &Config,
+        url: &str,
+        host: &str,
+        source_host: Option<&str>,
+        resource_type: Option<&'static str>,
+    ) -> Option<String> {
+        if !Self::is_enabled(config, source_host.unwrap_or(host)) {
+            return None;
+        }
+
+        let url = url.to_ascii_lowercase();
+        let host = host.to_ascii_lowercase();
+
+        self.filters
+            .load()
+            .blocking_rule(&ResourceRequest {
+                url: &url,
+                host: &host,
+                source_host,
+                resource_type,
+            })
+            .map(str::to_string)
+    }
+
+    /// A stylesheet hiding the elements the filter lists hide on pages on `page_host`, if any
+    pub fn hiding_css(&self, config: &Config, page_host: &str) -> Option<String> {
+        if !Self::is_enabled(config, page_host) {
+            return None;
+        }
+
+        let page_host = page_host.to_ascii_lowercase();
+        let filters = self.filters.load();
+        let selectors = filters.hiding_selectors(&page_host, config.blocking.generic_hiding);
+
+        if selectors.is_empty() {
+            return None;
+        }
+
+        // One rule per selector, so that a selector the browser doesn't support only drops itself
+        Some(
+            selectors
+                .iter()
+                .map(|selector| format!("{}{{display:none!important}}", selector))
+                .collect(),
+        )
+    }
+}
+
+async fn update(live_config: LiveConfig, current: Arc<ArcSwap<FilterSet>>) {
+    let client = reqwest::Client::new();
+
+    loop {
+        let config = live_config.load_full();
+
+        if config.blocking.enabled {
+            let mut filters = FilterSet::default();
+
+            for list in &config.blocking.lists {
+                match fetch_list(&client, list).await {
+                    Ok(text) => filters.add_list(&text),
+                    Err(e) => logf!(Error, "Error loading filter list {}: {}", list, e),
+                }
+            }
+
+            // Keep the previous rules rather than unblocking everything when the lists are down
+            if !filters.is_empty() || config.blocking.lists.is_empty() {
+                current.store(Arc::new(filters));
+                logf!(Info, "Loaded {} filter lists", config.blocking.lists.len());
+            }
+        }
+
+        // Check again shortly for blocking being turned on by a reload
+        let interval = if config.blocking.enabled {
+            config.blocking.update_interval_secs.max(60)
+        } else {
+            60
+        };
+
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+    }
+}
+
+/// Download a filter list, or read it from disk when it isn't an HTTP(S) URL
+async fn fetch_list(client: &reqwest::Client, list: &str) -> Result<String> {
+    if list.starts_with("http://") || list.starts_with("https://") {
+        Ok(client
+            .get(list)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?)
+    } else {
+        Ok(tokio::fs::read_to_string(list).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn stops_updating_once_dropped() {
+        let config: LiveConfig = Arc::new(ArcSwap::from_pointee(Config::default()));
+
+        let blocker = Blocker::spawn(config.clone());
+        tokio::task::yield_now().await;
+        assert_eq!(Arc::strong_count(&config), 2);
+
+        // The aborted task lets go of the configuration once it is polled again
+        drop(blocker);
+        tokio::task::yield_now().await;
+        assert_eq!(Arc::strong_count(&config), 1);
+    }
+}
@@ -0,0 +1,142 @@
+use std::{
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use lru::LruCache;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::state::CircuitBreakerConfig;
+
+/// How many upstream origins circuits are kept for, the least recently used are dropped first
+const TRACKED_ORIGINS: NonZeroUsize = NonZeroUsize::new(512).unwrap();
+
+#[derive(Clone, Copy, Default)]
+struct Circuit {
+    consecutive_failures: u32,
+    /// When the circuit last opened, `None` while it is closed
+    opened_at: Option<Instant>,
+    /// When the request testing whether the origin recovered was let through
+    trial_started: Option<Instant>,
+    trips: u64,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, ToSchema)]
+pub enum CircuitState {
+    /// Requests are sent to the origin
+    Closed,
+    /// Requests are answered with an error page until the cooldown ends
+    Open,
+    /// The cooldown ended, and the next request decides whether the circuit closes again
+    HalfOpen,
+}
+
+#[derive(Serialize, ToSchema)]
+/// The circuit of an upstream origin that has failed recently
+pub struct OriginCircuit {
+    pub origin: String,
+    pub state: CircuitState,
+    pub consecutive_failures: u32,
+    /// How long until a request is let through again, while the circuit is open
+    pub retry_after_secs: Option<u64>,
+    /// How often the circuit opened
+    pub trips: u64,
+}
+
+#[derive(Clone)]
+/// Stops sending requests to upstream origins that keep failing. After enough consecutive
+/// failures an origin's circuit opens, and requests to it are refused for a cooldown period. Once
+/// that is over a single request is let through, closing the circuit if it succeeds and opening it
+/// again if it fails.
+pub struct CircuitBreaker(Arc<Mutex<LruCache<String, Circuit>>>);
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(LruCache::new(TRACKED_ORIGINS))))
+    }
+}
+
+impl CircuitBreaker {
+    /// Whether a request to `origin` may be sent, or how long to wait before retrying if not
+    pub fn check(&self, config: &CircuitBreakerConfig, origin: &str) -> Result<(), Duration> {
+        let cooldown = Duration::from_secs(config.cooldown_secs);
+        let mut circuits = self.0.lock().unwrap();
+
+        let Some(circuit) = circuits.get_mut(origin) else {
+            return Ok(());
+        };
+
+        let Some(opened_at) = circuit.opened_at else {
+            return Ok(());
+        };
+
+        let now = Instant::now();
+        if let Some(remaining) = cooldown.checked_sub(now - opened_at) {
+            return Err(remaining);
+        }
+
+        // A trial that never reported back, e.g. because the client went away, is given up on
+        // after another cooldown
+        match circuit.trial_started {
+            Some(started) if now - started < cooldown => Err(cooldown - (now - started)),
+            _ => {
+                circuit.trial_started = Some(now);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn record_success(&self, origin: &str) {
+        // Healthy origins aren't tracked, so they don't push failing ones out of the cache
+        self.0.lock().unwrap().pop(origin);
+    }
+
+    pub fn record_failure(&self, config: &CircuitBreakerConfig, origin: &str) {
+        let mut circuits = self.0.lock().unwrap();
+
+        let circuit = circuits.get_or_insert_mut(origin.to_string(), Circuit::default);
+        circuit.consecutive_failures += 1;
+
+        // A failed trial opens the circuit again right away
+        if circuit.trial_started.is_some()
+            || circuit.consecutive_failures >= config.failure_threshold
+        {
+            circuit.opened_at = Some(Instant::now());
+            circuit.trial_started = None;
+            circuit.trips += 1;
+        }
+    }
+
+    /// The circuits of every origin that failed since it last succeeded
+    pub fn snapshot(&self, config: &CircuitBreakerConfig) -> Vec<OriginCircuit> {
+        let cooldown = Duration::from_secs(config.cooldown_secs);
+        let now = Instant::now();
+
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(origin, circuit)| {
+                let remaining = circuit
+                    .opened_at
+                    .and_then(|opened_at| cooldown.checked_sub(now - opened_at));
+
+                let state = match (circuit.opened_at, remaining) {
+                    (None, _) => CircuitState::Closed,
+                    (Some(_), Some(_)) => CircuitState::Open,
+                    (Some(_), None) => CircuitState::HalfOpen,
+                };
+
+                OriginCircuit {
+                    origin: origin.clone(),
+                    state,
+                    consecutive_failures: circuit.consecutive_failures,
+                    retry_after_secs: remaining.map(|remaining| remaining.as_secs() + 1),
+                    trips: circuit.trips,
+                }
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,60 @@
+use axum::{body::HttpBody, http::header::CONTENT_LENGTH};
+use tower_http::{
+    compression::{
+        predicate::{NotForContentType, Predicate},
+        CompressionLayer,
+    },
+    CompressionLevel,
+};
+
+use crate::state::LiveConfig;
+
+/// Compress responses that are sent to the client uncompressed, such as rewritten HTML. The
+/// algorithms and level are fixed when the layer is built, whether to compress and the minimum
+/// size follow the live configuration.
+pub fn layer(config: &LiveConfig) -> CompressionLayer<impl Predicate> {
+    let compression = config.load().compression.clone();
+
+    let predicate = LivePredicate(config.clone())
+        .and(NotForContentType::GRPC)
+        .and(NotForContentType::IMAGES)
+        .and(NotForContentType::SSE);
+
+    CompressionLayer::new()
+        .gzip(compression.gzip)
+        .br(compression.br)
+        .zstd(compression.zstd)
+        .no_deflate()
+        .quality(match compression.level {
+            Some(level) => CompressionLevel::Precise(level),
+            None => CompressionLevel::Default,
+        })
+        .compress_when(predicate)
+}
+
+#[derive(Clone)]
+struct LivePredicate(LiveConfig);
+
+impl Predicate for LivePredicate {
+    fn should_compress<B>(&self, response: &axum::http::Response<B>) -> bool
+    where
+        B: HttpBody,
+    {
+        let config = self.0.load();
+
+        if !config.compression.enabled {
+            return false;
+        }
+
+        let size = response.body().size_hint().exact().or_else(|| {
+            response
+                .headers()
+                .get(CONTENT_LENGTH)
+                .and_then(|length| length.to_str().ok())
+                .and_then(|length| length.parse().ok())
+        });
+
+        // Streamed bodies of unknown size are always compressed
+        size.is_none_or(|size| size >= config.compression.min_size_bytes)
+    }
+}
@@ -0,0 +1,126 @@
+use std::{
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use lru::LruCache;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+/// How many refusals are kept, the oldest are dropped first
+const KEPT_REFUSALS: NonZeroUsize = NonZeroUsize::new(4096).unwrap();
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug, ToSchema)]
+#[serde(rename_all = "snake_case")]
+/// Why the proxy refused a request
+pub enum RefusalReason {
+    /// The host isn't a proxied address the proxy can decode
+    InvalidAddress,
+    /// The upstream host isn't allowed to be proxied, or FTP isn't enabled
+    OriginNotAllowed,
+    /// The client's address or country isn't allowed to use the proxy
+    AccessDenied,
+    /// The client used up its bandwidth quota
+    QuotaExceeded,
+    /// A filter list blocked the request
+    Blocked,
+    /// One of the configured rules blocked the page
+    Rule,
+    /// The upstream failed too often recently, and its circuit is open
+    CircuitOpen,
+    /// The download is larger than the proxy allows
+    TooLarge,
+}
+
+#[derive(Clone)]
+/// Attached to the response to a request the proxy refused. The proxy handler records the refusal
+/// under a new correlation ID, and renders the page, if there is one, with the ID on it.
+pub struct Refused {
+    pub reason: RefusalReason,
+    /// What the refusal was about, for operators
+    pub detail: String,
+    /// The title and message of the page shown to the user
+    pub page: Option<(String, String)>,
+}
+
+#[derive(Serialize, Clone, ToSchema)]
+/// A refused request, looked up by the correlation ID its user was given
+pub struct Refusal {
+    pub id: String,
+    pub reason: RefusalReason,
+    /// What the refusal was about, such as the filter rule that matched
+    pub detail: String,
+    pub status: u16,
+    pub host: String,
+    pub method: String,
+    /// The path that was requested, without its query
+    pub path: String,
+    /// When the request was received, in RFC 3339
+    pub received: String,
+    /// How long the proxy took to refuse the request
+    pub total_ms: u64,
+    /// The public host of the tenant the request was for, whose keys may look it up
+    #[serde(skip)]
+    pub public_host: String,
+}
+
+#[derive(Clone)]
+/// The requests refused recently, kept so that operators can find out why from the correlation
+/// ID a user was shown
+pub struct Diagnostics(Arc<Mutex<LruCache<String, Refusal>>>);
+
+impl Default for Diagnostics {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(LruCache::new(KEPT_REFUSALS))))
+    }
+}
+
+/// Where a refused request was going and when it came in
+pub struct RefusedRequest<'a> {
+    pub public_host: &'a str,
+    pub host: &'a str,
+    pub method: &'a str,
+    pub path: &'a str,
+    pub received: DateTime<Utc>,
+    pub elapsed: Duration,
+}
+
+impl Diagnostics {
+    /// Keep `refused` under a new correlation ID, which is returned
+    pub fn record(&self, request: RefusedRequest, status: u16, refused: &Refused) -> String {
+        let id = format!("{:032x}", rand::random::<u128>());
+
+        self.0.lock().unwrap().put(
+            id.clone(),
+            Refusal {
+                id: id.clone(),
+                reason: refused.reason,
+                detail: refused.detail.clone(),
+                status,
+                host: request.host.to_string(),
+                method: request.method.to_string(),
+                path: request.path.to_string(),
+                received: request
+                    .received
+                    .to_rfc3339_opts(SecondsFormat::Millis, true),
+                total_ms: request.elapsed.as_millis() as u64,
+                public_host: request.public_host.to_string(),
+            },
+        );
+
+        id
+    }
+
+    /// The refusal with correlation ID `id`, if it was for the tenant of `public_host` and is still
+    /// kept
+    pub fn get(&self, public_host: &str, id: &str) -> Option<Refusal> {
+        self.0
+            .lock()
+            .unwrap()
+            .peek(id)
+            .filter(|refusal| refusal.public_host == public_host)
+            .cloned()
+    }
+}
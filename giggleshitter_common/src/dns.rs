@@ -0,0 +1,142 @@
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use futures_util::future;
+use hickory_resolver::{
+    config::{
+        LookupIpStrategy, NameServerConfig, NameServerConfigGroup, Protocol, ResolverConfig,
+        ResolverOpts,
+    },
+    TokioAsyncResolver,
+};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+
+use crate::{
+    state::{DnsConfig, DnsServer, IpPreference},
+    upstream::ConnectionStats,
+};
+
+/// Resolves upstream hosts for the proxy's HTTP clients, from the static records or the
+/// configured servers, with a cache that respects the TTLs of the answers. The clients resolve a
+/// host for every connection they open, which is counted in the connection statistics.
+pub struct DnsResolver {
+    config: DnsConfig,
+    ip_preference: IpPreference,
+    /// `None` when lookups are left to the system resolver
+    resolver: Option<TokioAsyncResolver>,
+    connections: ConnectionStats,
+}
+
+impl DnsResolver {
+    pub fn from_config(
+        config: &DnsConfig,
+        ip_preference: IpPreference,
+        connections: ConnectionStats,
+    ) -> Arc<Self> {
+        let resolver = (!config.servers.is_empty()).then(|| {
+            let mut servers = NameServerConfigGroup::with_capacity(config.servers.len());
+
+            for server in &config.servers {
+                match server {
+                    DnsServer::Plain(addr) => {
+                        servers.push(NameServerConfig::new(*addr, Protocol::Udp));
+                        servers.push(NameServerConfig::new(*addr, Protocol::Tcp));
+                    }
+                    DnsServer::Tls(addr, name) => {
+                        servers.push(encrypted(*addr, Protocol::Tls, name));
+                    }
+                    DnsServer::Https(addr, name) => {
+                        servers.push(encrypted(*addr, Protocol::Https, name));
+                    }
+                }
+            }
+
+            let mut options = ResolverOpts::default();
+            options.cache_size = config.cache_size;
+            options.positive_min_ttl = config.min_ttl_secs.map(Duration::from_secs);
+            options.positive_max_ttl = config.max_ttl_secs.map(Duration::from_secs);
+            // Answers come from the configured servers only
+            options.use_hosts_file = false;
+            options.ip_strategy = match ip_preference {
+                IpPreference::HappyEyeballs => LookupIpStrategy::Ipv4AndIpv6,
+                IpPreference::PreferIpv4 => LookupIpStrategy::Ipv4thenIpv6,
+                IpPreference::PreferIpv6 => LookupIpStrategy::Ipv6thenIpv4,
+                IpPreference::Ipv4Only => LookupIpStrategy::Ipv4Only,
+                IpPreference::Ipv6Only => LookupIpStrategy::Ipv6Only,
+            };
+
+            TokioAsyncResolver::tokio(ResolverConfig::from_parts(None, vec![], servers), options)
+        });
+
+        Arc::new(Self {
+            config: config.clone(),
+            ip_preference,
+            resolver,
+            connections,
+        })
+    }
+}
+
+fn encrypted(addr: SocketAddr, protocol: Protocol, name: &str) -> NameServerConfig {
+    let mut server = NameServerConfig::new(addr, protocol);
+    server.tls_dns_name = Some(name.to_string());
+    server
+}
+
+/// Put the addresses of the preferred family first, or leave out the other family entirely. The
+/// connector tries the family of the first address before falling back to the other one.
+fn order(
+    host: &str,
+    mut addrs: Vec<SocketAddr>,
+    preference: IpPreference,
+) -> Result<Addrs, Box<dyn std::error::Error + Send + Sync>> {
+    match preference {
+        IpPreference::HappyEyeballs => {}
+        IpPreference::PreferIpv4 => addrs.sort_by_key(|addr| !addr.is_ipv4()),
+        IpPreference::PreferIpv6 => addrs.sort_by_key(|addr| !addr.is_ipv6()),
+        IpPreference::Ipv4Only => addrs.retain(SocketAddr::is_ipv4),
+        IpPreference::Ipv6Only => addrs.retain(SocketAddr::is_ipv6),
+    }
+
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("{} has no address of the allowed family", host),
+        )
+        .into());
+    }
+
+    Ok(Box::new(addrs.into_iter()))
+}
+
+impl Resolve for DnsResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().trim_end_matches('.').to_ascii_lowercase();
+        self.connections.record_connection(&host);
+
+        let preference = self.ip_preference;
+
+        if let Some(ips) = self.config.static_records.get(&host) {
+            let addrs = ips.iter().map(|ip| SocketAddr::new(*ip, 0)).collect();
+            return Box::pin(future::ready(order(&host, addrs, preference)));
+        }
+
+        match &self.resolver {
+            Some(resolver) => {
+                let resolver = resolver.clone();
+
+                Box::pin(async move {
+                    let lookup = resolver.lookup_ip(host.as_str()).await?;
+                    let addrs = lookup
+                        .into_iter()
+                        .map(|ip| SocketAddr::new(ip, 0))
+                        .collect();
+                    order(&host, addrs, preference)
+                })
+            }
+            None => Box::pin(async move {
+                let addrs = tokio::net::lookup_host((host.as_str(), 0)).await?;
+                order(&host, addrs.collect(), preference)
+            }),
+        }
+    }
+}
@@ -1,7 +1,7 @@
 use core::fmt;
 
 use axum::{
-    http::StatusCode,
+    http::{header::ACCEPT, HeaderMap, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
@@ -17,7 +17,7 @@ pub type Result<T> = std::result::Result<T, AppError>;
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
         (
-            StatusCode::INTERNAL_SERVER_ERROR,
+            self.status(),
             Json(json!({
                 "error": self.0.to_string(),
             })),
@@ -26,6 +26,58 @@ impl IntoResponse for AppError {
     }
 }
 
+impl AppError {
+    /// The underlying error, if it is of type `E`, for handlers that respond to some errors
+    /// differently
+    pub fn downcast_ref<E>(&self) -> Option<&E>
+    where
+        E: fmt::Display + fmt::Debug + Send + Sync + 'static,
+    {
+        self.0.downcast_ref()
+    }
+
+    /// The status to respond with: 504 when the upstream took too long, 502 when it couldn't be
+    /// reached or its redirects went on too long, and 500 for everything else
+    pub fn status(&self) -> StatusCode {
+        match self.downcast_ref::<reqwest::Error>() {
+            Some(e) if e.is_timeout() => StatusCode::GATEWAY_TIMEOUT,
+            Some(e) if e.is_connect() || e.is_request() || e.is_body() || e.is_redirect() => {
+                StatusCode::BAD_GATEWAY
+            }
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Whether the error is the upstream's doing rather than the proxy's
+    pub fn is_upstream(&self) -> bool {
+        self.status() != StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+/// Whether a client would rather have errors as an HTML page than as JSON, as browsers navigating
+/// to a page do. Clients that don't send `Accept` get JSON.
+pub fn wants_html(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|range| {
+            let mut params = range.split(';').map(str::trim);
+            let media_type = params.next().unwrap_or_default();
+            let refused = params.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+
+            !refused
+                && (media_type.eq_ignore_ascii_case("text/html")
+                    || media_type.eq_ignore_ascii_case("application/xhtml+xml"))
+        })
+}
+
 impl fmt::Display for AppError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         self.0.fmt(f)
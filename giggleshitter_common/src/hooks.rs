@@ -0,0 +1,116 @@
+use std::{net::IpAddr, sync::Arc};
+
+use axum::response::Response;
+use futures_util::future::{self, BoxFuture};
+use hyper::{HeaderMap, Method, StatusCode};
+
+/// A request that is about to be sent to the upstream origin
+pub struct RequestEvent {
+    pub method: Method,
+    /// The decoded upstream URL, including the path and query
+    pub url: String,
+    /// The headers that will be sent upstream
+    pub headers: HeaderMap,
+    /// The address of the client that made the request, unknown on Unix sockets
+    pub client: Option<IpAddr>,
+}
+
+/// A response that was received from the upstream origin
+pub struct ResponseEvent {
+    pub method: Method,
+    /// The decoded upstream URL, including the path and query
+    pub url: String,
+    pub status: StatusCode,
+    /// The headers that will be sent to the client
+    pub headers: HeaderMap,
+    /// The address of the client that made the request, unknown on Unix sockets
+    pub client: Option<IpAddr>,
+}
+
+/// A request that failed before a response could be sent to the client
+pub struct ErrorEvent {
+    /// The host the client requested
+    pub host: String,
+    pub message: String,
+    /// The address of the client that made the request, unknown on Unix sockets
+    pub client: Option<IpAddr>,
+}
+
+/// A proxied WebSocket connection
+pub struct WebSocketEvent {
+    /// The decoded upstream URL of the WebSocket
+    pub url: String,
+}
+
+/// What to do with a request after a hook has seen it
+pub enum HookVerdict {
+    /// Send the (possibly modified) request upstream
+    Continue,
+    /// Don't contact the upstream and send this response to the client instead
+    Respond(Response),
+}
+
+/// Callbacks invoked around the proxied request lifecycle. Every method has a no-op default, so
+/// implementors only need to override the events they care about.
+pub trait ProxyHook: Send + Sync {
+    /// Called before the request is sent upstream. Hooks may modify the request in place or veto it
+    /// by returning [`HookVerdict::Respond`].
+    fn on_request<'a>(&'a self, _event: &'a mut RequestEvent) -> BoxFuture<'a, HookVerdict> {
+        Box::pin(future::ready(HookVerdict::Continue))
+    }
+
+    fn on_response(&self, _event: &ResponseEvent) {}
+
+    fn on_error(&self, _event: &ErrorEvent) {}
+
+    fn on_websocket_open(&self, _event: &WebSocketEvent) {}
+
+    fn on_websocket_close(&self, _event: &WebSocketEvent) {}
+}
+
+#[derive(Clone, Default)]
+/// The hooks registered on a proxy, invoked in registration order
+pub struct Hooks(Vec<Arc<dyn ProxyHook>>);
+
+impl Hooks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, hook: Arc<dyn ProxyHook>) {
+        self.0.push(hook);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Run the request hooks, stopping at the first one that vetoes the request
+    pub async fn on_request(&self, event: &mut RequestEvent) -> HookVerdict {
+        for hook in &self.0 {
+            if let HookVerdict::Respond(response) = hook.on_request(event).await {
+                return HookVerdict::Respond(response);
+            }
+        }
+
+        HookVerdict::Continue
+    }
+
+    pub fn on_response(&self, event: &ResponseEvent) {
+        self.0.iter().for_each(|hook| hook.on_response(event));
+    }
+
+    pub fn on_error(&self, event: &ErrorEvent) {
+        self.0.iter().for_each(|hook| hook.on_error(event));
+    }
+
+    pub fn on_websocket_open(&self, event: &WebSocketEvent) {
+        self.0.iter().for_each(|hook| hook.on_websocket_open(event));
+    }
+
+    pub fn on_websocket_close(&self, event: &WebSocketEvent) {
+        self.0
+            .iter()
+            .for_each(|hook| hook.on_websocket_close(event));
+    }
+}
@@ -1,69 +1,147 @@
-pub mod api;
+//! A web proxy that serves every proxied site on its own subdomain of a public host, rewriting
+//! pages so that links keep pointing through the proxy.
+//!
+//! [`prelude`] re-exports what embedders usually need. The proxy runs standalone with [`serve`],
+//! or as an axum [`Router`] from [`build_app`] or [`ServerBuilder`] when it should be composed with
+//! other routes, custom rewriters, hooks or plugins.
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use giggleshitter_common::prelude::*;
+//!
+//! # async fn example() -> Result<()> {
+//! let config = Config {
+//!     url_encoding_algorithm: UrlEncodingAlgorithm::Base32(Alphabet::Z),
+//!     host: "0.0.0.0:8080".parse()?,
+//!     public_host: "proxy.example".to_string(),
+//!     ..Default::default()
+//! };
+//!
+//! serve(Arc::new(config), async {
+//!     tokio::signal::ctrl_c().await.ok();
+//! })
+//! .await
+//! # }
+//! ```
+
+pub(crate) mod access;
+pub(crate) mod api;
+pub(crate) mod audit;
+pub(crate) mod blocking;
+pub(crate) mod breaker;
+pub(crate) mod compression;
+pub(crate) mod diagnostics;
+pub(crate) mod dns;
 pub mod error;
+pub mod hooks;
+pub mod listener;
+pub mod logging;
+#[cfg(feature = "media")]
+pub(crate) mod media;
+pub(crate) mod metrics;
+pub(crate) mod pages;
+pub mod plugins;
+pub mod prelude;
 pub mod proxy;
+pub(crate) mod recording;
 pub mod rewriting;
+pub mod rules;
+pub mod server;
+pub(crate) mod session;
+pub(crate) mod shortener;
+pub(crate) mod snapshot;
 pub mod state;
+pub(crate) mod telemetry;
+pub mod tenant;
+pub(crate) mod upstream;
+pub(crate) mod usage;
+pub mod validation;
 
 use std::{future::Future, sync::Arc};
 
-use axum::{
-    extract::{Host, Request, State},
-    handler::Handler,
-    routing::any,
-};
+use axum::Router;
 use error::Result;
-use reqwest::redirect::Policy;
-use rewriting::html::html_rewriter;
-use state::{APIState, Config, ProxyState, SharedState};
-use tower::ServiceExt;
+use listener::{ListenAddr, Listener};
+use server::ServerBuilder;
+use state::{Config, LiveConfig};
+use tokio::sync::oneshot;
 
 pub async fn serve<F>(config: Arc<Config>, graceful_shutdown: F) -> Result<()>
 where
     F: Future<Output = ()> + Send + 'static,
 {
-    let sharedstate = SharedState {
-        config: config.clone(),
-    };
-
-    let client = reqwest::Client::builder()
-        .redirect(Policy::none())
-        .gzip(true)
-        .brotli(true)
-        .deflate(true)
-        .zstd(true)
-        .build()?;
+    ServerBuilder::new(config).serve(graceful_shutdown).await
+}
 
-    let proxystate = ProxyState {
-        config: config.clone(),
-        client,
-        html_rewriter: Arc::new(html_rewriter::HtmlRewriter::new(Arc::new(
-            sharedstate.clone(),
-        ))),
-    };
+/// Like [`serve`], but sends the address the listener was bound to through `bound` before
+/// accepting connections, which reports the kernel-assigned port when binding port 0
+pub async fn serve_with_bound_addr<F>(
+    config: Arc<Config>,
+    graceful_shutdown: F,
+    bound: oneshot::Sender<ListenAddr>,
+) -> Result<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let listener = Listener::bind(&config.host).await?;
 
-    let proxyrouter = proxy::service::proxy.with_state(Arc::new(proxystate).clone());
+    let _ = bound.send(listener.local_addr()?);
 
-    let apistate = APIState {
-        config: config.clone(),
-    };
+    ServerBuilder::new(config)
+        .serve_with_listener(listener, graceful_shutdown)
+        .await
+}
 
-    let apirouter = api::service::service(Arc::new(apistate));
+/// Serve the proxy on an already bound listener, useful when the caller needs to know the bound
+/// address before the server starts accepting connections. Configuration stored into `config`
+/// applies to all requests made after the swap, apart from the listen address.
+pub async fn serve_with_listener<F>(
+    listener: impl Into<Listener>,
+    config: LiveConfig,
+    graceful_shutdown: F,
+) -> Result<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    ServerBuilder::with_live_config(config)
+        .serve_with_listener(listener, graceful_shutdown)
+        .await
+}
 
-    let app = any(
-        |State(state): State<SharedState>, Host(host): Host, req: Request| async move {
-            if host == format!("api.{}", state.config.public_host) {
-                return apirouter.oneshot(req).await;
-            }
-            proxyrouter.oneshot(req).await
-        },
-    )
-    .with_state(sharedstate);
+/// Build the proxy as an axum [`Router`] with the default configuration of [`ServerBuilder`], for
+/// composing with an existing application or serving it over a custom transport
+///
+/// ```no_run
+/// # use std::sync::Arc;
+/// # use axum::{routing::get, Router};
+/// # use giggleshitter_common::state::Config;
+/// # async fn example() -> giggleshitter_common::error::Result<()> {
+/// let proxy = giggleshitter_common::build_app(Arc::new(Config::default()))?;
+///
+/// let app = Router::new()
+///     .route("/healthz", get(|| async { "ok" }))
+///     .fallback_service(proxy);
+///
+/// let listener = tokio::net::UnixListener::bind("/run/giggleshitter.sock")?;
+/// giggleshitter_common::run(listener, app, std::future::pending()).await
+/// # }
+/// ```
+pub fn build_app(config: Arc<Config>) -> Result<Router> {
+    ServerBuilder::new(config).build_router()
+}
 
-    let listener = tokio::net::TcpListener::bind(config.host).await.unwrap();
-    axum::serve(listener, app.into_make_service())
-        .with_graceful_shutdown(graceful_shutdown)
-        .await
-        .unwrap();
+/// The OpenAPI document of the API host as JSON, for generating clients without a running server.
+/// A running server serves it as `/openapi.json`.
+pub fn openapi_json() -> String {
+    api::openapi::openapi_json()
+}
 
-    Ok(())
+/// Serve a router built by [`build_app`] or [`ServerBuilder::build_router`] on a TCP or Unix
+/// listener until `graceful_shutdown` resolves
+pub async fn run<F>(listener: impl Into<Listener>, app: Router, graceful_shutdown: F) -> Result<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    listener::serve(listener.into(), app, graceful_shutdown).await
 }
@@ -0,0 +1,339 @@
+use std::{
+    fmt, future::Future, io, net::SocketAddr, path::PathBuf, str::FromStr, sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request},
+    Router,
+};
+use futures_util::{pin_mut, FutureExt};
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto::Builder,
+    service::TowerToHyperService,
+};
+use scorched::{logf, LogData, LogImportance};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::watch,
+};
+use tower::ServiceExt;
+
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+use crate::error::Result;
+
+#[derive(Error, Debug, Clone)]
+pub struct InvalidListenAddrError(String);
+
+impl fmt::Display for InvalidListenAddrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid listen address `{}`, expected `<ip>:<port>`, `unix:<path>` or `systemd[:<index>]`",
+            self.0
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Where the server accepts connections
+pub enum ListenAddr {
+    /// A TCP socket address, written as `0.0.0.0:3069`
+    Tcp(SocketAddr),
+    /// A Unix domain socket, written as `unix:/path/to.sock`
+    Unix(PathBuf),
+    /// A socket inherited through systemd socket activation, written as `systemd` for the first
+    /// passed socket or `systemd:<index>`
+    Systemd(usize),
+}
+
+impl FromStr for ListenAddr {
+    type Err = InvalidListenAddrError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(InvalidListenAddrError(s.to_string()));
+            }
+
+            return Ok(ListenAddr::Unix(PathBuf::from(path)));
+        }
+
+        if s == "systemd" {
+            return Ok(ListenAddr::Systemd(0));
+        }
+
+        if let Some(index) = s.strip_prefix("systemd:") {
+            return index
+                .parse()
+                .map(ListenAddr::Systemd)
+                .map_err(|_| InvalidListenAddrError(s.to_string()));
+        }
+
+        s.parse()
+            .map(ListenAddr::Tcp)
+            .map_err(|_| InvalidListenAddrError(s.to_string()))
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+            ListenAddr::Systemd(0) => write!(f, "systemd"),
+            ListenAddr::Systemd(index) => write!(f, "systemd:{}", index),
+        }
+    }
+}
+
+impl From<SocketAddr> for ListenAddr {
+    fn from(addr: SocketAddr) -> Self {
+        ListenAddr::Tcp(addr)
+    }
+}
+
+impl Serialize for ListenAddr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ListenAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// A bound listener the server accepts connections on
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener::Tcp(listener)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixListener> for Listener {
+    fn from(listener: UnixListener) -> Self {
+        Listener::Unix(listener)
+    }
+}
+
+impl Listener {
+    pub async fn bind(addr: &ListenAddr) -> Result<Self> {
+        match addr {
+            ListenAddr::Tcp(addr) => Ok(TcpListener::bind(addr).await?.into()),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                // A socket left behind by a previous run would make the bind fail
+                if std::fs::symlink_metadata(path).is_ok_and(|metadata| {
+                    std::os::unix::fs::FileTypeExt::is_socket(&metadata.file_type())
+                }) {
+                    std::fs::remove_file(path)?;
+                }
+
+                Ok(UnixListener::bind(path)?.into())
+            }
+            #[cfg(unix)]
+            ListenAddr::Systemd(index) => Self::from_systemd(*index),
+            #[cfg(not(unix))]
+            _ => Err(anyhow::anyhow!("{} is only supported on unix", addr).into()),
+        }
+    }
+
+    /// Take over a listening socket passed by systemd, see `sd_listen_fds(3)`
+    #[cfg(unix)]
+    fn from_systemd(index: usize) -> Result<Self> {
+        use std::os::fd::{FromRawFd, IntoRawFd, RawFd};
+
+        const SD_LISTEN_FDS_START: RawFd = 3;
+
+        let pid_matches = std::env::var("LISTEN_PID")
+            .ok()
+            .and_then(|pid| pid.parse::<u32>().ok())
+            .is_some_and(|pid| pid == std::process::id());
+
+        let count: usize = std::env::var("LISTEN_FDS")
+            .ok()
+            .and_then(|count| count.parse().ok())
+            .unwrap_or(0);
+
+        if !pid_matches || index >= count {
+            return Err(anyhow::anyhow!(
+                "systemd did not pass a socket with index {} to this process",
+                index
+            )
+            .into());
+        }
+
+        let fd = SD_LISTEN_FDS_START + index as RawFd;
+
+        // Unix sockets report an AF_UNIX local address, anything else is treated as TCP
+        let unix = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+        if unix.local_addr().is_ok() {
+            unix.set_nonblocking(true)?;
+            return Ok(UnixListener::from_std(unix)?.into());
+        }
+
+        let tcp = unsafe { std::net::TcpListener::from_raw_fd(unix.into_raw_fd()) };
+        tcp.set_nonblocking(true)?;
+        Ok(TcpListener::from_std(tcp)?.into())
+    }
+
+    /// The address the listener is bound to, which includes the kernel-assigned port when a TCP
+    /// listener was bound to port 0
+    pub fn local_addr(&self) -> io::Result<ListenAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().map(ListenAddr::Tcp),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.local_addr().map(|addr| {
+                ListenAddr::Unix(addr.as_pathname().map(PathBuf::from).unwrap_or_default())
+            }),
+        }
+    }
+}
+
+/// Accept connections on `listener` and serve `app` on them until `graceful_shutdown` resolves,
+/// then wait for the open connections to finish
+pub(crate) async fn serve<F>(listener: Listener, app: Router, graceful_shutdown: F) -> Result<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let (signal_tx, signal_rx) = watch::channel(());
+    let signal_tx = Arc::new(signal_tx);
+    tokio::spawn(async move {
+        graceful_shutdown.await;
+        drop(signal_rx);
+    });
+
+    let (close_tx, close_rx) = watch::channel(());
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = accept(&listener) => accepted,
+            _ = signal_tx.closed() => break,
+        };
+
+        match accepted {
+            Some(Accepted::Tcp(stream, addr)) => spawn_connection(
+                stream,
+                Some(addr),
+                app.clone(),
+                signal_tx.clone(),
+                close_rx.clone(),
+            ),
+            #[cfg(unix)]
+            Some(Accepted::Unix(stream)) => spawn_connection(
+                stream,
+                None,
+                app.clone(),
+                signal_tx.clone(),
+                close_rx.clone(),
+            ),
+            None => continue,
+        }
+    }
+
+    drop(close_rx);
+    drop(listener);
+
+    close_tx.closed().await;
+
+    Ok(())
+}
+
+enum Accepted {
+    Tcp(tokio::net::TcpStream, SocketAddr),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+async fn accept(listener: &Listener) -> Option<Accepted> {
+    let accepted = match listener {
+        Listener::Tcp(listener) => listener
+            .accept()
+            .await
+            .map(|(stream, addr)| Accepted::Tcp(stream, addr)),
+        #[cfg(unix)]
+        Listener::Unix(listener) => listener
+            .accept()
+            .await
+            .map(|(stream, _)| Accepted::Unix(stream)),
+    };
+
+    match accepted {
+        Ok(accepted) => Some(accepted),
+        Err(e) if is_connection_error(&e) => None,
+        Err(e) => {
+            // Most likely out of file descriptors, give open connections a chance to close
+            logf!(Error, "Failed to accept connection: {}", e);
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            None
+        }
+    }
+}
+
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+    )
+}
+
+/// Serve a single connection. Requests on TCP connections carry the peer address as a
+/// [`ConnectInfo<SocketAddr>`] extension.
+fn spawn_connection<I>(
+    io: I,
+    peer: Option<SocketAddr>,
+    app: Router,
+    signal_tx: Arc<watch::Sender<()>>,
+    close_rx: watch::Receiver<()>,
+) where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = TowerToHyperService::new(app.map_request(move |req: Request<Incoming>| {
+        let mut req = req.map(Body::new);
+
+        if let Some(peer) = peer {
+            req.extensions_mut().insert(ConnectInfo(peer));
+        }
+
+        req
+    }));
+
+    tokio::spawn(async move {
+        let builder = Builder::new(TokioExecutor::new());
+        let conn = builder.serve_connection_with_upgrades(TokioIo::new(io), service);
+        pin_mut!(conn);
+
+        let signal_closed = signal_tx.closed().fuse();
+        pin_mut!(signal_closed);
+
+        loop {
+            tokio::select! {
+                _ = conn.as_mut() => break,
+                _ = &mut signal_closed => conn.as_mut().graceful_shutdown(),
+            }
+        }
+
+        drop(close_rx);
+    });
+}
@@ -0,0 +1,93 @@
+use std::io;
+
+use tracing_appender::{non_blocking::WorkerGuard, rolling};
+use tracing_subscriber::{
+    filter::LevelFilter, fmt::MakeWriter, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter,
+    Layer, Registry,
+};
+
+use crate::{
+    error::Result,
+    state::{Config, LogFileConfig, LogFormat, LogRotation},
+    telemetry::{self, TelemetryGuard},
+};
+
+pub(crate) type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+pub(crate) type Filter = EnvFilter;
+
+/// Keeps the log files and exported spans flowing, keep it alive for as long as the process
+/// serves requests. Buffered messages are written out when it is dropped.
+pub struct LoggingGuard {
+    _file: Option<WorkerGuard>,
+    _telemetry: TelemetryGuard,
+}
+
+/// Install the global tracing subscriber as configured in [`Config::logging`], exporting spans
+/// over OTLP when [`Config::telemetry`] has an endpoint. The `RUST_LOG` environment variable
+/// takes precedence over the configured filter. Fails if a subscriber is already installed.
+pub fn init(config: &Config) -> Result<LoggingGuard> {
+    let logging = &config.logging;
+    let directives = std::env::var("RUST_LOG").unwrap_or_else(|_| logging.filter.clone());
+    let filter = || {
+        EnvFilter::builder()
+            .with_default_directive(LevelFilter::INFO.into())
+            .parse_lossy(&directives)
+    };
+
+    let mut layers = vec![fmt_layer(logging.format, io::stdout, true, filter())];
+
+    let file_guard = match &logging.file {
+        Some(file) => {
+            let (writer, guard) = tracing_appender::non_blocking(file_appender(file)?);
+            let format = file.format.unwrap_or(logging.format);
+            layers.push(fmt_layer(format, writer, false, filter()));
+            Some(guard)
+        }
+        None => None,
+    };
+
+    let (otel, telemetry) = telemetry::layer(&config.telemetry, filter())?;
+    layers.extend(otel);
+
+    tracing_subscriber::registry().with(layers).try_init()?;
+
+    Ok(LoggingGuard {
+        _file: file_guard,
+        _telemetry: telemetry,
+    })
+}
+
+fn fmt_layer<W>(format: LogFormat, writer: W, ansi: bool, filter: Filter) -> BoxedLayer
+where
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi);
+
+    match format {
+        LogFormat::Full => layer.with_filter(filter).boxed(),
+        LogFormat::Compact => layer.compact().with_filter(filter).boxed(),
+        LogFormat::Pretty => layer.pretty().with_filter(filter).boxed(),
+        LogFormat::Json => layer.json().with_filter(filter).boxed(),
+    }
+}
+
+fn file_appender(config: &LogFileConfig) -> Result<rolling::RollingFileAppender> {
+    let rotation = match config.rotation {
+        LogRotation::Minutely => rolling::Rotation::MINUTELY,
+        LogRotation::Hourly => rolling::Rotation::HOURLY,
+        LogRotation::Daily => rolling::Rotation::DAILY,
+        LogRotation::Never => rolling::Rotation::NEVER,
+    };
+
+    let mut builder = rolling::Builder::new()
+        .rotation(rotation)
+        .filename_prefix(&config.prefix);
+
+    if let Some(max_files) = config.max_files {
+        builder = builder.max_log_files(max_files);
+    }
+
+    Ok(builder.build(&config.directory)?)
+}
@@ -0,0 +1,179 @@
+use std::io::Cursor;
+
+use image::{
+    codecs::{avif::AvifEncoder, jpeg::JpegEncoder, png::PngEncoder, webp::WebPEncoder},
+    imageops::FilterType,
+    DynamicImage, ImageDecoder, ImageFormat, ImageReader,
+};
+use scorched::{logf, LogData, LogImportance};
+
+use crate::{
+    error::Result,
+    state::{Config, RuleAction},
+};
+
+/// How hard the AVIF encoder tries, from 1 to 10. Anything slower takes seconds per image.
+const AVIF_SPEED: u8 = 8;
+
+#[derive(Clone)]
+/// What to do with an image response, decided before its body is read
+pub struct Transform {
+    format: ImageFormat,
+    /// A format the client accepts that the image may be converted to
+    preferred: Option<ImageFormat>,
+    max_width: Option<u32>,
+    max_height: Option<u32>,
+    strip_metadata: bool,
+    quality: u8,
+}
+
+impl Transform {
+    /// The transform for a response of `content_type` requested with `accept`, `None` when the
+    /// image is left alone
+    pub fn plan(
+        config: &Config,
+        actions: &[&RuleAction],
+        content_type: Option<&str>,
+        accept: Option<&str>,
+    ) -> Option<Self> {
+        let media = &config.media;
+
+        if !media.enabled {
+            return None;
+        }
+
+        // Other formats may be animated, which decoding and encoding again would lose
+        let format = match content_type? {
+            "image/jpeg" => ImageFormat::Jpeg,
+            "image/png" => ImageFormat::Png,
+            _ => return None,
+        };
+
+        let (max_width, max_height) = actions
+            .iter()
+            .rev()
+            .find_map(|action| match action {
+                RuleAction::ResizeImage(width, height) => Some((Some(*width), Some(*height))),
+                _ => None,
+            })
+            .unwrap_or((media.max_width, media.max_height));
+
+        let preferred = accept
+            .filter(|_| media.negotiate_formats)
+            .and_then(|accept| {
+                if accepts(accept, "image/avif") {
+                    Some(ImageFormat::Avif)
+                } else if accepts(accept, "image/webp") {
+                    Some(ImageFormat::WebP)
+                } else {
+                    None
+                }
+            });
+
+        let transform = Self {
+            format,
+            preferred,
+            max_width,
+            max_height,
+            strip_metadata: media.strip_metadata,
+            quality: media.quality.clamp(1, 100),
+        };
+
+        transform.is_needed().then_some(transform)
+    }
+
+    fn is_needed(&self) -> bool {
+        self.strip_metadata
+            || self.preferred.is_some()
+            || self.max_width.is_some()
+            || self.max_height.is_some()
+    }
+
+    /// Transform the image, returning the body to send and its content type. The image is passed
+    /// through unchanged when it can't be decoded.
+    pub fn apply(&self, body: Vec<u8>) -> (Vec<u8>, &'static str) {
+        match self.try_apply(&body) {
+            Ok(Some((transformed, format))) => (transformed, format.to_mime_type()),
+            Ok(None) => (body, self.format.to_mime_type()),
+            Err(e) => {
+                logf!(Warning, "Error transforming image: {}", e);
+                (body, self.format.to_mime_type())
+            }
+        }
+    }
+
+    fn try_apply(&self, body: &[u8]) -> Result<Option<(Vec<u8>, ImageFormat)>> {
+        let mut decoder =
+            ImageReader::with_format(Cursor::new(body), self.format).into_decoder()?;
+
+        // Encoding again drops the EXIF data, so its orientation has to be applied to the pixels
+        let orientation = decoder.orientation()?;
+        let mut image = DynamicImage::from_decoder(decoder)?;
+        image.apply_orientation(orientation);
+
+        let max_width = self.max_width.unwrap_or(u32::MAX);
+        let max_height = self.max_height.unwrap_or(u32::MAX);
+        let resized = image.width() > max_width || image.height() > max_height;
+
+        if resized {
+            image = image.resize(max_width, max_height, FilterType::Triangle);
+        }
+
+        let mut candidates = vec![self.encode(&image, self.format)?];
+        if let Some(preferred) = self.preferred {
+            candidates.push(self.encode(&image, preferred)?);
+        }
+
+        let smallest = candidates
+            .into_iter()
+            .min_by_key(|(encoded, _)| encoded.len())
+            .expect("the original format is always a candidate");
+
+        // Without anything that has to change, only send the result if it saves bandwidth
+        if !resized && !self.strip_metadata && smallest.0.len() >= body.len() {
+            return Ok(None);
+        }
+
+        Ok(Some(smallest))
+    }
+
+    fn encode(&self, image: &DynamicImage, format: ImageFormat) -> Result<(Vec<u8>, ImageFormat)> {
+        let mut output = vec![];
+
+        // The encoders only take 8 bit pixels, and JPEG has no alpha channel
+        let image = if format == ImageFormat::Jpeg || !image.color().has_alpha() {
+            DynamicImage::ImageRgb8(image.to_rgb8())
+        } else {
+            DynamicImage::ImageRgba8(image.to_rgba8())
+        };
+
+        match format {
+            ImageFormat::Jpeg => image
+                .write_with_encoder(JpegEncoder::new_with_quality(&mut output, self.quality))?,
+            ImageFormat::Avif => image.write_with_encoder(AvifEncoder::new_with_speed_quality(
+                &mut output,
+                AVIF_SPEED,
+                self.quality,
+            ))?,
+            // Only lossless WebP can be encoded, which mostly pays off for PNGs
+            ImageFormat::WebP => {
+                image.write_with_encoder(WebPEncoder::new_lossless(&mut output))?
+            }
+            _ => image.write_with_encoder(PngEncoder::new(&mut output))?,
+        }
+
+        Ok((output, format))
+    }
+}
+
+/// Whether an `Accept` header explicitly lists a MIME type, without refusing it with `q=0`
+fn accepts(accept: &str, mime: &str) -> bool {
+    accept.split(',').any(|range| {
+        let mut params = range.split(';').map(str::trim);
+
+        params
+            .next()
+            .is_some_and(|name| name.eq_ignore_ascii_case(mime))
+            && !params.any(|param| matches!(param, "q=0" | "q=0.0" | "q=0.00" | "q=0.000"))
+    })
+}
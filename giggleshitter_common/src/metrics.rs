@@ -0,0 +1,297 @@
+use std::{
+    collections::VecDeque,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{SecondsFormat, Utc};
+use lru::LruCache;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::state::MetricsConfig;
+
+/// How many upstream origins latencies are kept for, the least recently used are dropped first
+const TRACKED_ORIGINS: NonZeroUsize = NonZeroUsize::new(512).unwrap();
+
+/// The upper bounds of the latency histogram buckets, in milliseconds. Slower requests go in a
+/// last bucket without a bound.
+const BUCKETS_MS: [u64; 11] = [5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+#[derive(Clone, Copy, Default)]
+/// How long a proxied request spent in each phase, until its response was ready to be sent
+pub struct Timings {
+    /// Decoding the proxied address and preparing the request, including hooks and plugins
+    pub decode: Duration,
+    /// Waiting for the upstream's response headers
+    pub upstream: Duration,
+    /// Reading and rewriting the body, for responses that are rewritten
+    pub rewrite: Duration,
+    pub total: Duration,
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// The number of requests in each bucket, the last one for those slower than every bound
+    counts: [u64; BUCKETS_MS.len() + 1],
+    /// How many of the requests were answered with a server error
+    errors: u64,
+    total_ms: u64,
+    max_ms: u64,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Bucket {
+    /// The latency of the requests in the bucket is at most this, unbounded for the last bucket
+    pub le_ms: Option<u64>,
+    pub count: u64,
+}
+
+#[derive(Serialize, ToSchema)]
+/// The latencies of the requests proxied to an upstream origin
+pub struct OriginLatency {
+    pub origin: String,
+    pub requests: u64,
+    /// How many of the requests were answered with a server error
+    pub errors: u64,
+    pub mean_ms: u64,
+    pub max_ms: u64,
+    pub buckets: Vec<Bucket>,
+}
+
+#[derive(Serialize, Clone, ToSchema)]
+/// A request that took longer than the configured threshold
+pub struct SlowRequest {
+    /// When the response was ready, in RFC 3339
+    pub at: String,
+    pub origin: String,
+    pub method: String,
+    /// The path that was requested, without its query
+    pub path: String,
+    pub status: u16,
+    pub total_ms: u64,
+    pub decode_ms: u64,
+    pub upstream_ms: u64,
+    pub rewrite_ms: u64,
+}
+
+#[derive(Serialize, ToSchema)]
+/// The traffic through the proxy since the server started
+pub struct Traffic {
+    /// The requests that were sent to an upstream, or failed trying
+    pub requests: u64,
+    /// The requests that failed, or were answered with a server error
+    pub errors: u64,
+    /// The proxied requests being handled right now
+    pub in_flight: u64,
+    /// The proxied requests the client went away from before their response was all sent
+    pub cancelled: u64,
+    pub websockets_open: u64,
+    pub websockets_total: u64,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Stats {
+    pub origins: Vec<OriginLatency>,
+    /// The most recent slow requests, newest first
+    pub slow_requests: Vec<SlowRequest>,
+}
+
+struct Inner {
+    origins: LruCache<String, Histogram>,
+    slow: VecDeque<SlowRequest>,
+    requests: u64,
+    errors: u64,
+    in_flight: u64,
+    cancelled: u64,
+    websockets_open: u64,
+    websockets_total: u64,
+}
+
+#[derive(Clone)]
+/// Latency histograms of proxied requests by upstream origin, and a log of the slow ones
+pub struct Metrics(Arc<Mutex<Inner>>);
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(Inner {
+            origins: LruCache::new(TRACKED_ORIGINS),
+            slow: VecDeque::new(),
+            requests: 0,
+            errors: 0,
+            in_flight: 0,
+            cancelled: 0,
+            websockets_open: 0,
+            websockets_total: 0,
+        })))
+    }
+}
+
+impl Metrics {
+    /// Count a request to `origin` that took `timings`, and log it if it was slow
+    pub fn record(
+        &self,
+        config: &MetricsConfig,
+        origin: &str,
+        method: &str,
+        path: &str,
+        status: u16,
+        timings: Timings,
+    ) {
+        let total_ms = timings.total.as_millis() as u64;
+        let error = status >= 500;
+        let mut inner = self.0.lock().unwrap();
+
+        inner.requests += 1;
+        inner.errors += error as u64;
+
+        let histogram = inner
+            .origins
+            .get_or_insert_mut(origin.to_string(), Histogram::default);
+        let bucket = BUCKETS_MS
+            .iter()
+            .position(|&bound| total_ms <= bound)
+            .unwrap_or(BUCKETS_MS.len());
+        histogram.counts[bucket] += 1;
+        histogram.errors += error as u64;
+        histogram.total_ms += total_ms;
+        histogram.max_ms = histogram.max_ms.max(total_ms);
+
+        if total_ms < config.slow_request_ms || config.slow_log_size == 0 {
+            return;
+        }
+
+        inner.slow.truncate(config.slow_log_size - 1);
+        inner.slow.push_front(SlowRequest {
+            at: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            origin: origin.to_string(),
+            method: method.to_string(),
+            path: path.to_string(),
+            status,
+            total_ms,
+            decode_ms: timings.decode.as_millis() as u64,
+            upstream_ms: timings.upstream.as_millis() as u64,
+            rewrite_ms: timings.rewrite.as_millis() as u64,
+        });
+    }
+
+    /// Count a request that failed before a response could be sent
+    pub fn record_failure(&self) {
+        let mut inner = self.0.lock().unwrap();
+        inner.requests += 1;
+        inner.errors += 1;
+    }
+
+    /// Count a request as in flight until the returned guard is dropped
+    pub fn request_started(&self) -> InFlight {
+        self.0.lock().unwrap().in_flight += 1;
+        InFlight {
+            metrics: self.clone(),
+            websocket: false,
+        }
+    }
+
+    /// Count a proxied request as cancelled if the returned guard is dropped before it's finished
+    pub fn transfer_started(&self) -> Transfer {
+        Transfer {
+            metrics: self.clone(),
+            finished: false,
+        }
+    }
+
+    /// Count a proxied WebSocket as open until the returned guard is dropped
+    pub fn websocket_opened(&self) -> InFlight {
+        let mut inner = self.0.lock().unwrap();
+        inner.websockets_open += 1;
+        inner.websockets_total += 1;
+
+        InFlight {
+            metrics: self.clone(),
+            websocket: true,
+        }
+    }
+
+    pub fn traffic(&self) -> Traffic {
+        let inner = self.0.lock().unwrap();
+
+        Traffic {
+            requests: inner.requests,
+            errors: inner.errors,
+            in_flight: inner.in_flight,
+            cancelled: inner.cancelled,
+            websockets_open: inner.websockets_open,
+            websockets_total: inner.websockets_total,
+        }
+    }
+
+    pub fn snapshot(&self) -> Stats {
+        let inner = self.0.lock().unwrap();
+
+        let origins = inner
+            .origins
+            .iter()
+            .map(|(origin, histogram)| {
+                let requests = histogram.counts.iter().sum::<u64>();
+
+                OriginLatency {
+                    origin: origin.clone(),
+                    requests,
+                    errors: histogram.errors,
+                    mean_ms: histogram.total_ms / requests.max(1),
+                    max_ms: histogram.max_ms,
+                    buckets: BUCKETS_MS
+                        .iter()
+                        .map(|&bound| Some(bound))
+                        .chain([None])
+                        .zip(histogram.counts)
+                        .map(|(le_ms, count)| Bucket { le_ms, count })
+                        .collect(),
+                }
+            })
+            .collect();
+
+        Stats {
+            origins,
+            slow_requests: inner.slow.iter().cloned().collect(),
+        }
+    }
+}
+
+/// A request or WebSocket that is counted as open for as long as this is kept
+pub struct InFlight {
+    metrics: Metrics,
+    websocket: bool,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let mut inner = self.metrics.0.lock().unwrap();
+
+        match self.websocket {
+            true => inner.websockets_open -= 1,
+            false => inner.in_flight -= 1,
+        }
+    }
+}
+
+/// A proxied request from its start until its response has all been sent
+pub struct Transfer {
+    metrics: Metrics,
+    finished: bool,
+}
+
+impl Transfer {
+    /// The request is done with, whether it succeeded or not
+    pub fn finish(mut self) {
+        self.finished = true;
+    }
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.metrics.0.lock().unwrap().cancelled += 1;
+        }
+    }
+}
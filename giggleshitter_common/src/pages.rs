@@ -0,0 +1,150 @@
+use askama::Template;
+use axum::{
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+};
+use scorched::{logf, LogData, LogImportance};
+
+#[derive(Template)]
+#[template(path = "message.html")]
+struct MessagePage<'a> {
+    title: &'a str,
+    message: &'a str,
+    /// The correlation ID of a refused request
+    reference: Option<&'a str>,
+}
+
+#[derive(Template)]
+#[template(path = "retry.html")]
+struct RetryPage<'a> {
+    title: &'a str,
+    message: &'a str,
+    origin: Option<&'a str>,
+    delay_secs: u64,
+    max_delay_secs: u64,
+}
+
+#[derive(Template)]
+#[template(path = "admin.html")]
+struct AdminPage;
+
+#[derive(Template)]
+#[template(path = "bookmarklet.html")]
+struct BookmarkletPage<'a> {
+    public_host: &'a str,
+    bookmarklet: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "index.html")]
+struct IndexPage<'a> {
+    public_host: &'a str,
+}
+
+/// An entry of a [`listing_page`]
+pub struct ListingEntry {
+    pub name: String,
+    /// Relative to the listed directory, with a trailing slash for subdirectories
+    pub href: String,
+}
+
+#[derive(Template)]
+#[template(path = "listing.html")]
+struct ListingPage<'a> {
+    title: &'a str,
+    has_parent: bool,
+    entries: &'a [ListingEntry],
+}
+
+#[derive(Template)]
+#[template(path = "reader.html")]
+struct ReaderPage<'a> {
+    title: &'a str,
+    byline: Option<&'a str>,
+    /// Sanitized by the reader rewriter
+    content: &'a str,
+}
+
+fn render(template: &impl Template) -> String {
+    template.render().unwrap_or_else(|e| {
+        logf!(Error, "Error rendering page: {}", e);
+        String::new()
+    })
+}
+
+/// A standalone page shown instead of a proxied site, e.g. when a request is refused
+pub fn message_page(title: &str, message: &str) -> String {
+    render(&MessagePage {
+        title,
+        message,
+        reference: None,
+    })
+}
+
+/// A [`message_page`] refusing a request, with the correlation ID operators can look up why with
+pub fn refusal_page(title: &str, message: &str, reference: &str) -> String {
+    render(&MessagePage {
+        title,
+        message,
+        reference: Some(reference),
+    })
+}
+
+/// Respond with a [`message_page`]
+pub fn message_response(status: StatusCode, title: &str, message: &str) -> Response {
+    (status, Html(message_page(title, message))).into_response()
+}
+
+/// How long the [`retry_page`] waits before its first retry, doubling with every retry after
+pub const RETRY_DELAY_SECS: u64 = 5;
+
+/// The longest the [`retry_page`] waits between retries
+const MAX_RETRY_DELAY_SECS: u64 = 60;
+
+/// A page shown when the upstream of a proxied site failed, which reloads itself until it gets
+/// through
+pub fn retry_page(title: &str, message: &str, origin: Option<&str>) -> String {
+    render(&RetryPage {
+        title,
+        message,
+        origin,
+        delay_secs: RETRY_DELAY_SECS,
+        max_delay_secs: MAX_RETRY_DELAY_SECS,
+    })
+}
+
+/// A directory listing, such as that of an FTP directory served through the gateway
+pub fn listing_page(title: &str, has_parent: bool, entries: &[ListingEntry]) -> String {
+    render(&ListingPage {
+        title,
+        has_parent,
+        entries,
+    })
+}
+
+/// The article view of a page in reader mode
+pub fn reader_page(title: &str, byline: Option<&str>, content: &str) -> String {
+    render(&ReaderPage {
+        title,
+        byline,
+        content,
+    })
+}
+
+/// The admin dashboard, which loads its data from the admin API
+pub fn admin_page() -> String {
+    render(&AdminPage)
+}
+
+/// A page with a bookmarklet that opens pages through the proxy
+pub fn bookmarklet_page(public_host: &str, bookmarklet: &str) -> String {
+    render(&BookmarkletPage {
+        public_host,
+        bookmarklet,
+    })
+}
+
+/// The landing page of the API host, with a form that opens a URL through the proxy
+pub fn index_page(public_host: &str) -> String {
+    render(&IndexPage { public_host })
+}
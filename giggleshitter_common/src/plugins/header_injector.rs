@@ -0,0 +1,61 @@
+use futures_util::future::{self, BoxFuture};
+use hyper::{header::HeaderName, header::HeaderValue, HeaderMap};
+
+use crate::hooks::{HookVerdict, RequestEvent, ResponseEvent};
+
+use super::ProxyPlugin;
+
+#[derive(Clone, Default)]
+/// A plugin that sets fixed headers on upstream requests and client responses, replacing any
+/// header of the same name
+///
+/// ```
+/// # use std::sync::Arc;
+/// # use giggleshitter_common::{plugins::header_injector::HeaderInjector, state::Config};
+/// # use giggleshitter_common::server::ServerBuilder;
+/// # use hyper::header::{HeaderName, HeaderValue};
+/// let injector = HeaderInjector::new().response_header(
+///     HeaderName::from_static("x-served-by"),
+///     HeaderValue::from_static("giggleshitter"),
+/// );
+///
+/// let builder = ServerBuilder::new(Arc::new(Config::default())).with_plugin(Arc::new(injector));
+/// ```
+pub struct HeaderInjector {
+    request: HeaderMap,
+    response: HeaderMap,
+}
+
+impl HeaderInjector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set a header on every request sent upstream
+    pub fn request_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.request.insert(name, value);
+        self
+    }
+
+    /// Set a header on every response sent to the client
+    pub fn response_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.response.insert(name, value);
+        self
+    }
+}
+
+impl ProxyPlugin for HeaderInjector {
+    fn on_request<'a>(&'a self, request: &'a mut RequestEvent) -> BoxFuture<'a, HookVerdict> {
+        for (name, value) in &self.request {
+            request.headers.insert(name, value.clone());
+        }
+
+        Box::pin(future::ready(HookVerdict::Continue))
+    }
+
+    fn on_response(&self, response: &mut ResponseEvent) {
+        for (name, value) in &self.response {
+            response.headers.insert(name, value.clone());
+        }
+    }
+}
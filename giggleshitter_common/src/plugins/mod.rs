@@ -0,0 +1,148 @@
+pub mod header_injector;
+#[cfg(feature = "plugins")]
+pub mod wasm;
+
+use std::sync::Arc;
+
+use futures_util::future::{self, BoxFuture};
+
+use crate::hooks::{HookVerdict, RequestEvent, ResponseEvent};
+
+/// Which way a WebSocket message is travelling
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WsDirection {
+    /// From the client to the upstream
+    ToUpstream,
+    /// From the upstream to the client
+    ToClient,
+}
+
+/// The payload of a WebSocket data message. Control frames are forwarded without involving
+/// plugins.
+pub enum WsMessage {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl From<WsMessage> for axum::extract::ws::Message {
+    fn from(message: WsMessage) -> Self {
+        match message {
+            WsMessage::Text(text) => Self::Text(text),
+            WsMessage::Binary(bin) => Self::Binary(bin),
+        }
+    }
+}
+
+impl From<WsMessage> for reqwest_websocket::Message {
+    fn from(message: WsMessage) -> Self {
+        match message {
+            WsMessage::Text(text) => Self::Text(text),
+            WsMessage::Binary(bin) => Self::Binary(bin),
+        }
+    }
+}
+
+/// A WebSocket message that is about to be forwarded
+pub struct WsMessageEvent<'a> {
+    /// The decoded upstream URL of the WebSocket
+    pub url: &'a str,
+    pub direction: WsDirection,
+    pub message: WsMessage,
+}
+
+/// What to do with a WebSocket message after a plugin has seen it
+pub enum WsVerdict {
+    /// Forward the (possibly modified) message
+    Forward,
+    /// Drop the message without forwarding it
+    Drop,
+}
+
+/// Middleware around the upstream call that, unlike a [`crate::hooks::ProxyHook`], may change the
+/// response and WebSocket traffic as well as the request. Every method has a no-op default.
+pub trait ProxyPlugin: Send + Sync {
+    /// Called before the request is sent upstream, after the hooks. Plugins may modify the request
+    /// in place or answer it themselves by returning [`HookVerdict::Respond`].
+    fn on_request<'a>(&'a self, _request: &'a mut RequestEvent) -> BoxFuture<'a, HookVerdict> {
+        Box::pin(future::ready(HookVerdict::Continue))
+    }
+
+    /// Called with the upstream response before its body is rewritten, plugins may change the
+    /// status and headers sent to the client
+    fn on_response(&self, _response: &mut ResponseEvent) {}
+
+    /// Called with the body of a response after it was rewritten, returning the body to send.
+    /// Responses that are streamed through without rewriting don't pass through here.
+    fn on_body(&self, _url: &str, body: Vec<u8>) -> Vec<u8> {
+        body
+    }
+
+    /// Called for every text and binary message of a proxied WebSocket
+    fn on_ws_message(&self, _event: &mut WsMessageEvent) -> WsVerdict {
+        WsVerdict::Forward
+    }
+}
+
+#[derive(Clone, Default)]
+/// The plugins registered on a proxy, invoked in registration order
+pub struct Plugins(Vec<Arc<dyn ProxyPlugin>>);
+
+impl Plugins {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, plugin: Arc<dyn ProxyPlugin>) {
+        self.0.push(plugin);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Run the request plugins, stopping at the first one that answers the request
+    pub async fn on_request(&self, request: &mut RequestEvent) -> HookVerdict {
+        for plugin in &self.0 {
+            if let HookVerdict::Respond(response) = plugin.on_request(request).await {
+                return HookVerdict::Respond(response);
+            }
+        }
+
+        HookVerdict::Continue
+    }
+
+    pub fn on_response(&self, response: &mut ResponseEvent) {
+        self.0
+            .iter()
+            .for_each(|plugin| plugin.on_response(response));
+    }
+
+    pub fn on_body(&self, url: &str, body: Vec<u8>) -> Vec<u8> {
+        self.0
+            .iter()
+            .fold(body, |body, plugin| plugin.on_body(url, body))
+    }
+
+    /// Run the WebSocket plugins, returning the message to forward or `None` if one of them
+    /// dropped it
+    pub fn on_ws_message(
+        &self,
+        url: &str,
+        direction: WsDirection,
+        message: WsMessage,
+    ) -> Option<WsMessage> {
+        let mut event = WsMessageEvent {
+            url,
+            direction,
+            message,
+        };
+
+        for plugin in &self.0 {
+            if let WsVerdict::Drop = plugin.on_ws_message(&mut event) {
+                return None;
+            }
+        }
+
+        Some(event.message)
+    }
+}
@@ -0,0 +1,313 @@
+//! Plugins compiled to WebAssembly, loaded from the configured plugins directory.
+//!
+//! # ABI
+//!
+//! A module must export its `memory` and an allocator the proxy uses to pass it data:
+//!
+//! - `gs_alloc(len: i32) -> i32` returns a pointer to `len` writable bytes
+//!
+//! and any of these entry points, which receive a pointer and length of their input and return
+//! either `0` to leave everything unchanged, or the pointer of their output in the upper 32 bits
+//! and its length in the lower 32 bits:
+//!
+//! - `gs_on_request(ptr: i32, len: i32) -> i64` receives the request as JSON,
+//!   `{"method": "GET", "url": "https://example.com/", "headers": [["accept", "*/*"]],
+//!   "client": "203.0.113.7"}`, where `client` is `null` when the client's address is unknown
+//! - `gs_on_response(ptr: i32, len: i32) -> i64` receives the response as JSON, the request
+//!   fields plus `"status": 200`, with the headers that will be sent to the client
+//! - `gs_on_body(ptr: i32, len: i32) -> i64` receives the body of a rewritten response and
+//!   returns the replacement body
+//!
+//! The request and response entry points return a JSON verdict, every field of which is
+//! optional:
+//!
+//! ```json
+//! {
+//!     "block": {"status": 403, "body": "Blocked"},
+//!     "url": "https://example.com/other",
+//!     "status": 200,
+//!     "set_headers": [["x-example", "1"]],
+//!     "remove_headers": ["cookie"]
+//! }
+//! ```
+//!
+//! `block` answers a request without contacting the upstream, `url` sends it elsewhere and
+//! `status` only applies to responses. Every call runs in a fresh instance with a fuel limit, a
+//! module that traps or runs out of fuel is logged and ignored.
+
+use std::{fs, net::IpAddr, path::Path};
+
+use axum::response::IntoResponse;
+use futures_util::future::{self, BoxFuture};
+use hyper::{
+    header::{HeaderName, HeaderValue},
+    HeaderMap, StatusCode,
+};
+use scorched::{logf, LogData, LogImportance};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use wasmtime::{Config, Engine, InstancePre, Linker, Module, Store};
+
+use crate::{
+    error::Result,
+    hooks::{HookVerdict, RequestEvent, ResponseEvent},
+    proxy::headers::retain_headers,
+};
+
+use super::ProxyPlugin;
+
+/// How many instructions, roughly, a single call may execute
+const FUEL_PER_CALL: u64 = 100_000_000;
+
+/// A WebAssembly module implementing the plugin ABI
+pub struct WasmPlugin {
+    name: String,
+    engine: Engine,
+    instance: InstancePre<()>,
+}
+
+#[derive(Serialize)]
+struct RequestInput<'a> {
+    method: &'a str,
+    url: &'a str,
+    headers: Vec<(&'a str, &'a str)>,
+    client: Option<IpAddr>,
+}
+
+#[derive(Serialize)]
+struct ResponseInput<'a> {
+    method: &'a str,
+    url: &'a str,
+    status: u16,
+    headers: Vec<(&'a str, &'a str)>,
+    client: Option<IpAddr>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Verdict {
+    block: Option<Block>,
+    url: Option<String>,
+    status: Option<u16>,
+    set_headers: Vec<(String, String)>,
+    remove_headers: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct Block {
+    #[serde(default = "default_block_status")]
+    status: u16,
+    #[serde(default)]
+    body: String,
+}
+
+const fn default_block_status() -> u16 {
+    403
+}
+
+/// Load every `.wasm` file in `dir`, in file name order
+pub fn load_dir(dir: &Path) -> Result<Vec<WasmPlugin>> {
+    let mut config = Config::new();
+    config.consume_fuel(true);
+    let engine = Engine::new(&config)?;
+
+    let mut paths = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "wasm")
+        })
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    paths
+        .iter()
+        .map(|path| {
+            let module = Module::from_file(&engine, path)?;
+            let instance = Linker::new(&engine).instantiate_pre(&module)?;
+
+            logf!(Info, "Loaded plugin {}", path.display());
+
+            Ok(WasmPlugin {
+                name: path.display().to_string(),
+                engine: engine.clone(),
+                instance,
+            })
+        })
+        .collect()
+}
+
+impl WasmPlugin {
+    /// Call an entry point with `input`, returning its output or `None` when it left everything
+    /// unchanged or isn't exported
+    fn call(&self, entry_point: &str, input: &[u8]) -> Option<Vec<u8>> {
+        let mut store = Store::new(&self.engine, ());
+
+        match self.try_call(&mut store, entry_point, input) {
+            Ok(output) => output,
+            Err(e) => {
+                logf!(
+                    Error,
+                    "Plugin {} failed in {}: {}",
+                    self.name,
+                    entry_point,
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    fn try_call(
+        &self,
+        store: &mut Store<()>,
+        entry_point: &str,
+        input: &[u8],
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        store.set_fuel(FUEL_PER_CALL)?;
+
+        let instance = self.instance.instantiate(&mut *store)?;
+
+        let Some(function) = instance.get_func(&mut *store, entry_point) else {
+            return Ok(None);
+        };
+        let function = function.typed::<(i32, i32), i64>(&*store)?;
+
+        let memory = instance
+            .get_memory(&mut *store, "memory")
+            .ok_or_else(|| anyhow::anyhow!("the module doesn't export its memory"))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut *store, "gs_alloc")?;
+
+        let len = i32::try_from(input.len())?;
+        let ptr = alloc.call(&mut *store, len)?;
+        memory.write(&mut *store, ptr as u32 as usize, input)?;
+
+        let packed = function.call(&mut *store, (ptr, len))? as u64;
+        if packed == 0 {
+            return Ok(None);
+        }
+
+        let (ptr, len) = ((packed >> 32) as usize, (packed & 0xffff_ffff) as usize);
+        let mut output = vec![0; len];
+        memory.read(&*store, ptr, &mut output)?;
+
+        Ok(Some(output))
+    }
+
+    fn call_json<T: DeserializeOwned>(
+        &self,
+        entry_point: &str,
+        input: &impl Serialize,
+    ) -> Option<T> {
+        let input = serde_json::to_vec(input).ok()?;
+        let output = self.call(entry_point, &input)?;
+
+        match serde_json::from_slice(&output) {
+            Ok(output) => Some(output),
+            Err(e) => {
+                logf!(
+                    Error,
+                    "Plugin {} returned an invalid verdict: {}",
+                    self.name,
+                    e
+                );
+                None
+            }
+        }
+    }
+}
+
+fn header_pairs(headers: &HeaderMap) -> Vec<(&str, &str)> {
+    headers
+        .iter()
+        .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?)))
+        .collect()
+}
+
+/// Remove and set the headers of a verdict. Setting a header replaces the values it had, but a
+/// header set more than once, such as `set-cookie`, keeps every value it's set to.
+fn apply_headers(verdict: &Verdict, headers: &mut HeaderMap) {
+    let set = verdict
+        .set_headers
+        .iter()
+        .filter_map(|(name, value)| {
+            Some((
+                HeaderName::from_bytes(name.as_bytes()).ok()?,
+                HeaderValue::from_str(value).ok()?,
+            ))
+        })
+        .collect::<Vec<_>>();
+
+    retain_headers(headers, |name| {
+        !verdict
+            .remove_headers
+            .iter()
+            .any(|removed| name.as_str().eq_ignore_ascii_case(removed))
+            && !set.iter().any(|(set, _)| set == name)
+    });
+
+    headers.extend(set);
+}
+
+impl ProxyPlugin for WasmPlugin {
+    fn on_request<'a>(&'a self, request: &'a mut RequestEvent) -> BoxFuture<'a, HookVerdict> {
+        let verdict: Option<Verdict> = self.call_json(
+            "gs_on_request",
+            &RequestInput {
+                method: request.method.as_str(),
+                url: &request.url,
+                headers: header_pairs(&request.headers),
+                client: request.client,
+            },
+        );
+
+        let Some(verdict) = verdict else {
+            return Box::pin(future::ready(HookVerdict::Continue));
+        };
+
+        if let Some(block) = verdict.block {
+            let status = StatusCode::from_u16(block.status).unwrap_or(StatusCode::FORBIDDEN);
+
+            return Box::pin(future::ready(HookVerdict::Respond(
+                (status, block.body).into_response(),
+            )));
+        }
+
+        apply_headers(&verdict, &mut request.headers);
+
+        if let Some(url) = verdict.url {
+            request.url = url;
+        }
+
+        Box::pin(future::ready(HookVerdict::Continue))
+    }
+
+    fn on_response(&self, response: &mut ResponseEvent) {
+        let verdict: Option<Verdict> = self.call_json(
+            "gs_on_response",
+            &ResponseInput {
+                method: response.method.as_str(),
+                url: &response.url,
+                status: response.status.as_u16(),
+                headers: header_pairs(&response.headers),
+                client: response.client,
+            },
+        );
+
+        let Some(verdict) = verdict else {
+            return;
+        };
+
+        apply_headers(&verdict, &mut response.headers);
+
+        if let Some(status) = verdict
+            .status
+            .and_then(|status| StatusCode::from_u16(status).ok())
+        {
+            response.status = status;
+        }
+    }
+
+    fn on_body(&self, _url: &str, body: Vec<u8>) -> Vec<u8> {
+        self.call("gs_on_body", &body).unwrap_or(body)
+    }
+}
@@ -0,0 +1,30 @@
+//! The types and functions most embedders need, re-exported from one place. Everything here is
+//! kept stable across releases, while the modules they come from may be reorganised.
+//!
+//! ```
+//! use giggleshitter_common::prelude::*;
+//!
+//! let config = Config {
+//!     url_encoding_algorithm: UrlEncodingAlgorithm::Base32(Alphabet::Z),
+//!     public_host: "proxy.example".to_string(),
+//!     ..Default::default()
+//! };
+//!
+//! let proxied = encode_url(&config, "https://example.com/index.html");
+//! assert_eq!(decode_url(&config, &proxied)?, "https://example.com:443/index.html");
+//! # Ok::<(), giggleshitter_common::error::AppError>(())
+//! ```
+
+pub use base32::Alphabet;
+
+pub use crate::{
+    build_app,
+    error::{AppError, Result},
+    hooks::{HookVerdict, ProxyHook},
+    plugins::ProxyPlugin,
+    proxy::util::{decode_url, encode_url, proxied_origin, strip_tracking_params, Origin, Scheme},
+    rewriting::rewriter::{RewriteContext, Rewriter},
+    run, serve,
+    server::ServerBuilder,
+    state::{Config, LiveConfig, UrlEncodingAlgorithm},
+};
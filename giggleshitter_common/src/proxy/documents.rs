@@ -0,0 +1,54 @@
+//! PDFs and office documents, which are never rewritten. They are either served as the upstream
+//! sent them, as attachments, or for PDFs, as a sanitizer service turned them out.
+
+use hyper::{
+    header::{HeaderValue, CONTENT_DISPOSITION, CONTENT_TYPE},
+    HeaderMap,
+};
+
+use crate::{error::Result, rules::glob, state::DocumentConfig};
+
+/// Whether a response of `content_type` is a document
+pub fn is_document(config: &DocumentConfig, content_type: Option<&str>) -> bool {
+    content_type.is_some_and(|content_type| {
+        config
+            .content_types
+            .iter()
+            .any(|pattern| glob(pattern, content_type))
+    })
+}
+
+/// Have the browser download the response rather than show it, keeping the file name the upstream
+/// gave it
+pub fn force_attachment(headers: &mut HeaderMap) {
+    let parameters = headers
+        .get(CONTENT_DISPOSITION)
+        .and_then(|disposition| disposition.to_str().ok())
+        .and_then(|disposition| disposition.split_once(';'))
+        .map(|(_, parameters)| parameters.trim().to_string());
+
+    let disposition = match parameters {
+        Some(parameters) if !parameters.is_empty() => {
+            HeaderValue::from_str(&format!("attachment; {}", parameters))
+                .unwrap_or(HeaderValue::from_static("attachment"))
+        }
+        _ => HeaderValue::from_static("attachment"),
+    };
+
+    headers.insert(CONTENT_DISPOSITION, disposition);
+}
+
+/// The PDF the sanitizer at `url` makes of `pdf`
+pub async fn sanitize(client: &reqwest::Client, url: &str, pdf: Vec<u8>) -> Result<Vec<u8>> {
+    let sanitized = client
+        .post(url)
+        .header(CONTENT_TYPE, "application/pdf")
+        .body(pdf)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+
+    Ok(sanitized.to_vec())
+}
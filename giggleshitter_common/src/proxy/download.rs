@@ -0,0 +1,210 @@
+use std::{io, time::Duration};
+
+use futures_util::{stream, StreamExt, TryStreamExt};
+use hyper::{
+    header::{HeaderValue, ACCEPT_RANGES, CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE},
+    HeaderMap, Method, StatusCode,
+};
+use scorched::{logf, LogData, LogImportance};
+
+use crate::state::DownloadConfig;
+
+use super::encoding::ByteStream;
+
+/// How long to wait before asking the upstream for the rest of an interrupted download
+const RESUME_DELAY: Duration = Duration::from_millis(500);
+
+/// What is needed to ask the upstream for the rest of a response whose body broke off
+pub struct Resume {
+    client: reqwest::Client,
+    url: String,
+    headers: HeaderMap,
+    /// A strong `ETag` or `Last-Modified`, so that a changed file isn't stitched together
+    validator: HeaderValue,
+    /// The offset in the file of the next byte to send the client
+    position: u64,
+    /// The offset of the last byte the client asked for, if it asked for a range
+    end: Option<u64>,
+    attempts: u32,
+    stall_timeout: Option<Duration>,
+}
+
+impl Resume {
+    /// `None` unless the response is a plain GET of a file that the upstream serves ranges of.
+    /// Bodies the client decompressed have no `Content-Length` and are never resumed, as ranges
+    /// refer to the compressed bytes.
+    pub fn new(
+        client: &reqwest::Client,
+        method: &Method,
+        url: &str,
+        request_headers: HeaderMap,
+        response: &reqwest::Response,
+        config: &DownloadConfig,
+    ) -> Option<Self> {
+        let attempts = config.resume_attempts;
+
+        if attempts == 0 || method != Method::GET || response.content_length().is_none() {
+            return None;
+        }
+
+        let headers = response.headers();
+
+        if headers.get(ACCEPT_RANGES)?.as_bytes() != b"bytes" {
+            return None;
+        }
+
+        let validator = match headers.get(ETAG) {
+            Some(etag) if !etag.as_bytes().starts_with(b"W/") => etag.clone(),
+            _ => headers.get(LAST_MODIFIED)?.clone(),
+        };
+
+        let (position, end) = match response.status() {
+            StatusCode::OK => (0, None),
+            StatusCode::PARTIAL_CONTENT => {
+                let (start, end) = content_range(headers.get(CONTENT_RANGE)?)?;
+                (start, Some(end))
+            }
+            _ => return None,
+        };
+
+        Some(Self {
+            client: client.clone(),
+            url: url.to_string(),
+            headers: request_headers,
+            validator,
+            position,
+            end,
+            attempts,
+            stall_timeout: config.stall_timeout_secs.map(Duration::from_secs),
+        })
+    }
+
+    /// Request the rest of the file from the current position
+    async fn reconnect(&self) -> io::Result<ByteStream> {
+        let range = match self.end {
+            Some(end) => format!("bytes={}-{}", self.position, end),
+            None => format!("bytes={}-", self.position),
+        };
+
+        let mut headers = self.headers.clone();
+        headers.insert(
+            RANGE,
+            HeaderValue::from_str(&range).map_err(io::Error::other)?,
+        );
+        headers.insert(IF_RANGE, self.validator.clone());
+
+        let res = self
+            .client
+            .get(&self.url)
+            .headers(headers)
+            .send()
+            .await
+            .map_err(io::Error::other)?;
+
+        // Anything but the requested range means the file changed or ranges aren't served anymore
+        let resumes_here = res.status() == StatusCode::PARTIAL_CONTENT
+            && res
+                .headers()
+                .get(CONTENT_RANGE)
+                .and_then(content_range)
+                .is_some_and(|(start, _)| start == self.position);
+
+        if !resumes_here {
+            return Err(io::Error::other(format!(
+                "the upstream answered the resumed request with {}",
+                res.status()
+            )));
+        }
+
+        let rest = res.bytes_stream().map_err(io::Error::other).boxed();
+
+        Ok(match self.stall_timeout {
+            Some(timeout) => stall_guarded(rest, timeout),
+            None => rest,
+        })
+    }
+}
+
+/// Pass the upstream body through, requesting the rest of it again when the connection to the
+/// upstream breaks off before it is complete
+pub fn resumable(upstream: ByteStream, resume: Resume) -> ByteStream {
+    stream::unfold(Some((upstream, resume)), |state| async move {
+        let (mut upstream, mut resume) = state?;
+
+        loop {
+            match upstream.next().await {
+                Some(Ok(chunk)) => {
+                    resume.position += chunk.len() as u64;
+                    return Some((Ok(chunk), Some((upstream, resume))));
+                }
+                Some(Err(e)) if resume.attempts > 0 => {
+                    resume.attempts -= 1;
+                    logf!(
+                        Warning,
+                        "Download of {} broke off at byte {}, resuming: {}",
+                        resume.url,
+                        resume.position,
+                        e
+                    );
+
+                    tokio::time::sleep(RESUME_DELAY).await;
+
+                    match resume.reconnect().await {
+                        Ok(rest) => upstream = rest,
+                        Err(e) => return Some((Err(e), None)),
+                    }
+                }
+                Some(Err(e)) => return Some((Err(e), None)),
+                None => return None,
+            }
+        }
+    })
+    .boxed()
+}
+
+/// Pass the upstream body through, failing it once nothing has arrived for `timeout`
+pub fn stall_guarded(upstream: ByteStream, timeout: Duration) -> ByteStream {
+    stream::unfold(upstream, move |mut upstream| async move {
+        match tokio::time::timeout(timeout, upstream.next()).await {
+            Ok(chunk) => Some((chunk?, upstream)),
+            Err(_) => Some((Err(stalled(timeout)), stream::empty().boxed())),
+        }
+    })
+    .boxed()
+}
+
+/// The error of an upstream that stopped sending for `timeout`
+pub fn stalled(timeout: Duration) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("the upstream sent nothing for {}s", timeout.as_secs()),
+    )
+}
+
+/// Pass the upstream body through until it grows past `max_bytes`, then fail it so the client
+/// doesn't mistake the truncated body for a complete one
+pub fn capped(upstream: ByteStream, max_bytes: u64) -> ByteStream {
+    let mut sent = 0u64;
+
+    upstream
+        .map(move |chunk| {
+            let chunk = chunk?;
+            sent += chunk.len() as u64;
+
+            if sent > max_bytes {
+                return Err(io::Error::other("the download is larger than allowed"));
+            }
+
+            Ok(chunk)
+        })
+        .boxed()
+}
+
+/// The first and last byte of a `Content-Range` such as `bytes 0-499/1234`
+fn content_range(value: &HeaderValue) -> Option<(u64, u64)> {
+    let range = value.to_str().ok()?.strip_prefix("bytes ")?;
+    let (range, _) = range.split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+
+    Some((start.trim().parse().ok()?, end.trim().parse().ok()?))
+}
@@ -0,0 +1,59 @@
+use std::io;
+
+use async_compression::tokio::bufread::{BrotliDecoder, GzipDecoder, ZlibDecoder, ZstdDecoder};
+use axum::body::Bytes;
+use futures_util::{stream::BoxStream, StreamExt};
+use hyper::header::HeaderValue;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+/// The content codings the proxy can decode, and so may let upstreams respond with
+const SUPPORTED: &[&str] = &["gzip", "br", "deflate", "zstd"];
+
+/// Every supported content coding, sent upstream when the proxy decodes all responses itself
+pub const ALL: &str = "gzip, br, deflate, zstd";
+
+pub type ByteStream = BoxStream<'static, io::Result<Bytes>>;
+
+pub fn is_supported(encoding: &str) -> bool {
+    encoding.eq_ignore_ascii_case("identity")
+        || SUPPORTED
+            .iter()
+            .any(|supported| encoding.eq_ignore_ascii_case(supported))
+}
+
+/// The client's `Accept-Encoding`, restricted to the codings the proxy can decode in case the
+/// response has to be rewritten. `None` when nothing is left, asking for an uncompressed response.
+pub fn accepted(client: Option<&HeaderValue>) -> Option<HeaderValue> {
+    let accepted = client?
+        .to_str()
+        .ok()?
+        .split(',')
+        .map(str::trim)
+        .filter(|coding| {
+            let name = coding.split(';').next().unwrap_or_default().trim();
+            SUPPORTED
+                .iter()
+                .any(|supported| name.eq_ignore_ascii_case(supported))
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    if accepted.is_empty() {
+        return None;
+    }
+
+    HeaderValue::from_str(&accepted).ok()
+}
+
+/// Decode a body sent with the given `Content-Encoding`, which must be [`is_supported`]
+pub fn decode(encoding: &str, body: ByteStream) -> ByteStream {
+    let reader = StreamReader::new(body);
+
+    match encoding.to_ascii_lowercase().as_str() {
+        "gzip" => ReaderStream::new(GzipDecoder::new(reader)).boxed(),
+        "br" => ReaderStream::new(BrotliDecoder::new(reader)).boxed(),
+        "deflate" => ReaderStream::new(ZlibDecoder::new(reader)).boxed(),
+        "zstd" => ReaderStream::new(ZstdDecoder::new(reader)).boxed(),
+        _ => ReaderStream::new(reader).boxed(),
+    }
+}
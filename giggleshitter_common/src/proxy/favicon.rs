@@ -0,0 +1,185 @@
+//! `/favicon.ico`, asked for by browsers on every proxied host. Origins' icons are kept so that
+//! they are only fetched once, and a default icon stands in for the origins without one.
+
+use std::{
+    num::NonZeroUsize,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Bytes,
+    response::{IntoResponse, Response},
+};
+use hyper::{
+    header::{HeaderValue, CACHE_CONTROL, CONTENT_TYPE},
+    Method, StatusCode, Uri,
+};
+use lru::LruCache;
+use scorched::{logf, LogData, LogImportance};
+
+use crate::state::{Config, FaviconConfig};
+
+use super::util::Origin;
+
+/// How many origins' icons are remembered, the least recently used are forgotten first
+const CAPACITY: NonZeroUsize = NonZeroUsize::new(1024).unwrap();
+
+struct Kept {
+    icon: Option<Icon>,
+    expires: Instant,
+}
+
+#[derive(Clone)]
+struct Icon {
+    content_type: HeaderValue,
+    body: Bytes,
+}
+
+#[derive(Clone)]
+/// Origins' icons, or that they have none, by tenant and origin. Tenants keep their own, as they
+/// may allow icons of different sizes for different times.
+pub struct Favicons(Arc<Mutex<LruCache<String, Kept>>>);
+
+impl Default for Favicons {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(LruCache::new(CAPACITY))))
+    }
+}
+
+impl Favicons {
+    /// The icon of `origin`, or the default icon if it has none
+    pub async fn serve(
+        &self,
+        config: &Config,
+        client: &reqwest::Client,
+        origin: &Origin,
+    ) -> Response {
+        let favicon = &config.favicon;
+        let origin = origin.ascii_serialization();
+        let key = format!("{} {}", config.public_host, origin);
+
+        let kept = self
+            .0
+            .lock()
+            .unwrap()
+            .get(&key)
+            .filter(|kept| kept.expires > Instant::now())
+            .map(|kept| kept.icon.clone());
+
+        let icon = match kept {
+            Some(icon) => icon,
+            None => {
+                let icon = fetch(favicon, client, &origin).await;
+                self.0.lock().unwrap().put(
+                    key,
+                    Kept {
+                        icon: icon.clone(),
+                        expires: Instant::now() + Duration::from_secs(favicon.ttl_secs),
+                    },
+                );
+                icon
+            }
+        };
+
+        match icon {
+            Some(icon) => icon_response(favicon, icon),
+            None => default_icon(favicon).await,
+        }
+    }
+}
+
+/// Whether the request is for the icon browsers ask every host for
+pub fn is_requested(config: &FaviconConfig, method: &Method, uri: &Uri) -> bool {
+    config.enabled && matches!(*method, Method::GET | Method::HEAD) && uri.path() == "/favicon.ico"
+}
+
+/// The default icon, or a bare 404 when there is none
+pub async fn default_icon(config: &FaviconConfig) -> Response {
+    let Some(path) = &config.default_icon else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    match tokio::fs::read(path).await {
+        Ok(body) => icon_response(
+            config,
+            Icon {
+                content_type: HeaderValue::from_static(content_type(path)),
+                body: body.into(),
+            },
+        ),
+        Err(e) => {
+            logf!(
+                Warning,
+                "Couldn't read the default icon {}: {}",
+                path.display(),
+                e
+            );
+            StatusCode::NOT_FOUND.into_response()
+        }
+    }
+}
+
+/// The icon at `/favicon.ico` on `origin`, if it serves an image there that isn't too large
+async fn fetch(config: &FaviconConfig, client: &reqwest::Client, origin: &str) -> Option<Icon> {
+    let res = client
+        .get(format!("{}/favicon.ico", origin))
+        .send()
+        .await
+        .ok()?;
+
+    if !res.status().is_success()
+        || res
+            .content_length()
+            .is_some_and(|length| length > config.max_bytes as u64)
+    {
+        return None;
+    }
+
+    // Pages sent as icons are the origin's error pages
+    let content_type = match res.headers().get(CONTENT_TYPE) {
+        Some(content_type) if content_type.as_bytes().starts_with(b"image/") => {
+            content_type.clone()
+        }
+        Some(_) => return None,
+        None => HeaderValue::from_static("image/x-icon"),
+    };
+
+    let body = res.bytes().await.ok()?;
+    if body.is_empty() || body.len() > config.max_bytes {
+        return None;
+    }
+
+    Some(Icon { content_type, body })
+}
+
+fn icon_response(config: &FaviconConfig, icon: Icon) -> Response {
+    (
+        [
+            (CONTENT_TYPE, icon.content_type),
+            (
+                CACHE_CONTROL,
+                HeaderValue::from_str(&format!("public, max-age={}", config.ttl_secs)).unwrap(),
+            ),
+        ],
+        icon.body,
+    )
+        .into_response()
+}
+
+/// The type of the default icon by its extension
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase)
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        _ => "image/x-icon",
+    }
+}
@@ -0,0 +1,424 @@
+use std::{io, net::SocketAddr, time::Duration};
+
+use axum::{
+    body::Body,
+    http::HeaderValue,
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use futures_util::StreamExt;
+use hyper::{
+    header::{CONTENT_LENGTH, CONTENT_TYPE},
+    StatusCode, Uri,
+};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use scorched::{logf, LogData, LogImportance};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    time::timeout,
+};
+use tokio_util::io::ReaderStream;
+
+use crate::{
+    pages::{listing_page, message_response, ListingEntry},
+    state::FtpConfig,
+};
+
+use super::util::Origin;
+
+/// Characters escaped in the links of a listing. `:` is escaped so names aren't read as schemes.
+const LINK: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b':')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'\\')
+    .add(b'`');
+
+/// Replies longer than this are treated as the server misbehaving
+const MAX_REPLY_BYTES: usize = 64 * 1024;
+
+/// Serve the file or directory listing at `uri` of an `ftp://` origin
+pub(crate) async fn serve(config: &FtpConfig, origin: &Origin, uri: &Uri) -> Response {
+    let Ok(path) = percent_decode_str(uri.path()).decode_utf8() else {
+        return message_response(
+            StatusCode::BAD_REQUEST,
+            "Bad request",
+            "The path is not valid.",
+        );
+    };
+
+    // Commands are sent line by line, so a line break in the path would start another one
+    if path.contains(['\r', '\n']) {
+        return message_response(
+            StatusCode::BAD_REQUEST,
+            "Bad request",
+            "The path is not valid.",
+        );
+    }
+
+    match fetch(config, origin, uri.path(), &path).await {
+        Ok(response) => response,
+        Err(e) => {
+            logf!(
+                Warning,
+                "FTP gateway request to {} failed: {}",
+                origin.host(),
+                e
+            );
+
+            message_response(
+                StatusCode::BAD_GATEWAY,
+                "Site unreachable",
+                &format!("{} could not be reached over FTP.", origin.host()),
+            )
+        }
+    }
+}
+
+async fn fetch(
+    config: &FtpConfig,
+    origin: &Origin,
+    raw_path: &str,
+    path: &str,
+) -> io::Result<Response> {
+    let mut control = Control::connect(config, origin).await?;
+
+    let is_directory = control.command(&format!("CWD {path}")).await?.code == 250;
+
+    if path.ends_with('/') {
+        if !is_directory {
+            return Ok(not_found(path));
+        }
+
+        let mut entries = control.list().await?;
+        entries.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+        let entries: Vec<_> = entries
+            .into_iter()
+            .map(|(name, is_directory)| {
+                let mut href = utf8_percent_encode(&name, LINK).to_string();
+                if is_directory {
+                    href.push('/');
+                }
+
+                ListingEntry { name, href }
+            })
+            .collect();
+
+        let title = format!("Index of ftp://{}{}", origin.authority(), path);
+        return Ok(Html(listing_page(&title, path != "/", &entries)).into_response());
+    }
+
+    // Directories are listed with a trailing slash, for the relative links in them to work
+    if is_directory {
+        return Ok(Redirect::permanent(&format!("{raw_path}/")).into_response());
+    }
+
+    let size = control.command(&format!("SIZE {path}")).await?;
+    let size = match size.code {
+        213 => size.text.trim().parse::<u64>().ok(),
+        _ => None,
+    };
+
+    let data = control.open_data().await?;
+    if !control
+        .command(&format!("RETR {path}"))
+        .await?
+        .is_preliminary()
+    {
+        return Ok(not_found(path));
+    }
+
+    // The server may end the transfer once the control connection closes, so it is kept open
+    // until the whole file has been streamed
+    let body = Body::from_stream(ReaderStream::new(data).map(move |chunk| {
+        let _ = &control;
+        chunk
+    }));
+
+    let mut response = body.into_response();
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type(path)));
+    if let Some(size) = size {
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(size));
+    }
+
+    Ok(response)
+}
+
+fn not_found(path: &str) -> Response {
+    message_response(
+        StatusCode::NOT_FOUND,
+        "Not found",
+        &format!("{path} does not exist on this FTP server."),
+    )
+}
+
+struct Reply {
+    code: u16,
+    text: String,
+}
+
+impl Reply {
+    /// A 1xx reply, after which the server sends another one once it is done
+    fn is_preliminary(&self) -> bool {
+        (100..200).contains(&self.code)
+    }
+
+    fn unexpected(&self) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected reply {} {}", self.code, self.text.trim_end()),
+        )
+    }
+}
+
+/// The control connection to an FTP server
+struct Control {
+    stream: BufReader<TcpStream>,
+    timeout: Duration,
+}
+
+impl Control {
+    /// Connect to and log in on the server of `origin`, switching to binary transfers
+    async fn connect(config: &FtpConfig, origin: &Origin) -> io::Result<Self> {
+        let duration = Duration::from_secs(config.timeout_secs);
+        let host = origin.host().trim_start_matches('[').trim_end_matches(']');
+        let stream = timeout(duration, TcpStream::connect((host, origin.port()))).await??;
+
+        let mut control = Self {
+            stream: BufReader::new(stream),
+            timeout: duration,
+        };
+
+        let greeting = control.reply().await?;
+        if greeting.code != 220 {
+            return Err(greeting.unexpected());
+        }
+
+        let mut reply = control.command(&format!("USER {}", config.user)).await?;
+        if reply.code == 331 {
+            reply = control
+                .command(&format!("PASS {}", config.password))
+                .await?;
+        }
+        if reply.code != 230 {
+            return Err(reply.unexpected());
+        }
+
+        let reply = control.command("TYPE I").await?;
+        if reply.code != 200 {
+            return Err(reply.unexpected());
+        }
+
+        Ok(control)
+    }
+
+    async fn command(&mut self, command: &str) -> io::Result<Reply> {
+        let line = format!("{command}\r\n");
+        timeout(
+            self.timeout,
+            self.stream.get_mut().write_all(line.as_bytes()),
+        )
+        .await??;
+
+        self.reply().await
+    }
+
+    /// Read a reply, which spans several lines when its code is followed by a `-`
+    async fn reply(&mut self) -> io::Result<Reply> {
+        let mut text = String::new();
+        let mut line = String::new();
+        let mut code = None;
+
+        loop {
+            line.clear();
+            if timeout(self.timeout, self.stream.read_line(&mut line)).await?? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+
+            text.push_str(&line);
+            if text.len() > MAX_REPLY_BYTES {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "reply too long"));
+            }
+
+            let parsed = line.get(..3).and_then(|digits| digits.parse::<u16>().ok());
+
+            let code = match code {
+                Some(code) => code,
+                None => {
+                    let parsed = parsed.ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidData, "malformed reply")
+                    })?;
+                    *code.insert(parsed)
+                }
+            };
+
+            // The last line of a reply repeats the code, followed by a space
+            if parsed == Some(code) && !line[3..].starts_with('-') {
+                return Ok(Reply {
+                    code,
+                    text: text[4.min(text.len())..].to_string(),
+                });
+            }
+        }
+    }
+
+    /// Open a passive data connection, to the address of the control connection whatever the
+    /// server says, so it can't point the gateway at other hosts
+    async fn open_data(&mut self) -> io::Result<TcpStream> {
+        let peer = self.stream.get_ref().peer_addr()?;
+
+        let reply = self.command("EPSV").await?;
+        let port = match reply.code {
+            229 => parse_epsv(&reply.text),
+            _ => {
+                let reply = self.command("PASV").await?;
+                match reply.code {
+                    227 => parse_pasv(&reply.text),
+                    _ => return Err(reply.unexpected()),
+                }
+            }
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed passive reply"))?;
+
+        timeout(
+            self.timeout,
+            TcpStream::connect(SocketAddr::new(peer.ip(), port)),
+        )
+        .await?
+    }
+
+    /// The names of the current directory's entries, and whether they are directories
+    async fn list(&mut self) -> io::Result<Vec<(String, bool)>> {
+        let data = self.open_data().await?;
+        let reply = self.command("MLSD").await?;
+
+        if reply.is_preliminary() {
+            let listing = self.read_listing(data).await?;
+            return Ok(listing.lines().filter_map(parse_mlsd_line).collect());
+        }
+
+        // Older servers only have the human readable listing
+        drop(data);
+        let data = self.open_data().await?;
+        let reply = self.command("LIST").await?;
+        if !reply.is_preliminary() {
+            return Err(reply.unexpected());
+        }
+
+        let listing = self.read_listing(data).await?;
+        Ok(listing.lines().filter_map(parse_list_line).collect())
+    }
+
+    async fn read_listing(&mut self, mut data: TcpStream) -> io::Result<String> {
+        let mut listing = Vec::new();
+        timeout(self.timeout, data.read_to_end(&mut listing)).await??;
+
+        let reply = self.reply().await?;
+        if !matches!(reply.code, 226 | 250) {
+            return Err(reply.unexpected());
+        }
+
+        Ok(String::from_utf8_lossy(&listing).into_owned())
+    }
+}
+
+/// The port of a `229 Entering Extended Passive Mode (|||port|)` reply
+fn parse_epsv(text: &str) -> Option<u16> {
+    let start = text.find('(')?;
+    let end = text[start..].find(')')? + start;
+    let inner = &text[start + 1..end];
+    let delimiter = inner.chars().next()?;
+
+    inner.split(delimiter).nth(3)?.parse().ok()
+}
+
+/// The port of a `227 Entering Passive Mode (h1,h2,h3,h4,p1,p2)` reply
+fn parse_pasv(text: &str) -> Option<u16> {
+    let numbers = text
+        .split(|c: char| !(c.is_ascii_digit() || c == ','))
+        .find(|part| part.matches(',').count() == 5)?;
+    let mut numbers = numbers.split(',').skip(4).map(|n| n.parse::<u8>().ok());
+
+    let high = numbers.next()??;
+    let low = numbers.next()??;
+    Some(u16::from(high) << 8 | u16::from(low))
+}
+
+/// An `MLSD` line, `type=dir;size=0; name`, leaving out the directory itself and its parent
+fn parse_mlsd_line(line: &str) -> Option<(String, bool)> {
+    let (facts, name) = line.trim_end_matches('\r').split_once(' ')?;
+
+    let kind = facts.split(';').find_map(|fact| {
+        let (key, value) = fact.split_once('=')?;
+        key.eq_ignore_ascii_case("type")
+            .then(|| value.to_ascii_lowercase())
+    });
+
+    match kind.as_deref() {
+        Some("cdir" | "pdir") => None,
+        _ if name.is_empty() => None,
+        kind => Some((name.to_string(), kind == Some("dir"))),
+    }
+}
+
+/// A `LIST` line in the format of `ls -l`, which nearly every server uses. Lines in other formats
+/// are listed as they are.
+fn parse_list_line(line: &str) -> Option<(String, bool)> {
+    let line = line.trim_end_matches('\r');
+    if line.is_empty() || line.starts_with("total ") {
+        return None;
+    }
+
+    // The name follows the mode, links, owner, group, size and three date fields
+    let mut rest = line;
+    for _ in 0..8 {
+        rest = rest.trim_start();
+        match rest.find(char::is_whitespace) {
+            Some(end) => rest = &rest[end..],
+            None => return Some((line.trim().to_string(), false)),
+        }
+    }
+
+    let mut name = rest.trim_start();
+    if line.starts_with('l') {
+        name = name.split(" -> ").next().unwrap_or(name);
+    }
+
+    match name {
+        "" | "." | ".." => None,
+        name => Some((name.to_string(), line.starts_with('d'))),
+    }
+}
+
+/// The type of a downloaded file by its extension. Files aren't rewritten, so pages, scripts and
+/// SVGs are downloaded rather than run on the proxy's origin.
+fn content_type(path: &str) -> &'static str {
+    let extension = path
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_ascii_lowercase())
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "txt" | "md" | "asc" | "sig" => "text/plain; charset=utf-8",
+        "json" => "application/json",
+        "xml" => "application/xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" | "tgz" => "application/gzip",
+        "tar" => "application/x-tar",
+        "mp3" => "audio/mpeg",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
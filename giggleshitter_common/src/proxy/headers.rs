@@ -0,0 +1,432 @@
+use std::{collections::BTreeMap, mem};
+
+use hyper::{
+    header::{
+        HeaderName, HeaderValue, ACCEPT_LANGUAGE, CACHE_CONTROL, CONNECTION, CONTENT_LENGTH, HOST,
+        PROXY_AUTHENTICATE, PROXY_AUTHORIZATION, TE, TRAILER, TRANSFER_ENCODING, UPGRADE,
+        USER_AGENT,
+    },
+    HeaderMap,
+};
+
+use crate::{
+    error::Result,
+    rules::matches_origin,
+    state::{Config, Fingerprint},
+};
+
+use super::util::{encode_url, Origin};
+
+/// Headers that only apply to a single connection (RFC 7230, section 6.1), which a proxy must not
+/// forward in either direction. `Proxy-Connection` is not standard, but still sent by some
+/// clients.
+static HOP_BY_HOP: [HeaderName; 9] = [
+    CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    HeaderName::from_static("proxy-connection"),
+    PROXY_AUTHENTICATE,
+    PROXY_AUTHORIZATION,
+    TE,
+    TRAILER,
+    TRANSFER_ENCODING,
+    UPGRADE,
+];
+
+/// Headers describing the client's device, network and settings, which set it apart from other
+/// clients. Client hints prefixed with `sec-ch-` are matched separately.
+static FINGERPRINTING: [&str; 10] = [
+    "device-memory",
+    "dpr",
+    "viewport-width",
+    "width",
+    "downlink",
+    "ect",
+    "rtt",
+    "save-data",
+    "dnt",
+    "x-client-data",
+];
+
+/// Headers that are never removed for being listed in `Connection`, as dropping them would
+/// change how the message is framed or routed
+static PROTECTED: [HeaderName; 2] = [HOST, CONTENT_LENGTH];
+
+/// Set the headers of every origin glob in `configured` that matches the upstream origin, such as
+/// `origin_headers` or `response_headers`, replacing headers of the same name. An empty value
+/// removes the header instead.
+pub fn apply_configured_headers(
+    configured: &BTreeMap<String, BTreeMap<String, String>>,
+    origin: &Origin,
+    headers: &mut HeaderMap,
+) -> Result<()> {
+    for (pattern, added) in configured {
+        if !matches_origin(pattern, origin) {
+            continue;
+        }
+
+        for (name, value) in added {
+            let name = HeaderName::from_bytes(name.as_bytes())?;
+
+            if value.is_empty() {
+                retain_headers(headers, |kept| *kept != name);
+            } else {
+                headers.insert(name, HeaderValue::from_str(value)?);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Present the browser of every fingerprint in `fingerprints` that matches the upstream origin.
+/// What a fingerprint leaves unset is kept from the ones before it, or as the client sent it.
+pub fn apply_fingerprints(
+    fingerprints: &BTreeMap<String, Fingerprint>,
+    origin: &Origin,
+    headers: &mut HeaderMap,
+) -> Result<()> {
+    // Decided on what the client sent, before any fingerprint changes it
+    let mobile = is_mobile(headers);
+
+    for (pattern, fingerprint) in fingerprints {
+        if !matches_origin(pattern, origin) || (fingerprint.mobile_only && !mobile) {
+            continue;
+        }
+
+        if let Some(user_agent) = &fingerprint.user_agent {
+            retain_headers(headers, |name| !name.as_str().starts_with("sec-ch-ua"));
+            headers.insert(USER_AGENT, HeaderValue::from_str(user_agent)?);
+        }
+
+        if let Some(accept_language) = &fingerprint.accept_language {
+            headers.insert(ACCEPT_LANGUAGE, HeaderValue::from_str(accept_language)?);
+        }
+
+        for (name, value) in &fingerprint.client_hints {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove the headers that tell the client apart, before a request is sent upstream
+pub fn strip_fingerprinting(headers: &mut HeaderMap) {
+    retain_headers(headers, |name| {
+        !name.as_str().starts_with("sec-ch-") && !FINGERPRINTING.contains(&name.as_str())
+    });
+}
+
+/// Remove the headers a response asks for client hints with, so that browsers don't send them
+pub fn strip_client_hint_requests(headers: &mut HeaderMap) {
+    retain_headers(headers, |name| {
+        !matches!(name.as_str(), "accept-ch" | "critical-ch")
+    });
+}
+
+/// Whether the client is on a mobile device, by its `Sec-CH-UA-Mobile` hint or else its user
+/// agent
+fn is_mobile(headers: &HeaderMap) -> bool {
+    match headers.get("sec-ch-ua-mobile") {
+        Some(mobile) => mobile.as_bytes() == b"?1",
+        None => headers
+            .get(USER_AGENT)
+            .is_some_and(|user_agent| user_agent.as_bytes().windows(4).any(|w| w == b"Mobi")),
+    }
+}
+
+/// Remove the hop-by-hop headers, and the headers the `Connection` header marks as hop-by-hop,
+/// before a message is forwarded to the other side of the proxy
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let listed = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|token| HeaderName::from_bytes(token.trim().as_bytes()).ok())
+        .filter(|name| !PROTECTED.contains(name))
+        .collect::<Vec<_>>();
+
+    retain_headers(headers, |name| {
+        !HOP_BY_HOP.contains(name) && !listed.contains(name)
+    });
+}
+
+/// Whether the `Cache-Control` of a response keeps it from being stored, or from being shared with
+/// anyone but the user it was made for
+pub fn is_private(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|directive| {
+            let directive = directive.trim();
+            directive.eq_ignore_ascii_case("no-store") || directive.eq_ignore_ascii_case("private")
+        })
+}
+
+/// Remove the headers `keep` turns down, leaving the others in the order they came in, with every
+/// value of a name. [`HeaderMap::remove`] moves the last header into the place of the one it
+/// removes, which reorders them.
+pub fn retain_headers(headers: &mut HeaderMap, mut keep: impl FnMut(&HeaderName) -> bool) {
+    if headers.keys().all(&mut keep) {
+        return;
+    }
+
+    let mut kept = None;
+    for (name, value) in mem::take(headers) {
+        // Only the first value of a name comes with it
+        if let Some(name) = name {
+            kept = keep(&name).then_some(name);
+        }
+
+        if let Some(name) = &kept {
+            headers.append(name.clone(), value);
+        }
+    }
+}
+
+/// A `Link` header (RFC 8288) with the targets of its links pointed at the proxy, such as the
+/// preloads an upstream sends along with a page. Targets are only found between angle brackets
+/// outside of quoted parameters, and relative ones are kept as they are.
+pub fn proxied_link(config: &Config, link: &str) -> String {
+    let mut proxied = String::with_capacity(link.len());
+    let mut rest = link;
+    let mut quoted = false;
+
+    while let Some(index) = rest.find(['<', '"', '\\']) {
+        let (before, after) = rest.split_at(index);
+        proxied.push_str(before);
+
+        match after.as_bytes()[0] {
+            b'\\' if quoted => {
+                // The escaped character is taken along, so that it can't end the quotes
+                let escaped = after.chars().take(2).map(char::len_utf8).sum();
+                proxied.push_str(&after[..escaped]);
+                rest = &after[escaped..];
+                continue;
+            }
+            b'"' => quoted = !quoted,
+            b'<' if !quoted => {
+                if let Some(end) = after.find('>') {
+                    proxied.push('<');
+                    proxied.push_str(&encode_url(config, &after[1..end]));
+                    proxied.push('>');
+                    rest = &after[end + 1..];
+                    continue;
+                }
+            }
+            _ => {}
+        }
+
+        proxied.push_str(&after[..1]);
+        rest = &after[1..];
+    }
+    proxied.push_str(rest);
+
+    proxied
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::{HeaderValue, ACCEPT, CONTENT_TYPE, COOKIE};
+
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| {
+                (
+                    HeaderName::from_static(name),
+                    HeaderValue::from_static(value),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn recognises_private_responses() {
+        assert!(is_private(&headers(&[(
+            "cache-control",
+            "max-age=60, private"
+        )])));
+        assert!(is_private(&headers(&[
+            ("cache-control", "public"),
+            ("cache-control", "No-Store"),
+        ])));
+        assert!(!is_private(&headers(&[(
+            "cache-control",
+            "public, max-age=60"
+        )])));
+        assert!(!is_private(&headers(&[])));
+    }
+
+    #[test]
+    fn removes_hop_by_hop_headers() {
+        let mut headers = headers(&[
+            ("connection", "keep-alive"),
+            ("keep-alive", "timeout=5"),
+            ("proxy-authorization", "Basic Zm9vOmJhcg=="),
+            ("proxy-authenticate", "Basic"),
+            ("proxy-connection", "keep-alive"),
+            ("te", "trailers"),
+            ("trailer", "expires"),
+            ("transfer-encoding", "chunked"),
+            ("upgrade", "h2c"),
+            ("accept", "*/*"),
+        ]);
+
+        strip_hop_by_hop(&mut headers);
+
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers[ACCEPT], "*/*");
+    }
+
+    #[test]
+    fn removes_headers_listed_in_connection() {
+        let mut headers = headers(&[
+            ("connection", "close, x-secret"),
+            ("connection", "Cookie"),
+            ("x-secret", "1"),
+            ("cookie", "a=1"),
+            ("content-type", "text/plain"),
+        ]);
+
+        strip_hop_by_hop(&mut headers);
+
+        assert!(!headers.contains_key("x-secret"));
+        assert!(!headers.contains_key(COOKIE));
+        assert_eq!(headers[CONTENT_TYPE], "text/plain");
+    }
+
+    #[test]
+    fn keeps_framing_headers_listed_in_connection() {
+        let mut headers = headers(&[
+            ("connection", "host, content-length"),
+            ("host", "example.com"),
+            ("content-length", "3"),
+        ]);
+
+        strip_hop_by_hop(&mut headers);
+
+        assert_eq!(headers[HOST], "example.com");
+        assert_eq!(headers[CONTENT_LENGTH], "3");
+        assert!(!headers.contains_key(CONNECTION));
+    }
+
+    #[test]
+    fn proxies_link_targets() {
+        let config = Config {
+            url_encoding_algorithm: crate::state::UrlEncodingAlgorithm::Base32(base32::Alphabet::Z),
+            public_host: "proxy.example".to_string(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            proxied_link(
+                &config,
+                r#"<http://127.0.0.1/a.css>; rel=preload; as=style, </b.woff2>; rel=preload; as=font; title="<http://127.0.0.1/> \" <x>""#
+            ),
+            r#"<https://pb48ehb4fhzunctzfaanhcbqgr.proxy.example/a.css>; rel=preload; as=style, </b.woff2>; rel=preload; as=font; title="<http://127.0.0.1/> \" <x>""#
+        );
+        assert_eq!(proxied_link(&config, "<unclosed"), "<unclosed");
+    }
+
+    #[test]
+    fn presents_fingerprints() {
+        let fingerprints = BTreeMap::from([
+            (
+                "*".to_string(),
+                Fingerprint {
+                    user_agent: Some("Desktop".to_string()),
+                    client_hints: BTreeMap::from([(
+                        "Sec-CH-UA-Mobile".to_string(),
+                        "?0".to_string(),
+                    )]),
+                    mobile_only: true,
+                    ..Default::default()
+                },
+            ),
+            (
+                "example.com".to_string(),
+                Fingerprint {
+                    accept_language: Some("en-US".to_string()),
+                    ..Default::default()
+                },
+            ),
+        ]);
+        let origin = "https://example.com".parse::<Origin>().unwrap();
+
+        let mut mobile = headers(&[
+            ("user-agent", "Phone Mobile"),
+            ("sec-ch-ua", "\"Phone\""),
+            ("sec-ch-ua-platform", "\"Android\""),
+            ("accept-language", "nl"),
+        ]);
+        apply_fingerprints(&fingerprints, &origin, &mut mobile).unwrap();
+
+        assert_eq!(
+            mobile,
+            headers(&[
+                ("user-agent", "Desktop"),
+                ("sec-ch-ua-mobile", "?0"),
+                ("accept-language", "en-US"),
+            ])
+        );
+
+        // Only the fingerprint without `mobile_only` applies to desktop clients
+        let mut desktop = headers(&[("user-agent", "Desktop"), ("sec-ch-ua-mobile", "?0")]);
+        apply_fingerprints(&fingerprints, &origin, &mut desktop).unwrap();
+
+        assert_eq!(
+            desktop,
+            headers(&[
+                ("user-agent", "Desktop"),
+                ("sec-ch-ua-mobile", "?0"),
+                ("accept-language", "en-US"),
+            ])
+        );
+    }
+
+    #[test]
+    fn keeps_the_order_and_duplicates_of_headers() {
+        let mut headers = headers(&[
+            ("set-cookie", "a=1"),
+            ("connection", "x-hop"),
+            ("x-first", "1"),
+            ("set-cookie", "b=2"),
+            ("x-hop", "1"),
+            ("keep-alive", "timeout=5"),
+            ("x-second", "2"),
+        ]);
+
+        strip_hop_by_hop(&mut headers);
+
+        assert_eq!(
+            headers
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.to_str().unwrap()))
+                .collect::<Vec<_>>(),
+            [
+                ("set-cookie", "a=1"),
+                ("set-cookie", "b=2"),
+                ("x-first", "1"),
+                ("x-second", "2"),
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_invalid_connection_tokens() {
+        let mut headers = headers(&[("connection", "close, , not a header"), ("accept", "*/*")]);
+
+        strip_hop_by_hop(&mut headers);
+
+        assert_eq!(headers.len(), 1);
+    }
+}
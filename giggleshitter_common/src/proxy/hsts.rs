@@ -0,0 +1,143 @@
+use std::{
+    num::NonZeroUsize,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use hyper::{
+    header::{LOCATION, STRICT_TRANSPORT_SECURITY},
+    HeaderMap, StatusCode, Uri,
+};
+use lru::LruCache;
+
+use crate::state::HstsConfig;
+
+/// How many upstream hosts are remembered, the least recently used are forgotten first
+const KNOWN_HOSTS: NonZeroUsize = NonZeroUsize::new(4096).unwrap();
+
+/// Policies are kept for at most a year, as browsers do
+const MAX_AGE: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// Upstream hosts that only want to be reached over HTTPS, shared by every tenant as they proxy the
+/// same upstreams
+static KNOWN: LazyLock<Mutex<LruCache<String, HttpsPolicy>>> =
+    LazyLock::new(|| Mutex::new(LruCache::new(KNOWN_HOSTS)));
+
+#[derive(Clone, Copy)]
+struct HttpsPolicy {
+    expires: Instant,
+    include_subdomains: bool,
+}
+
+/// Whether `host` or a parent domain covering its subdomains asked to be reached over HTTPS only
+pub(crate) fn requires_https(host: &str) -> bool {
+    let host = host.to_ascii_lowercase();
+    let now = Instant::now();
+    let known = KNOWN.lock().unwrap();
+
+    let mut domain = host.as_str();
+    loop {
+        if let Some(policy) = known.peek(domain) {
+            if policy.expires > now && (domain == host || policy.include_subdomains) {
+                return true;
+            }
+        }
+
+        match domain.split_once('.') {
+            Some((_, parent)) if parent.contains('.') => domain = parent,
+            _ => return false,
+        }
+    }
+}
+
+/// Remember the upstream host of `url` if the response says it only wants HTTPS, either with a
+/// `Strict-Transport-Security` header over HTTPS or by redirecting plain HTTP to HTTPS on the same
+/// host
+pub(crate) fn learn(
+    config: &HstsConfig,
+    url: &reqwest::Url,
+    status: StatusCode,
+    headers: &HeaderMap,
+) {
+    let Some(host) = url.host_str() else {
+        return;
+    };
+    let host = host.to_ascii_lowercase();
+
+    match url.scheme() {
+        // Browsers ignore the header over plain HTTP, where anyone could have added it
+        "https" => {
+            let Some((max_age, include_subdomains)) = headers
+                .get(STRICT_TRANSPORT_SECURITY)
+                .and_then(|value| value.to_str().ok())
+                .and_then(parse_header)
+            else {
+                return;
+            };
+
+            let mut known = KNOWN.lock().unwrap();
+
+            if max_age.is_zero() {
+                known.pop(&host);
+            } else {
+                known.put(
+                    host,
+                    HttpsPolicy {
+                        expires: expires_after(max_age),
+                        include_subdomains,
+                    },
+                );
+            }
+        }
+        "http" if status.is_redirection() => {
+            let upgraded = headers
+                .get(LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .and_then(|location| location.parse::<Uri>().ok())
+                .is_some_and(|location| {
+                    location.scheme_str() == Some("https")
+                        && location
+                            .host()
+                            .is_some_and(|location_host| location_host.eq_ignore_ascii_case(&host))
+                });
+
+            if upgraded {
+                KNOWN.lock().unwrap().put(
+                    host,
+                    HttpsPolicy {
+                        expires: expires_after(Duration::from_secs(config.redirect_max_age_secs)),
+                        include_subdomains: false,
+                    },
+                );
+            }
+        }
+        _ => {}
+    }
+}
+
+fn expires_after(age: Duration) -> Instant {
+    let now = Instant::now();
+
+    now.checked_add(age.min(MAX_AGE)).unwrap_or(now)
+}
+
+/// The `max-age` and `includeSubDomains` directives of a `Strict-Transport-Security` header, `None`
+/// when it has no valid `max-age`
+fn parse_header(value: &str) -> Option<(Duration, bool)> {
+    let mut max_age = None;
+    let mut include_subdomains = false;
+
+    for directive in value.split(';').map(str::trim) {
+        match directive.split_once('=') {
+            Some((name, value)) if name.trim().eq_ignore_ascii_case("max-age") => {
+                max_age = value.trim().trim_matches('"').parse().ok();
+            }
+            None if directive.eq_ignore_ascii_case("includesubdomains") => {
+                include_subdomains = true;
+            }
+            _ => {}
+        }
+    }
+
+    max_age.map(|secs| (Duration::from_secs(secs), include_subdomains))
+}
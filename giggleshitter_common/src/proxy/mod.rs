@@ -1,2 +1,15 @@
-pub mod service;
+pub(crate) mod documents;
+pub(crate) mod download;
+pub(crate) mod encoding;
+pub(crate) mod favicon;
+pub(crate) mod ftp;
+pub(crate) mod headers;
+pub(crate) mod hsts;
+pub(crate) mod offline;
+pub(crate) mod prefetch;
+pub(crate) mod runtime;
+pub(crate) mod service;
+pub(crate) mod sniff;
+pub(crate) mod trailers;
 pub mod util;
+pub(crate) mod websocket;
@@ -0,0 +1,219 @@
+//! Offline replay: the last good copy of the responses of chosen origins is kept on disk, and
+//! served in place of the upstream when it can't be reached
+
+use std::path::{Path, PathBuf};
+
+use axum::body::Bytes;
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures_util::{stream, StreamExt};
+use hyper::{
+    header::{CONTENT_LENGTH, SET_COOKIE, TRANSFER_ENCODING},
+    HeaderMap, Method, StatusCode,
+};
+use reqwest::{ResponseBuilderExt, Url};
+use scorched::{logf, LogData, LogImportance};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{rules::matches_origin, state::Config};
+
+use super::{encoding::ByteStream, headers::is_private, util::Origin};
+
+/// What is kept of a response besides its body
+#[derive(Serialize, Deserialize)]
+struct Saved {
+    url: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+    /// When the copy was made, in RFC 3339 format
+    saved: String,
+}
+
+/// A response being copied to disk as it is passed on, which is only kept once the whole body
+/// went through
+pub struct Mirror {
+    dir: PathBuf,
+    saved: Saved,
+    body: Vec<u8>,
+    max_body_bytes: usize,
+}
+
+impl Mirror {
+    /// Start copying the response to a request for `url`, if its origin is replayed offline and
+    /// the response may be kept. Only complete, successful responses to `GET`s are kept, and none
+    /// that are private to the user. Copies are replayed to anyone, so they must only be started
+    /// for requests without the user's credentials.
+    pub fn start(
+        config: &Config,
+        origin: &Origin,
+        method: &Method,
+        url: &str,
+        status: StatusCode,
+        headers: &HeaderMap,
+    ) -> Option<Self> {
+        let offline = &config.offline;
+        let dir = offline.dir.as_ref()?;
+
+        if method != Method::GET || status != StatusCode::OK {
+            return None;
+        }
+
+        if !offline
+            .origins
+            .iter()
+            .any(|pattern| matches_origin(pattern, origin))
+        {
+            return None;
+        }
+
+        if is_private(headers) {
+            return None;
+        }
+
+        let headers = headers
+            .iter()
+            .filter(|(name, _)| {
+                // The length is set again when the copy is served, and cookies belong to the
+                // user the response was made for
+                *name != CONTENT_LENGTH && *name != TRANSFER_ENCODING && *name != SET_COOKIE
+            })
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect();
+
+        Some(Self {
+            dir: dir.clone(),
+            saved: Saved {
+                url: url.to_string(),
+                status: status.as_u16(),
+                headers,
+                saved: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            },
+            body: vec![],
+            max_body_bytes: offline.max_body_bytes,
+        })
+    }
+
+    /// Pass `body` on, keeping the copy once it has all been read
+    pub fn tee(self, body: ByteStream) -> ByteStream {
+        stream::unfold((body, Some(self)), |(mut body, mut mirror)| async move {
+            match body.next().await {
+                Some(Ok(chunk)) => {
+                    if let Some(copy) = mirror.as_mut() {
+                        copy.body.extend_from_slice(&chunk);
+
+                        // Too large to keep, the rest is passed on untouched
+                        if copy.body.len() > copy.max_body_bytes {
+                            mirror = None;
+                        }
+                    }
+
+                    Some((Ok(chunk), (body, mirror)))
+                }
+                Some(Err(e)) => Some((Err(e), (body, None))),
+                None => {
+                    if let Some(mirror) = mirror {
+                        tokio::spawn(mirror.save());
+                    }
+
+                    None
+                }
+            }
+        })
+        .boxed()
+    }
+
+    async fn save(self) {
+        let (meta_path, body_path) = paths(&self.dir, &self.saved.url);
+
+        let result = async {
+            tokio::fs::create_dir_all(&self.dir).await?;
+            // The metadata is written last, so that it never points at a body that isn't there
+            write_atomically(&body_path, &self.body).await?;
+            write_atomically(&meta_path, &serde_json::to_vec(&self.saved)?).await?;
+
+            anyhow::Ok(())
+        }
+        .await;
+
+        if let Err(e) = result {
+            logf!(
+                Error,
+                "Error saving the offline copy of {}: {}",
+                self.saved.url,
+                e
+            );
+        }
+    }
+}
+
+/// The last good copy of the response to a `GET` for `url`, as if the upstream had sent it, along
+/// with when it was made
+pub async fn replay(
+    config: &Config,
+    origin: &Origin,
+    method: &Method,
+    url: &str,
+) -> Option<(reqwest::Response, DateTime<Utc>)> {
+    let offline = &config.offline;
+    let dir = offline.dir.as_ref()?;
+
+    if method != Method::GET
+        || !offline
+            .origins
+            .iter()
+            .any(|pattern| matches_origin(pattern, origin))
+    {
+        return None;
+    }
+
+    let (meta_path, body_path) = paths(dir, url);
+    let saved: Saved = serde_json::from_slice(&tokio::fs::read(meta_path).await.ok()?).ok()?;
+    let body = tokio::fs::read(body_path).await.ok()?;
+
+    let mut response = hyper::Response::builder()
+        .status(saved.status)
+        .url(Url::parse(&saved.url).ok()?);
+    for (name, value) in &saved.headers {
+        response = response.header(name, value);
+    }
+    let response = response
+        .header(CONTENT_LENGTH, body.len())
+        .body(Bytes::from(body))
+        .ok()?;
+
+    let saved = DateTime::parse_from_rfc3339(&saved.saved).ok()?.to_utc();
+
+    Some((response.into(), saved))
+}
+
+/// A banner telling that the page is an offline copy, as a stylesheet so that it shows even
+/// when the page's scripts don't run
+pub fn banner(saved: DateTime<Utc>) -> String {
+    format!(
+        r#"<style>body::before{{content:"This site can't be reached right now. You are seeing a copy saved on {}.";display:block;position:sticky;top:0;z-index:2147483647;padding:8px 12px;background:#fff3cd;color:#664d03;font:14px/1.4 system-ui,sans-serif;border-bottom:1px solid #ffe69c}}</style>"#,
+        saved.format("%Y-%m-%d %H:%M UTC")
+    )
+}
+
+/// Where the metadata and the body of the copy of `url` are kept
+fn paths(dir: &Path, url: &str) -> (PathBuf, PathBuf) {
+    let key = Sha256::digest(url.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+
+    (
+        dir.join(format!("{}.json", key)),
+        dir.join(format!("{}.body", key)),
+    )
+}
+
+/// Write `contents` to a temporary file next to `path` and move it into place, so that readers
+/// never see half of it
+async fn write_atomically(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(format!(".{:016x}.tmp", rand::random::<u64>()));
+
+    tokio::fs::write(&temporary, contents).await?;
+    tokio::fs::rename(&temporary, path).await
+}
@@ -0,0 +1,297 @@
+//! Subresource prefetching: the stylesheets and fonts a page links to are fetched while the page
+//! is sent, and handed to the browser's requests for them instead of asking the upstream again
+
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::body::Bytes;
+use futures_util::{
+    future::{BoxFuture, Shared},
+    FutureExt, StreamExt,
+};
+use hyper::{
+    header::{HeaderValue, ACCEPT, ORIGIN, REFERER},
+    HeaderMap, StatusCode,
+};
+use lol_html::{ElementContentHandlers, HtmlRewriter, Settings};
+use lru::LruCache;
+use reqwest::{ResponseBuilderExt, Url};
+use scorched::{logf, LogData, LogImportance};
+
+use crate::{
+    snapshot::{absolute_url, has_rel},
+    state::{Config, PrefetchConfig},
+};
+
+use super::headers::is_private;
+
+/// How many prefetched responses are kept at most, the least recently started are dropped first
+const CAPACITY: NonZeroUsize = NonZeroUsize::new(128).unwrap();
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+/// What a subresource is loaded as, its `as` in a preload link
+pub enum Destination {
+    Style,
+    Font,
+}
+
+impl Destination {
+    fn as_str(self) -> &'static str {
+        match self {
+            Destination::Style => "style",
+            Destination::Font => "font",
+        }
+    }
+
+    fn accept(self) -> &'static str {
+        match self {
+            Destination::Style => "text/css,*/*;q=0.1",
+            Destination::Font => "*/*",
+        }
+    }
+}
+
+/// A subresource of a page on the page's own origin
+pub struct Subresource {
+    pub url: Url,
+    pub destination: Destination,
+}
+
+impl Subresource {
+    /// A `Link` header asking the browser to preload the subresource. Being on the page's origin,
+    /// its path is all the browser needs to find it on the page's proxied host.
+    pub fn preload_header(&self) -> Option<HeaderValue> {
+        let path = match self.url.query() {
+            Some(query) => format!("{}?{}", self.url.path(), query),
+            None => self.url.path().to_string(),
+        };
+
+        // Fonts are always fetched in CORS mode, the preload has to match to be used
+        let crossorigin = match self.destination {
+            Destination::Font => "; crossorigin",
+            Destination::Style => "",
+        };
+
+        HeaderValue::from_str(&format!(
+            "<{}>; rel=preload; as={}{}",
+            path,
+            self.destination.as_str(),
+            crossorigin
+        ))
+        .ok()
+    }
+}
+
+/// The first `limit` stylesheets and fonts that `html`, loaded from `page`, links to on its own
+/// origin. Fonts are only found when the page preloads them itself.
+pub fn subresources(html: &[u8], page: &Url, limit: usize) -> Vec<Subresource> {
+    // Set by a `<base>`, which may come after the first links
+    let base = RefCell::new(page.clone());
+    let mut found: Vec<Subresource> = vec![];
+
+    let element_content_handlers = vec![
+        (
+            Cow::Owned("base[href]".parse().unwrap()),
+            ElementContentHandlers::default().element(|el| {
+                let href = el.get_attribute("href").unwrap_or_default();
+                if let Some(url) = absolute_url(&base.borrow(), &href) {
+                    *base.borrow_mut() = url;
+                }
+                Ok(())
+            }),
+        ),
+        (
+            Cow::Owned("link[rel][href]".parse().unwrap()),
+            ElementContentHandlers::default().element(|el| {
+                let destination = if has_rel(el, &["stylesheet"]) {
+                    Destination::Style
+                } else if has_rel(el, &["preload"]) {
+                    match el.get_attribute("as").as_deref() {
+                        Some("style") => Destination::Style,
+                        Some("font") => Destination::Font,
+                        _ => return Ok(()),
+                    }
+                } else {
+                    return Ok(());
+                };
+
+                let href = el.get_attribute("href").unwrap_or_default();
+                let Some(url) = absolute_url(&base.borrow(), &href) else {
+                    return Ok(());
+                };
+
+                if found.len() < limit
+                    && url.origin() == page.origin()
+                    && !found.iter().any(|subresource| subresource.url == url)
+                {
+                    found.push(Subresource { url, destination });
+                }
+
+                Ok(())
+            }),
+        ),
+    ];
+
+    let mut rewriter = HtmlRewriter::new(
+        Settings {
+            element_content_handlers,
+            ..Settings::default()
+        },
+        |_: &[u8]| {},
+    );
+
+    // A page that can't be parsed is still sent, just without anything prefetched
+    if rewriter.write(html).and_then(|_| rewriter.end()).is_err() {
+        return vec![];
+    }
+
+    found
+}
+
+/// A prefetched response, as much of it as is needed to serve it again
+#[derive(Clone)]
+struct Prefetched {
+    url: Url,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+type PendingFetch = Shared<BoxFuture<'static, Option<Arc<Prefetched>>>>;
+
+#[derive(Clone)]
+/// The prefetched responses, and the ones still being fetched, by upstream URL
+pub struct Prefetcher {
+    entries: Arc<Mutex<LruCache<String, (Instant, PendingFetch)>>>,
+}
+
+impl Default for Prefetcher {
+    fn default() -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(LruCache::new(CAPACITY))),
+        }
+    }
+}
+
+impl Prefetcher {
+    /// Start fetching `subresources` in the background, with the headers of the request for the
+    /// page they were found on
+    pub fn start(
+        &self,
+        client: &reqwest::Client,
+        config: &Config,
+        headers: &HeaderMap,
+        subresources: &[Subresource],
+    ) {
+        let ttl = Duration::from_secs(config.prefetch.ttl_secs);
+        let mut entries = self.entries.lock().unwrap();
+
+        for subresource in subresources {
+            let key = key(config, &subresource.url);
+            if entries
+                .peek(&key)
+                .is_some_and(|(started, _)| started.elapsed() <= ttl)
+            {
+                continue;
+            }
+
+            let mut headers = headers.clone();
+            headers.remove(REFERER);
+            headers.remove(ORIGIN);
+            headers.insert(
+                ACCEPT,
+                HeaderValue::from_static(subresource.destination.accept()),
+            );
+
+            let fetch = fetch(
+                client.clone(),
+                config.prefetch.clone(),
+                subresource.url.clone(),
+                headers,
+            )
+            .boxed()
+            .shared();
+
+            // Fetched whether or not the browser asks for it before it is done
+            tokio::spawn(fetch.clone());
+            entries.put(key, (Instant::now(), fetch));
+        }
+    }
+
+    /// The prefetched response to a request for `url`, waiting for it if it is still being
+    /// fetched. Each response is only handed out once.
+    pub async fn take(&self, config: &Config, url: &str) -> Option<reqwest::Response> {
+        let url = Url::parse(url).ok()?;
+        let (started, fetch) = self.entries.lock().unwrap().pop(&key(config, &url))?;
+
+        if started.elapsed() > Duration::from_secs(config.prefetch.ttl_secs) {
+            return None;
+        }
+
+        let prefetched = fetch.await?;
+
+        let mut response = hyper::Response::builder()
+            .status(prefetched.status)
+            .url(prefetched.url.clone());
+        *response.headers_mut()? = prefetched.headers.clone();
+
+        Some(response.body(prefetched.body.clone()).ok()?.into())
+    }
+}
+
+/// Tenants may add their own headers to upstream requests, so responses aren't shared between them
+fn key(config: &Config, url: &Url) -> String {
+    format!("{} {}", config.public_host, url)
+}
+
+async fn fetch(
+    client: reqwest::Client,
+    prefetch: PrefetchConfig,
+    url: Url,
+    headers: HeaderMap,
+) -> Option<Arc<Prefetched>> {
+    let result = async {
+        let res = client.get(url.clone()).headers(headers).send().await?;
+
+        // Only what could be served to anyone else asking for it is kept
+        if res.status() != StatusCode::OK
+            || is_private(res.headers())
+            || res
+                .content_length()
+                .is_some_and(|length| length > prefetch.max_body_bytes as u64)
+        {
+            return anyhow::Ok(None);
+        }
+
+        let status = res.status();
+        let headers = res.headers().clone();
+        let final_url = res.url().clone();
+
+        let mut body = vec![];
+        let mut stream = res.bytes_stream();
+        while let Some(chunk) = stream.next().await {
+            body.extend_from_slice(&chunk?);
+            if body.len() > prefetch.max_body_bytes {
+                return Ok(None);
+            }
+        }
+
+        Ok(Some(Arc::new(Prefetched {
+            url: final_url,
+            status,
+            headers,
+            body: body.into(),
+        })))
+    }
+    .await;
+
+    result.unwrap_or_else(|e| {
+        logf!(Warning, "Error prefetching {}: {}", url, e);
+        None
+    })
+}
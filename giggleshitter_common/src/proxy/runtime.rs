@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::Query,
+    response::{IntoResponse, Redirect, Response},
+};
+use hyper::{header::CONTENT_TYPE, StatusCode, Uri};
+
+use crate::{pages::message_response, state::Config};
+
+use super::util::{encode_url, proxied_origin};
+
+/// Redirects to the proxied URL of its `url` parameter, for URLs the page opens that the
+/// rewriter never saw, such as those passed to `window.open`
+pub(crate) const OPEN_PATH: &str = "/__gs_open";
+
+/// Answers with the proxied origin of its `encode` parameter or the upstream origin of its
+/// `decode` parameter, for translating the origins of `postMessage` calls
+pub(crate) const ORIGIN_PATH: &str = "/__gs_origin";
+
+/// Answer a request to one of the paths the injected runtime uses on every proxied host, `None`
+/// for any other path
+pub(crate) fn respond(config: &Config, uri: &Uri) -> Option<Response> {
+    let path = uri.path();
+    if path != OPEN_PATH && path != ORIGIN_PATH {
+        return None;
+    }
+
+    let params = Query::<HashMap<String, String>>::try_from_uri(uri)
+        .map(|Query(params)| params)
+        .unwrap_or_default();
+
+    let response = match path {
+        OPEN_PATH => params
+            .get("url")
+            .and_then(|url| absolute_url(url))
+            .map(|url| Redirect::to(&encode_url(config, &url.to_string())).into_response()),
+        _ => match (params.get("encode"), params.get("decode")) {
+            (Some(origin), _) => encode_origin(config, origin).map(text_response),
+            (None, Some(origin)) => decode_origin(config, origin).map(text_response),
+            (None, None) => None,
+        },
+    };
+
+    Some(response.unwrap_or_else(|| {
+        message_response(
+            StatusCode::BAD_REQUEST,
+            "Bad request",
+            "The address to open is missing or not valid.",
+        )
+    }))
+}
+
+/// `url` if it is an absolute URL the proxy can serve, so the redirect never leaves the proxy
+fn absolute_url(url: &str) -> Option<Uri> {
+    let uri = url.parse::<Uri>().ok()?;
+
+    let supported = matches!(uri.scheme_str(), Some("http" | "https" | "ftp"));
+    (supported && uri.authority().is_some()).then_some(uri)
+}
+
+fn encode_origin(config: &Config, origin: &str) -> Option<String> {
+    let uri = absolute_url(origin)?;
+    if !matches!(uri.path(), "" | "/") || uri.query().is_some() {
+        return None;
+    }
+
+    let proxied = encode_url(
+        config,
+        &format!("{}://{}/", uri.scheme_str()?, uri.authority()?),
+    );
+
+    Some(proxied.trim_end_matches('/').to_string())
+}
+
+fn decode_origin(config: &Config, origin: &str) -> Option<String> {
+    let host = origin.parse::<Uri>().ok()?.host()?.to_string();
+
+    proxied_origin(config, &host)
+        .ok()
+        .map(|origin| origin.ascii_serialization())
+}
+
+fn text_response(text: String) -> Response {
+    ([(CONTENT_TYPE, "text/plain; charset=utf-8")], text).into_response()
+}
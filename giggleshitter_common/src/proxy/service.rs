@@ -1,102 +1,1246 @@
-use std::{sync::Arc, usize};
+use std::{
+    io, mem,
+    net::{IpAddr, SocketAddr},
+    ops::ControlFlow,
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
 
 use crate::{
-    error::Result, proxy::util::encode_url, rewriting::rewriter::Rewriter, state::ProxyState,
+    access::Denial,
+    diagnostics::{RefusalReason, Refused, RefusedRequest},
+    error::{self, AppError, Result},
+    hooks::{ErrorEvent, HookVerdict, RequestEvent, ResponseEvent, WebSocketEvent},
+    metrics::{Timings, Transfer},
+    pages::{message_response, refusal_page, retry_page, RETRY_DELAY_SECS},
+    plugins::{WsDirection, WsMessage},
+    proxy::util::encode_url,
+    recording::PendingExchange,
+    rewriting::{
+        reader::reader_rewriter::ReaderRewriter,
+        rewriter::{RewriteContext, Rewriter},
+    },
+    rules::{self, RuleRewriter},
+    session::RecordedSession,
+    state::{
+        Config, DocumentHandling, InvalidAddressBehavior, ProxyState, ReferrerPolicy, RuleAction,
+        WebSocketConfig,
+    },
+    tenant::TenantConfig,
+    usage::Usage,
 };
 use axum::{
-    body::{to_bytes, Body},
+    body::{Body, Bytes, HttpBody},
     debug_handler,
     extract::{
         ws::{CloseFrame, WebSocket},
-        Host, Request, State, WebSocketUpgrade,
+        ConnectInfo, Extension, Host, Request, State, WebSocketUpgrade,
     },
-    http::{HeaderName, HeaderValue},
-    response::{IntoResponse, Response},
+    http::{request::Parts, HeaderName, HeaderValue},
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures_util::{future, stream, SinkExt, Stream, StreamExt};
+#[cfg(feature = "media")]
+use hyper::header::{ACCEPT, VARY};
+use hyper::header::{
+    ACCEPT_ENCODING, ACCESS_CONTROL_ALLOW_ORIGIN, AUTHORIZATION, CONTENT_ENCODING, CONTENT_LENGTH,
+    CONTENT_SECURITY_POLICY, COOKIE, EXPECT, LINK, RANGE, REFERER, RETRY_AFTER, SET_COOKIE, TE,
+    TRAILER, TRANSFER_ENCODING,
 };
-use futures_util::{SinkExt, StreamExt};
-use hyper::header::{ACCEPT_ENCODING, CONTENT_ENCODING, SET_COOKIE, TRANSFER_ENCODING};
-use hyper::StatusCode;
 use hyper::{
-    header::{CONTENT_TYPE, HOST, LOCATION},
-    HeaderMap,
+    body::{Frame, SizeHint},
+    StatusCode,
 };
+use hyper::{
+    header::{CONTENT_TYPE, HOST, LOCATION, ORIGIN},
+    HeaderMap, Method, Uri,
+};
+use reqwest::Url;
 use reqwest_websocket::RequestBuilderExt;
 use scorched::{logf, LogData, LogImportance};
+use tracing::{field, info_span, Instrument, Span};
+
+#[cfg(feature = "media")]
+use crate::media;
+
+use super::{
+    documents,
+    download::{self, Resume},
+    encoding::{self, ByteStream},
+    favicon, ftp,
+    headers::{
+        apply_configured_headers, apply_fingerprints, proxied_link, retain_headers,
+        strip_client_hint_requests, strip_fingerprinting, strip_hop_by_hop,
+    },
+    hsts,
+    offline::{self, Mirror},
+    prefetch, runtime,
+    sniff::{sniff, Sniffed},
+    trailers::{self, Trailers},
+    util::{
+        decode_url, proxied_origin, strip_tracking_params, InvalidAddressError, Origin, Scheme,
+    },
+    websocket::WsLimits,
+};
+
+/// Set on responses that would have been rewritten, but were passed through unmodified
+const REWRITE_SKIPPED: HeaderName = HeaderName::from_static("x-gs-rewrite-skipped");
+
+/// Set on offline copies served while the upstream can't be reached, to when the copy was saved
+const OFFLINE_COPY: HeaderName = HeaderName::from_static("x-gs-offline-copy");
+
+/// Set on responses to requests that were blocked by a filter list
+const BLOCKED: HeaderName = HeaderName::from_static("x-gs-blocked");
+
+/// Set on the responses to refused requests, with the ID the refusal can be looked up by at
+/// `/diagnostics/<id>` on the admin API
+const CORRELATION_ID: HeaderName = HeaderName::from_static("x-gs-correlation-id");
+
+const X_ROBOTS_TAG: HeaderName = HeaderName::from_static("x-robots-tag");
 
-use super::util::{proxied_origin, Scheme};
+/// A query parameter that asks for the response without rewriting, e.g. `?__gs_raw=1`
+const RAW_PARAM: &str = "__gs_raw";
+
+/// A request header that asks for the response without rewriting, like [`RAW_PARAM`]
+const RAW_HEADER: HeaderName = HeaderName::from_static("x-gs-raw");
+
+/// A query parameter that asks for a page as a plain article, e.g. `?__gs_reader=1`
+const READER_PARAM: &str = "__gs_reader";
+
+/// A request header that asks for a page as a plain article, like [`READER_PARAM`]
+const READER_HEADER: HeaderName = HeaderName::from_static("x-gs-reader");
 
 #[debug_handler]
 pub async fn proxy(
     ws: Option<WebSocketUpgrade>,
     State(state): State<Arc<ProxyState>>,
     Host(host): Host,
+    Extension(TenantConfig(config)): Extension<TenantConfig>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    req: Request,
+) -> Result<Response> {
+    let client = connect_info.map(|ConnectInfo(addr)| addr);
+    let wants_html = error::wants_html(req.headers());
+    let _in_flight = state.metrics.request_started();
+    // Dropped unfinished along with this future or the body if the client goes away, which also
+    // drops the upstream request or response with them
+    let transfer = state.metrics.transfer_started();
+    let head = req.method() == Method::HEAD;
+    let favicon = favicon::is_requested(&config.favicon, req.method(), req.uri());
+
+    let received = Utc::now();
+    let started = Instant::now();
+    let method = req.method().to_string();
+    let path = req.uri().path().to_string();
+    let refused_request = || RefusedRequest {
+        public_host: &config.public_host,
+        host: &host,
+        method: &method,
+        path: &path,
+        received,
+        elapsed: started.elapsed(),
+    };
+
+    match proxy_request(ws, state.clone(), config.clone(), &host, client, req).await {
+        Ok(response) => {
+            let mut response = record_refusal(&state, refused_request(), response);
+            let status = response.status();
+
+            if head
+                || status.is_informational()
+                || matches!(status, StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED)
+            {
+                transfer.finish();
+            } else {
+                // The body isn't read any further once its length has been sent
+                let remaining = response
+                    .headers()
+                    .get(CONTENT_LENGTH)
+                    .and_then(|length| length.to_str().ok()?.parse().ok())
+                    .or(response.body().size_hint().exact());
+
+                response = response.map(|body| {
+                    Body::new(TransferBody {
+                        body,
+                        remaining,
+                        transfer: Some(transfer),
+                    })
+                });
+            }
+
+            if let Some(tag) = &config.crawlers.x_robots_tag {
+                response
+                    .headers_mut()
+                    .insert(X_ROBOTS_TAG, HeaderValue::from_str(tag)?);
+            }
+
+            Ok(response)
+        }
+        Err(e) if e.downcast_ref::<InvalidAddressError>().is_some() => {
+            transfer.finish();
+
+            // Browsers ask for it on any host they are sent to
+            if favicon {
+                return Ok(favicon::default_icon(&config.favicon).await);
+            }
+
+            let response = invalid_address_response(&config, e.to_string());
+            Ok(record_refusal(&state, refused_request(), response))
+        }
+        Err(e) => {
+            transfer.finish();
+            state.metrics.record_failure();
+            state.hooks.on_error(&ErrorEvent {
+                host: host.clone(),
+                message: e.to_string(),
+                client: client.map(|addr| addr.ip()),
+            });
+
+            if wants_html && e.is_upstream() {
+                return Ok(upstream_error_response(&config, &host, &e));
+            }
+
+            Err(e)
+        }
+    }
+}
+
+/// A refusal of the request, shown as a page with `title` and `message`. `detail` is kept for
+/// operators along with the correlation ID [`record_refusal`] puts on the page.
+fn refusal(
+    status: StatusCode,
+    reason: RefusalReason,
+    detail: impl Into<String>,
+    title: &str,
+    message: &str,
+) -> Response {
+    let refused = Refused {
+        reason,
+        detail: detail.into(),
+        page: Some((title.to_string(), message.to_string())),
+    };
+
+    (status, Extension(refused)).into_response()
+}
+
+/// Keep the refusal `response` is for, if it is one, and give it the correlation ID it is kept
+/// under
+fn record_refusal(state: &ProxyState, request: RefusedRequest, mut response: Response) -> Response {
+    let Some(refused) = response.extensions_mut().remove::<Refused>() else {
+        return response;
+    };
+
+    let id = state
+        .diagnostics
+        .record(request, response.status().as_u16(), &refused);
+
+    if let Some((title, message)) = &refused.page {
+        let (parts, _) = response.into_parts();
+        let page = Html(refusal_page(title, message, &id)).into_response();
+        response = (parts, page).into_response();
+    }
+
+    response
+        .headers_mut()
+        .insert(CORRELATION_ID, HeaderValue::from_str(&id).unwrap());
+
+    response
+}
+
+/// The page shown to browsers when the upstream couldn't be reached or timed out, which retries
+/// on its own, or whose redirects went on too long
+fn upstream_error_response(config: &Config, host: &str, e: &AppError) -> Response {
+    // Retrying won't get out of a redirect loop
+    if e.downcast_ref::<reqwest::Error>()
+        .is_some_and(|e| e.is_redirect())
+    {
+        return message_response(
+            StatusCode::BAD_GATEWAY,
+            "Too many redirects",
+            "The site kept redirecting without leading to a page. It may be misconfigured.",
+        );
+    }
+
+    let status = e.status();
+    let origin = host
+        .split(':')
+        .next()
+        .and_then(|host| proxied_origin(config, host).ok())
+        .map(|origin| origin.ascii_serialization());
+
+    let (title, message) = match status {
+        StatusCode::GATEWAY_TIMEOUT => (
+            "Site took too long",
+            "The site didn't respond in time. It may be overloaded, this page tries again shortly.",
+        ),
+        _ => (
+            "Site can't be reached",
+            "The site couldn't be reached through the proxy. It may be down, this page tries \
+             again shortly.",
+        ),
+    };
+
+    (
+        status,
+        [(RETRY_AFTER, HeaderValue::from(RETRY_DELAY_SECS))],
+        Html(retry_page(title, message, origin.as_deref())),
+    )
+        .into_response()
+}
+
+#[tracing::instrument(
+    name = "proxy",
+    skip_all,
+    fields(%host, client = field::Empty, origin = field::Empty, status = field::Empty)
+)]
+async fn proxy_request(
+    ws: Option<WebSocketUpgrade>,
+    state: Arc<ProxyState>,
+    config: Arc<Config>,
+    host: &str,
+    client: Option<SocketAddr>,
     req: Request,
-) -> Result<impl IntoResponse> {
-    let origin = proxied_origin(&state.config, &host)?;
+) -> Result<Response> {
+    let started = Instant::now();
+    let origin = proxied_origin(&config, host).map_err(|e| InvalidAddressError::new(host, e))?;
+
+    let span = Span::current();
+    span.record("origin", String::from(origin.clone()));
+    if let Some(client) = client {
+        span.record("client", field::display(client.ip()));
+    }
+
+    let client_ip = client.map(|addr| addr.ip());
+
+    if let Some(response) = admission_refusal(&state, &config, &origin, client_ip) {
+        return Ok(response);
+    }
+
+    let audit_path = req
+        .uri()
+        .path_and_query()
+        .map(|path| path.to_string())
+        .unwrap_or_default();
+
+    if origin.scheme() == Scheme::Ftp {
+        let response = match config.ftp.enabled {
+            true => ftp::serve(&config.ftp, &origin, req.uri()).await,
+            false => refusal(
+                StatusCode::FORBIDDEN,
+                RefusalReason::OriginNotAllowed,
+                "FTP isn't enabled",
+                "Site not available",
+                "FTP sites can't be opened through this proxy.",
+            ),
+        };
+
+        state.audit.start(
+            client_ip,
+            origin.into(),
+            audit_path,
+            response.status().as_u16(),
+        );
+        return Ok(response);
+    }
 
     if let Some(ws) = ws {
-        return Ok(ws.on_upgrade(move |socket| {
-            proxy_ws(
-                state.client.clone(),
-                socket,
-                format!(
-                    "{}://{}{}{}",
-                    match origin.scheme() {
-                        Scheme::Http => "ws",
-                        Scheme::Https => "wss",
-                    },
-                    origin.host(),
-                    if origin.port() == 0 {
-                        "".to_string()
-                    } else {
-                        format!(":{}", origin.port())
-                    },
-                    req.uri(),
-                ),
+        return upgrade_websocket(ws, state, &config, origin, client_ip, audit_path, req);
+    }
+
+    if let Some(response) = local_response(&state, &config, &origin, req.method(), req.uri()).await
+    {
+        return Ok(response);
+    }
+
+    let (mut request, body) =
+        match prepare(&state, &config, &origin, client_ip, audit_path, req).await? {
+            ControlFlow::Continue(prepared) => prepared,
+            ControlFlow::Break(response) => return Ok(response),
+        };
+
+    let fetched = match fetch(&state, &config, &origin, &mut request, body).await? {
+        ControlFlow::Continue(fetched) => fetched,
+        ControlFlow::Break(response) => return Ok(response),
+    };
+
+    respond(&state, &config, &origin, request, fetched, started).await
+}
+
+/// The refusal of requests to origins that may not be proxied, or from clients that may not use
+/// the proxy
+fn admission_refusal(
+    state: &ProxyState,
+    config: &Config,
+    origin: &Origin,
+    client_ip: Option<IpAddr>,
+) -> Option<Response> {
+    if !config.allows(origin.host()) {
+        return Some(refusal(
+            StatusCode::FORBIDDEN,
+            RefusalReason::OriginNotAllowed,
+            format!("{} isn't an allowed host", origin.host()),
+            "Site not available",
+            &format!("{} can't be opened through this proxy.", origin.host()),
+        ));
+    }
+
+    if let Some(denial) = client_ip.and_then(|ip| state.access.check(config, ip)) {
+        let (detail, default_message) = match denial {
+            Denial::Address => (
+                "the client's address isn't allowed",
+                "Your network is not allowed to use this proxy.",
+            ),
+            Denial::Country => (
+                "the client's country isn't allowed",
+                "This proxy is not available in your country.",
+            ),
+        };
+
+        return Some(refusal(
+            StatusCode::FORBIDDEN,
+            RefusalReason::AccessDenied,
+            detail,
+            "Access denied",
+            config
+                .access
+                .denial_message
+                .as_deref()
+                .unwrap_or(default_message),
+        ));
+    }
+
+    if client_ip.is_some_and(|ip| state.usage.is_exceeded(ip)) {
+        return Some(refusal(
+            StatusCode::TOO_MANY_REQUESTS,
+            RefusalReason::QuotaExceeded,
+            "the client used up its daily bandwidth quota",
+            "Quota exceeded",
+            "You have used up today's bandwidth on this proxy. The quota resets at midnight UTC.",
+        ));
+    }
+
+    None
+}
+
+/// Accept the WebSocket upgrade of `req` and connect the socket to the upstream, unless a filter
+/// list or a rule blocks it
+fn upgrade_websocket(
+    ws: WebSocketUpgrade,
+    state: Arc<ProxyState>,
+    config: &Config,
+    origin: Origin,
+    client_ip: Option<IpAddr>,
+    audit_path: String,
+    req: Request,
+) -> Result<Response> {
+    // Filter lists tell WebSockets apart by their own resource type
+    if let Some(response) = filtered_response(
+        &state,
+        config,
+        &origin,
+        req.uri(),
+        source_host(config, req.headers()).as_deref(),
+        Some("websocket"),
+    ) {
+        return Ok(response);
+    }
+
+    state.audit.start(
+        client_ip,
+        origin.clone().into(),
+        audit_path,
+        StatusCode::SWITCHING_PROTOCOLS.as_u16(),
+    );
+
+    let mut upstream_headers = HeaderMap::new();
+    apply_configured_headers(&config.origin_headers, &origin, &mut upstream_headers)?;
+
+    let limits = config.websockets.clone();
+    let ws = ws
+        .max_message_size(limits.max_message_bytes)
+        .max_frame_size(limits.max_message_bytes);
+
+    Ok(ws.on_upgrade(move |socket| {
+        proxy_ws(
+            state,
+            limits,
+            socket,
+            upstream_headers,
+            format!(
+                "{}://{}{}{}",
+                match origin.scheme() {
+                    Scheme::Http => "ws",
+                    Scheme::Https => "wss",
+                    Scheme::Ftp => unreachable!("FTP origins are served by the gateway"),
+                },
+                origin.host(),
+                if origin.port() == 0 {
+                    "".to_string()
+                } else {
+                    format!(":{}", origin.port())
+                },
+                req.uri(),
+            ),
+        )
+    }))
+}
+
+/// The responses the proxy gives itself, without asking the upstream: its runtime, redirects to
+/// HTTPS for hosts known to want it, and `robots.txt` and icons when it is set up to serve them
+async fn local_response(
+    state: &ProxyState,
+    config: &Config,
+    origin: &Origin,
+    method: &Method,
+    uri: &Uri,
+) -> Option<Response> {
+    if let Some(response) = runtime::respond(config, uri) {
+        return Some(response);
+    }
+
+    // Skip the upstream's own redirect for hosts known to want HTTPS
+    if config.hsts.enabled
+        && origin.scheme() == Scheme::Http
+        && origin.port() == 80
+        && hsts::requires_https(origin.host())
+    {
+        let upgraded = encode_url(config, &format!("https://{}{}", origin.host(), uri));
+        return Some(Redirect::temporary(&upgraded).into_response());
+    }
+
+    if config.crawlers.disallow_all && uri.path() == "/robots.txt" {
+        return Some(
+            (
+                [(CONTENT_TYPE, HeaderValue::from_static("text/plain"))],
+                "User-agent: *\nDisallow: /\n",
             )
-        }));
+                .into_response(),
+        );
     }
 
+    if favicon::is_requested(&config.favicon, method, uri) {
+        return Some(state.favicons.serve(config, &state.client, origin).await);
+    }
+
+    None
+}
+
+/// A request as it is sent to the upstream, along with what the client asked of the proxy. Its
+/// body is kept apart, as it can't be shared while it streams.
+struct UpstreamRequest {
+    method: Method,
+    /// The upstream URL, without tracking parameters
+    url: String,
+    /// Taken by [`fetch`] as the request is sent
+    headers: HeaderMap,
+    client_ip: Option<IpAddr>,
+    /// The path and query the client asked for, as they are audited
+    audit_path: String,
+    /// The upstream path, which rules are matched against
+    path: String,
+    /// The client asked for the response without rewriting, see [`RAW_PARAM`]
+    raw: bool,
+    /// The client asked for the page as a plain article, see [`READER_PARAM`]
+    reader: bool,
+    /// The client's `Accept`, which images are converted to the best format of
+    #[cfg(feature = "media")]
+    accept: Option<String>,
+    /// Trailers from the upstream are passed on to the client
+    accepts_trailers: bool,
+    grpc_web: bool,
+    /// The response is passed on with its compression, see [`upstream_client`]
+    passthrough: bool,
+    /// The client's own `Origin` and what the upstream was told instead, to recognise the upstream
+    /// allowing it in `Access-Control-Allow-Origin`
+    translated_origin: Option<(HeaderValue, String)>,
+    /// The request belongs to a session that is recorded
+    recorded_session: bool,
+}
+
+/// The request to send upstream, once the filter lists, the rules, the hooks and the plugins let
+/// it through. Headers that only concern the client's connection or would identify it are
+/// dropped, and the ones that point at the proxy are pointed at the upstream.
+async fn prepare(
+    state: &ProxyState,
+    config: &Config,
+    origin: &Origin,
+    client_ip: Option<IpAddr>,
+    audit_path: String,
+    req: Request,
+) -> Result<ControlFlow<Response, (UpstreamRequest, Body)>> {
     let (mut parts, body) = req.into_parts();
 
-    let body_bytes: Vec<u8> = to_bytes(body, usize::MAX).await?.to_vec();
+    let raw = take_flag(&mut parts, RAW_PARAM, RAW_HEADER)?;
+    let reader = take_flag(&mut parts, READER_PARAM, READER_HEADER)?;
 
-    parts
+    #[cfg(feature = "media")]
+    let accept = parts
         .headers
-        .insert(HOST, HeaderValue::from_str(origin.host())?);
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .map(str::to_string);
 
-    parts
+    let resource_type = resource_type(&parts.headers);
+    // Navigations aren't loaded by the page they come from
+    let source_host = match resource_type {
+        Some("document") => None,
+        _ => source_host(config, &parts.headers),
+    };
+
+    if let Some(response) = filtered_response(
+        state,
+        config,
+        origin,
+        &parts.uri,
+        source_host.as_deref(),
+        resource_type,
+    ) {
+        return Ok(ControlFlow::Break(response));
+    }
+
+    // `100-continue` is met by the proxy itself, which asks the client for the body as it starts
+    // reading it. The upstream client sends bodies without waiting for a `100 Continue`, so the
+    // expectation isn't passed on.
+    if let Some(expect) = parts.headers.get(EXPECT) {
+        if !expect.as_bytes().eq_ignore_ascii_case(b"100-continue") {
+            return Ok(ControlFlow::Break(message_response(
+                StatusCode::EXPECTATION_FAILED,
+                "Expectation failed",
+                "The request expects something this proxy can't provide.",
+            )));
+        }
+    }
+    retain_headers(&mut parts.headers, |name| *name != EXPECT);
+
+    // `TE` only applies to the client's connection, but trailers from the upstream are passed on
+    // to clients that take them
+    let accepts_trailers = parts
         .headers
-        .insert(ACCEPT_ENCODING, "gzip, br, deflate, zstd".parse().unwrap());
+        .get_all(TE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| coding.trim().eq_ignore_ascii_case("trailers"));
+
+    // WebSocket upgrades are handed off before requests are prepared, so nothing here is upgraded
+    strip_hop_by_hop(&mut parts.headers);
+
+    if accepts_trailers {
+        parts
+            .headers
+            .insert(TE, HeaderValue::from_static("trailers"));
+    }
 
     parts
         .headers
-        .clone()
-        .iter()
-        .filter(|(name, _)| {
-            name.as_str().starts_with("cf-")
-                || matches!(name.as_str(), "referer" | "x-forwarded-for" | "cdn-loop")
-        })
-        .for_each(|(name, _)| {
-            parts.headers.remove(name);
-        });
+        .insert(HOST, HeaderValue::from_str(&origin.authority())?);
 
-    let origin_url: String = origin.into();
+    // gRPC-web calls are passed through byte for byte, so their `Accept-Encoding` is sent as the
+    // client put it. Other passed through responses keep their compression, so only ask for what
+    // the client accepts.
+    let grpc_web = is_grpc_web(parts.headers.get(CONTENT_TYPE));
+    let passthrough = config.compression.passthrough || grpc_web;
+    if !grpc_web {
+        if passthrough {
+            match encoding::accepted(parts.headers.get(ACCEPT_ENCODING)) {
+                Some(accepted) => {
+                    parts.headers.insert(ACCEPT_ENCODING, accepted);
+                }
+                None => retain_headers(&mut parts.headers, |name| *name != ACCEPT_ENCODING),
+            }
+        } else {
+            parts
+                .headers
+                .insert(ACCEPT_ENCODING, HeaderValue::from_static(encoding::ALL));
+        }
+    }
+
+    let referer = match config.referrer_policy {
+        ReferrerPolicy::Strip => None,
+        ReferrerPolicy::OriginRoot => Some(format!("{}/", String::from(origin.clone()))),
+        ReferrerPolicy::Translate => parts
+            .headers
+            .get(REFERER)
+            .and_then(|referer| referer.to_str().ok())
+            .and_then(|referer| decode_url(config, referer).ok()),
+    };
+
+    // Headers that would identify the client or the proxy in front of it
+    retain_headers(&mut parts.headers, |name| {
+        !name.as_str().starts_with("cf-")
+            && !matches!(name.as_str(), "referer" | "x-forwarded-for" | "cdn-loop")
+    });
+
+    if let Some(referer) = referer {
+        parts
+            .headers
+            .insert(REFERER, HeaderValue::from_str(&referer)?);
+    }
+
+    let translated_origin = match parts.headers.get(ORIGIN) {
+        Some(client_origin) if config.translate_origin => {
+            let upstream_origin = client_origin
+                .to_str()
+                .ok()
+                .and_then(|client_origin| Uri::from_str(client_origin).ok())
+                .and_then(|uri| proxied_origin(config, uri.host()?).ok())
+                .map(|origin| origin.ascii_serialization());
+
+            match upstream_origin {
+                Some(upstream_origin) => {
+                    let client_origin = client_origin.clone();
+                    parts
+                        .headers
+                        .insert(ORIGIN, HeaderValue::from_str(&upstream_origin)?);
+                    Some((client_origin, upstream_origin))
+                }
+                None => None,
+            }
+        }
+        _ => None,
+    };
+
+    let recorded_session = parts.extensions.get::<RecordedSession>().is_some();
+    let path = parts.uri.path().to_string();
+
+    let mut event = RequestEvent {
+        method: parts.method,
+        url: format!(
+            "{}{}",
+            String::from(origin.clone()),
+            strip_tracking_params(config, &parts.uri.to_string())
+        ),
+        headers: parts.headers,
+        client: client_ip,
+    };
+
+    if let HookVerdict::Respond(response) = state.hooks.on_request(&mut event).await {
+        return Ok(ControlFlow::Break(response));
+    }
+
+    if let HookVerdict::Respond(response) = state.plugins.on_request(&mut event).await {
+        return Ok(ControlFlow::Break(response));
+    }
+
+    let RequestEvent {
+        method,
+        url,
+        headers,
+        ..
+    } = event;
+
+    let request = UpstreamRequest {
+        method,
+        url,
+        headers,
+        client_ip,
+        audit_path,
+        path,
+        raw,
+        reader,
+        #[cfg(feature = "media")]
+        accept,
+        accepts_trailers,
+        grpc_web,
+        passthrough,
+        translated_origin,
+        recorded_session,
+    };
+
+    Ok(ControlFlow::Continue((request, body)))
+}
+
+/// The response to a request for `uri` that a filter list or a rule keeps from reaching the
+/// upstream, if one does. Only the rules that don't depend on the type of the response apply.
+fn filtered_response(
+    state: &ProxyState,
+    config: &Config,
+    origin: &Origin,
+    uri: &Uri,
+    source_host: Option<&str>,
+    resource_type: Option<&'static str>,
+) -> Option<Response> {
+    if let Some(rule) = state.blocker.blocking_rule(
+        config,
+        &format!("{}{}", origin.ascii_serialization(), uri),
+        origin.host(),
+        source_host,
+        resource_type,
+    ) {
+        logf!(Info, "Blocked {}{} by {}", origin.host(), uri, rule);
+
+        // Blocked subresources aren't shown to the user, so there is no page to put the ID on
+        let refused = Refused {
+            reason: RefusalReason::Blocked,
+            detail: format!("blocked by the filter rule {}", rule),
+            page: None,
+        };
+
+        return Some(
+            (
+                StatusCode::FORBIDDEN,
+                [(BLOCKED, HeaderValue::from_static("1"))],
+                Extension(refused),
+            )
+                .into_response(),
+        );
+    }
+
+    rule_response(config, &rules::actions(config, origin, uri.path(), None))
+}
+
+/// The upstream's response to a request, or the offline copy standing in for it
+struct Fetched {
+    res: reqwest::Response,
+    /// When the offline copy was saved, if the response is one
+    stale: Option<DateTime<Utc>>,
+    exchange: Option<PendingExchange>,
+    /// The request carried none of the user's credentials, so the response may be shared
+    anonymous: bool,
+    /// Kept to fetch the subresources of the page with, if the response is one
+    prefetch_headers: Option<HeaderMap>,
+    /// Kept to ask for the rest of the body if the download breaks off
+    resume_headers: Option<HeaderMap>,
+    sending: Instant,
+    received: Instant,
+}
+
+/// Send the request upstream, unless its response was prefetched. The offline copy of the
+/// response stands in for it while the origin's circuit is open or the origin can't be reached,
+/// and the upstream's own responses are taken as news about it.
+async fn fetch(
+    state: &ProxyState,
+    config: &Config,
+    origin: &Origin,
+    request: &mut UpstreamRequest,
+    body: Body,
+) -> Result<ControlFlow<Response, Fetched>> {
+    // Recorded before the configured headers are added, to keep upstream credentials out of the
+    // archives. The users' own are redacted as the exchange is written.
+    let mut exchange = state.recorder.start(
+        config,
+        request.recorded_session,
+        origin,
+        &request.method,
+        &request.url,
+        &request.headers,
+    );
+
+    // Bodies are streamed to the upstream as they arrive, so that large uploads aren't held in
+    // memory. Recorded exchanges only buffer as much of the body as is recorded.
+    let body = match exchange.as_mut() {
+        Some(exchange) => record_request_body(body, exchange).await?,
+        None => body,
+    };
+    let body = upstream_body(body, request.client_ip.map(|ip| (state.usage.clone(), ip)));
+
+    // Prefetched responses and offline copies are only shared between requests without the user's
+    // credentials
+    let mut headers = mem::take(&mut request.headers);
+    let anonymous = request.method == Method::GET
+        && !headers.contains_key(COOKIE)
+        && !headers.contains_key(AUTHORIZATION);
+
+    // Stripped before the fingerprints, which may send client hints of their own
+    if config.strip_fingerprinting_headers {
+        strip_fingerprinting(&mut headers);
+    }
+    apply_fingerprints(&config.fingerprints, origin, &mut headers)?;
+    // Added after the hooks and plugins ran, so that credentials for the upstream aren't exposed
+    // to them
+    apply_configured_headers(&config.origin_headers, origin, &mut headers)?;
+
+    let prefetch_headers = (config.prefetch.enabled && anonymous).then(|| headers.clone());
+    let prefetcher = match anonymous && !headers.contains_key(RANGE) {
+        true => Some(&state.prefetcher),
+        false => None,
+    };
+
+    let resume_headers = (request.method == Method::GET && config.downloads.resume_attempts > 0)
+        .then(|| headers.clone());
+
+    // When the upstream can't be reached, the offline copy of the response is served instead
+    let mut stale = None;
 
-    let res = state
-        .client
-        .request(parts.method, format!("{}{}", origin_url, parts.uri))
-        .headers(parts.headers)
-        .body(body_bytes)
-        .send()
-        .await?;
+    let origin_url: String = origin.clone().into();
+    let breaker = &config.circuit_breaker;
+    let open_circuit = match breaker.enabled {
+        true => state.breaker.check(breaker, &origin_url).err(),
+        false => None,
+    };
+
+    let (method, url) = (&request.method, &request.url);
+
+    let sending = Instant::now();
+    let res = match open_circuit {
+        Some(retry_after) => match offline::replay(config, origin, method, url).await {
+            Some((res, saved)) => {
+                stale = Some(saved);
+                res
+            }
+            None => {
+                return Ok(ControlFlow::Break(origin_unavailable_response(
+                    origin.host(),
+                    retry_after,
+                )))
+            }
+        },
+        None => {
+            state.connections.record_request(origin.host());
+
+            let prefetched = match prefetcher {
+                Some(prefetcher) => prefetcher.take(config, url).await,
+                None => None,
+            };
 
-    let mut response_builder = Response::builder().status(res.status().as_u16());
+            let res = match prefetched {
+                Some(res) => Ok(res),
+                None => {
+                    upstream_client(state, request.passthrough)
+                        .request(method.clone(), url)
+                        .headers(headers)
+                        .body(body)
+                        .send()
+                        .instrument(info_span!("upstream", %method, %url))
+                        .await
+                }
+            };
+
+            match res {
+                Ok(res) => res,
+                Err(e) => {
+                    if breaker.enabled {
+                        state.breaker.record_failure(breaker, &origin_url);
+                    }
+
+                    match offline::replay(config, origin, method, url).await {
+                        Some((res, saved)) => {
+                            logf!(Info, "Serving the offline copy of {}: {}", url, e);
+                            stale = Some(saved);
+                            res
+                        }
+                        None => return Err(e.into()),
+                    }
+                }
+            }
+        }
+    };
+
+    let received = Instant::now();
+
+    // The copy is neither recorded again nor taken as news about the upstream
+    if stale.is_some() {
+        exchange = None;
+    } else {
+        if config.hsts.enabled {
+            hsts::learn(&config.hsts, res.url(), res.status(), res.headers());
+        }
+
+        if breaker.enabled {
+            match res.status() {
+                StatusCode::BAD_GATEWAY
+                | StatusCode::SERVICE_UNAVAILABLE
+                | StatusCode::GATEWAY_TIMEOUT => state.breaker.record_failure(breaker, &origin_url),
+                _ => state.breaker.record_success(&origin_url),
+            }
+        }
+    }
+
+    Ok(ControlFlow::Continue(Fetched {
+        res,
+        stale,
+        exchange,
+        anonymous,
+        prefetch_headers,
+        resume_headers,
+        sending,
+        received,
+    }))
+}
+
+/// The client requests are sent upstream with. Passed through responses are left compressed.
+fn upstream_client(state: &ProxyState, passthrough: bool) -> &reqwest::Client {
+    match passthrough {
+        true => &state.passthrough_client,
+        false => &state.client,
+    }
+}
+
+/// Pass the upstream's response on, with its headers pointed at the proxy. Its body is rewritten
+/// or transformed when it's small enough to be taken whole, and streamed as it comes otherwise.
+async fn respond(
+    state: &ProxyState,
+    config: &Arc<Config>,
+    origin: &Origin,
+    request: UpstreamRequest,
+    fetched: Fetched,
+    started: Instant,
+) -> Result<Response> {
+    let Fetched {
+        mut res,
+        stale,
+        mut exchange,
+        anonymous,
+        prefetch_headers,
+        resume_headers,
+        sending,
+        received,
+    } = fetched;
+
+    // Followed redirects stay on the origin, but may lead to another path that links on the page
+    // are relative to
+    let page_url = res.url().clone();
+    let request_path = match page_url.query() {
+        Some(query) => format!("{}?{}", page_url.path(), query),
+        None => page_url.path().to_string(),
+    };
+
+    let mut headers = response_headers(
+        config,
+        origin,
+        res.headers(),
+        request.translated_origin.as_ref(),
+    )?;
+
+    let (content_type, charset) = media_type(res.headers());
+    let peeked = peek(state, config, &mut res, content_type.as_deref()).await?;
+
+    let content_type = match peeked.html {
+        true => Some("text/html".to_string()),
+        false => content_type,
+    };
+    let actions = rules::actions(config, origin, &request.path, content_type.as_deref());
+
+    // Rules without a content type were already applied to the request
+    if content_type.is_some() {
+        if let Some(response) = rule_response(config, &actions) {
+            return Ok(response);
+        }
+    }
+
+    for action in &actions {
+        match action {
+            RuleAction::AddHeader(name, value) => {
+                headers.append(
+                    HeaderName::from_bytes(name.as_bytes())?,
+                    HeaderValue::from_str(value)?,
+                );
+            }
+            // Also covers whatever the rewriter can't see, such as responses fetched raw
+            RuleAction::DisableScripts => {
+                headers.append(
+                    CONTENT_SECURITY_POLICY,
+                    HeaderValue::from_static("script-src 'none'"),
+                );
+            }
+            _ => {}
+        }
+    }
+
+    let mut event = ResponseEvent {
+        method: request.method.clone(),
+        url: request.url.clone(),
+        status: res.status(),
+        headers,
+        client: request.client_ip,
+    };
+
+    state.plugins.on_response(&mut event);
+    state.hooks.on_response(&event);
+
+    let mut headers = mem::take(&mut event.headers);
+
+    if let Some(saved) = stale {
+        headers.insert(
+            OFFLINE_COPY,
+            HeaderValue::from_str(&saved.to_rfc3339_opts(SecondsFormat::Secs, true))?,
+        );
+    }
+
+    let mut rewriter = match peeked.html {
+        true => state.rewriters.get("text/html"),
+        false => res
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|content_type| state.rewriters.get(content_type.to_str().unwrap_or(""))),
+    };
+
+    let is_html = content_type.as_deref() == Some("text/html");
+    if let Some(rule_rewriter) = RuleRewriter::new(rewriter.clone(), &actions, is_html) {
+        rewriter = Some(Arc::new(rule_rewriter));
+    }
+
+    // The article view replaces the page, with none of the page's scripts or policies
+    if request.reader && is_html {
+        rewriter = Some(Arc::new(ReaderRewriter::new()));
+
+        headers.insert(
+            CONTENT_SECURITY_POLICY,
+            HeaderValue::from_static("script-src 'none'"),
+        );
+    }
+
+    // Nothing in a gRPC-web exchange is rewritten, its messages are framed by their lengths
+    let grpc_web = request.grpc_web || is_grpc_web(res.headers().get(CONTENT_TYPE));
+    if grpc_web {
+        rewriter = None;
+    }
+
+    // Their headers describe a body that isn't sent, and there is nothing to rewrite
+    let bodiless = is_bodiless(&event.method, event.status);
+    if bodiless {
+        rewriter = None;
+    }
+
+    // Binary data mislabeled as text would be mangled by rewriting it
+    let binary = peeked.binary && rewriter.take().is_some();
+
+    let status = event.status.as_u16();
+    Span::current().record("status", status);
+    let content_encoding = res
+        .headers()
+        .get(CONTENT_ENCODING)
+        .map(|encoding| encoding.to_str().unwrap_or_default().to_string());
+
+    if let (Some(max_bytes), Some(length)) = (config.downloads.max_bytes, res.content_length()) {
+        if length > max_bytes {
+            return Ok(refusal(
+                StatusCode::FORBIDDEN,
+                RefusalReason::TooLarge,
+                format!("{} bytes, over the limit of {} bytes", length, max_bytes),
+                "Download too large",
+                &format!(
+                    "This file is {} bytes, but the proxy only allows downloads of up to {} bytes.",
+                    length, max_bytes
+                ),
+            ));
+        }
+    }
+
+    let resume = resume_headers.and_then(|headers| {
+        Resume::new(
+            upstream_client(state, request.passthrough),
+            &event.method,
+            &event.url,
+            headers,
+            &res,
+            &config.downloads,
+        )
+    });
+
+    if let Some(exchange) = exchange.as_mut() {
+        exchange.respond(res.status(), res.headers());
+    }
+
+    // Only copies that could be served to anyone are kept, like prefetched responses
+    let mirror = match stale {
+        None if anonymous => Mirror::start(
+            config,
+            origin,
+            &event.method,
+            &event.url,
+            res.status(),
+            res.headers(),
+        ),
+        _ => None,
+    };
+
+    let mut outgoing = outgoing_body(config, res, peeked.first_chunk, resume, mirror, exchange);
+    outgoing.skipped = binary.then_some("binary-content");
+
+    if request.raw && rewriter.is_some() {
+        rewriter = None;
+        outgoing.skipped = Some("raw-requested");
+    }
 
-    let mut headers = HeaderMap::with_capacity(res.headers().len());
+    // Images are transformed whole, so compressed ones are left alone like unsupported encodings
+    #[cfg(feature = "media")]
+    let media = match (&rewriter, &content_encoding) {
+        (None, None) if !request.raw && !grpc_web && !bodiless => media::Transform::plan(
+            config,
+            &actions,
+            content_type.as_deref(),
+            request.accept.as_deref(),
+        ),
+        _ => None,
+    };
+
+    let rewriting = Instant::now();
+    let outgoing = transform_body(
+        state,
+        config,
+        origin,
+        &request,
+        BodyPlan {
+            rewriter,
+            content_type,
+            charset,
+            content_encoding,
+            #[cfg(feature = "media")]
+            media,
+            grpc_web,
+            bodiless,
+            is_html,
+            prefetch_headers,
+            page_url,
+            request_path,
+            stale,
+        },
+        outgoing,
+        &mut headers,
+    )
+    .await?;
+
+    state.metrics.record(
+        &config.metrics,
+        &String::from(origin.clone()),
+        event.method.as_str(),
+        &request.path,
+        status,
+        Timings {
+            decode: sending - started,
+            upstream: received - sending,
+            rewrite: rewriting.elapsed(),
+            total: started.elapsed(),
+        },
+    );
+
+    if let Some(reason) = outgoing.skipped {
+        headers.insert(REWRITE_SKIPPED, HeaderValue::from_static(reason));
+    }
+
+    let body = finish(
+        state,
+        origin,
+        &request,
+        event.status,
+        &mut headers,
+        outgoing,
+    );
+
+    let mut response = Response::new(body);
+    *response.status_mut() = event.status;
+    *response.headers_mut() = headers;
+
+    Ok(response)
+}
+
+/// The upstream's response headers as they are passed on. Policies that would break the page on
+/// the proxy's host are dropped, and the URLs in the others are pointed at the proxy.
+fn response_headers(
+    config: &Config,
+    origin: &Origin,
+    upstream: &HeaderMap,
+    translated_origin: Option<&(HeaderValue, String)>,
+) -> Result<HeaderMap> {
+    let mut headers = HeaderMap::with_capacity(upstream.len());
     headers.extend(
-        res.headers()
-            .into_iter()
+        upstream
+            .iter()
             .filter(|(name, _)| {
                 !matches!(
                     name.as_str(),
@@ -119,85 +1263,910 @@ pub async fn proxy(
                 )
             })
             .map(|(name, value)| {
-                let name = HeaderName::from_bytes(name.as_ref()).unwrap();
-                let mut value = HeaderValue::from_bytes(value.as_ref()).unwrap();
+                let name = name.clone();
+                let mut value = value.clone();
+
+                if name == ACCESS_CONTROL_ALLOW_ORIGIN && config.translate_origin {
+                    value = match (translated_origin, value.to_str()) {
+                        (_, Ok("*" | "null")) => value,
+                        (Some((client_origin, upstream_origin)), Ok(allowed))
+                            if allowed == upstream_origin =>
+                        {
+                            client_origin.clone()
+                        }
+                        (_, Ok(allowed)) => {
+                            HeaderValue::from_str(encode_url(config, allowed).trim_end_matches('/'))
+                                .unwrap_or(value)
+                        }
+                        (_, Err(_)) => value,
+                    };
+                }
 
+                // Values that aren't plain ASCII are left alone rather than mangled
                 if name == LOCATION {
-                    let unproxied_location = value.to_str().unwrap();
-                    let proxied_location = encode_url(&state.config, unproxied_location);
-                    value = HeaderValue::from_str(&proxied_location).unwrap();
+                    value = proxied_location(config, &value).unwrap_or(value);
                 }
 
                 if name == SET_COOKIE {
-                    let cookies = value.to_str().unwrap();
-
-                    let cookies = cookies
-                        .split("; ")
-                        .map(|cookie| {
-                            let mut parts = cookie.splitn(2, '=');
-                            let name = parts.next().unwrap();
-                            let value = parts.next().unwrap_or("");
-                            (name, value)
-                        })
-                        .map(|(name, value)| {
-                            if name.eq_ignore_ascii_case("domain") {
-                                (name, state.config.public_host.as_str())
-                            } else {
-                                (name, value)
-                            }
-                        })
-                        .map(|(name, value)| format!("{}={}", name, value))
-                        .collect::<Vec<_>>()
-                        .join("; ");
+                    value = proxied_set_cookie(config, &value).unwrap_or(value);
+                }
 
-                    value = HeaderValue::from_str(&cookies).unwrap();
+                // 103 Early Hints would carry the same links ahead of the page, but can't be passed
+                // on yet. hyper's client reports informational responses through
+                // `hyper::ext::on_informational`, which reqwest doesn't expose, and hyper's server
+                // can't send one before the final response. Once both can, the upstream's hints are
+                // forwarded after going through `proxied_link` like this, and hints for pages can
+                // be generated from the subresources the prefetcher found on them.
+                if name == LINK {
+                    value = value
+                        .to_str()
+                        .ok()
+                        .and_then(|link| HeaderValue::from_str(&proxied_link(config, link)).ok())
+                        .unwrap_or(value);
                 }
 
                 (name, value)
             }),
     );
+    strip_hop_by_hop(&mut headers);
+    if config.strip_fingerprinting_headers {
+        strip_client_hint_requests(&mut headers);
+    }
+    apply_configured_headers(&config.response_headers, origin, &mut headers)?;
 
-    *response_builder.headers_mut().unwrap() = headers;
+    Ok(headers)
+}
 
-    let body = if let Some(content_type) = res.headers().get(CONTENT_TYPE) {
-        if content_type.to_str().unwrap_or("").contains("text/html") {
-            let rewriter = state.html_rewriter.clone();
+/// The MIME type of a response, lowercased and without its parameters, and its charset
+fn media_type(headers: &HeaderMap) -> (Option<String>, Option<String>) {
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok());
 
-            let headers = response_builder.headers_mut().unwrap();
+    let essence = content_type.map(|content_type| {
+        content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase()
+    });
+    let charset = content_type.and_then(|content_type| {
+        content_type.split(';').skip(1).find_map(|parameter| {
+            let (name, value) = parameter.split_once('=')?;
+            name.trim()
+                .eq_ignore_ascii_case("charset")
+                .then(|| value.trim().trim_matches('"').to_ascii_lowercase())
+        })
+    });
 
-            headers.remove(CONTENT_ENCODING);
-            headers.remove(TRANSFER_ENCODING);
+    (essence, charset)
+}
 
-            let mut body = res.bytes().await?.to_vec();
+/// What the start of a response body showed of it
+struct Peeked {
+    /// Read off the body, to be put back in front of the rest of it
+    first_chunk: Option<Bytes>,
+    /// The body is HTML, served as plain text or without a content type
+    html: bool,
+    /// The body is binary, though its content type is one that is rewritten
+    binary: bool,
+}
 
-            body = match rewriter.rewrite(body) {
-                Ok(body) => body,
-                Err(e) => {
-                    logf!(Error, "Error rewriting HTML: {:?}", e);
-                    b"<html><body><h1>Error rewriting HTML</h1></body></html>".to_vec()
+/// Some origins serve HTML as plain text or without a content type, and binary files as HTML.
+/// The first chunk of the body is looked at then. Compressed bodies that are passed through can't
+/// be looked into.
+async fn peek(
+    state: &ProxyState,
+    config: &Config,
+    res: &mut reqwest::Response,
+    content_type: Option<&str>,
+) -> Result<Peeked> {
+    let sniffing = &config.sniffing;
+    let may_sniff_html = sniffing.html && matches!(content_type, None | Some("text/plain"));
+    let may_guard = sniffing.binary_guard
+        && res.headers().get(CONTENT_TYPE).is_some_and(|content_type| {
+            state
+                .rewriters
+                .get(content_type.to_str().unwrap_or(""))
+                .is_some()
+        });
+
+    let mut first_chunk = None;
+    let mut sniffed = Sniffed::Unknown;
+    if (may_sniff_html || may_guard)
+        && res.status() == StatusCode::OK
+        && !res.headers().contains_key(CONTENT_ENCODING)
+    {
+        let chunk = res.chunk();
+        first_chunk = match config.downloads.stall_timeout_secs.map(Duration::from_secs) {
+            Some(timeout) => tokio::time::timeout(timeout, chunk)
+                .await
+                .map_err(|_| download::stalled(timeout))??,
+            None => chunk.await?,
+        };
+        if let Some(chunk) = &first_chunk {
+            sniffed = sniff(&chunk[..chunk.len().min(sniffing.bytes)]);
+        }
+    }
+
+    Ok(Peeked {
+        first_chunk,
+        html: may_sniff_html && sniffed == Sniffed::Html,
+        binary: may_guard && sniffed == Sniffed::Binary,
+    })
+}
+
+/// A response body on its way to the client
+struct Outgoing {
+    /// The body as it comes from the upstream
+    upstream: ByteStream,
+    trailers: Trailers,
+    /// Announced again if the body is passed on as it streams in, with the trailers themselves
+    announced_trailers: Vec<HeaderValue>,
+    advertised_length: Option<u64>,
+    /// The whole body once it was rewritten or transformed, which is sent instead
+    rewritten: Option<Vec<u8>>,
+    /// Why the body wasn't rewritten, told to the client in [`REWRITE_SKIPPED`]
+    skipped: Option<&'static str>,
+}
+
+/// The body of the upstream's response as it is passed on. Bodies that stall or break off are
+/// resumed, downloads are cut off at their limit, and the offline copy and the recording are made
+/// as the body goes through.
+fn outgoing_body(
+    config: &Config,
+    res: reqwest::Response,
+    first_chunk: Option<Bytes>,
+    resume: Option<Resume>,
+    mirror: Option<Mirror>,
+    exchange: Option<PendingExchange>,
+) -> Outgoing {
+    let advertised_length = res.content_length();
+    let announced_trailers = res
+        .headers()
+        .get_all(TRAILER)
+        .iter()
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let (mut upstream, trailers) = trailers::split(res);
+
+    if let Some(chunk) = first_chunk {
+        upstream = stream::once(future::ready(Ok(chunk)))
+            .chain(upstream)
+            .boxed();
+    }
+
+    // Stalls break the body off, which is where resuming picks up
+    if let Some(timeout) = config.downloads.stall_timeout_secs.map(Duration::from_secs) {
+        upstream = download::stall_guarded(upstream, timeout);
+    }
+
+    if let Some(resume) = resume {
+        upstream = download::resumable(upstream, resume);
+    }
+
+    if let Some(max_bytes) = config.downloads.max_bytes {
+        upstream = download::capped(upstream, max_bytes);
+    }
+
+    if let Some(mirror) = mirror {
+        upstream = mirror.tee(upstream);
+    }
+
+    // The exchange is written once the body was passed on, whether it was rewritten or streamed
+    if let Some(mut exchange) = exchange {
+        upstream = upstream
+            .inspect(move |chunk| {
+                if let Ok(chunk) = chunk {
+                    exchange.add_body(chunk);
                 }
-            };
+            })
+            .boxed();
+    }
+
+    Outgoing {
+        upstream,
+        trailers,
+        announced_trailers,
+        advertised_length,
+        rewritten: None,
+        skipped: None,
+    }
+}
+
+/// What a response body is to become, and what is known about it
+struct BodyPlan {
+    rewriter: Option<Arc<dyn Rewriter>>,
+    content_type: Option<String>,
+    charset: Option<String>,
+    content_encoding: Option<String>,
+    /// How the image in the body is transformed, if it is one
+    #[cfg(feature = "media")]
+    media: Option<media::Transform>,
+    grpc_web: bool,
+    bodiless: bool,
+    is_html: bool,
+    /// The headers to prefetch the page's subresources with, if they are prefetched
+    prefetch_headers: Option<HeaderMap>,
+    /// Where the page was found, after the redirects that were followed
+    page_url: Url,
+    request_path: String,
+    /// When the offline copy was saved, if the response is one
+    stale: Option<DateTime<Utc>>,
+}
+
+/// Rewrite the body, transform the image or sanitize the document it holds, as far as that applies
+/// and the body is small enough to be taken whole
+async fn transform_body(
+    state: &ProxyState,
+    config: &Arc<Config>,
+    origin: &Origin,
+    request: &UpstreamRequest,
+    plan: BodyPlan,
+    outgoing: Outgoing,
+    headers: &mut HeaderMap,
+) -> Result<Outgoing> {
+    let BodyPlan {
+        mut rewriter,
+        content_type,
+        charset,
+        content_encoding,
+        #[cfg(feature = "media")]
+        media,
+        grpc_web,
+        bodiless,
+        is_html,
+        prefetch_headers,
+        page_url,
+        request_path,
+        stale,
+    } = plan;
+    let Outgoing {
+        mut upstream,
+        mut advertised_length,
+        mut skipped,
+        ..
+    } = outgoing;
+
+    let limit = config.max_rewrite_body_bytes;
+    let client = upstream_client(state, request.passthrough);
 
-            Body::from(body)
+    // Documents aren't rewritten, but they can hold scripts and links of their own. Only whole,
+    // uncompressed PDFs can be sanitized.
+    let document = rewriter.is_none()
+        && !grpc_web
+        && documents::is_document(&config.documents, content_type.as_deref());
+    let sanitizer = config.documents.pdf_sanitizer.as_deref().filter(|_| {
+        document
+            && !bodiless
+            && content_encoding.is_none()
+            && content_type.as_deref() == Some("application/pdf")
+    });
+
+    // Only passed through responses arrive compressed, they have to be decoded to be rewritten
+    if let (Some(_), Some(content_encoding)) = (&rewriter, content_encoding) {
+        if encoding::is_supported(&content_encoding) {
+            upstream = encoding::decode(&content_encoding, upstream);
+            advertised_length = None;
+
+            retain_headers(headers, |name| {
+                ![CONTENT_ENCODING, CONTENT_LENGTH].contains(name)
+            });
         } else {
-            Body::from_stream(res.bytes_stream())
+            rewriter = None;
+            skipped = Some("unsupported-encoding");
         }
+    }
+
+    // Element hiding is injected like any other HTML
+    let hiding_css = rewriter
+        .as_ref()
+        .and_then(|_| state.blocker.hiding_css(config, origin.host()));
+    let rewrite_config = if hiding_css.is_none() && stale.is_none() {
+        config.clone()
     } else {
-        Body::from_stream(res.bytes_stream())
+        let mut rewrite_config = (**config).clone();
+
+        if let Some(css) = hiding_css {
+            rewrite_config.inject_html = Some(format!(
+                "{}<style>{}</style>",
+                config.inject_html.as_deref().unwrap_or_default(),
+                css
+            ));
+        }
+        if let Some(saved) = stale {
+            rewrite_config.inject_html = Some(format!(
+                "{}{}",
+                rewrite_config.inject_html.as_deref().unwrap_or_default(),
+                offline::banner(saved)
+            ));
+        }
+
+        Arc::new(rewrite_config)
     };
 
-    match response_builder.body(body) {
-        Ok(response) => Ok(response.into_response()),
-        Err(e) => {
-            logf!(Error, "Error building response: {:?}", e);
-            Ok((StatusCode::INTERNAL_SERVER_ERROR, "Error building response").into_response())
+    let rewritten = match &rewriter {
+        // Don't even start buffering a body that is known to be too large
+        Some(_) if advertised_length.is_some_and(|length| length > limit) => {
+            skipped = Some("body-too-large");
+            None
+        }
+        Some(rewriter) => match buffer_body(&mut upstream, limit, advertised_length).await? {
+            Buffered::Complete(body) => Some({
+                // Found in the page as the upstream sent it, the rewritten one only links to the
+                // proxy
+                if let Some(prefetch_headers) =
+                    prefetch_headers.filter(|_| is_html && !request.reader)
+                {
+                    let subresources =
+                        prefetch::subresources(&body, &page_url, config.prefetch.max_resources);
+
+                    headers.extend(
+                        subresources
+                            .iter()
+                            .filter_map(|subresource| Some((LINK, subresource.preload_header()?))),
+                    );
+                    state
+                        .prefetcher
+                        .start(client, config, &prefetch_headers, &subresources);
+                }
+
+                let context = RewriteContext {
+                    origin,
+                    request_path: &request_path,
+                    charset: charset.as_deref(),
+                    content_type: content_type.as_deref(),
+                };
+                // Rewriters take the body, so it's only kept when it would be sent on failure
+                let original = config.serve_unrewritten_on_error.then(|| body.clone());
+                match info_span!("rewrite", bytes = body.len())
+                    .in_scope(|| rewriter.rewrite(&rewrite_config, &context, body))
+                {
+                    Ok(body) => state.plugins.on_body(&request.url, body),
+                    Err(e) => {
+                        logf!(
+                            Error,
+                            "Error rewriting response from {}: {:?}",
+                            request.url,
+                            e
+                        );
+
+                        match original {
+                            Some(original) => {
+                                skipped = Some("rewrite-failed");
+                                original
+                            }
+                            None => {
+                                b"<html><body><h1>Error rewriting HTML</h1></body></html>".to_vec()
+                            }
+                        }
+                    }
+                }
+            }),
+            Buffered::TooLarge(prefix) => {
+                upstream = stream::once(future::ready(Ok(prefix.into())))
+                    .chain(upstream)
+                    .boxed();
+                skipped = Some("body-too-large");
+                None
+            }
+        },
+        None => None,
+    };
+
+    #[cfg(feature = "media")]
+    let rewritten = match (rewritten, media) {
+        (None, Some(transform)) if advertised_length.is_none_or(|length| length <= limit) => {
+            match buffer_body(&mut upstream, limit, advertised_length).await? {
+                Buffered::Complete(body) => {
+                    let (body, content_type) =
+                        tokio::task::spawn_blocking(move || transform.apply(body)).await?;
+
+                    headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+                    if config.media.negotiate_formats {
+                        headers.append(VARY, HeaderValue::from_static("accept"));
+                    }
+
+                    Some(body)
+                }
+                Buffered::TooLarge(prefix) => {
+                    upstream = stream::once(future::ready(Ok(prefix.into())))
+                        .chain(upstream)
+                        .boxed();
+                    None
+                }
+            }
+        }
+        (rewritten, _) => rewritten,
+    };
+
+    let rewritten = match (rewritten, sanitizer) {
+        (None, Some(sanitizer)) if advertised_length.is_none_or(|length| length <= limit) => {
+            match buffer_body(&mut upstream, limit, advertised_length).await? {
+                Buffered::Complete(body) => {
+                    match documents::sanitize(client, sanitizer, body.clone()).await {
+                        Ok(sanitized) => Some(sanitized),
+                        Err(e) => {
+                            logf!(Warning, "Couldn't sanitize {}: {}", request.url, e);
+                            documents::force_attachment(headers);
+                            Some(body)
+                        }
+                    }
+                }
+                Buffered::TooLarge(prefix) => {
+                    upstream = stream::once(future::ready(Ok(prefix.into())))
+                        .chain(upstream)
+                        .boxed();
+                    documents::force_attachment(headers);
+                    None
+                }
+            }
+        }
+        (None, sanitizer)
+            if document
+                && (sanitizer.is_some()
+                    || config.documents.handling == DocumentHandling::Attachment) =>
+        {
+            documents::force_attachment(headers);
+            None
         }
+        (rewritten, _) => rewritten,
+    };
+
+    Ok(Outgoing {
+        upstream,
+        advertised_length,
+        rewritten,
+        skipped,
+        ..outgoing
+    })
+}
+
+/// The body sent to the client, the rewritten one or the upstream's as it streams in. It is
+/// counted towards the audit log and the client's usage either way.
+fn finish(
+    state: &ProxyState,
+    origin: &Origin,
+    request: &UpstreamRequest,
+    status: StatusCode,
+    headers: &mut HeaderMap,
+    outgoing: Outgoing,
+) -> Body {
+    let client_ip = request.client_ip;
+    let audit_origin = String::from(origin.clone());
+    let audit_path = request.audit_path.clone();
+
+    if is_bodiless(&request.method, status) {
+        // A length is only meaningful for the body a HEAD or a 304 stands for
+        let lengthless = status == StatusCode::NO_CONTENT || status.is_informational();
+        retain_headers(headers, |name| {
+            *name != TRANSFER_ENCODING && !(lengthless && *name == CONTENT_LENGTH)
+        });
+
+        // Logged as it's dropped, with no bytes sent
+        state
+            .audit
+            .start(client_ip, audit_origin, audit_path, status.as_u16());
+
+        // An empty body of unknown size, axum would otherwise give it a `Content-Length: 0`
+        return Body::from_stream(stream::empty::<io::Result<Bytes>>());
+    }
+
+    if let Some(body) = outgoing.rewritten {
+        retain_headers(headers, |name| {
+            ![CONTENT_ENCODING, TRANSFER_ENCODING, CONTENT_LENGTH].contains(name)
+        });
+
+        if let Some(mut record) =
+            state
+                .audit
+                .start(client_ip, audit_origin, audit_path, status.as_u16())
+        {
+            record.add_bytes(body.len());
+        }
+
+        if let Some(ip) = client_ip {
+            state.usage.add(ip, body.len() as u64);
+        }
+
+        return Body::from(body);
     }
+
+    let mut record = state
+        .audit
+        .start(client_ip, audit_origin, audit_path, status.as_u16());
+
+    let usage = state.usage.clone();
+
+    if request.accepts_trailers {
+        for announced in outgoing.announced_trailers {
+            headers.append(TRAILER, announced);
+        }
+    }
+
+    // The record is written once the client has received the whole body, or went away
+    let upstream = outgoing
+        .upstream
+        .inspect(move |chunk| {
+            let Ok(chunk) = chunk else {
+                return;
+            };
+
+            if let Some(record) = record.as_mut() {
+                record.add_bytes(chunk.len());
+            }
+
+            if let Some(ip) = client_ip {
+                usage.add(ip, chunk.len() as u64);
+            }
+        })
+        .boxed();
+
+    trailers::body(upstream, outgoing.trailers)
 }
 
-async fn proxy_ws(client: reqwest::Client, socket: WebSocket, dest: String) {
-    if let Ok(res) = client.get(&dest).upgrade().send().await {
+/// Whether the response has no body, though its headers may describe one
+fn is_bodiless(method: &Method, status: StatusCode) -> bool {
+    *method == Method::HEAD
+        || status.is_informational()
+        || matches!(status, StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED)
+}
+
+/// Record the request body of `exchange`. Bodies are buffered up to the size recorded, bodies
+/// larger than that are recorded cut off and the rest is streamed on after what was buffered.
+async fn record_request_body(body: Body, exchange: &mut PendingExchange) -> Result<Body> {
+    let mut stream = body.into_data_stream();
+    let mut buffered = Vec::new();
+
+    while let Some(chunk) = stream.next().await {
+        buffered.extend_from_slice(&chunk?);
+
+        if buffered.len() > exchange.max_body_bytes() {
+            let buffered = Bytes::from(buffered);
+            exchange.request_body(buffered.clone());
+
+            let rest = stream::once(future::ready(Ok(buffered))).chain(stream);
+            return Ok(Body::from_stream(rest));
+        }
+    }
+
+    let buffered = Bytes::from(buffered);
+    exchange.request_body(buffered.clone());
+    Ok(Body::from(buffered))
+}
+
+/// A request body as it is sent to the upstream, counted towards the client's usage as it goes.
+/// The client's `Content-Length`, if any, is forwarded along with it, and the body is sent as it
+/// arrived.
+fn upstream_body(body: Body, usage: Option<(Usage, IpAddr)>) -> reqwest::Body {
+    // Requests without a body are sent without one, rather than with an empty chunked one
+    if body.size_hint().exact() == Some(0) {
+        return reqwest::Body::from(Bytes::new());
+    }
+
+    let stream = body
+        .into_data_stream()
+        .inspect(move |chunk| {
+            if let (Ok(chunk), Some((usage, ip))) = (chunk, &usage) {
+                usage.add(*ip, chunk.len() as u64);
+            }
+        })
+        .boxed();
+
+    reqwest::Body::wrap_stream(SyncStream(Mutex::new(stream)))
+}
+
+/// A stream that can be shared between threads, as the upstream client requires of request
+/// bodies. It is only ever polled through a mutable reference, so the lock is never contended.
+struct SyncStream<S>(Mutex<S>);
+
+impl<S: Stream + Unpin> Stream for SyncStream<S> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        self.get_mut().0.get_mut().unwrap().poll_next_unpin(cx)
+    }
+}
+
+/// A response body that finishes its [`Transfer`] once it has all been sent
+struct TransferBody {
+    body: Body,
+    /// How much of the body's length is left to send, for bodies of a known length
+    remaining: Option<u64>,
+    transfer: Option<Transfer>,
+}
+
+impl TransferBody {
+    fn finish(&mut self) {
+        if let Some(transfer) = self.transfer.take() {
+            transfer.finish();
+        }
+    }
+}
+
+impl HttpBody for TransferBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<std::result::Result<Frame<Bytes>, axum::Error>>> {
+        let frame = ready!(Pin::new(&mut self.body).poll_frame(cx));
+
+        match &frame {
+            Some(Ok(frame)) => {
+                let sent = frame.data_ref().map_or(0, |data| data.len() as u64);
+
+                if let Some(remaining) = &mut self.remaining {
+                    *remaining = remaining.saturating_sub(sent);
+                    if *remaining == 0 {
+                        self.finish();
+                    }
+                }
+            }
+            // A body that fails didn't fail for the client going away
+            _ => self.finish(),
+        }
+
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+impl Drop for TransferBody {
+    fn drop(&mut self) {
+        // Empty bodies are dropped without being read
+        if self.remaining == Some(0) || self.body.is_end_stream() {
+            self.finish();
+        }
+    }
+}
+
+/// Whether a `Content-Type` is one of gRPC-web's, such as `application/grpc-web+proto` or
+/// `application/grpc-web-text`
+fn is_grpc_web(content_type: Option<&HeaderValue>) -> bool {
+    content_type
+        .and_then(|content_type| content_type.as_bytes().get(..20))
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case(b"application/grpc-web"))
+}
+
+/// The page shown while the circuit of an upstream origin is open
+fn origin_unavailable_response(host: &str, retry_after: Duration) -> Response {
+    // Rounded up, so that retrying right on time doesn't hit the end of the cooldown
+    let retry_after = retry_after.as_secs() + 1;
+
+    let mut response = refusal(
+        StatusCode::SERVICE_UNAVAILABLE,
+        RefusalReason::CircuitOpen,
+        format!(
+            "the circuit of {} is open for {} seconds",
+            host, retry_after
+        ),
+        "Site unavailable",
+        &format!(
+            "{} isn't responding. Try again in {} seconds.",
+            host, retry_after
+        ),
+    );
+
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+
+    response
+}
+
+/// A `Location` header pointing at the proxied URL
+fn proxied_location(config: &Config, location: &HeaderValue) -> Option<HeaderValue> {
+    let location = encode_url(config, location.to_str().ok()?);
+
+    HeaderValue::from_str(&location).ok()
+}
+
+/// A `Set-Cookie` header with its `Domain` moved to the public host
+fn proxied_set_cookie(config: &Config, cookie: &HeaderValue) -> Option<HeaderValue> {
+    let cookie = cookie
+        .to_str()
+        .ok()?
+        .split("; ")
+        .map(|attribute| match attribute.split_once('=') {
+            Some((name, _)) if name.eq_ignore_ascii_case("domain") => {
+                format!("{}={}", name, config.public_host)
+            }
+            Some((name, value)) => format!("{}={}", name, value),
+            None => format!("{}=", attribute),
+        })
+        .collect::<Vec<_>>()
+        .join("; ");
+
+    HeaderValue::from_str(&cookie).ok()
+}
+
+/// The response for a block or redirect rule, if one of the actions is either
+fn rule_response(config: &Config, actions: &[&RuleAction]) -> Option<Response> {
+    actions.iter().find_map(|action| match action {
+        RuleAction::Block => Some(refusal(
+            StatusCode::FORBIDDEN,
+            RefusalReason::Rule,
+            "blocked by a rule",
+            "Blocked",
+            "This page was blocked by the proxy's rules.",
+        )),
+        RuleAction::Redirect(url) => {
+            Some((StatusCode::FOUND, [(LOCATION, encode_url(config, url))]).into_response())
+        }
+        _ => None,
+    })
+}
+
+/// The response to a request whose host isn't a valid proxied address, because of `detail`
+fn invalid_address_response(config: &Config, detail: String) -> Response {
+    let response = match config.invalid_address {
+        InvalidAddressBehavior::Page => {
+            return refusal(
+                StatusCode::NOT_FOUND,
+                RefusalReason::InvalidAddress,
+                detail,
+                "Invalid address",
+                "This address doesn't point to a site. The link may be mistyped or cut off.",
+            )
+        }
+        InvalidAddressBehavior::RedirectToLanding => {
+            let landing = match &config.api_path_prefix {
+                Some(prefix) => format!("https://{}{}/", config.public_host, prefix),
+                None => format!("https://api.{}/", config.public_host),
+            };
+
+            (StatusCode::FOUND, [(LOCATION, landing)]).into_response()
+        }
+        InvalidAddressBehavior::NotFound => StatusCode::NOT_FOUND.into_response(),
+    };
+
+    let refused = Refused {
+        reason: RefusalReason::InvalidAddress,
+        detail,
+        page: None,
+    };
+
+    (Extension(refused), response).into_response()
+}
+
+/// The type of the requested resource in filter list terms, from the `Sec-Fetch-Dest` header
+fn resource_type(headers: &HeaderMap) -> Option<&'static str> {
+    let destination = headers.get("sec-fetch-dest")?.to_str().ok()?;
+
+    Some(match destination {
+        "document" => "document",
+        "iframe" | "frame" | "fencedframe" => "subdocument",
+        "script" | "worker" | "sharedworker" | "serviceworker" | "audioworklet"
+        | "paintworklet" => "script",
+        "style" => "stylesheet",
+        "image" => "image",
+        "font" => "font",
+        "audio" | "video" | "track" => "media",
+        "object" | "embed" => "object",
+        "empty" => "xmlhttprequest",
+        _ => "other",
+    })
+}
+
+/// The upstream host of the page that loaded the resource, from the proxied `Referer`, or the
+/// `Origin` of requests without one such as WebSocket handshakes
+fn source_host(config: &Config, headers: &HeaderMap) -> Option<String> {
+    let referer = headers
+        .get(REFERER)
+        .or(headers.get(ORIGIN))?
+        .to_str()
+        .ok()?;
+    let url = decode_url(config, referer).ok()?;
+
+    Uri::from_str(&url).ok()?.host().map(str::to_string)
+}
+
+/// Remove a flag such as the raw opt-out from the request's query and headers so it isn't sent
+/// upstream, returning whether it was set
+fn take_flag(parts: &mut Parts, param: &str, header: HeaderName) -> Result<bool> {
+    let is_set = |value: &str| value != "0" && value != "false";
+
+    let mut flag = parts
+        .headers
+        .remove(header)
+        .is_some_and(|value| is_set(value.to_str().unwrap_or_default()));
+
+    let Some(query) = parts.uri.query() else {
+        return Ok(flag);
+    };
+
+    let mut found = false;
+    let query = query
+        .split('&')
+        .filter(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, "1"));
+
+            if name != param {
+                return true;
+            }
+
+            found = true;
+            flag |= is_set(value);
+            false
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+
+    if found {
+        let path = parts.uri.path();
+        parts.uri = if query.is_empty() {
+            path.parse()?
+        } else {
+            format!("{}?{}", path, query).parse()?
+        };
+    }
+
+    Ok(flag)
+}
+
+enum Buffered {
+    Complete(Vec<u8>),
+    /// The body grew past the limit, holding what was read so far
+    TooLarge(Vec<u8>),
+}
+
+/// Read the upstream body into memory, giving up once it grows past `limit` bytes. The buffer is
+/// allocated up front when the upstream advertised the length of the body.
+async fn buffer_body(
+    upstream: &mut ByteStream,
+    limit: u64,
+    advertised_length: Option<u64>,
+) -> Result<Buffered> {
+    let capacity = advertised_length.map_or(0, |length| length.min(limit) as usize);
+    let mut body = Vec::with_capacity(capacity);
+
+    while let Some(chunk) = upstream.next().await {
+        body.extend_from_slice(&chunk?);
+
+        if body.len() as u64 > limit {
+            return Ok(Buffered::TooLarge(body));
+        }
+    }
+
+    Ok(Buffered::Complete(body))
+}
+
+#[tracing::instrument(name = "websocket", skip_all, fields(url = %dest))]
+async fn proxy_ws(
+    state: Arc<ProxyState>,
+    limits: WebSocketConfig,
+    socket: WebSocket,
+    headers: HeaderMap,
+    dest: String,
+) {
+    let ProxyState {
+        client,
+        hooks,
+        plugins,
+        metrics,
+        ..
+    } = &*state;
+
+    if let Ok(res) = client.get(&dest).headers(headers).upgrade().send().await {
         if let Ok(dest_socket) = res.into_websocket().await {
+            let event = WebSocketEvent { url: dest };
+            hooks.on_websocket_open(&event);
+            let _open = metrics.websocket_opened();
+
+            let limits = WsLimits::new(limits);
+
             let (mut dest_tx, mut dest_rx) = dest_socket.split();
 
             let (mut tx, mut rx) = socket.split();
@@ -205,14 +2174,28 @@ async fn proxy_ws(client: reqwest::Client, socket: WebSocket, dest: String) {
             let rx_to_dest = async {
                 while let Some(msg) = rx.next().await {
                     if let Ok(msg) = msg {
+                        if let Err(limit) = limits.client_message(ws_message_len(&msg)) {
+                            return Some(limit);
+                        }
+
                         match msg {
                             axum::extract::ws::Message::Text(text) => {
-                                let dest_msg = reqwest_websocket::Message::Text(text);
-                                let _ = dest_tx.send(dest_msg).await;
+                                if let Some(msg) = plugins.on_ws_message(
+                                    &event.url,
+                                    WsDirection::ToUpstream,
+                                    WsMessage::Text(text),
+                                ) {
+                                    let _ = dest_tx.send(msg.into()).await;
+                                }
                             }
                             axum::extract::ws::Message::Binary(bin) => {
-                                let dest_msg = reqwest_websocket::Message::Binary(bin);
-                                let _ = dest_tx.send(dest_msg).await;
+                                if let Some(msg) = plugins.on_ws_message(
+                                    &event.url,
+                                    WsDirection::ToUpstream,
+                                    WsMessage::Binary(bin),
+                                ) {
+                                    let _ = dest_tx.send(msg.into()).await;
+                                }
                             }
                             axum::extract::ws::Message::Close(close) => {
                                 let close = close.unwrap_or(CloseFrame {
@@ -237,19 +2220,35 @@ async fn proxy_ws(client: reqwest::Client, socket: WebSocket, dest: String) {
                         }
                     }
                 }
+
+                None
             };
 
             let tx_to_src = async {
                 while let Some(msg) = dest_rx.next().await {
                     if let Ok(msg) = msg {
+                        if let Err(limit) = limits.upstream_message(upstream_ws_message_len(&msg)) {
+                            return Some(limit);
+                        }
+
                         match msg {
                             reqwest_websocket::Message::Text(text) => {
-                                let src_msg = axum::extract::ws::Message::from(text);
-                                let _ = tx.send(src_msg).await;
+                                if let Some(msg) = plugins.on_ws_message(
+                                    &event.url,
+                                    WsDirection::ToClient,
+                                    WsMessage::Text(text),
+                                ) {
+                                    let _ = tx.send(msg.into()).await;
+                                }
                             }
                             reqwest_websocket::Message::Binary(bin) => {
-                                let src_msg = axum::extract::ws::Message::from(bin);
-                                let _ = tx.send(src_msg).await;
+                                if let Some(msg) = plugins.on_ws_message(
+                                    &event.url,
+                                    WsDirection::ToClient,
+                                    WsMessage::Binary(bin),
+                                ) {
+                                    let _ = tx.send(msg.into()).await;
+                                }
                             }
                             reqwest_websocket::Message::Close { code, reason } => {
                                 let src_msg = axum::extract::ws::Message::Close(Some(CloseFrame {
@@ -270,12 +2269,58 @@ async fn proxy_ws(client: reqwest::Client, socket: WebSocket, dest: String) {
                         }
                     }
                 }
+
+                None
+            };
+
+            let exceeded = tokio::select! {
+                limit = rx_to_dest => limit,
+                limit = tx_to_src => limit,
+                limit = limits.expired() => Some(limit),
             };
 
-            tokio::select! {
-                _ = rx_to_dest => {}
-                _ = tx_to_src => {}
+            // Close both ends when the proxy ends the connection itself
+            if let Some(limit) = exceeded {
+                let (code, reason) = limit.close_frame();
+                logf!(Info, "Closing WebSocket to {}: {}", event.url, reason);
+
+                let _ = tx
+                    .send(axum::extract::ws::Message::Close(Some(CloseFrame {
+                        code,
+                        reason: reason.into(),
+                    })))
+                    .await;
+                let _ = dest_tx
+                    .send(reqwest_websocket::Message::Close {
+                        code: code.into(),
+                        reason: reason.to_string(),
+                    })
+                    .await;
             }
+
+            hooks.on_websocket_close(&event);
         }
     }
 }
+
+/// The payload size of a message from the client
+fn ws_message_len(msg: &axum::extract::ws::Message) -> usize {
+    match msg {
+        axum::extract::ws::Message::Text(text) => text.len(),
+        axum::extract::ws::Message::Binary(data)
+        | axum::extract::ws::Message::Ping(data)
+        | axum::extract::ws::Message::Pong(data) => data.len(),
+        axum::extract::ws::Message::Close(_) => 0,
+    }
+}
+
+/// The payload size of a message from the upstream
+fn upstream_ws_message_len(msg: &reqwest_websocket::Message) -> usize {
+    match msg {
+        reqwest_websocket::Message::Text(text) => text.len(),
+        reqwest_websocket::Message::Binary(data)
+        | reqwest_websocket::Message::Ping(data)
+        | reqwest_websocket::Message::Pong(data) => data.len(),
+        reqwest_websocket::Message::Close { .. } => 0,
+    }
+}
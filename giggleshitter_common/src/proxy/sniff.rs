@@ -0,0 +1,84 @@
+//! Guessing what a body holds from its first bytes, for origins that label their responses wrong.
+//! The guesses are conservative, following the patterns browsers sniff for: what isn't clearly
+//! HTML or clearly binary is left to its declared content type.
+
+/// Tags that a document starting with is taken to be HTML, as browsers sniff for them
+const HTML_TAGS: [&[u8]; 17] = [
+    b"<!doctype html",
+    b"<html",
+    b"<head",
+    b"<script",
+    b"<iframe",
+    b"<h1",
+    b"<div",
+    b"<font",
+    b"<table",
+    b"<a",
+    b"<style",
+    b"<title",
+    b"<b",
+    b"<body",
+    b"<br",
+    b"<p",
+    b"<!--",
+];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Sniffed {
+    Html,
+    Binary,
+    Unknown,
+}
+
+/// What the start of a body looks like
+pub fn sniff(bytes: &[u8]) -> Sniffed {
+    // Text in UTF-16 is full of zero bytes, but isn't binary
+    if bytes.starts_with(&[0xfe, 0xff]) || bytes.starts_with(&[0xff, 0xfe]) {
+        return Sniffed::Unknown;
+    }
+
+    if bytes.iter().any(|&byte| is_binary_byte(byte)) {
+        return Sniffed::Binary;
+    }
+
+    let text = bytes.strip_prefix(b"\xef\xbb\xbf").unwrap_or(bytes);
+    let start = text
+        .iter()
+        .position(|byte| !byte.is_ascii_whitespace())
+        .unwrap_or(text.len());
+    let text = &text[start..];
+
+    let is_html = HTML_TAGS.iter().any(|tag| {
+        text.len() > tag.len()
+            && text[..tag.len()].eq_ignore_ascii_case(tag)
+            // The tag has to end there, "<bdi" or "<abbr" don't count
+            && (*tag == b"<!--" || matches!(text[tag.len()], b' ' | b'>' | b'\t' | b'\n' | b'\r'))
+    });
+
+    match is_html {
+        true => Sniffed::Html,
+        false => Sniffed::Unknown,
+    }
+}
+
+/// Control characters that don't appear in text, as in the binary data bytes of the MIME sniffing
+/// standard
+fn is_binary_byte(byte: u8) -> bool {
+    matches!(byte, 0x00..=0x08 | 0x0b | 0x0e..=0x1a | 0x1c..=0x1f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniffs_html_binary_and_neither() {
+        assert_eq!(sniff(b"\n  <!DOCTYPE html><p>hi"), Sniffed::Html);
+        assert_eq!(sniff(b"\xef\xbb\xbf<html lang=en>"), Sniffed::Html);
+        assert_eq!(sniff(b"<!-- comment -->"), Sniffed::Html);
+        assert_eq!(sniff(b"<abbr>not a document</abbr>"), Sniffed::Unknown);
+        assert_eq!(sniff(b"plain text with a <p> in it"), Sniffed::Unknown);
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), Sniffed::Binary);
+        assert_eq!(sniff(b"\xff\xfe<\0h\0t\0m\0l\0"), Sniffed::Unknown);
+    }
+}
@@ -0,0 +1,100 @@
+//! Trailer fields of streamed responses, such as the status of gRPC-web calls. They are set aside
+//! while the body goes through the proxy's byte streams, and sent again once it has been passed on.
+
+use std::{
+    future::poll_fn,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use axum::body::{Body, Bytes};
+use futures_util::{
+    ready,
+    stream::{self, Fuse},
+    StreamExt,
+};
+use hyper::{
+    body::{Body as HttpBody, Frame},
+    HeaderMap,
+};
+
+use super::{encoding::ByteStream, headers::strip_hop_by_hop};
+
+#[derive(Clone, Default)]
+/// The trailers of a body, once it has been read to the end
+pub struct Trailers(Arc<Mutex<Option<HeaderMap>>>);
+
+impl Trailers {
+    fn take(&self) -> Option<HeaderMap> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+/// The body of `res` as a stream of bytes, along with where its trailers are kept once the stream
+/// has ended
+pub fn split(res: reqwest::Response) -> (ByteStream, Trailers) {
+    let trailers = Trailers::default();
+    let kept = trailers.clone();
+
+    let stream = stream::unfold(reqwest::Body::from(res), move |mut body| {
+        let kept = kept.clone();
+
+        async move {
+            loop {
+                let frame = match poll_fn(|cx| Pin::new(&mut body).poll_frame(cx)).await? {
+                    Ok(frame) => frame,
+                    Err(e) => return Some((Err(io::Error::other(e)), body)),
+                };
+
+                match frame.into_data() {
+                    Ok(data) => return Some((Ok(data), body)),
+                    Err(frame) => {
+                        if let Ok(mut trailers) = frame.into_trailers() {
+                            // They were only meant for the connection to the proxy
+                            strip_hop_by_hop(&mut trailers);
+                            *kept.0.lock().unwrap() = Some(trailers);
+                        }
+                    }
+                }
+            }
+        }
+    })
+    .boxed();
+
+    (stream, trailers)
+}
+
+/// A response body sending `stream`, then the `trailers` it left behind. Clients only get them
+/// when they asked for trailers with `TE: trailers`, and the response lists them in `Trailer`.
+pub fn body(stream: ByteStream, trailers: Trailers) -> Body {
+    Body::new(WithTrailers {
+        stream: stream.fuse(),
+        trailers,
+    })
+}
+
+struct WithTrailers {
+    stream: Fuse<ByteStream>,
+    trailers: Trailers,
+}
+
+impl HttpBody for WithTrailers {
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, io::Error>>> {
+        match ready!(self.stream.poll_next_unpin(cx)) {
+            Some(chunk) => Poll::Ready(Some(chunk.map(Frame::data))),
+            None => Poll::Ready(
+                self.trailers
+                    .take()
+                    .map(|trailers| Ok(Frame::trailers(trailers))),
+            ),
+        }
+    }
+}
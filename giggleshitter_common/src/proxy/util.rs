@@ -1,9 +1,214 @@
-use std::str::FromStr;
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    iter,
+    num::NonZeroUsize,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        LazyLock, Mutex,
+    },
+};
 
 use crate::state::{Config, UrlEncodingAlgorithm};
 use anyhow::Result;
+use base32::Alphabet;
+use chrono::{DateTime, SecondsFormat, Utc};
 use hyper::Uri;
+use lru::LruCache;
+use serde::Serialize;
 use thiserror::Error;
+use utoipa::ToSchema;
+
+use super::hsts;
+
+/// How many origins the encoding and decoding caches each hold
+const ORIGIN_CACHE_SIZE: NonZeroUsize = NonZeroUsize::new(1024).unwrap();
+
+static ORIGIN_CACHE: LazyLock<OriginCache> = LazyLock::new(|| OriginCache {
+    decoded: Mutex::new(LruCache::new(ORIGIN_CACHE_SIZE)),
+    encoded: Mutex::new(LruCache::new(ORIGIN_CACHE_SIZE)),
+    hits: AtomicU64::new(0),
+    misses: AtomicU64::new(0),
+});
+
+/// The most recently used origins of proxied hosts, so that requests to the same host don't
+/// decode it again. Entries are tagged with the [`fingerprint`] of the algorithm they were encoded
+/// with, as tenants and sessions encode origins differently.
+struct OriginCache {
+    /// Encoded labels and the origins they decode to
+    decoded: Mutex<LruCache<String, (u64, Origin)>>,
+    /// Origins as written in URLs and the labels they encode to
+    encoded: Mutex<LruCache<String, (u64, String)>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl OriginCache {
+    fn get<V: Clone>(
+        &self,
+        cache: &Mutex<LruCache<String, (u64, V)>>,
+        key: &str,
+        fingerprint: u64,
+    ) -> Option<V> {
+        let value = cache
+            .lock()
+            .unwrap()
+            .get(key)
+            .filter(|(cached_fingerprint, _)| *cached_fingerprint == fingerprint)
+            .map(|(_, value)| value.clone());
+
+        let counter = match value {
+            Some(_) => &self.hits,
+            None => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        value
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+/// How well the origin cache has been working since the server started
+pub struct OriginCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// The share of lookups that were answered from the cache, from 0 to 1
+    pub hit_rate: f64,
+    pub decoded_entries: usize,
+    pub encoded_entries: usize,
+}
+
+pub fn origin_cache_stats() -> OriginCacheStats {
+    let hits = ORIGIN_CACHE.hits.load(Ordering::Relaxed);
+    let misses = ORIGIN_CACHE.misses.load(Ordering::Relaxed);
+
+    OriginCacheStats {
+        hits,
+        misses,
+        hit_rate: match hits + misses {
+            0 => 0.0,
+            lookups => hits as f64 / lookups as f64,
+        },
+        decoded_entries: ORIGIN_CACHE.decoded.lock().unwrap().len(),
+        encoded_entries: ORIGIN_CACHE.encoded.lock().unwrap().len(),
+    }
+}
+
+/// How many hosts each encoding algorithm decoded and when it last did, by [`fingerprint`]
+static KEY_MATCHES: LazyLock<Mutex<HashMap<u64, KeyMatches>>> = LazyLock::new(Default::default);
+
+type KeyMatches = (u64, DateTime<Utc>);
+
+#[derive(Clone, Copy, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+/// Where an algorithm hosts are decoded with comes from in the configuration
+pub enum KeyRole {
+    /// [`Config::url_encoding_algorithm`]
+    Current,
+    /// One of [`Config::previous_keys`]
+    Previous,
+    /// One of [`Config::legacy_url_encoding_algorithms`]
+    Legacy,
+}
+
+#[derive(Serialize, ToSchema)]
+/// How much a key is still used, so that operators can tell when it is safe to retire
+pub struct KeyStats {
+    pub role: KeyRole,
+    /// The position of the key in the list of its role
+    pub index: usize,
+    /// How many proxied hosts were decoded with the key since the server started
+    pub matches: u64,
+    /// When a proxied host was last decoded with the key, in RFC 3339 format
+    pub last_matched: Option<String>,
+}
+
+/// How much each of the algorithms `config` decodes hosts with was used, in the order they are
+/// tried
+pub fn key_stats(config: &Config) -> Vec<KeyStats> {
+    let matches = KEY_MATCHES.lock().unwrap();
+    let mut indices = HashMap::new();
+
+    decoding_algorithms(config)
+        .map(|(role, algorithm)| {
+            let index = indices.entry(role as u8).or_insert(0);
+            let (count, last) = matches
+                .get(&fingerprint(&algorithm))
+                .map_or((0, None), |(count, last)| (*count, Some(*last)));
+
+            let stats = KeyStats {
+                role,
+                index: *index,
+                matches: count,
+                last_matched: last.map(|last| last.to_rfc3339_opts(SecondsFormat::Secs, true)),
+            };
+            *index += 1;
+
+            stats
+        })
+        .collect()
+}
+
+/// The algorithms hosts are decoded with, in the order they are tried. Previous keys are used
+/// with the alphabet of the current algorithm, and only built once they are reached.
+fn decoding_algorithms(
+    config: &Config,
+) -> impl Iterator<Item = (KeyRole, Cow<'_, UrlEncodingAlgorithm>)> {
+    let alphabet = match &config.url_encoding_algorithm {
+        UrlEncodingAlgorithm::Base32(alphabet) | UrlEncodingAlgorithm::Base32Xor(alphabet, _) => {
+            *alphabet
+        }
+    };
+
+    iter::once((
+        KeyRole::Current,
+        Cow::Borrowed(&config.url_encoding_algorithm),
+    ))
+    .chain(config.previous_keys.iter().map(move |key| {
+        (
+            KeyRole::Previous,
+            Cow::Owned(UrlEncodingAlgorithm::Base32Xor(alphabet, key.clone())),
+        )
+    }))
+    .chain(
+        config
+            .legacy_url_encoding_algorithms
+            .iter()
+            .map(|algorithm| (KeyRole::Legacy, Cow::Borrowed(algorithm))),
+    )
+}
+
+/// Whether an alphabet only has uppercase letters
+fn is_uppercase(alphabet: &Alphabet) -> bool {
+    matches!(
+        alphabet,
+        Alphabet::Crockford | Alphabet::Rfc4648 { .. } | Alphabet::Rfc4648Hex { .. }
+    )
+}
+
+/// Identifies an encoding algorithm and its key
+fn fingerprint(algorithm: &UrlEncodingAlgorithm) -> u64 {
+    let alphabet_id = |alphabet: &Alphabet| match alphabet {
+        Alphabet::Crockford => (0, false),
+        Alphabet::Rfc4648 { padding } => (1, *padding),
+        Alphabet::Rfc4648Lower { padding } => (2, *padding),
+        Alphabet::Rfc4648Hex { padding } => (3, *padding),
+        Alphabet::Rfc4648HexLower { padding } => (4, *padding),
+        Alphabet::Z => (5, false),
+    };
+
+    let mut hasher = DefaultHasher::new();
+    match algorithm {
+        UrlEncodingAlgorithm::Base32(alphabet) => alphabet_id(alphabet).hash(&mut hasher),
+        UrlEncodingAlgorithm::Base32Xor(alphabet, key) => {
+            alphabet_id(alphabet).hash(&mut hasher);
+            key.hash(&mut hasher);
+        }
+    }
+    hasher.finish()
+}
 
 #[derive(Error, Debug, Clone)]
 struct DecodeError;
@@ -35,37 +240,156 @@ impl std::fmt::Display for InvalidOriginError {
     }
 }
 
+#[derive(Error, Debug, Clone)]
+/// A request was made to a host under the public host that doesn't decode to an upstream origin,
+/// usually a mistyped or truncated link
+pub struct InvalidAddressError {
+    pub host: String,
+    reason: String,
+}
+
+impl InvalidAddressError {
+    pub fn new(host: &str, reason: impl std::fmt::Display) -> Self {
+        Self {
+            host: host.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for InvalidAddressError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} is not a valid proxied address: {}",
+            self.host, self.reason
+        )
+    }
+}
+
+/// The upstream origin of a proxied host, the `Host` a client sent to the proxy. The encoded label
+/// may arrive in either case and the host may carry a port. Labels that don't decode with the
+/// configured encoding are tried with [`Config::previous_keys`], then with
+/// [`Config::legacy_url_encoding_algorithms`], in order.
+///
+/// ```
+/// use giggleshitter_common::prelude::*;
+///
+/// let config = Config {
+///     url_encoding_algorithm: UrlEncodingAlgorithm::Base32(Alphabet::Z),
+///     public_host: "proxy.example".to_string(),
+///     ..Default::default()
+/// };
+///
+/// let origin = proxied_origin(&config, "pb48ehb4fhzunctzfaanhcbqgr.proxy.example:443")?;
+/// assert_eq!(origin.host(), "127.0.0.1");
+/// assert_eq!(origin.scheme(), Scheme::Http);
+/// assert_eq!(origin.port(), 80);
+///
+/// assert!(proxied_origin(&config, "not-proxied.example").is_err());
+/// # Ok::<(), AppError>(())
+/// ```
 pub fn proxied_origin(config: &Config, origin: &str) -> Result<Origin> {
     let origin = match origin.rfind(':') {
         Some(index) => &origin[..index],
         None => origin,
     };
 
-    let origin = origin
+    let label = origin
         .strip_suffix(&config.public_host)
         .ok_or(InvalidHostError)?
         .trim_end_matches('.');
 
+    // Labels encoded before the encoding was rotated are only tried once the current one fails,
+    // and the error of the current one is the one reported
+    let mut error = None;
+    for (_, algorithm) in decoding_algorithms(config) {
+        match decode_label(&algorithm, label) {
+            Ok(origin) => {
+                let mut matches = KEY_MATCHES.lock().unwrap();
+                let (count, last) = matches
+                    .entry(fingerprint(&algorithm))
+                    .or_insert((0, Utc::now()));
+                *count += 1;
+                *last = Utc::now();
+
+                return Ok(origin);
+            }
+            Err(e) => {
+                error.get_or_insert(e);
+            }
+        }
+    }
+
+    Err(error.expect("the current algorithm is always tried"))
+}
+
+/// The origin a subdomain label encodes with `algorithm`
+fn decode_label(algorithm: &UrlEncodingAlgorithm, label: &str) -> Result<Origin> {
+    // Host names are case insensitive, so the label may arrive in either case
+    let label = match algorithm {
+        UrlEncodingAlgorithm::Base32(alphabet) | UrlEncodingAlgorithm::Base32Xor(alphabet, _)
+            if is_uppercase(alphabet) =>
+        {
+            label.to_ascii_uppercase()
+        }
+        _ => label.to_ascii_lowercase(),
+    };
+    let label = label.as_str();
+
+    let fingerprint = fingerprint(algorithm);
+    if let Some(origin) = ORIGIN_CACHE.get(&ORIGIN_CACHE.decoded, label, fingerprint) {
+        return Ok(origin);
+    }
+
     // Decode the proxied origin
-    match &config.url_encoding_algorithm {
+    let origin = match algorithm {
         UrlEncodingAlgorithm::Base32(alphabet) => parse_origin(&String::from_utf8(
-            base32::decode(*alphabet, origin).ok_or(DecodeError)?,
+            base32::decode(*alphabet, label).ok_or(DecodeError)?,
         )?),
         UrlEncodingAlgorithm::Base32Xor(alphabet, key) => parse_origin(&String::from_utf8(
-            base32::decode(*alphabet, origin)
+            base32::decode(*alphabet, label)
                 .ok_or(DecodeError)?
                 .iter()
                 .zip(key.iter().cycle())
                 .map(|(byte, key_byte)| byte ^ key_byte)
                 .collect(),
         )?),
-    }
+    }?;
+
+    ORIGIN_CACHE
+        .decoded
+        .lock()
+        .unwrap()
+        .put(label.to_string(), (fingerprint, origin.clone()));
+
+    Ok(origin)
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Scheme {
     Http,
     Https,
+    /// Served through the FTP gateway, when it is enabled
+    Ftp,
+}
+
+impl Scheme {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scheme::Http => "http",
+            Scheme::Https => "https",
+            Scheme::Ftp => "ftp",
+        }
+    }
+
+    pub fn default_port(&self) -> u16 {
+        match self {
+            Scheme::Http => 80,
+            Scheme::Https => 443,
+            Scheme::Ftp => 21,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -90,14 +414,39 @@ impl Origin {
     pub fn port(&self) -> u16 {
         self.port
     }
+
+    /// The host and port as sent in the `Host` header, leaving out the default port
+    pub fn authority(&self) -> String {
+        if self.port == self.scheme.default_port() {
+            self.host.clone()
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+
+    /// The origin as browsers send it in `Origin` headers, leaving out the default port
+    pub fn ascii_serialization(&self) -> String {
+        format!("{}://{}", self.scheme.as_str(), self.authority())
+    }
 }
 
 impl From<Origin> for String {
     fn from(origin: Origin) -> String {
-        match origin.scheme {
-            Scheme::Http => format!("http://{}:{}", origin.host, origin.port),
-            Scheme::Https => format!("https://{}:{}", origin.host, origin.port),
-        }
+        format!(
+            "{}://{}:{}",
+            origin.scheme.as_str(),
+            origin.host,
+            origin.port
+        )
+    }
+}
+
+impl FromStr for Origin {
+    type Err = anyhow::Error;
+
+    /// Parse an origin such as `https://example.com:8443`
+    fn from_str(origin: &str) -> Result<Self> {
+        parse_origin(origin)
     }
 }
 
@@ -111,28 +460,72 @@ fn parse_origin(origin: &str) -> Result<Origin> {
     let scheme = match parts.next() {
         Some("http") => Scheme::Http,
         Some("https") => Scheme::Https,
+        Some("ftp") => Scheme::Ftp,
         _ => return Err(InvalidOriginError.into()),
     };
 
-    let mut parts = parts.next().ok_or(InvalidOriginError)?.splitn(2, ':');
-    let host = parts.next().ok_or(InvalidOriginError)?.to_string();
-    if host.contains('/') || host.contains(':') {
+    let authority = parts.next().ok_or(InvalidOriginError)?;
+
+    // IPv6 addresses are bracketed, as they contain colons themselves
+    let (host, port) = if authority.starts_with('[') {
+        let end = authority.find(']').ok_or(InvalidOriginError)?;
+        let (host, port) = authority.split_at(end + 1);
+        let port = match port {
+            "" => None,
+            port => Some(port.strip_prefix(':').ok_or(InvalidOriginError)?),
+        };
+        (host, port)
+    } else {
+        let mut parts = authority.splitn(2, ':');
+        (parts.next().ok_or(InvalidOriginError)?, parts.next())
+    };
+
+    if host.is_empty()
+        || host.contains(['/', '?', '#', '@'])
+        || (!host.starts_with('[') && host.contains(':'))
+    {
         return Err(InvalidOriginError.into());
     }
+    let host = host.to_string();
 
-    let port = parts
-        .next()
-        .map(|port| port.parse().map_err(|_| InvalidOriginError))
-        .unwrap_or_else(|| match scheme {
-            Scheme::Http => Ok(80),
-            Scheme::Https => Ok(443),
-        })?;
+    let port = match port {
+        Some(port) => port.parse().map_err(|_| InvalidOriginError)?,
+        None => scheme.default_port(),
+    };
 
     Ok(Origin { scheme, host, port })
 }
 
+/// The proxied URL of `url`. Relative URLs, URLs that already point at the proxy and anything that
+/// doesn't parse as an absolute URL are returned as they are.
+///
+/// ```
+/// use giggleshitter_common::prelude::*;
+///
+/// let config = Config {
+///     url_encoding_algorithm: UrlEncodingAlgorithm::Base32(Alphabet::Z),
+///     public_host: "proxy.example".to_string(),
+///     ..Default::default()
+/// };
+///
+/// assert_eq!(
+///     encode_url(&config, "http://127.0.0.1/page?q=1"),
+///     "https://pb48ehb4fhzunctzfaanhcbqgr.proxy.example/page?q=1"
+/// );
+/// assert_eq!(encode_url(&config, "/relative"), "/relative");
+/// assert_eq!(
+///     encode_url(&config, "https://pb48ehb4fhzunctzfaanhcbqgr.proxy.example/page"),
+///     "https://pb48ehb4fhzunctzfaanhcbqgr.proxy.example/page"
+/// );
+/// ```
 pub fn encode_url(config: &Config, url: &str) -> String {
-    if !url.contains("://") {
+    // Inline URLs hold their content rather than point at it, even when it contains `://`
+    if !url.contains("://")
+        || ["data:", "blob:"].iter().any(|scheme| {
+            url.get(..scheme.len())
+                .is_some_and(|s| s.eq_ignore_ascii_case(scheme))
+        })
+    {
         return url.to_string();
     }
 
@@ -151,34 +544,151 @@ pub fn encode_url(config: &Config, url: &str) -> String {
         None => return url.to_string(),
     };
 
+    // Already proxied, such as links resolved against a proxied page. Encoding them again would
+    // nest the proxy's host in a label that doesn't decode.
+    if is_proxied_host(config, auth.host()) {
+        return url.to_string();
+    }
+
     let port = match auth.port_u16() {
         Some(p) => format!(":{}", p),
         None => "".to_string(),
     };
 
+    // Hosts known to want HTTPS are linked to over it directly, unless a port was given
+    let scheme = match scheme {
+        "http" if port.is_empty() && config.hsts.enabled && hsts::requires_https(auth.host()) => {
+            "https"
+        }
+        scheme => scheme,
+    };
+
     let origin = format!("{}://{}{}", scheme, auth.host(), port);
 
     let path = match uri.path_and_query() {
-        Some(pq) => pq.as_str(),
-        None => "/",
+        Some(pq) => strip_tracking_params(config, pq.as_str()),
+        None => Cow::Borrowed("/"),
     };
 
-    return match config.url_encoding_algorithm.clone() {
-        UrlEncodingAlgorithm::Base32(alphabet) => {
-            let encoded_origin = base32::encode(alphabet, origin.as_bytes());
-            format!("https://{}.{}{}", encoded_origin, config.public_host, path)
+    let fingerprint = fingerprint(&config.url_encoding_algorithm);
+    let encoded_origin = match ORIGIN_CACHE.get(&ORIGIN_CACHE.encoded, &origin, fingerprint) {
+        Some(encoded_origin) => encoded_origin,
+        None => {
+            let encoded_origin = match &config.url_encoding_algorithm {
+                UrlEncodingAlgorithm::Base32(alphabet) => {
+                    base32::encode(*alphabet, origin.as_bytes())
+                }
+                UrlEncodingAlgorithm::Base32Xor(alphabet, key) => base32::encode(
+                    *alphabet,
+                    &origin
+                        .as_bytes()
+                        .iter()
+                        .zip(key.iter().cycle())
+                        .map(|(byte, key_byte)| byte ^ key_byte)
+                        .collect::<Vec<u8>>(),
+                ),
+            };
+
+            ORIGIN_CACHE
+                .encoded
+                .lock()
+                .unwrap()
+                .put(origin, (fingerprint, encoded_origin.clone()));
+
+            encoded_origin
         }
-        UrlEncodingAlgorithm::Base32Xor(alphabet, key) => {
-            let encoded_origin = base32::encode(
-                alphabet,
-                &origin
-                    .as_bytes()
-                    .iter()
-                    .zip(key.iter().cycle())
-                    .map(|(byte, key_byte)| byte ^ key_byte)
-                    .collect::<Vec<u8>>(),
-            );
-            format!("https://{}.{}{}", encoded_origin, config.public_host, path)
+    };
+
+    format!("https://{}.{}{}", encoded_origin, config.public_host, path)
+}
+
+/// `path` without the query parameters [`TrackingParamsConfig`] strips, if it's enabled. The other
+/// parameters are kept as they were written, in their order.
+///
+/// ```
+/// use giggleshitter_common::prelude::*;
+///
+/// let mut config = Config::default();
+/// config.tracking_params.enabled = true;
+///
+/// assert_eq!(
+///     strip_tracking_params(&config, "/page?id=1&utm_source=mail&fbclid=x#top"),
+///     "/page?id=1#top"
+/// );
+/// assert_eq!(strip_tracking_params(&config, "/page?UTM_medium=a"), "/page");
+/// ```
+///
+/// [`TrackingParamsConfig`]: crate::state::TrackingParamsConfig
+pub fn strip_tracking_params<'a>(config: &Config, path: &'a str) -> Cow<'a, str> {
+    let tracking = &config.tracking_params;
+    if !tracking.enabled {
+        return Cow::Borrowed(path);
+    }
+
+    let (before_fragment, fragment) = match path.find('#') {
+        Some(index) => path.split_at(index),
+        None => (path, ""),
+    };
+    let Some((without_query, query)) = before_fragment.split_once('?') else {
+        return Cow::Borrowed(path);
+    };
+
+    let is_tracking = |param: &str| {
+        let name = param.split('=').next().unwrap_or_default();
+
+        tracking
+            .params
+            .iter()
+            .any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => name
+                    .get(..prefix.len())
+                    .is_some_and(|start| start.eq_ignore_ascii_case(prefix)),
+                None => name.eq_ignore_ascii_case(pattern),
+            })
+    };
+
+    if !query.split('&').any(is_tracking) {
+        return Cow::Borrowed(path);
+    }
+
+    let kept = query
+        .split('&')
+        .filter(|param| !is_tracking(param))
+        .collect::<Vec<_>>()
+        .join("&");
+
+    Cow::Owned(match kept.is_empty() {
+        true => format!("{}{}", without_query, fragment),
+        false => format!("{}?{}{}", without_query, kept, fragment),
+    })
+}
+
+/// Whether `host` is the proxy's public host or one of its subdomains
+fn is_proxied_host(config: &Config, host: &str) -> bool {
+    let host = host.trim_end_matches('.');
+
+    match host.len().checked_sub(config.public_host.len()) {
+        Some(0) => host.eq_ignore_ascii_case(&config.public_host),
+        Some(start) => {
+            host.as_bytes()[start - 1] == b'.'
+                && host[start..].eq_ignore_ascii_case(&config.public_host)
         }
+        None => false,
+    }
+}
+
+/// Reverse [`encode_url`], turning a proxied URL back into the URL of the upstream resource
+pub fn decode_url(config: &Config, url: &str) -> Result<String> {
+    let uri = Uri::from_str(url)?;
+
+    let host = uri.host().ok_or(InvalidOriginError)?;
+
+    let origin: String = proxied_origin(config, host)?.into();
+
+    let path = match uri.path_and_query() {
+        Some(pq) => pq.as_str(),
+        None => "/",
     };
+
+    Ok(format!("{}{}", origin, path))
 }
@@ -0,0 +1,119 @@
+use std::{sync::Mutex, time::Duration};
+
+use tokio::time::{sleep_until, Instant};
+
+use crate::state::WebSocketConfig;
+
+/// A limit of [`WebSocketConfig`] that a connection went over
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum WsLimit {
+    MessageSize,
+    MessageRate,
+    Idle,
+    Lifetime,
+}
+
+impl WsLimit {
+    /// The close code and reason sent to both ends of the connection
+    pub(crate) fn close_frame(self) -> (u16, &'static str) {
+        match self {
+            WsLimit::MessageSize => (1009, "Message too big"),
+            WsLimit::MessageRate => (1008, "Too many messages"),
+            WsLimit::Idle => (1001, "Idle timeout"),
+            WsLimit::Lifetime => (1001, "Connection lifetime exceeded"),
+        }
+    }
+}
+
+/// Tracks one connection against its limits, shared by both directions of the connection
+pub(crate) struct WsLimits {
+    config: WebSocketConfig,
+    opened: Instant,
+    last_activity: Mutex<Instant>,
+    /// The start of the current one second window and the client messages counted in it
+    window: Mutex<(Instant, u32)>,
+}
+
+impl WsLimits {
+    pub(crate) fn new(config: WebSocketConfig) -> Self {
+        let now = Instant::now();
+
+        Self {
+            config,
+            opened: now,
+            last_activity: Mutex::new(now),
+            window: Mutex::new((now, 0)),
+        }
+    }
+
+    /// Count a message from the client
+    pub(crate) fn client_message(&self, len: usize) -> Result<(), WsLimit> {
+        self.message(len)?;
+
+        let Some(max) = self.config.max_messages_per_sec else {
+            return Ok(());
+        };
+
+        let now = Instant::now();
+        let mut window = self.window.lock().unwrap();
+        if now.duration_since(window.0) >= Duration::from_secs(1) {
+            *window = (now, 0);
+        }
+        window.1 += 1;
+
+        if window.1 > max {
+            return Err(WsLimit::MessageRate);
+        }
+
+        Ok(())
+    }
+
+    /// Count a message from the upstream
+    pub(crate) fn upstream_message(&self, len: usize) -> Result<(), WsLimit> {
+        self.message(len)
+    }
+
+    fn message(&self, len: usize) -> Result<(), WsLimit> {
+        *self.last_activity.lock().unwrap() = Instant::now();
+
+        if len > self.config.max_message_bytes {
+            return Err(WsLimit::MessageSize);
+        }
+
+        Ok(())
+    }
+
+    /// Resolves once the connection has been idle or open for too long, never when neither is
+    /// limited
+    pub(crate) async fn expired(&self) -> WsLimit {
+        let lifetime = async {
+            match self.config.max_lifetime_secs {
+                Some(secs) => sleep_until(self.opened + Duration::from_secs(secs)).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        let idle = async {
+            let Some(secs) = self.config.idle_timeout_secs else {
+                return std::future::pending().await;
+            };
+            let timeout = Duration::from_secs(secs);
+
+            // Sleep until the connection would time out, and again if it saw activity meanwhile
+            loop {
+                let deadline = *self.last_activity.lock().unwrap() + timeout;
+
+                if Instant::now() >= deadline {
+                    return;
+                }
+
+                sleep_until(deadline).await;
+            }
+        };
+
+        tokio::select! {
+            _ = lifetime => WsLimit::Lifetime,
+            _ = idle => WsLimit::Idle,
+        }
+    }
+}
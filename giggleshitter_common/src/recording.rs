@@ -0,0 +1,438 @@
+//! Recording of proxied requests and their responses into WARC files, for archiving and analysis
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use axum::body::Bytes;
+use chrono::{DateTime, SecondsFormat, Utc};
+use hyper::{
+    header::{
+        HeaderName, AUTHORIZATION, CONTENT_LENGTH, COOKIE, PROXY_AUTHORIZATION, SET_COOKIE,
+        TRANSFER_ENCODING,
+    },
+    HeaderMap, Method, StatusCode,
+};
+use scorched::{logf, LogData, LogImportance};
+use serde::Serialize;
+use tokio::{
+    fs::{self, File},
+    io::AsyncWriteExt,
+    sync::mpsc,
+};
+use utoipa::ToSchema;
+
+use crate::{
+    proxy::util::Origin,
+    rules::matches_origin,
+    state::{Config, LiveConfig, RecordingConfig},
+};
+
+/// The prefix and extension of the files written, which are the only ones served by the API
+const FILE_PREFIX: &str = "gs-";
+const FILE_EXTENSION: &str = ".warc";
+
+/// The headers holding the users' credentials, which are redacted unless
+/// [`RecordingConfig::credentials`] is set
+const CREDENTIALS: [HeaderName; 4] = [AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION, SET_COOKIE];
+
+/// A request and the response it got, as they went to and came from the upstream
+struct Exchange {
+    date: DateTime<Utc>,
+    url: String,
+    method: Method,
+    request_headers: HeaderMap,
+    request_body: Bytes,
+    /// Whether the request body was cut off at [`RecordingConfig::max_body_bytes`]
+    request_truncated: bool,
+    response: Option<(StatusCode, HeaderMap)>,
+    body: Vec<u8>,
+    /// Whether the response body was cut off at [`RecordingConfig::max_body_bytes`]
+    truncated: bool,
+}
+
+#[derive(Clone)]
+/// A handle to the background task that writes the recorded exchanges. Exchanges are dropped
+/// while the current configuration has recording disabled.
+pub struct Recorder {
+    tx: mpsc::UnboundedSender<Exchange>,
+}
+
+impl Recorder {
+    /// Start the writer task, which follows the recording settings of the live configuration
+    pub fn spawn(config: LiveConfig) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(write_exchanges(config, rx));
+
+        Self { tx }
+    }
+
+    /// Start recording a request to `origin`, if its origin is recorded or it belongs to a
+    /// `recorded_session`
+    pub fn start(
+        &self,
+        config: &Config,
+        recorded_session: bool,
+        origin: &Origin,
+        method: &Method,
+        url: &str,
+        headers: &HeaderMap,
+    ) -> Option<PendingExchange> {
+        let recording = &config.recording;
+        recording.dir.as_ref()?;
+
+        let recorded = recorded_session
+            || recording
+                .origins
+                .iter()
+                .any(|pattern| matches_origin(pattern, origin));
+        if !recorded {
+            return None;
+        }
+
+        Some(PendingExchange {
+            recorder: self.clone(),
+            max_body_bytes: recording.max_body_bytes,
+            exchange: Some(Exchange {
+                date: Utc::now(),
+                url: url.to_string(),
+                method: method.clone(),
+                request_headers: headers.clone(),
+                request_body: Bytes::new(),
+                request_truncated: false,
+                response: None,
+                body: vec![],
+                truncated: false,
+            }),
+        })
+    }
+}
+
+/// A recorded exchange, which is written once it is dropped so that streamed bodies can be
+/// recorded as they are sent. Exchanges that never got a response aren't written.
+pub struct PendingExchange {
+    recorder: Recorder,
+    max_body_bytes: usize,
+    exchange: Option<Exchange>,
+}
+
+impl PendingExchange {
+    /// Record the request body, cutting off bodies longer than `max_body_bytes`
+    pub fn request_body(&mut self, body: Bytes) {
+        if let Some(exchange) = self.exchange.as_mut() {
+            exchange.request_truncated = body.len() > self.max_body_bytes;
+            exchange.request_body = body.slice(..body.len().min(self.max_body_bytes));
+        }
+    }
+
+    /// How much of the request body is recorded, more than this needn't be buffered
+    pub fn max_body_bytes(&self) -> usize {
+        self.max_body_bytes
+    }
+
+    pub fn respond(&mut self, status: StatusCode, headers: &HeaderMap) {
+        if let Some(exchange) = self.exchange.as_mut() {
+            exchange.response = Some((status, headers.clone()));
+        }
+    }
+
+    pub fn add_body(&mut self, chunk: &[u8]) {
+        let Some(exchange) = self.exchange.as_mut() else {
+            return;
+        };
+
+        let room = self.max_body_bytes.saturating_sub(exchange.body.len());
+        if chunk.len() > room {
+            exchange.truncated = true;
+        }
+        exchange
+            .body
+            .extend_from_slice(&chunk[..chunk.len().min(room)]);
+    }
+}
+
+impl Drop for PendingExchange {
+    fn drop(&mut self) {
+        if let Some(exchange) = self.exchange.take() {
+            if exchange.response.is_some() {
+                let _ = self.recorder.tx.send(exchange);
+            }
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+/// A WARC file in the recording directory
+pub struct Archive {
+    pub name: String,
+    pub bytes: u64,
+    /// When the file was last written to, in RFC 3339 format
+    pub modified: String,
+}
+
+/// The WARC files in `dir`, newest first
+pub async fn list(dir: &Path) -> io::Result<Vec<Archive>> {
+    let mut archives = vec![];
+
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(archives),
+        Err(e) => return Err(e),
+    };
+
+    while let Some(entry) = entries.next_entry().await? {
+        let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+            continue;
+        };
+        if !is_archive_name(&name) {
+            continue;
+        }
+
+        let metadata = entry.metadata().await?;
+        archives.push(Archive {
+            name,
+            bytes: metadata.len(),
+            modified: DateTime::<Utc>::from(metadata.modified()?)
+                .to_rfc3339_opts(SecondsFormat::Secs, true),
+        });
+    }
+
+    // The names start with the time the file was started at
+    archives.sort_by(|a, b| b.name.cmp(&a.name));
+
+    Ok(archives)
+}
+
+/// The path of the WARC file `name` in `dir`, `None` for names that aren't ones we write
+pub fn archive_path(dir: &Path, name: &str) -> Option<PathBuf> {
+    is_archive_name(name).then(|| dir.join(name))
+}
+
+fn is_archive_name(name: &str) -> bool {
+    name.strip_prefix(FILE_PREFIX)
+        .and_then(|name| name.strip_suffix(FILE_EXTENSION))
+        .is_some_and(|stamp| {
+            !stamp.is_empty()
+                && stamp
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        })
+}
+
+/// The open WARC file, along with the directory it was opened in and its current size
+struct Output {
+    dir: PathBuf,
+    file: File,
+    size: u64,
+}
+
+async fn write_exchanges(config: LiveConfig, mut rx: mpsc::UnboundedReceiver<Exchange>) {
+    let mut output: Option<Output> = None;
+
+    while let Some(exchange) = rx.recv().await {
+        let recording = config.load().recording.clone();
+        let Some(dir) = recording.dir.clone() else {
+            output = None;
+            continue;
+        };
+
+        let records = exchange_records(&exchange, recording.credentials);
+
+        if let Err(e) = write_records(&recording, &dir, &mut output, &records).await {
+            logf!(Error, "Error writing recording to {}: {}", dir.display(), e);
+            output = None;
+        }
+    }
+}
+
+async fn write_records(
+    recording: &RecordingConfig,
+    dir: &Path,
+    output: &mut Option<Output>,
+    records: &[u8],
+) -> io::Result<()> {
+    // Start a new file when the configured directory changed, or the current file is full
+    if output.as_ref().is_some_and(|output| {
+        output.dir != dir
+            || (output.size > 0 && output.size + records.len() as u64 > recording.max_file_bytes)
+    }) {
+        *output = None;
+    }
+
+    let output = match output {
+        Some(output) => output,
+        None => {
+            fs::create_dir_all(dir).await?;
+
+            let now = Utc::now();
+            let name = format!(
+                "{}{}{}",
+                FILE_PREFIX,
+                now.format("%Y%m%d%H%M%S%3f"),
+                FILE_EXTENSION
+            );
+            let mut file = File::create(dir.join(&name)).await?;
+
+            let info = warcinfo_record(now, &name);
+            file.write_all(&info).await?;
+
+            prune(dir, recording.max_files).await?;
+
+            output.insert(Output {
+                dir: dir.to_path_buf(),
+                file,
+                size: info.len() as u64,
+            })
+        }
+    };
+
+    output.file.write_all(records).await?;
+    output.size += records.len() as u64;
+
+    Ok(())
+}
+
+/// Delete the oldest files in `dir` beyond `max_files`
+async fn prune(dir: &Path, max_files: usize) -> io::Result<()> {
+    for archive in list(dir).await?.iter().skip(max_files.max(1)) {
+        fs::remove_file(dir.join(&archive.name)).await?;
+    }
+
+    Ok(())
+}
+
+fn warcinfo_record(date: DateTime<Utc>, name: &str) -> Vec<u8> {
+    let fields = format!(
+        "software: giggleshitter/{}\r\nformat: WARC File Format 1.1\r\n",
+        env!("CARGO_PKG_VERSION")
+    );
+
+    record(
+        &[
+            ("WARC-Type", "warcinfo".to_string()),
+            ("WARC-Record-ID", record_id()),
+            ("WARC-Date", warc_date(date)),
+            ("WARC-Filename", name.to_string()),
+            ("Content-Type", "application/warc-fields".to_string()),
+        ],
+        fields.as_bytes(),
+    )
+}
+
+/// The `request` and `response` records of an exchange, with the users' credentials unless they
+/// are to be redacted
+fn exchange_records(exchange: &Exchange, credentials: bool) -> Vec<u8> {
+    let Some((status, headers)) = &exchange.response else {
+        return vec![];
+    };
+
+    let date = warc_date(exchange.date);
+    let request_id = record_id();
+    let response_id = record_id();
+
+    let target = exchange.url.parse::<hyper::Uri>().ok();
+    let path = target
+        .as_ref()
+        .and_then(|uri| uri.path_and_query())
+        .map_or("/", |path| path.as_str());
+
+    let mut request = format!("{} {} HTTP/1.1\r\n", exchange.method, path).into_bytes();
+    write_headers(&mut request, &exchange.request_headers, credentials);
+    request.extend_from_slice(&exchange.request_body);
+
+    // The body was recorded as the client received it, without the upstream's chunking
+    let mut headers = headers.clone();
+    headers.remove(TRANSFER_ENCODING);
+    headers.insert(CONTENT_LENGTH, exchange.body.len().into());
+
+    let mut response = format!(
+        "HTTP/1.1 {} {}\r\n",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or_default()
+    )
+    .into_bytes();
+    write_headers(&mut response, &headers, credentials);
+    response.extend_from_slice(&exchange.body);
+
+    let mut response_fields = vec![
+        ("WARC-Type", "response".to_string()),
+        ("WARC-Record-ID", response_id.clone()),
+        ("WARC-Date", date.clone()),
+        ("WARC-Target-URI", exchange.url.clone()),
+        (
+            "Content-Type",
+            "application/http;msgtype=response".to_string(),
+        ),
+    ];
+    if exchange.truncated {
+        response_fields.push(("WARC-Truncated", "length".to_string()));
+    }
+
+    let mut request_fields = vec![
+        ("WARC-Type", "request".to_string()),
+        ("WARC-Record-ID", request_id),
+        ("WARC-Date", date),
+        ("WARC-Target-URI", exchange.url.clone()),
+        ("WARC-Concurrent-To", response_id),
+        (
+            "Content-Type",
+            "application/http;msgtype=request".to_string(),
+        ),
+    ];
+    if exchange.request_truncated {
+        request_fields.push(("WARC-Truncated", "length".to_string()));
+    }
+
+    let mut records = record(&response_fields, &response);
+    records.extend(record(&request_fields, &request));
+
+    records
+}
+
+fn write_headers(block: &mut Vec<u8>, headers: &HeaderMap, credentials: bool) {
+    for (name, value) in headers {
+        block.extend_from_slice(name.as_str().as_bytes());
+        block.extend_from_slice(b": ");
+        if credentials || !CREDENTIALS.contains(name) {
+            block.extend_from_slice(value.as_bytes());
+        } else {
+            block.extend_from_slice(b"[redacted]");
+        }
+        block.extend_from_slice(b"\r\n");
+    }
+    block.extend_from_slice(b"\r\n");
+}
+
+fn record(fields: &[(&str, String)], block: &[u8]) -> Vec<u8> {
+    let mut record = b"WARC/1.1\r\n".to_vec();
+
+    for (name, value) in fields {
+        record.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+    }
+    record.extend_from_slice(format!("Content-Length: {}\r\n\r\n", block.len()).as_bytes());
+    record.extend_from_slice(block);
+    record.extend_from_slice(b"\r\n\r\n");
+
+    record
+}
+
+fn warc_date(date: DateTime<Utc>) -> String {
+    date.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// A random (version 4) UUID as a URN
+fn record_id() -> String {
+    let bits = (rand::random::<u128>() & !(0xf << 76) & !(0x3 << 62)) | (0x4 << 76) | (0x2 << 62);
+    let hex = format!("{:032x}", bits);
+
+    format!(
+        "<urn:uuid:{}-{}-{}-{}-{}>",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
@@ -1,65 +1,138 @@
-use std::sync::Arc;
+use std::{borrow::Cow, cell::Cell};
 
-use lol_html::{element, html_content::ContentType, Settings};
+use hyper::Uri;
+use lol_html::{
+    html_content::{ContentType, Element},
+    ElementContentHandlers, HandlerResult, Selector, Settings,
+};
 
 use crate::{
-    error::Result, proxy::util::encode_url, rewriting::rewriter::Rewriter, state::SharedState,
+    error::Result,
+    rewriting::{
+        rewriter::{RewriteContext, Rewriter},
+        urls::{is_relative, rewrite_url},
+    },
+    state::{Config, FrameSandbox, ThirdPartyFrames},
 };
 
+/// The attributes holding URLs that are sent through the proxy
+const URL_ATTRIBUTES: [&str; 3] = ["href", "src", "poster"];
+
+/// Where the injected script takes the page's upstream origin, as a JSON object or `null`
+const PAGE_PLACEHOLDER: &str = "__GS_PAGE__";
+
+/// Rewrites the URLs in HTML documents. The selectors and the injected script are prepared once,
+/// and the instance is shared by every request through the rewriter registry.
 pub struct HtmlRewriter {
-    state: Arc<SharedState>,
+    head: Selector,
+    iframe: Selector,
+    /// The selector matching each of [`URL_ATTRIBUTES`], in the same order
+    url_attributes: Vec<Selector>,
+    /// The injected script, with [`PAGE_PLACEHOLDER`] filled in for each page
+    patches: String,
 }
 
 impl HtmlRewriter {
-    pub fn new(state: Arc<SharedState>) -> Self {
-        Self { state }
+    pub fn new() -> Self {
+        Self {
+            head: "head".parse().unwrap(),
+            iframe: "iframe".parse().unwrap(),
+            url_attributes: URL_ATTRIBUTES
+                .iter()
+                .map(|attribute| format!("[{}]", attribute).parse().unwrap())
+                .collect(),
+            patches: format!(
+                r#"<script type="text/javascript">{}</script>"#,
+                include_str!("../patches.js")
+            ),
+        }
     }
 }
 
-impl Rewriter for HtmlRewriter {
-    fn rewrite(&self, input: Vec<u8>) -> Result<Vec<u8>> {
-        let mut output = vec![];
-        let mut rewriter = lol_html::HtmlRewriter::new(
-            Settings {
-                element_content_handlers: vec![
-                    // Inject console.log script in head
-                    element!("head", |el| {
-                        el.append(
-                            &format!(
-                                r#"<script type="text/javascript">{}</script>"#,
-                                include_str!("../patches.js")
-                            ),
-                            ContentType::Html,
-                        );
-
-                        Ok(())
-                    }),
-                    element!("[href]", |el| {
-                        let href = el.get_attribute("href").unwrap();
-
-                        el.set_attribute("href", &encode_url(&self.state.config, &href))
-                            .unwrap();
-
-                        Ok(())
-                    }),
-                    element!("[src]", |el| {
-                        let src = el.get_attribute("src").unwrap();
-
-                        el.set_attribute("src", &encode_url(&self.state.config, &src))
-                            .unwrap();
-
-                        Ok(())
-                    }),
-                    element!("[poster]", |el| {
-                        let poster = el.get_attribute("poster").unwrap();
+impl Default for HtmlRewriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-                        el.set_attribute("poster", &encode_url(&self.state.config, &poster))
-                            .unwrap();
+impl Rewriter for HtmlRewriter {
+    fn rewrite(
+        &self,
+        config: &Config,
+        context: &RewriteContext,
+        input: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        let page = match config.spoof_origin {
+            true => serde_json::json!({
+                "origin": context.origin.ascii_serialization(),
+            })
+            .to_string()
+            // Keeps the JSON from closing the script element
+            .replace("</", "<\\/"),
+            false => "null".to_string(),
+        };
+        let patches = self.patches.replacen(PAGE_PLACEHOLDER, &page, 1);
+        // Whether the document set a `<base>` URL yet
+        let based = &Cell::new(false);
+
+        let mut element_content_handlers = vec![(
+            Cow::Borrowed(&self.head),
+            // The patches go first, so they are in place before any of the page's scripts run
+            ElementContentHandlers::default().element(|el| {
+                el.prepend(&patches, ContentType::Html);
+
+                if let Some(inject_html) = &config.inject_html {
+                    el.append(inject_html, ContentType::Html);
+                }
+
+                Ok(())
+            }),
+        )];
+
+        // Before the URL attributes are rewritten, so the frame's own host can be told
+        element_content_handlers.push((
+            Cow::Borrowed(&self.iframe),
+            ElementContentHandlers::default().element(|el| rewrite_frame(config, context, el)),
+        ));
+
+        element_content_handlers.extend(URL_ATTRIBUTES.iter().zip(&self.url_attributes).map(
+            |(attribute, selector)| {
+                (
+                    Cow::Borrowed(selector),
+                    ElementContentHandlers::default().element(move |el| {
+                        if el.removed() {
+                            return Ok(());
+                        }
+
+                        let Some(url) = el.get_attribute(attribute) else {
+                            return Ok(());
+                        };
+
+                        // Relative URLs after a `<base>` are the browser's to resolve against it
+                        if based.get() && is_relative(&url) {
+                            return Ok(());
+                        }
+
+                        match rewrite_url(config, context, &url) {
+                            Some(rewritten) => el.set_attribute(attribute, &rewritten)?,
+                            None => el.remove_attribute(attribute),
+                        }
+
+                        if *attribute == "href" && el.tag_name() == "base" {
+                            based.set(true);
+                        }
 
                         Ok(())
                     }),
-                ],
+                )
+            },
+        ));
 
+        let mut output = Vec::with_capacity(input.len());
+        let mut rewriter = lol_html::HtmlRewriter::new(
+            Settings {
+                element_content_handlers,
+                encoding: context.html_encoding(),
                 ..Settings::default()
             },
             |c: &[u8]| output.extend_from_slice(c),
@@ -72,3 +145,97 @@ impl Rewriter for HtmlRewriter {
         Ok(output)
     }
 }
+
+/// Apply [`Config::frames`] to an `<iframe>`
+fn rewrite_frame(config: &Config, context: &RewriteContext, el: &mut Element) -> HandlerResult {
+    let policy = &config.frames;
+
+    match &policy.sandbox {
+        FrameSandbox::Keep => {}
+        FrameSandbox::Remove => el.remove_attribute("sandbox"),
+        FrameSandbox::Set(value) => el.set_attribute("sandbox", value)?,
+    }
+
+    if policy.third_party == ThirdPartyFrames::Allow {
+        return Ok(());
+    }
+
+    let Some(frame_host) = el.get_attribute("src").as_deref().and_then(absolute_host) else {
+        return Ok(());
+    };
+
+    if frame_host.eq_ignore_ascii_case(context.origin.host()) {
+        return Ok(());
+    }
+
+    match policy.third_party {
+        ThirdPartyFrames::Allow => {}
+        ThirdPartyFrames::Block => el.remove(),
+        ThirdPartyFrames::ClickToLoad => {
+            let placeholder = frame_placeholder(config, context, el, &frame_host);
+            el.replace(&placeholder, ContentType::Html);
+        }
+    }
+
+    Ok(())
+}
+
+/// The host of an absolute or protocol-relative URL
+fn absolute_host(url: &str) -> Option<String> {
+    let url = url.trim();
+    let uri = match url.strip_prefix("//") {
+        Some(rest) => format!("https://{rest}").parse::<Uri>(),
+        None => url.parse::<Uri>(),
+    }
+    .ok()?;
+
+    uri.host().map(str::to_string)
+}
+
+/// A button taking the place of a frame, that swaps the frame in when clicked
+fn frame_placeholder(
+    config: &Config,
+    context: &RewriteContext,
+    el: &Element,
+    frame_host: &str,
+) -> String {
+    let mut frame = String::from("<iframe");
+    let mut size = String::new();
+
+    for attribute in el.attributes() {
+        let name = attribute.name();
+        let mut value = attribute.value();
+
+        if name == "src" {
+            value = rewrite_url(config, context, &value)
+                .unwrap_or_default()
+                .into_owned();
+        }
+
+        if name == "width" || name == "height" {
+            let unit = if value.bytes().all(|b| b.is_ascii_digit()) {
+                "px"
+            } else {
+                ""
+            };
+            size.push_str(&format!("{name}:{value}{unit};"));
+        }
+
+        frame.push_str(&format!(r#" {}="{}""#, name, escape(&value)));
+    }
+    frame.push_str("></iframe>");
+
+    format!(
+        r#"<div data-gs-frame="{}" style="display:inline-flex;align-items:center;justify-content:center;border:1px solid #888;{}"><button type="button" onclick="this.parentNode.outerHTML=this.parentNode.dataset.gsFrame">Load content from {}</button></div>"#,
+        escape(&frame),
+        escape(&size),
+        escape(frame_host),
+    )
+}
+
+pub(crate) fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
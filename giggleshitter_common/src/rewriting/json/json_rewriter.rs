@@ -0,0 +1,57 @@
+use serde_json::Value;
+
+use crate::{
+    error::Result,
+    proxy::util::encode_url,
+    rewriting::rewriter::{RewriteContext, Rewriter},
+    state::Config,
+};
+
+/// Rewrites the absolute URLs in string values of JSON documents, for pages that load media from
+/// URLs their API returns
+#[derive(Default)]
+pub struct JsonRewriter;
+
+impl JsonRewriter {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Rewriter for JsonRewriter {
+    fn rewrite(
+        &self,
+        config: &Config,
+        _context: &RewriteContext,
+        input: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        // Leave bodies that only claim to be JSON alone
+        let Ok(mut document) = serde_json::from_slice::<Value>(&input) else {
+            return Ok(input);
+        };
+
+        rewrite_value(config, &mut document);
+
+        Ok(serde_json::to_vec(&document)?)
+    }
+}
+
+fn rewrite_value(config: &Config, value: &mut Value) {
+    match value {
+        Value::String(string) if is_absolute_url(string) => {
+            *string = encode_url(config, string);
+        }
+        Value::Array(values) => values
+            .iter_mut()
+            .for_each(|value| rewrite_value(config, value)),
+        Value::Object(map) => map
+            .values_mut()
+            .for_each(|value| rewrite_value(config, value)),
+        _ => {}
+    }
+}
+
+fn is_absolute_url(string: &str) -> bool {
+    (string.starts_with("http://") || string.starts_with("https://"))
+        && !string.contains(char::is_whitespace)
+}
@@ -1,2 +1,8 @@
 pub mod html;
+pub mod json;
+pub mod reader;
+pub mod registry;
 pub mod rewriter;
+pub mod svg;
+pub(crate) mod urls;
+pub mod xml;
@@ -1 +1,260 @@
 console.log("Hello from the proxy!");
+
+// Keeps popups, links added by scripts and cross-window messages inside the proxy
+(() => {
+  "use strict";
+
+  // The first label of a proxied host is the encoded upstream origin, the rest is the proxy's
+  const publicHost = location.hostname.slice(location.hostname.indexOf(".") + 1);
+  const isProxied = (url) =>
+    url.hostname === publicHost || url.hostname.endsWith("." + publicHost);
+
+  // The absolute URL `url` points at, if it leaves the proxy
+  const outsideUrl = (url) => {
+    if (url === undefined || url === null || url === "") {
+      return null;
+    }
+
+    let resolved;
+    try {
+      resolved = new URL(String(url), document.baseURI);
+    } catch {
+      return null;
+    }
+
+    return /^(https?|ftp):$/.test(resolved.protocol) && !isProxied(resolved)
+      ? resolved
+      : null;
+  };
+
+  const proxiedUrl = (url) => {
+    const outside = outsideUrl(url);
+
+    return outside
+      ? `${location.origin}/__gs_open?url=${encodeURIComponent(outside.href)}`
+      : url;
+  };
+
+  // Translating origins takes the proxy's key, so it is asked, once per origin
+  const origins = new Map();
+  const translateOrigin = (direction, origin) => {
+    const key = `${direction} ${origin}`;
+
+    if (!origins.has(key)) {
+      let translated = origin;
+
+      try {
+        const request = new XMLHttpRequest();
+        request.open(
+          "GET",
+          `/__gs_origin?${direction}=${encodeURIComponent(origin)}`,
+          false,
+        );
+        request.send();
+
+        if (request.status === 200) {
+          translated = request.responseText;
+        }
+      } catch {}
+
+      origins.set(key, translated);
+    }
+
+    return origins.get(key);
+  };
+
+  const targetOrigin = (origin) => {
+    if (typeof origin !== "string" || origin === "*" || origin === "/") {
+      return origin;
+    }
+
+    return outsideUrl(origin) ? translateOrigin("encode", origin) : origin;
+  };
+
+  const postMessageArgs = (message, options, transfer) => {
+    if (options !== null && typeof options === "object") {
+      return [message, { ...options, targetOrigin: targetOrigin(options.targetOrigin) }];
+    }
+
+    return transfer === undefined
+      ? [message, targetOrigin(options)]
+      : [message, targetOrigin(options), transfer];
+  };
+
+  // Other windows may be on another origin, where nothing can be patched, so they are wrapped
+  const windows = new WeakMap();
+  const wrapWindow = (win) => {
+    if (!win || win === window) {
+      return win;
+    }
+
+    if (!windows.has(win)) {
+      windows.set(
+        win,
+        new Proxy(win, {
+          get(target, property) {
+            if (property === "postMessage") {
+              return (...args) => target.postMessage(...postMessageArgs(...args));
+            }
+
+            const value = target[property];
+            return typeof value === "function" ? value.bind(target) : value;
+          },
+          set(target, property, value) {
+            target[property] = value;
+            return true;
+          },
+        }),
+      );
+    }
+
+    return windows.get(win);
+  };
+
+  const postMessage = window.postMessage;
+  window.postMessage = function (...args) {
+    return postMessage.apply(this, postMessageArgs(...args));
+  };
+
+  const open = window.open;
+  window.open = function (url, ...args) {
+    return wrapWindow(open.call(this, proxiedUrl(url), ...args));
+  };
+
+  const wrapGetter = (object, property) => {
+    const descriptor = Object.getOwnPropertyDescriptor(object, property);
+
+    if (descriptor && descriptor.get && descriptor.configurable) {
+      Object.defineProperty(object, property, {
+        ...descriptor,
+        get() {
+          return wrapWindow(descriptor.get.call(this));
+        },
+      });
+    }
+  };
+  wrapGetter(window, "opener");
+  wrapGetter(window, "parent");
+  wrapGetter(HTMLIFrameElement.prototype, "contentWindow");
+
+  // Messages from proxied windows report the upstream origin they pretend to be on
+  window.addEventListener(
+    "message",
+    (event) => {
+      let origin;
+      try {
+        origin = new URL(event.origin);
+      } catch {
+        return;
+      }
+
+      if (isProxied(origin) && origin.host !== publicHost) {
+        Object.defineProperty(event, "origin", {
+          value: translateOrigin("decode", event.origin),
+        });
+      }
+
+      if (event.source) {
+        Object.defineProperty(event, "source", { value: wrapWindow(event.source) });
+      }
+    },
+    true,
+  );
+
+  // Links the rewriter never saw, because a script added them after the page loaded
+  const retarget = (event) => {
+    const link = event.target instanceof Element && event.target.closest("a[href], area[href]");
+
+    if (link && outsideUrl(link.getAttribute("href"))) {
+      link.setAttribute("href", proxiedUrl(link.getAttribute("href")));
+    }
+  };
+  window.addEventListener("click", retarget, true);
+  window.addEventListener("auxclick", retarget, true);
+})();
+
+// Presents the upstream origin to the page's scripts wherever the browser lets it be replaced.
+// `location` itself can't be, so the address bar keeps showing the proxied URL.
+((page) => {
+  "use strict";
+
+  if (!page) {
+    return;
+  }
+
+  const upstream = new URL(page.origin);
+
+  // The upstream URL of a proxied URL on this page's host
+  const upstreamUrl = (url) => {
+    const proxied = new URL(url);
+
+    return proxied.host === location.host
+      ? upstream.origin + proxied.pathname + proxied.search + proxied.hash
+      : url;
+  };
+
+  const redefine = (object, property, replace) => {
+    const descriptor = Object.getOwnPropertyDescriptor(object, property);
+
+    if (descriptor && descriptor.configurable) {
+      Object.defineProperty(object, property, { ...descriptor, ...replace(descriptor) });
+    }
+  };
+
+  let domain = upstream.hostname;
+  redefine(Document.prototype, "domain", () => ({
+    get() {
+      return domain;
+    },
+    // Relaxing to a parent domain is accepted, the browser couldn't act on it anyway
+    set(value) {
+      value = String(value).toLowerCase();
+
+      if (value !== domain && !upstream.hostname.endsWith("." + value)) {
+        throw new DOMException(`'${value}' is not a suffix of '${domain}'.`, "SecurityError");
+      }
+
+      domain = value;
+    },
+  }));
+
+  for (const property of ["URL", "documentURI"]) {
+    redefine(Document.prototype, property, (descriptor) => ({
+      get() {
+        return upstreamUrl(descriptor.get.call(this));
+      },
+    }));
+  }
+
+  redefine(window, "origin", () => ({
+    get() {
+      return upstream.origin;
+    },
+  }));
+
+  // Pages push URLs on the origin they think they are on, which the browser refuses
+  const sameDocumentUrl = (url) => {
+    if (url === undefined || url === null) {
+      return url;
+    }
+
+    let resolved;
+    try {
+      resolved = new URL(String(url), location.href);
+    } catch {
+      return url;
+    }
+
+    return resolved.origin === upstream.origin
+      ? resolved.pathname + resolved.search + resolved.hash
+      : url;
+  };
+
+  for (const method of ["pushState", "replaceState"]) {
+    const original = History.prototype[method];
+
+    History.prototype[method] = function (state, title, ...url) {
+      return original.call(this, state, title, ...url.map(sameDocumentUrl));
+    };
+  }
+})(__GS_PAGE__);
@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+
+use scraper::{ElementRef, Html, Node, Selector};
+
+use crate::{
+    error::Result,
+    pages::reader_page,
+    rewriting::{
+        html::html_rewriter::escape,
+        rewriter::{RewriteContext, Rewriter},
+        urls::{is_javascript_url, rewrite_url},
+    },
+    state::Config,
+};
+
+/// Elements kept in the article, with the attributes kept on them. Other elements are replaced by
+/// their content.
+const KEPT: &[(&str, &[&str])] = &[
+    ("a", &["href", "title"]),
+    ("img", &["src", "alt", "title", "width", "height"]),
+    ("td", &["colspan", "rowspan"]),
+    ("th", &["colspan", "rowspan"]),
+    ("p", &[]),
+    ("h1", &[]),
+    ("h2", &[]),
+    ("h3", &[]),
+    ("h4", &[]),
+    ("h5", &[]),
+    ("h6", &[]),
+    ("ul", &[]),
+    ("ol", &[]),
+    ("li", &[]),
+    ("dl", &[]),
+    ("dt", &[]),
+    ("dd", &[]),
+    ("blockquote", &[]),
+    ("pre", &[]),
+    ("code", &[]),
+    ("em", &[]),
+    ("strong", &[]),
+    ("b", &[]),
+    ("i", &[]),
+    ("sup", &[]),
+    ("sub", &[]),
+    ("figure", &[]),
+    ("figcaption", &[]),
+    ("table", &[]),
+    ("thead", &[]),
+    ("tbody", &[]),
+    ("tr", &[]),
+    ("br", &[]),
+    ("hr", &[]),
+];
+
+/// Elements left out of the article along with their content
+const DROPPED: &[&str] = &[
+    "script", "style", "noscript", "template", "iframe", "object", "embed", "svg", "canvas",
+    "form", "button", "input", "select", "textarea", "nav", "aside", "footer",
+];
+
+const VOID: &[&str] = &["img", "br", "hr"];
+
+/// Class and id words of elements that hold the article, and of those that don't
+const POSITIVE: &[&str] = &[
+    "article", "content", "main", "post", "entry", "story", "text",
+];
+const NEGATIVE: &[&str] = &[
+    "comment", "footer", "sidebar", "nav", "menu", "share", "related", "promo", "banner", "ad-",
+];
+
+/// How deep the article is copied, deeper elements are left out
+const MAX_DEPTH: usize = 256;
+
+/// Turns a page into a plain article view on the proxy's own styling, keeping the element that
+/// holds most of the page's text. Scripts, styles and embeds are left out, and URLs are proxied.
+#[derive(Default)]
+pub struct ReaderRewriter;
+
+impl ReaderRewriter {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Rewriter for ReaderRewriter {
+    fn rewrite(
+        &self,
+        config: &Config,
+        context: &RewriteContext,
+        input: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        let document = Html::parse_document(&String::from_utf8_lossy(&input));
+
+        let title = meta(&document, r#"meta[property="og:title"]"#)
+            .or_else(|| first_text(&document, "title"))
+            .or_else(|| first_text(&document, "h1"))
+            .unwrap_or_else(|| "Untitled".to_string());
+        let byline = meta(&document, r#"meta[name="author"]"#);
+
+        let mut content = String::new();
+        if let Some(article) = article(&document) {
+            write_children(config, context, &mut content, article, 0);
+        }
+
+        Ok(reader_page(&title, byline.as_deref(), &content).into_bytes())
+    }
+}
+
+fn meta(document: &Html, selector: &str) -> Option<String> {
+    let selector = Selector::parse(selector).unwrap();
+
+    document
+        .select(&selector)
+        .filter_map(|meta| meta.attr("content"))
+        .map(|content| content.trim().to_string())
+        .find(|content| !content.is_empty())
+}
+
+fn first_text(document: &Html, selector: &str) -> Option<String> {
+    let selector = Selector::parse(selector).unwrap();
+
+    document
+        .select(&selector)
+        .map(|element| element.text().collect::<String>().trim().to_string())
+        .find(|text| !text.is_empty())
+}
+
+/// The element holding the article. Paragraphs score their parent and, by half, their
+/// grandparent by their length and commas, and the best scoring element after discounting its
+/// links wins.
+fn article(document: &Html) -> Option<ElementRef<'_>> {
+    let paragraphs = Selector::parse("p, pre, td").unwrap();
+    let mut scores = HashMap::new();
+
+    for paragraph in document.select(&paragraphs) {
+        let length = text_length(paragraph);
+        if length < 25 {
+            continue;
+        }
+
+        let commas = paragraph
+            .text()
+            .map(|text| text.matches(',').count())
+            .sum::<usize>();
+        let score = 1.0 + commas as f64 + (length as f64 / 100.0).min(3.0);
+
+        let parent = paragraph.parent().and_then(ElementRef::wrap);
+        let grandparent = parent
+            .and_then(|parent| parent.parent())
+            .and_then(ElementRef::wrap);
+
+        for (ancestor, share) in [(parent, 1.0), (grandparent, 0.5)] {
+            if let Some(ancestor) = ancestor {
+                scores
+                    .entry(ancestor.id())
+                    .or_insert_with(|| (ancestor, class_weight(ancestor)))
+                    .1 += score * share;
+            }
+        }
+    }
+
+    let best = scores
+        .into_values()
+        .map(|(element, score)| (element, score * (1.0 - link_density(element))))
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(element, _)| element);
+
+    best.or_else(|| {
+        let fallback = Selector::parse("article, main, body").unwrap();
+        document.select(&fallback).next()
+    })
+}
+
+fn class_weight(element: ElementRef) -> f64 {
+    let names = format!(
+        "{} {}",
+        element.value().attr("class").unwrap_or_default(),
+        element.value().id().unwrap_or_default()
+    )
+    .to_ascii_lowercase();
+
+    let mut weight = 0.0;
+    if POSITIVE.iter().any(|word| names.contains(word)) {
+        weight += 25.0;
+    }
+    if NEGATIVE.iter().any(|word| names.contains(word)) {
+        weight -= 25.0;
+    }
+
+    weight
+}
+
+fn text_length(element: ElementRef) -> usize {
+    element.text().map(|text| text.trim().chars().count()).sum()
+}
+
+/// The share of an element's text that is in links
+fn link_density(element: ElementRef) -> f64 {
+    let links = Selector::parse("a").unwrap();
+    let length = text_length(element);
+    if length == 0 {
+        return 0.0;
+    }
+
+    let link_length = element.select(&links).map(text_length).sum::<usize>();
+    link_length as f64 / length as f64
+}
+
+fn write_children(
+    config: &Config,
+    context: &RewriteContext,
+    output: &mut String,
+    element: ElementRef,
+    depth: usize,
+) {
+    if depth > MAX_DEPTH {
+        return;
+    }
+
+    for child in element.children() {
+        match child.value() {
+            Node::Text(text) => output.push_str(&escape(text)),
+            Node::Element(_) => {
+                if let Some(child) = ElementRef::wrap(child) {
+                    write_element(config, context, output, child, depth + 1);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn write_element(
+    config: &Config,
+    context: &RewriteContext,
+    output: &mut String,
+    element: ElementRef,
+    depth: usize,
+) {
+    let name = element.value().name();
+
+    if DROPPED.contains(&name) {
+        return;
+    }
+
+    let Some((_, attributes)) = KEPT.iter().find(|(kept, _)| *kept == name) else {
+        write_children(config, context, output, element, depth);
+        return;
+    };
+
+    output.push('<');
+    output.push_str(name);
+
+    for attribute in *attributes {
+        let value = match *attribute {
+            // Lazy loaded images keep a placeholder in `src`
+            "src" => element.attr("data-src").or_else(|| element.attr("src")),
+            attribute => element.attr(attribute),
+        };
+
+        let value = match (*attribute, value) {
+            (_, None) => continue,
+            ("href" | "src", Some(url)) => match article_url(config, context, url) {
+                Some(url) => url,
+                None => continue,
+            },
+            (_, Some(value)) => value.to_string(),
+        };
+
+        output.push_str(&format!(r#" {}="{}""#, attribute, escape(&value)));
+    }
+
+    output.push('>');
+
+    if VOID.contains(&name) {
+        return;
+    }
+
+    write_children(config, context, output, element, depth);
+    output.push_str(&format!("</{}>", name));
+}
+
+/// A URL in the article, through the proxy. Relative URLs are left as they are, as the article
+/// is shown at the address of the page.
+fn article_url(config: &Config, context: &RewriteContext, url: &str) -> Option<String> {
+    let url = url.trim();
+
+    if is_javascript_url(url) {
+        return None;
+    }
+
+    rewrite_url(config, context, url).map(|url| url.into_owned())
+}
@@ -0,0 +1,37 @@
+use std::{collections::HashMap, sync::Arc};
+
+use super::rewriter::Rewriter;
+
+#[derive(Clone, Default)]
+/// The rewriters used by the proxy, keyed by the MIME type of the content they rewrite
+pub struct RewriterRegistry {
+    rewriters: HashMap<String, Arc<dyn Rewriter>>,
+}
+
+impl RewriterRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a rewriter for a MIME type such as `text/html`, replacing any rewriter that was
+    /// previously registered for it
+    pub fn register(&mut self, mime: &str, rewriter: Arc<dyn Rewriter>) {
+        self.rewriters.insert(mime.to_ascii_lowercase(), rewriter);
+    }
+
+    pub fn contains(&self, mime: &str) -> bool {
+        self.rewriters.contains_key(&mime.to_ascii_lowercase())
+    }
+
+    /// Look up the rewriter for a `Content-Type` header value, ignoring its parameters
+    pub fn get(&self, content_type: &str) -> Option<Arc<dyn Rewriter>> {
+        let essence = content_type
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_ascii_lowercase();
+
+        self.rewriters.get(&essence).cloned()
+    }
+}
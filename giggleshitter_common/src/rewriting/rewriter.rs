@@ -1,3 +1,40 @@
-pub trait Rewriter {
-    fn rewrite(&self, input: Vec<u8>) -> crate::Result<Vec<u8>>;
+use encoding_rs::{Encoding, UTF_8};
+use lol_html::AsciiCompatibleEncoding;
+
+use crate::{proxy::util::Origin, state::Config};
+
+/// What a rewriter is told about the response it rewrites
+pub struct RewriteContext<'a> {
+    /// The upstream origin the response came from
+    pub origin: &'a Origin,
+    /// The path the response was requested at, with its query
+    pub request_path: &'a str,
+    /// The `charset` parameter of the response's content type, lowercased
+    pub charset: Option<&'a str>,
+    /// The response's content type, lowercased and without its parameters
+    pub content_type: Option<&'a str>,
+}
+
+impl RewriteContext<'_> {
+    /// The encoding HTML documents are parsed and written in, UTF-8 unless the charset names an
+    /// other encoding lol_html can handle
+    pub(crate) fn html_encoding(&self) -> AsciiCompatibleEncoding {
+        let encoding = self
+            .charset
+            .and_then(|charset| Encoding::for_label_no_replacement(charset.as_bytes()))
+            .filter(|encoding| encoding.is_ascii_compatible())
+            .unwrap_or(UTF_8);
+
+        AsciiCompatibleEncoding::new(encoding).unwrap()
+    }
+}
+
+pub trait Rewriter: Send + Sync {
+    /// Rewrite a response body, with the configuration of the tenant serving it
+    fn rewrite(
+        &self,
+        config: &Config,
+        context: &RewriteContext,
+        input: Vec<u8>,
+    ) -> crate::Result<Vec<u8>>;
 }
@@ -0,0 +1 @@
+pub mod svg_rewriter;
@@ -0,0 +1,137 @@
+use quick_xml::{
+    events::{BytesCData, BytesStart, BytesText, Event},
+    Reader, Writer,
+};
+use regex::{Captures, Regex};
+
+use crate::{
+    error::Result,
+    proxy::util::encode_url,
+    rewriting::{
+        rewriter::{RewriteContext, Rewriter},
+        urls::rewrite_url,
+    },
+    state::Config,
+};
+
+/// Attributes holding URLs. `xlink:href` has the same local name as `href`.
+const URL_ATTRIBUTES: &[&[u8]] = &[b"href", b"src"];
+
+/// Rewrites the references of SVG documents, the `href`s of `<image>`, `<use>`, `<a>` and
+/// `<script>` elements, the `src`s of HTML in `<foreignObject>`, and absolute `url()`s in styles,
+/// so that SVGs opened on their own don't load anything from the upstream directly
+pub struct SvgRewriter {
+    css_url: Regex,
+}
+
+impl SvgRewriter {
+    pub fn new() -> Self {
+        Self {
+            css_url: Regex::new(r#"(?i)url\(\s*(['"]?)(https?://[^'"()\s]+)"#).unwrap(),
+        }
+    }
+
+    fn rewrite_document(
+        &self,
+        config: &Config,
+        context: &RewriteContext,
+        input: &[u8],
+    ) -> quick_xml::Result<Vec<u8>> {
+        let mut reader = Reader::from_reader(input);
+        let mut writer = Writer::new(Vec::with_capacity(input.len()));
+
+        // Whether the innermost open element is a `<style>`
+        let mut in_style = false;
+
+        loop {
+            match reader.read_event()? {
+                Event::Eof => break,
+                Event::Start(element) => {
+                    in_style = element.local_name().as_ref() == b"style";
+                    writer.write_event(Event::Start(
+                        self.rewrite_attributes(config, context, &element)?,
+                    ))?;
+                }
+                Event::Empty(element) => {
+                    writer.write_event(Event::Empty(
+                        self.rewrite_attributes(config, context, &element)?,
+                    ))?;
+                }
+                Event::End(element) => {
+                    in_style = false;
+                    writer.write_event(Event::End(element))?;
+                }
+                Event::Text(text) if in_style => {
+                    let css = self.rewrite_css(config, &text.unescape()?);
+                    writer.write_event(Event::Text(BytesText::new(&css)))?;
+                }
+                Event::CData(data) if in_style => {
+                    let css = self.rewrite_css(config, &String::from_utf8_lossy(&data));
+                    writer.write_event(Event::CData(BytesCData::new(css)))?;
+                }
+                event => writer.write_event(event)?,
+            }
+        }
+
+        Ok(writer.into_inner())
+    }
+
+    fn rewrite_attributes<'a>(
+        &self,
+        config: &Config,
+        context: &RewriteContext,
+        element: &BytesStart<'a>,
+    ) -> quick_xml::Result<BytesStart<'a>> {
+        let mut rewritten = element.to_owned();
+        rewritten.clear_attributes();
+
+        for attribute in element.attributes() {
+            let attribute = attribute?;
+            let name = attribute.key.local_name();
+
+            if URL_ATTRIBUTES.contains(&name.as_ref()) {
+                let value = attribute.unescape_value()?;
+
+                // Dropped `data:` URLs leave the element without the attribute
+                if let Some(url) = rewrite_url(config, context, &value) {
+                    rewritten.push_attribute((attribute.key.as_ref(), url.as_bytes()));
+                }
+            } else if name.as_ref() == b"style" {
+                let css = self.rewrite_css(config, &attribute.unescape_value()?);
+                rewritten.push_attribute((attribute.key.as_ref(), css.as_bytes()));
+            } else {
+                rewritten.push_attribute(attribute);
+            }
+        }
+
+        Ok(rewritten)
+    }
+
+    fn rewrite_css(&self, config: &Config, css: &str) -> String {
+        self.css_url
+            .replace_all(css, |captures: &Captures| {
+                format!("url({}{}", &captures[1], encode_url(config, &captures[2]))
+            })
+            .into_owned()
+    }
+}
+
+impl Default for SvgRewriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Rewriter for SvgRewriter {
+    fn rewrite(
+        &self,
+        config: &Config,
+        context: &RewriteContext,
+        input: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        // Leave documents that can't be parsed alone, browsers refuse to render them anyway
+        Ok(self
+            .rewrite_document(config, context, &input)
+            .unwrap_or(input))
+    }
+}
@@ -0,0 +1,224 @@
+use std::borrow::Cow;
+
+use reqwest::Url;
+
+use crate::{proxy::util::encode_url, rewriting::rewriter::RewriteContext, state::Config};
+
+/// The URL a page's `url` is replaced with, `None` when it should be dropped. `data:` and `blob:`
+/// URLs are kept as they are, unless [`Config::data_uris`] drops them.
+pub(crate) fn rewrite_url<'a>(
+    config: &Config,
+    context: &RewriteContext,
+    url: &'a str,
+) -> Option<Cow<'a, str>> {
+    let trimmed = url.trim_start();
+
+    if has_scheme(trimmed, "blob") {
+        return Some(Cow::Borrowed(url));
+    }
+
+    if has_scheme(trimmed, "data") {
+        let policy = &config.data_uris;
+
+        if policy.max_bytes.is_some_and(|max| trimmed.len() > max) {
+            return None;
+        }
+
+        if policy.block_html && is_html(&trimmed[5..]) {
+            return None;
+        }
+
+        return Some(Cow::Borrowed(url));
+    }
+
+    Some(Cow::Owned(encode_url(
+        config,
+        &resolve(config, context, url),
+    )))
+}
+
+/// `url` made absolute on the page's origin when it's protocol-relative, or against the page's
+/// URL for any other relative URL with [`Config::resolve_relative_urls`]. Links to fragments of the
+/// page itself are left alone, as are relative URLs without the option, which the browser
+/// resolves against the proxied URL of the page.
+fn resolve<'a>(config: &Config, context: &RewriteContext, url: &'a str) -> Cow<'a, str> {
+    let trimmed = url.trim();
+
+    if is_protocol_relative(trimmed) {
+        // Browsers take backslashes for slashes in these, so they can't be kept
+        return Cow::Owned(format!(
+            "{}://{}",
+            context.origin.scheme().as_str(),
+            &trimmed[2..]
+        ));
+    }
+
+    if !config.resolve_relative_urls || trimmed.starts_with('#') || !is_relative(trimmed) {
+        return Cow::Borrowed(url);
+    }
+
+    let page = format!(
+        "{}{}",
+        context.origin.ascii_serialization(),
+        context.request_path
+    );
+    match Url::parse(&page).and_then(|page| page.join(trimmed)) {
+        Ok(resolved) => Cow::Owned(resolved.into()),
+        Err(_) => Cow::Borrowed(url),
+    }
+}
+
+fn is_protocol_relative(url: &str) -> bool {
+    let mut slashes = url.chars().take_while(|&c| c == '/' || c == '\\');
+
+    slashes.next().is_some() && slashes.next().is_some()
+}
+
+/// Whether `url` is relative to the page it's in, such as `/index.html` or `../image.png`
+pub(crate) fn is_relative(url: &str) -> bool {
+    let url = url.trim();
+
+    // Anything with a scheme parses on its own
+    Url::parse(url).is_err() && !is_protocol_relative(url)
+}
+
+fn has_scheme(url: &str, scheme: &str) -> bool {
+    url.get(..scheme.len())
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case(scheme))
+        && url[scheme.len()..].starts_with(':')
+}
+
+/// Whether the part of a `data:` URL after the scheme holds an HTML document
+fn is_html(data: &str) -> bool {
+    let media_type = data.split([',', ';']).next().unwrap_or_default().trim();
+
+    media_type.eq_ignore_ascii_case("text/html")
+        || media_type.eq_ignore_ascii_case("application/xhtml+xml")
+}
+
+/// Whether a URL runs script, as browsers parse it, skipping leading whitespace and ignoring tabs
+/// and newlines within the scheme
+pub(crate) fn is_javascript_url(url: &str) -> bool {
+    let scheme = url
+        .trim_start_matches(|c: char| c.is_ascii_whitespace() || c.is_ascii_control())
+        .chars()
+        .filter(|c| !matches!(c, '\t' | '\n' | '\r'))
+        .take("javascript:".len())
+        .collect::<String>();
+
+    scheme.eq_ignore_ascii_case("javascript:")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::DataUriConfig;
+
+    fn config(data_uris: DataUriConfig) -> Config {
+        Config {
+            public_host: "proxy.test".to_string(),
+            data_uris,
+            ..Default::default()
+        }
+    }
+
+    /// `url` rewritten in a page at `https://example.com/dir/page`
+    fn rewrite(config: &Config, url: &str) -> Option<String> {
+        let origin = "https://example.com".parse().unwrap();
+        let context = RewriteContext {
+            origin: &origin,
+            request_path: "/dir/page",
+            charset: None,
+            content_type: Some("text/html"),
+        };
+
+        rewrite_url(config, &context, url).map(Cow::into_owned)
+    }
+
+    #[test]
+    fn keeps_inline_urls() {
+        let config = config(DataUriConfig::default());
+
+        for url in [
+            "data:image/png;base64,iVBORw0KGgo=",
+            "DATA:text/plain,a://b",
+            "data:text/html,<a href=\"http://example.com\">",
+            "blob:https://example.com/0b8e4a3c-5d3f-4c1e-9a55-1f2d3c4b5a69",
+        ] {
+            assert_eq!(rewrite(&config, url).as_deref(), Some(url));
+        }
+    }
+
+    #[test]
+    fn encodes_other_urls() {
+        let config = config(DataUriConfig::default());
+        let url = "https://example.com/page";
+
+        assert_eq!(
+            rewrite(&config, url).as_deref(),
+            Some(encode_url(&config, url).as_str())
+        );
+    }
+
+    #[test]
+    fn keeps_proxied_urls() {
+        let config = config(DataUriConfig::default());
+        let proxied = encode_url(&config, "https://example.com/page");
+
+        for url in [
+            proxied.as_str(),
+            "https://proxy.test/s/abc2345",
+            "https://NOT-DECODABLE.PROXY.TEST/page",
+        ] {
+            assert_eq!(rewrite(&config, url).as_deref(), Some(url));
+        }
+        assert_ne!(
+            rewrite(&config, "https://notproxy.test/").as_deref(),
+            Some("https://notproxy.test/")
+        );
+    }
+
+    #[test]
+    fn resolves_relative_urls() {
+        let mut config = config(DataUriConfig::default());
+        let proxied = |url| encode_url(&config, url);
+
+        assert_eq!(
+            rewrite(&config, "//cdn.example/x.js"),
+            Some(proxied("https://cdn.example/x.js"))
+        );
+        assert_eq!(rewrite(&config, "image.png").as_deref(), Some("image.png"));
+
+        config.resolve_relative_urls = true;
+        let proxied = |url| encode_url(&config, url);
+
+        assert_eq!(
+            rewrite(&config, "image.png"),
+            Some(proxied("https://example.com/dir/image.png"))
+        );
+        assert_eq!(
+            rewrite(&config, "\\root?q=1"),
+            Some(proxied("https://example.com/root?q=1"))
+        );
+        assert_eq!(rewrite(&config, "#section").as_deref(), Some("#section"));
+    }
+
+    #[test]
+    fn drops_blocked_data_urls() {
+        let config = config(DataUriConfig {
+            max_bytes: Some(32),
+            block_html: true,
+        });
+
+        assert_eq!(rewrite(&config, "data:text/html,<p>hi</p>"), None);
+        assert_eq!(
+            rewrite(&config, "data:Text/HTML;charset=utf-8,<p>hi</p>"),
+            None
+        );
+        assert_eq!(
+            rewrite(&config, "data:image/png;base64,iVBORw0KGgoAAAANSUhEUgAA"),
+            None
+        );
+        assert!(rewrite(&config, "data:text/plain,hi").is_some());
+    }
+}
@@ -0,0 +1,120 @@
+use quick_xml::{
+    events::{BytesCData, BytesStart, BytesText, Event},
+    Reader, Writer,
+};
+
+use crate::{
+    error::Result,
+    proxy::util::encode_url,
+    rewriting::rewriter::{RewriteContext, Rewriter},
+    state::Config,
+};
+
+/// Attributes holding URLs, such as Atom's `<link href>` and RSS enclosures' `url`
+const URL_ATTRIBUTES: &[&[u8]] = &[b"href", b"src", b"url"];
+
+/// Elements whose text is a URL, such as RSS's `<link>` and the `<loc>` of sitemap entries
+const URL_ELEMENTS: &[&[u8]] = &[b"link", b"url", b"comments", b"loc"];
+
+/// Rewrites the URLs of XML documents, mainly RSS and Atom feeds so that feed readers fetch the
+/// entries through the proxy, and sitemaps and sitemap indexes so that crawlers of a mirrored
+/// site stay on it
+#[derive(Default)]
+pub struct XmlRewriter;
+
+impl XmlRewriter {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Rewriter for XmlRewriter {
+    fn rewrite(
+        &self,
+        config: &Config,
+        _context: &RewriteContext,
+        input: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        // Leave documents that can't be parsed alone rather than mangling them
+        Ok(rewrite_document(config, &input).unwrap_or(input))
+    }
+}
+
+fn rewrite_document(config: &Config, input: &[u8]) -> quick_xml::Result<Vec<u8>> {
+    let mut reader = Reader::from_reader(input);
+    let mut writer = Writer::new(Vec::with_capacity(input.len()));
+
+    // Whether the innermost open element has a URL as its text
+    let mut in_url_element = false;
+
+    loop {
+        match reader.read_event()? {
+            Event::Eof => break,
+            Event::Start(element) => {
+                in_url_element = URL_ELEMENTS.contains(&element.local_name().as_ref());
+                writer.write_event(Event::Start(rewrite_attributes(config, &element)?))?;
+            }
+            Event::Empty(element) => {
+                writer.write_event(Event::Empty(rewrite_attributes(config, &element)?))?;
+            }
+            Event::End(element) => {
+                in_url_element = false;
+                writer.write_event(Event::End(element))?;
+            }
+            Event::Text(text) if in_url_element => {
+                let url = text.unescape()?;
+                let trimmed = url.trim();
+
+                if is_absolute_url(trimmed) {
+                    let encoded = encode_url(config, trimmed);
+                    writer.write_event(Event::Text(BytesText::new(&encoded)))?;
+                } else {
+                    writer.write_event(Event::Text(text))?;
+                }
+            }
+            Event::CData(data) if in_url_element => {
+                let url = String::from_utf8_lossy(&data).trim().to_string();
+
+                if is_absolute_url(&url) {
+                    let encoded = encode_url(config, &url);
+                    writer.write_event(Event::CData(BytesCData::new(encoded)))?;
+                } else {
+                    writer.write_event(Event::CData(data))?;
+                }
+            }
+            event => writer.write_event(event)?,
+        }
+    }
+
+    Ok(writer.into_inner())
+}
+
+fn rewrite_attributes<'a>(
+    config: &Config,
+    element: &BytesStart<'a>,
+) -> quick_xml::Result<BytesStart<'a>> {
+    let mut rewritten = element.to_owned();
+    rewritten.clear_attributes();
+
+    for attribute in element.attributes() {
+        let attribute = attribute?;
+        let value = attribute.unescape_value()?;
+
+        if URL_ATTRIBUTES.contains(&attribute.key.local_name().as_ref()) && is_absolute_url(&value)
+        {
+            rewritten.push_attribute((
+                attribute.key.as_ref(),
+                encode_url(config, &value).as_bytes(),
+            ));
+        } else {
+            rewritten.push_attribute(attribute);
+        }
+    }
+
+    Ok(rewritten)
+}
+
+fn is_absolute_url(string: &str) -> bool {
+    (string.starts_with("http://") || string.starts_with("https://"))
+        && !string.contains(char::is_whitespace)
+}
@@ -0,0 +1,263 @@
+use std::{borrow::Cow, sync::Arc};
+
+use lol_html::{ElementContentHandlers, Selector, Settings};
+use regex::Regex;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    error::Result,
+    proxy::util::Origin,
+    rewriting::{
+        rewriter::{RewriteContext, Rewriter},
+        urls::is_javascript_url,
+    },
+    state::{Config, Rule, RuleAction},
+};
+
+#[derive(Clone)]
+/// A regular expression searched for in the path of proxied requests, compiled when the
+/// configuration is loaded
+pub struct PathPattern(pub Regex);
+
+impl Serialize for PathPattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for PathPattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+
+        Regex::new(&pattern)
+            .map(PathPattern)
+            .map_err(de::Error::custom)
+    }
+}
+
+impl Rule {
+    fn matches(&self, origin: &Origin, path: &str, content_type: Option<&str>) -> bool {
+        let origin_matches = self
+            .origin
+            .as_deref()
+            .is_none_or(|pattern| matches_origin(pattern, origin));
+
+        let content_type_matches = match (&self.content_type, content_type) {
+            (None, _) => true,
+            (Some(pattern), Some(content_type)) => glob(pattern, content_type),
+            (Some(_), None) => false,
+        };
+
+        origin_matches
+            && content_type_matches
+            && self
+                .path
+                .as_ref()
+                .is_none_or(|PathPattern(regex)| regex.is_match(path))
+    }
+}
+
+/// The actions of the rules matching a request. Without a content type only the rules that
+/// don't depend on one match, as is the case before the upstream responded.
+pub fn actions<'a>(
+    config: &'a Config,
+    origin: &Origin,
+    path: &str,
+    content_type: Option<&str>,
+) -> Vec<&'a RuleAction> {
+    config
+        .rules
+        .iter()
+        .filter(|rule| rule.matches(origin, path, content_type))
+        .flat_map(|rule| &rule.actions)
+        .collect()
+}
+
+/// Match a glob against the upstream host, or the whole origin if the glob contains `://`
+pub(crate) fn matches_origin(pattern: &str, origin: &Origin) -> bool {
+    if pattern.contains("://") {
+        glob(pattern, &origin.ascii_serialization())
+    } else {
+        glob(pattern, origin.host())
+    }
+}
+
+/// Match a glob where `*` stands for any number of characters, ignoring case
+pub(crate) fn glob(pattern: &str, text: &str) -> bool {
+    fn glob_bytes(pattern: &[u8], text: &[u8]) -> bool {
+        match pattern.split_first() {
+            None => text.is_empty(),
+            Some((b'*', rest)) => (0..=text.len()).any(|skip| glob_bytes(rest, &text[skip..])),
+            Some((c, rest)) => text
+                .split_first()
+                .is_some_and(|(t, text)| t.eq_ignore_ascii_case(c) && glob_bytes(rest, text)),
+        }
+    }
+
+    glob_bytes(pattern.as_bytes(), text.as_bytes())
+}
+
+/// Applies the element removals and string replacements of the matching rules, after the
+/// rewriter registered for the content type if there is one
+pub struct RuleRewriter {
+    inner: Option<Arc<dyn Rewriter>>,
+    remove: Vec<Selector>,
+    replace: Vec<(String, String)>,
+    disable_scripts: bool,
+}
+
+impl RuleRewriter {
+    /// `None` when none of the actions change the body
+    pub fn new(
+        inner: Option<Arc<dyn Rewriter>>,
+        actions: &[&RuleAction],
+        is_html: bool,
+    ) -> Option<Self> {
+        let remove = actions
+            .iter()
+            .filter(|_| is_html)
+            .filter_map(|action| match action {
+                RuleAction::RemoveElement(selector) => selector.parse().ok(),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        let replace = actions
+            .iter()
+            .filter_map(|action| match action {
+                RuleAction::ReplaceString(from, to) if !from.is_empty() => {
+                    Some((from.clone(), to.clone()))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        let disable_scripts = is_html
+            && actions
+                .iter()
+                .any(|action| matches!(action, RuleAction::DisableScripts));
+
+        if remove.is_empty() && replace.is_empty() && !disable_scripts {
+            return None;
+        }
+
+        Some(Self {
+            inner,
+            remove,
+            replace,
+            disable_scripts,
+        })
+    }
+}
+
+impl Rewriter for RuleRewriter {
+    fn rewrite(
+        &self,
+        config: &Config,
+        context: &RewriteContext,
+        input: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        let mut body = match &self.inner {
+            Some(inner) => inner.rewrite(config, context, input)?,
+            None => input,
+        };
+
+        if !self.remove.is_empty() || self.disable_scripts {
+            let mut element_content_handlers = self
+                .remove
+                .iter()
+                .map(|selector| {
+                    (
+                        Cow::Borrowed(selector),
+                        ElementContentHandlers::default().element(|el| {
+                            el.remove();
+                            Ok(())
+                        }),
+                    )
+                })
+                .collect::<Vec<_>>();
+
+            if self.disable_scripts {
+                element_content_handlers.extend(script_handlers());
+            }
+
+            let mut output = vec![];
+            let mut rewriter = lol_html::HtmlRewriter::new(
+                Settings {
+                    element_content_handlers,
+                    encoding: context.html_encoding(),
+                    ..Settings::default()
+                },
+                |c: &[u8]| output.extend_from_slice(c),
+            );
+
+            rewriter.write(&body)?;
+            rewriter.end()?;
+
+            body = output;
+        }
+
+        for (from, to) in &self.replace {
+            body = replace_all(&body, from.as_bytes(), to.as_bytes());
+        }
+
+        Ok(body)
+    }
+}
+
+/// Handlers removing scripts, and showing the `<noscript>` fallbacks in their place
+pub(crate) fn script_handlers() -> Vec<(Cow<'static, Selector>, ElementContentHandlers<'static>)> {
+    vec![
+        (
+            Cow::Owned("script".parse().unwrap()),
+            ElementContentHandlers::default().element(|el| {
+                el.remove();
+                Ok(())
+            }),
+        ),
+        (
+            Cow::Owned("noscript".parse().unwrap()),
+            ElementContentHandlers::default().element(|el| {
+                el.remove_and_keep_content();
+                Ok(())
+            }),
+        ),
+        (
+            Cow::Owned("*".parse().unwrap()),
+            ElementContentHandlers::default().element(|el| {
+                let scripted = el
+                    .attributes()
+                    .iter()
+                    .filter(|attribute| {
+                        let name = attribute.name();
+                        name.starts_with("on") || is_javascript_url(&attribute.value())
+                    })
+                    .map(|attribute| attribute.name())
+                    .collect::<Vec<_>>();
+
+                for name in scripted {
+                    el.remove_attribute(&name);
+                }
+
+                Ok(())
+            }),
+        ),
+    ]
+}
+
+fn replace_all(body: &[u8], from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(body.len());
+    let mut rest = body;
+
+    while !rest.is_empty() {
+        if rest.starts_with(from) {
+            output.extend_from_slice(to);
+            rest = &rest[from.len()..];
+        } else {
+            output.push(rest[0]);
+            rest = &rest[1..];
+        }
+    }
+
+    output
+}
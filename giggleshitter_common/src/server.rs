@@ -0,0 +1,450 @@
+use std::{any::Any, future::Future, sync::Arc, time::Duration};
+
+use arc_swap::ArcSwap;
+use axum::{
+    extract::{Host, Request, State},
+    http::Uri,
+    http::{header::SET_COOKIE, HeaderValue, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+    Router,
+};
+use reqwest::redirect::Policy;
+use scorched::{logf, LogData, LogImportance};
+use tower::ServiceExt;
+use tower_http::catch_panic::CatchPanicLayer;
+
+use crate::{
+    access::AccessControl,
+    api::{self, keys::RateLimiter},
+    audit::AuditLog,
+    blocking::Blocker,
+    breaker::CircuitBreaker,
+    compression,
+    diagnostics::Diagnostics,
+    dns::DnsResolver,
+    error::Result,
+    hooks::{Hooks, ProxyHook},
+    listener::Listener,
+    metrics::Metrics,
+    pages::message_response,
+    plugins::{Plugins, ProxyPlugin},
+    proxy::{self, favicon::Favicons, prefetch::Prefetcher},
+    recording::Recorder,
+    rewriting::{
+        html::html_rewriter::HtmlRewriter, json::json_rewriter::JsonRewriter,
+        registry::RewriterRegistry, rewriter::Rewriter, svg::svg_rewriter::SvgRewriter,
+        xml::xml_rewriter::XmlRewriter,
+    },
+    session,
+    state::{APIState, Config, LiveConfig, ProxyState, SharedState, UpstreamConfig},
+    tenant::TenantConfig,
+    upstream::ConnectionStats,
+    usage::Usage,
+};
+
+/// Builds a proxy server, letting embedders swap out the pieces the plain [`crate::serve`] sets up
+/// with defaults
+pub struct ServerBuilder {
+    config: LiveConfig,
+    client: Option<reqwest::Client>,
+    passthrough_client: Option<reqwest::Client>,
+    rewriters: RewriterRegistry,
+    router_extensions: Vec<Router>,
+    hooks: Hooks,
+    plugins: Plugins,
+}
+
+impl ServerBuilder {
+    pub fn new(config: Arc<Config>) -> Self {
+        Self::with_live_config(Arc::new(ArcSwap::new(config)))
+    }
+
+    /// Build a server whose configuration can be swapped while it is running
+    pub fn with_live_config(config: LiveConfig) -> Self {
+        Self {
+            config,
+            client: None,
+            passthrough_client: None,
+            rewriters: RewriterRegistry::new(),
+            router_extensions: vec![],
+            hooks: Hooks::new(),
+            plugins: Plugins::new(),
+        }
+    }
+
+    /// Use a custom client for upstream requests. The client should not follow redirects, so that
+    /// they can be rewritten to point through the proxy.
+    pub fn with_client(mut self, client: reqwest::Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Use a custom client for upstream requests in pass-through compression mode. Besides not
+    /// following redirects, the client must not decompress responses.
+    pub fn with_passthrough_client(mut self, client: reqwest::Client) -> Self {
+        self.passthrough_client = Some(client);
+        self
+    }
+
+    /// Rewrite responses with the given MIME type, replacing the built in rewriter if there is one
+    pub fn with_rewriter(mut self, mime: &str, rewriter: Box<dyn Rewriter>) -> Self {
+        self.rewriters.register(mime, rewriter.into());
+        self
+    }
+
+    /// Merge extra routes into the API router served on `api.<public_host>`
+    pub fn with_router_extension(mut self, router: Router) -> Self {
+        self.router_extensions.push(router);
+        self
+    }
+
+    /// Register a hook that is invoked around every proxied request
+    pub fn with_request_hook(mut self, hook: Arc<dyn ProxyHook>) -> Self {
+        self.hooks.register(hook);
+        self
+    }
+
+    /// Register a plugin that may change requests, responses and WebSocket messages around the
+    /// upstream call
+    pub fn with_plugin(mut self, plugin: Arc<dyn ProxyPlugin>) -> Self {
+        self.plugins.register(plugin);
+        self
+    }
+
+    pub async fn serve<F>(self, graceful_shutdown: F) -> Result<()>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let host = self.config.load().host.clone();
+        let listener = Listener::bind(&host).await?;
+
+        self.serve_with_listener(listener, graceful_shutdown).await
+    }
+
+    pub async fn serve_with_listener<F>(
+        self,
+        listener: impl Into<Listener>,
+        graceful_shutdown: F,
+    ) -> Result<()>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        crate::run(listener, self.build_router()?, graceful_shutdown).await
+    }
+
+    /// Build the router without binding a listener. The router dispatches on the `Host` header, so
+    /// it should be mounted as the fallback of an outer application rather than nested under a
+    /// path. Fails if the configuration doesn't pass [`Config::validate`].
+    pub fn build_router(self) -> Result<Router> {
+        let config = self.config;
+
+        config.load().validate()?;
+
+        let sharedstate = SharedState {
+            config: config.clone(),
+        };
+
+        // Shared by both clients, so that they share its cache
+        let connections = ConnectionStats::default();
+        let breaker = CircuitBreaker::default();
+        let metrics = Metrics::default();
+        let diagnostics = Diagnostics::default();
+        let upstream = config.load().upstream.clone();
+        let resolver = DnsResolver::from_config(
+            &config.load().dns,
+            upstream.ip_preference,
+            connections.clone(),
+        );
+
+        let client = match self.client {
+            Some(client) => client,
+            None => default_client(Some(resolver.clone()), &upstream)?,
+        };
+
+        let passthrough_client = match self.passthrough_client {
+            Some(client) => client,
+            None => passthrough_client(Some(resolver), &upstream)?,
+        };
+
+        let mut rewriters = self.rewriters;
+
+        if !rewriters.contains("text/html") {
+            rewriters.register("text/html", Arc::new(HtmlRewriter::new()));
+        }
+
+        for mime in [
+            "application/xml",
+            "text/xml",
+            "application/rss+xml",
+            "application/atom+xml",
+            "application/x-rss+xml",
+        ] {
+            if !rewriters.contains(mime) {
+                rewriters.register(mime, Arc::new(XmlRewriter::new()));
+            }
+        }
+
+        if !rewriters.contains("image/svg+xml") {
+            rewriters.register("image/svg+xml", Arc::new(SvgRewriter::new()));
+        }
+
+        if config.load().rewrite_json && !rewriters.contains("application/json") {
+            rewriters.register("application/json", Arc::new(JsonRewriter::new()));
+        }
+
+        let usage = Usage::spawn(config.clone());
+        let access = AccessControl::load(&config.load())?;
+
+        let mut plugins = self.plugins;
+        load_plugins_dir(&config.load(), &mut plugins)?;
+
+        let proxystate = ProxyState {
+            client: client.clone(),
+            passthrough_client,
+            rewriters,
+            hooks: self.hooks,
+            plugins,
+            blocker: Blocker::spawn(config.clone()),
+            access: access.clone(),
+            audit: AuditLog::spawn(config.clone()),
+            recorder: Recorder::spawn(config.clone()),
+            prefetcher: Prefetcher::default(),
+            favicons: Favicons::default(),
+            usage: usage.clone(),
+            connections: connections.clone(),
+            breaker: breaker.clone(),
+            metrics: metrics.clone(),
+            diagnostics: diagnostics.clone(),
+        };
+
+        let proxyrouter = Router::new()
+            .fallback(proxy::service::proxy)
+            .layer(compression::layer(&config))
+            .with_state(Arc::new(proxystate));
+
+        let apistate = APIState {
+            config: config.clone(),
+            usage,
+            access,
+            rate_limiter: RateLimiter::default(),
+            connections,
+            breaker,
+            metrics,
+            diagnostics,
+            upstream,
+            client,
+        };
+
+        let apirouter = self
+            .router_extensions
+            .into_iter()
+            .fold(api::service::service(Arc::new(apistate)), Router::merge);
+
+        Ok(Router::new()
+            .fallback(
+                |State(state): State<SharedState>, Host(host): Host, mut req: Request| async move {
+                    let tenant = state.config.load_full().for_host(&host);
+
+                    let is_api =
+                        host == format!("api.{}", tenant.public_host) || host == tenant.public_host;
+                    let (config, session_cookie) =
+                        match session::establish(&tenant, &mut req, is_api) {
+                            Ok(established) => established,
+                            Err(response) => return Ok(*response),
+                        };
+                    req.extensions_mut().insert(TenantConfig(config.clone()));
+
+                    if host == format!("api.{}", config.public_host) {
+                        return apirouter
+                            .oneshot(req)
+                            .await
+                            .map(|response| with_cookie(response, session_cookie));
+                    }
+
+                    if let (true, Some(prefix)) =
+                        (host == config.public_host, &config.api_path_prefix)
+                    {
+                        // Relative links on the landing page need the trailing slash
+                        if req.uri().path() == prefix {
+                            return Ok(Redirect::permanent(&format!("{}/", prefix)).into_response());
+                        }
+
+                        if let Some(uri) = strip_api_prefix(req.uri(), prefix) {
+                            *req.uri_mut() = uri;
+                            return apirouter
+                                .oneshot(req)
+                                .await
+                                .map(|response| with_cookie(response, session_cookie));
+                        }
+                    }
+
+                    // Short links are shared as the bare public host, being the shortest there is
+                    if host == config.public_host && req.uri().path().starts_with("/s/") {
+                        return apirouter
+                            .oneshot(req)
+                            .await
+                            .map(|response| with_cookie(response, session_cookie));
+                    }
+
+                    proxyrouter.oneshot(req).await
+                },
+            )
+            .layer(CatchPanicLayer::custom(panic_response))
+            .with_state(sharedstate))
+    }
+}
+
+/// Register the WebAssembly plugins of the configured plugins directory, after any plugins
+/// registered on the builder
+#[cfg(feature = "plugins")]
+fn load_plugins_dir(config: &Config, plugins: &mut Plugins) -> Result<()> {
+    if let Some(dir) = &config.plugins_dir {
+        for plugin in crate::plugins::wasm::load_dir(dir)? {
+            plugins.register(Arc::new(plugin));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(feature = "plugins"))]
+fn load_plugins_dir(config: &Config, _plugins: &mut Plugins) -> Result<()> {
+    if let Some(dir) = &config.plugins_dir {
+        logf!(
+            Warning,
+            "Not loading plugins from {}, this build does not include the plugins feature",
+            dir.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Answer a request whose handling panicked, so that only this request fails rather than every
+/// request on its connection
+fn panic_response(panic: Box<dyn Any + Send + 'static>) -> Response {
+    let message = panic
+        .downcast_ref::<String>()
+        .map(String::as_str)
+        .or_else(|| panic.downcast_ref::<&str>().copied())
+        .unwrap_or("no message");
+    logf!(Error, "Request handler panicked: {}", message);
+
+    message_response(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Internal error",
+        "Something went wrong handling this request. Other pages should still load.",
+    )
+}
+
+/// Hand a newly started session to the client along with the response
+fn with_cookie(mut response: Response, cookie: Option<HeaderValue>) -> Response {
+    if let Some(cookie) = cookie {
+        response.headers_mut().append(SET_COOKIE, cookie);
+    }
+
+    response
+}
+
+/// The URI of an API request served under `prefix` on the bare public host, as the API router
+/// expects it. `None` when the path is outside the prefix.
+fn strip_api_prefix(uri: &Uri, prefix: &str) -> Option<Uri> {
+    let path = uri.path().strip_prefix(prefix)?;
+
+    if !path.starts_with('/') {
+        return None;
+    }
+
+    match uri.query() {
+        Some(query) => format!("{}?{}", path, query).parse().ok(),
+        None => path.parse().ok(),
+    }
+}
+
+/// Follow at most `max_hops` redirects, failing on one that leads back to a URL visited before.
+/// Redirects to other origins are passed on, to be followed by the browser on their own proxied
+/// host.
+fn redirect_policy(max_hops: Option<usize>) -> Policy {
+    let Some(max_hops) = max_hops else {
+        return Policy::none();
+    };
+
+    Policy::custom(move |attempt| {
+        let cross_origin = attempt
+            .previous()
+            .first()
+            .is_some_and(|first| first.origin() != attempt.url().origin());
+
+        if cross_origin {
+            attempt.stop()
+        } else if attempt.previous().contains(attempt.url()) {
+            let error = format!("redirect loop at {}", attempt.url());
+            attempt.error(error)
+        } else if attempt.previous().len() > max_hops {
+            attempt.error(format!("more than {} redirects", max_hops))
+        } else {
+            attempt.follow()
+        }
+    })
+}
+
+/// The defaults of every upstream client: redirects are passed to the client unless configured to
+/// be followed, connections are kept as configured, and hosts are resolved with the configured DNS
+/// settings
+fn client_builder(
+    resolver: Option<Arc<DnsResolver>>,
+    upstream: &UpstreamConfig,
+) -> reqwest::ClientBuilder {
+    let mut builder = reqwest::Client::builder()
+        .redirect(redirect_policy(upstream.follow_redirects))
+        .tcp_nodelay(upstream.tcp_nodelay)
+        .tcp_keepalive(upstream.tcp_keepalive_secs.map(Duration::from_secs))
+        .pool_idle_timeout(upstream.pool_idle_timeout_secs.map(Duration::from_secs))
+        .http2_keep_alive_interval(
+            upstream
+                .http2_keep_alive_interval_secs
+                .map(Duration::from_secs),
+        )
+        .http2_keep_alive_timeout(Duration::from_secs(upstream.http2_keep_alive_timeout_secs))
+        .http2_keep_alive_while_idle(upstream.http2_keep_alive_while_idle);
+
+    if let Some(max_idle) = upstream.pool_max_idle_per_host {
+        builder = builder.pool_max_idle_per_host(max_idle);
+    }
+
+    if upstream.title_case_headers {
+        builder = builder.http1_title_case_headers();
+    }
+
+    match resolver {
+        Some(resolver) => builder.dns_resolver(resolver),
+        None => builder,
+    }
+}
+
+/// The client used for upstream requests when none is provided
+pub fn default_client(
+    resolver: Option<Arc<DnsResolver>>,
+    upstream: &UpstreamConfig,
+) -> Result<reqwest::Client> {
+    Ok(client_builder(resolver, upstream)
+        .gzip(true)
+        .brotli(true)
+        .deflate(true)
+        .zstd(true)
+        .build()?)
+}
+
+/// The client used for pass-through compression mode when none is provided, which leaves response
+/// bodies as the upstream sent them
+pub fn passthrough_client(
+    resolver: Option<Arc<DnsResolver>>,
+    upstream: &UpstreamConfig,
+) -> Result<reqwest::Client> {
+    Ok(client_builder(resolver, upstream)
+        .no_gzip()
+        .no_brotli()
+        .no_deflate()
+        .no_zstd()
+        .build()?)
+}
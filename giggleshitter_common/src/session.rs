@@ -0,0 +1,282 @@
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{extract::Request, response::Response};
+use hmac::{Hmac, Mac};
+use hyper::{
+    header::{HeaderValue, COOKIE},
+    HeaderMap, StatusCode,
+};
+use sha2::Sha256;
+
+use crate::{
+    pages::message_response,
+    state::{Config, UrlEncodingAlgorithm},
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// A browsing session, which encodes proxied URLs with a key of its own so that they only work in
+/// the browser the session was issued to
+pub struct Session {
+    id: String,
+    issued: u64,
+    /// Whether the session's exchanges are recorded, see [`crate::state::RecordingConfig`]
+    record: bool,
+}
+
+#[derive(Clone, Copy)]
+/// Attached to the requests of a session that asked to be recorded, while recording sessions is
+/// allowed
+pub struct RecordedSession;
+
+impl Session {
+    /// Start a new session with a random id
+    pub fn new() -> Self {
+        Self {
+            id: format!("{:032x}", rand::random::<u128>()),
+            issued: now(),
+            record: false,
+        }
+    }
+
+    /// Have the session's exchanges recorded
+    pub fn recorded(mut self) -> Self {
+        self.record = true;
+        self
+    }
+
+    /// The session of a request, if it carries a session cookie that is signed with the
+    /// configured secret and hasn't expired
+    fn from_headers(config: &Config, headers: &HeaderMap) -> Option<Self> {
+        let value = cookie_values(headers, &config.sessions.cookie_name).next()?;
+
+        let (payload, signature) = value.rsplit_once('.')?;
+        let mut fields = payload.split('.');
+        let id = fields.next()?;
+        let issued = fields.next()?.parse().ok()?;
+        let record = match fields.next() {
+            None => false,
+            Some("record") => true,
+            Some(_) => return None,
+        };
+        if fields.next().is_some() {
+            return None;
+        }
+
+        let mut mac = mac(config, b"cookie");
+        mac.update(payload.as_bytes());
+        mac.verify_slice(&hex_decode(signature)?).ok()?;
+
+        if now().saturating_sub(issued) > config.sessions.max_age_secs {
+            return None;
+        }
+
+        Some(Self {
+            id: id.to_string(),
+            issued,
+            record,
+        })
+    }
+
+    /// The `Set-Cookie` value handing the session to the client, shared by every proxied host
+    pub fn cookie(&self, config: &Config) -> HeaderValue {
+        let mut payload = format!("{}.{}", self.id, self.issued);
+        if self.record {
+            payload.push_str(".record");
+        }
+
+        let mut mac = mac(config, b"cookie");
+        mac.update(payload.as_bytes());
+        let signature = hex_encode(&mac.finalize().into_bytes());
+
+        let cookie = format!(
+            "{}={}.{}; Domain={}; Path=/; Max-Age={}; HttpOnly; Secure; SameSite=Lax",
+            config.sessions.cookie_name,
+            payload,
+            signature,
+            config.public_host,
+            config.sessions.max_age_secs
+        );
+
+        HeaderValue::from_str(&cookie).expect("the cookie is made of header-safe characters")
+    }
+
+    /// The configuration with the encoding key of this session in place of the configured one
+    pub fn apply(&self, config: &Config) -> Config {
+        let mut mac = mac(config, b"key");
+        mac.update(self.id.as_bytes());
+        let key = mac.finalize().into_bytes().to_vec();
+
+        let alphabet = match &config.url_encoding_algorithm {
+            UrlEncodingAlgorithm::Base32(alphabet)
+            | UrlEncodingAlgorithm::Base32Xor(alphabet, _) => *alphabet,
+        };
+
+        Config {
+            url_encoding_algorithm: UrlEncodingAlgorithm::Base32Xor(alphabet, key),
+            // Links from before the session would work in any browser
+            legacy_url_encoding_algorithms: vec![],
+            previous_keys: vec![],
+            ..config.clone()
+        }
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The configuration to serve a request with when sessions are enabled, along with the cookie of
+/// a session that was started for it. The session cookie is removed from the request, so that it
+/// isn't sent upstream, and requests of recorded sessions are marked with [`RecordedSession`].
+///
+/// Requests to the API host start a session when they don't carry one, proxied requests without a
+/// valid session are refused.
+pub fn establish(
+    config: &Arc<Config>,
+    req: &mut Request,
+    is_api: bool,
+) -> Result<(Arc<Config>, Option<HeaderValue>), Box<Response>> {
+    if !config.sessions.enabled {
+        return Ok((config.clone(), None));
+    }
+
+    let session = Session::from_headers(config, req.headers());
+    strip_cookie(&config.sessions.cookie_name, req.headers_mut());
+
+    match session {
+        Some(session) => {
+            // Sessions stay recorded only for as long as recording them is allowed
+            if session.record && config.recording.sessions {
+                req.extensions_mut().insert(RecordedSession);
+            }
+            Ok((Arc::new(session.apply(config)), None))
+        }
+        None if is_api => {
+            let session = Session::new();
+            Ok((
+                Arc::new(session.apply(config)),
+                Some(session.cookie(config)),
+            ))
+        }
+        None => Err(Box::new(message_response(
+            StatusCode::FORBIDDEN,
+            "Session expired",
+            "Links opened through this proxy only work in the browser they were opened in, for a \
+             limited time. Open the site again from the proxy's home page.",
+        ))),
+    }
+}
+
+/// An HMAC keyed with the session secret, with `purpose` mixed in so that the signing and
+/// encoding keys differ
+fn mac(config: &Config, purpose: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(config.sessions.secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(purpose);
+    mac.update(b":");
+    mac
+}
+
+fn cookie_values<'a>(headers: &'a HeaderMap, name: &'a str) -> impl Iterator<Item = &'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(move |pair| {
+            let (cookie_name, value) = pair.trim().split_once('=')?;
+            (cookie_name == name).then_some(value)
+        })
+}
+
+/// Remove a cookie from the `Cookie` headers, dropping headers that end up empty
+fn strip_cookie(name: &str, headers: &mut HeaderMap) {
+    let values = headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .map(|value| {
+            value
+                .split(';')
+                .map(str::trim)
+                .filter(|pair| pair.split_once('=').map_or(*pair, |(name, _)| name) != name)
+                .collect::<Vec<_>>()
+                .join("; ")
+        })
+        .filter(|value| !value.is_empty())
+        .collect::<Vec<_>>();
+
+    headers.remove(COOKIE);
+
+    for value in values {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.append(COOKIE, value);
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hex_decode(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn marks_the_requests_of_recorded_sessions() {
+        let mut config = Config::default();
+        config.sessions.enabled = true;
+        config.sessions.secret = "secret".to_string();
+        config.recording.dir = Some("recordings".into());
+        config.recording.sessions = true;
+
+        let request = |config: &Config, session: Session| {
+            let cookie = session.cookie(config);
+            let cookie = cookie.to_str().unwrap().split(';').next().unwrap();
+            Request::builder()
+                .header(COOKIE, cookie)
+                .body(axum::body::Body::empty())
+                .unwrap()
+        };
+
+        let mut req = request(&config, Session::new().recorded());
+        establish(&Arc::new(config.clone()), &mut req, false).unwrap();
+        assert!(req.extensions().get::<RecordedSession>().is_some());
+        // The session cookie stays with the proxy
+        assert!(!req.headers().contains_key(COOKIE));
+
+        let mut req = request(&config, Session::new());
+        establish(&Arc::new(config.clone()), &mut req, false).unwrap();
+        assert!(req.extensions().get::<RecordedSession>().is_none());
+
+        // Sessions that were recorded stop being recorded once that isn't allowed anymore
+        config.recording.sessions = false;
+        let mut req = request(&config, Session::new().recorded());
+        establish(&Arc::new(config), &mut req, false).unwrap();
+        assert!(req.extensions().get::<RecordedSession>().is_none());
+    }
+}
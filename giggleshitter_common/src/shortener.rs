@@ -0,0 +1,59 @@
+use std::{io, path::Path};
+
+use rand::Rng;
+use tokio::io::AsyncWriteExt;
+
+/// The characters of short IDs, without the ones that are easily mistaken for each other when
+/// read out, such as `0` and `o` or `1` and `l`
+const ALPHABET: &[u8] = b"23456789abcdefghjkmnpqrstuvwxyz";
+
+/// How many times a new ID is drawn when the previous one was taken
+const MAX_ATTEMPTS: usize = 8;
+
+/// Store `url` in `dir` under a new ID of `length` characters, which is returned
+pub async fn store(dir: &Path, url: &str, length: usize) -> io::Result<String> {
+    tokio::fs::create_dir_all(dir).await?;
+
+    for _ in 0..MAX_ATTEMPTS {
+        let id = {
+            let mut rng = rand::thread_rng();
+            (0..length)
+                .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char)
+                .collect::<String>()
+        };
+
+        // Existing links are never replaced, whoever shared them expects them to keep working
+        let created = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(dir.join(&id))
+            .await;
+
+        match created {
+            Ok(mut file) => {
+                file.write_all(url.as_bytes()).await?;
+                return Ok(id);
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(io::Error::other("no unused short ID was found"))
+}
+
+/// The URL stored in `dir` under `id`, `None` when there is none
+pub async fn load(dir: &Path, id: &str) -> io::Result<Option<String>> {
+    // Anything but an ID we made could name a file outside of the directory
+    if id.is_empty() || !id.bytes().all(|b| ALPHABET.contains(&b)) {
+        return Ok(None);
+    }
+
+    match tokio::fs::read_to_string(dir.join(id)).await {
+        // Empty while the link is still being written
+        Ok(url) if url.is_empty() => Ok(None),
+        Ok(url) => Ok(Some(url)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
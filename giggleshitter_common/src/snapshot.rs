@@ -0,0 +1,608 @@
+//! Snapshots of proxied pages, saved as a single HTML document with their stylesheets and images
+//! inlined as `data:` URLs, so that they can be read without the upstream
+
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    io,
+    path::Path,
+    sync::LazyLock,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures_util::{stream, StreamExt};
+use hyper::{
+    header::{CONTENT_TYPE, LOCATION},
+    StatusCode,
+};
+use lol_html::{
+    html_content::{ContentType, Element},
+    ElementContentHandlers, HtmlRewriter, Selector, Settings,
+};
+use regex::{Captures, Regex};
+use reqwest::Url;
+use thiserror::Error;
+
+use crate::{
+    proxy::util::encode_url, rewriting::urls::is_javascript_url, rules::script_handlers,
+    state::Config,
+};
+
+/// How many redirects are followed for the page and for each of its resources
+const MAX_REDIRECTS: usize = 5;
+
+/// How many resources of a page are fetched at once
+const CONCURRENCY: usize = 8;
+
+/// The attributes holding URLs that are made absolute and sent through the proxy, for the links
+/// of a snapshot to keep working when it is opened from somewhere else
+const URL_ATTRIBUTES: [&str; 5] = ["href", "src", "poster", "action", "formaction"];
+
+/// The `rel`s of links that only tell the browser to fetch something early, which a snapshot has
+/// no use for
+const PRELOAD_RELS: [&str; 5] = [
+    "preload",
+    "prefetch",
+    "modulepreload",
+    "preconnect",
+    "dns-prefetch",
+];
+
+/// `url()`s in CSS, capturing the URL whether it is double, single or not quoted
+static CSS_URL: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?i)url\(\s*(?:"([^"]*)"|'([^']*)'|([^'"()\s]*))\s*\)"#).unwrap()
+});
+
+#[derive(Error, Debug)]
+/// Why a page couldn't be saved
+pub enum SnapshotError {
+    #[error("{0} is not an absolute http or https URL")]
+    InvalidUrl(String),
+    #[error("{0} may not be proxied")]
+    NotAllowed(String),
+    #[error("the upstream could not be reached: {0}")]
+    Unreachable(#[from] reqwest::Error),
+    #[error("the upstream responded with {0}")]
+    Upstream(StatusCode),
+    #[error("more than {MAX_REDIRECTS} redirects")]
+    TooManyRedirects,
+    #[error("the page is not HTML")]
+    NotHtml,
+    #[error("the page is larger than {0} bytes")]
+    TooLarge(usize),
+    #[error("the page could not be rewritten: {0}")]
+    Rewrite(#[from] lol_html::errors::RewritingError),
+}
+
+impl SnapshotError {
+    /// The status the API responds with
+    pub fn status(&self) -> StatusCode {
+        match self {
+            SnapshotError::InvalidUrl(_) => StatusCode::BAD_REQUEST,
+            SnapshotError::NotAllowed(_) => StatusCode::FORBIDDEN,
+            SnapshotError::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            SnapshotError::Rewrite(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            SnapshotError::Unreachable(_)
+            | SnapshotError::Upstream(_)
+            | SnapshotError::TooManyRedirects
+            | SnapshotError::NotHtml => StatusCode::BAD_GATEWAY,
+        }
+    }
+}
+
+/// A response body, read in full
+struct Fetched {
+    /// Where the body came from, after redirects
+    url: Url,
+    content_type: String,
+    body: Vec<u8>,
+}
+
+/// Save the page at `url` as a single HTML document. Its stylesheets, images and the resources
+/// of its stylesheets are inlined for as long as they fit within [`Config::snapshots`], the rest
+/// and every link are pointed at the proxy. Scripts are removed.
+pub async fn capture(
+    client: &reqwest::Client,
+    config: &Config,
+    url: &str,
+) -> Result<String, SnapshotError> {
+    let limits = &config.snapshots;
+    let url = Url::parse(url).map_err(|_| SnapshotError::InvalidUrl(url.to_string()))?;
+
+    let page = fetch(client, config, url, limits.max_bytes).await?;
+    let media_type = page.content_type.split(';').next().unwrap_or_default();
+    if !media_type.trim().eq_ignore_ascii_case("text/html") {
+        return Err(SnapshotError::NotHtml);
+    }
+    let html = String::from_utf8_lossy(&page.body).into_owned();
+
+    let mut inliner = Inliner {
+        bytes_left: limits.max_bytes.saturating_sub(html.len()),
+        resources_left: limits.max_resources,
+        ..Default::default()
+    };
+
+    let found = find_resources(&html, &page.url)?;
+
+    let stylesheets = inliner.fetch_all(client, config, found.stylesheets).await;
+    let mut urls = found.images;
+    for stylesheet in stylesheets.values() {
+        let css = String::from_utf8_lossy(&stylesheet.body);
+        urls.extend(css_urls(&css, &stylesheet.url));
+    }
+    urls.extend(found.styles.iter().flat_map(|css| css_urls(css, &page.url)));
+
+    let resources = inliner.fetch_all(client, config, urls).await;
+    let data_urls = resources
+        .into_iter()
+        .map(|(url, resource)| (url, data_url(&resource)))
+        .collect::<HashMap<_, _>>();
+    let stylesheets = stylesheets
+        .into_iter()
+        .map(|(url, stylesheet)| {
+            let css = String::from_utf8_lossy(&stylesheet.body);
+            let css = inline_css(config, &css, &stylesheet.url, &data_urls);
+            (url, css)
+        })
+        .collect::<HashMap<_, _>>();
+
+    let snapshot = rewrite(config, &html, &page.url, &stylesheets, &data_urls)?;
+
+    Ok(format!(
+        "<!-- saved from {} on {} -->\n{}",
+        page.url,
+        chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        snapshot
+    ))
+}
+
+/// Store `snapshot` in `dir` under a new ID, which is returned
+pub async fn store(dir: &Path, snapshot: &str) -> io::Result<String> {
+    let id = rand::random::<[u8; 16]>()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<String>();
+
+    tokio::fs::create_dir_all(dir).await?;
+    tokio::fs::write(dir.join(format!("{id}.html")), snapshot).await?;
+
+    Ok(id)
+}
+
+/// The snapshot stored in `dir` under `id`, `None` when there is none
+pub async fn load(dir: &Path, id: &str) -> io::Result<Option<String>> {
+    // Anything but an ID we made could name a file outside of the directory
+    if id.len() != 32 || !id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        return Ok(None);
+    }
+
+    match tokio::fs::read_to_string(dir.join(format!("{id}.html"))).await {
+        Ok(snapshot) => Ok(Some(snapshot)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// GET `url`, following redirects to the hosts that may be proxied
+async fn fetch(
+    client: &reqwest::Client,
+    config: &Config,
+    mut url: Url,
+    max_bytes: usize,
+) -> Result<Fetched, SnapshotError> {
+    for _ in 0..=MAX_REDIRECTS {
+        let host = match url.scheme() {
+            "http" | "https" => url.host_str(),
+            _ => None,
+        }
+        .ok_or_else(|| SnapshotError::InvalidUrl(url.to_string()))?;
+        if !config.allows(host) {
+            return Err(SnapshotError::NotAllowed(host.to_string()));
+        }
+
+        let mut response = client.get(url.clone()).send().await?;
+        let status = response.status();
+
+        if status.is_redirection() {
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .and_then(|location| url.join(location).ok());
+
+            if let Some(location) = location {
+                url = location;
+                continue;
+            }
+        }
+
+        if !status.is_success() {
+            return Err(SnapshotError::Upstream(status));
+        }
+
+        if response
+            .content_length()
+            .is_some_and(|length| length > max_bytes as u64)
+        {
+            return Err(SnapshotError::TooLarge(max_bytes));
+        }
+
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            if body.len() + chunk.len() > max_bytes {
+                return Err(SnapshotError::TooLarge(max_bytes));
+            }
+            body.extend_from_slice(&chunk);
+        }
+
+        return Ok(Fetched {
+            url,
+            content_type,
+            body,
+        });
+    }
+
+    Err(SnapshotError::TooManyRedirects)
+}
+
+#[derive(Default)]
+/// Fetches the resources of a page while they fit within the limits of a snapshot
+struct Inliner {
+    bytes_left: usize,
+    resources_left: usize,
+    /// The URLs fetched so far, each is only fetched once
+    seen: HashSet<Url>,
+}
+
+impl Inliner {
+    /// Fetch the `urls` that haven't been fetched yet, keyed by the URL they were asked for.
+    /// Resources that fail, or don't fit, are left out.
+    async fn fetch_all(
+        &mut self,
+        client: &reqwest::Client,
+        config: &Config,
+        urls: Vec<Url>,
+    ) -> HashMap<Url, Fetched> {
+        let mut urls = urls
+            .into_iter()
+            .filter(|url| self.seen.insert(url.clone()))
+            .collect::<Vec<_>>();
+        urls.truncate(self.resources_left);
+        self.resources_left -= urls.len();
+
+        let max_bytes = self.bytes_left;
+        let mut fetched = stream::iter(urls)
+            .map(|url| async move {
+                let resource = fetch(client, config, url.clone(), max_bytes).await;
+                (url, resource)
+            })
+            .buffered(CONCURRENCY)
+            .collect::<Vec<_>>()
+            .await;
+
+        // Inlined as base64, which takes four bytes for every three
+        fetched.retain(|(_, resource)| match resource {
+            Ok(resource) if resource.body.len().div_ceil(3) * 4 <= self.bytes_left => {
+                self.bytes_left -= resource.body.len().div_ceil(3) * 4;
+                true
+            }
+            _ => false,
+        });
+
+        fetched
+            .into_iter()
+            .filter_map(|(url, resource)| Some((url, resource.ok()?)))
+            .collect()
+    }
+}
+
+#[derive(Default)]
+/// What a page links to that can be inlined into its snapshot
+struct Resources {
+    stylesheets: Vec<Url>,
+    images: Vec<Url>,
+    /// The contents of `<style>` elements and `style` attributes, which can link to resources of
+    /// their own
+    styles: Vec<String>,
+}
+
+fn find_resources(html: &str, base: &Url) -> Result<Resources, SnapshotError> {
+    let mut stylesheets = vec![];
+    let mut icons = vec![];
+    let mut images = vec![];
+    let mut styles = vec![];
+    let mut style_elements = vec![];
+    let mut style_text = String::new();
+
+    let element_content_handlers = vec![
+        (
+            Cow::Owned("link[rel][href]".parse().unwrap()),
+            ElementContentHandlers::default().element(|el| {
+                let href = el.get_attribute("href").unwrap_or_default();
+                let url = absolute_url(base, &href);
+
+                if has_rel(el, &["stylesheet"]) {
+                    stylesheets.extend(url);
+                } else if has_rel(el, &["icon"]) {
+                    icons.extend(url);
+                }
+
+                Ok(())
+            }),
+        ),
+        (
+            Cow::Owned("img[src], [poster]".parse().unwrap()),
+            ElementContentHandlers::default().element(|el| {
+                let src = el
+                    .get_attribute("src")
+                    .or_else(|| el.get_attribute("poster"))
+                    .unwrap_or_default();
+                images.extend(absolute_url(base, &src));
+
+                Ok(())
+            }),
+        ),
+        (
+            Cow::Owned("[style]".parse().unwrap()),
+            ElementContentHandlers::default().element(|el| {
+                styles.extend(el.get_attribute("style"));
+                Ok(())
+            }),
+        ),
+        (
+            Cow::Owned("style".parse().unwrap()),
+            ElementContentHandlers::default().text(|chunk| {
+                style_text.push_str(chunk.as_str());
+
+                if chunk.last_in_text_node() {
+                    style_elements.push(std::mem::take(&mut style_text));
+                }
+
+                Ok(())
+            }),
+        ),
+    ];
+
+    let mut rewriter = HtmlRewriter::new(
+        Settings {
+            element_content_handlers,
+            ..Settings::default()
+        },
+        |_: &[u8]| {},
+    );
+    rewriter.write(html.as_bytes())?;
+    rewriter.end()?;
+
+    images.extend(icons);
+    styles.extend(style_elements);
+
+    Ok(Resources {
+        stylesheets,
+        images,
+        styles,
+    })
+}
+
+/// The snapshot of `html`, with the fetched `stylesheets` and `data_urls` inlined
+fn rewrite(
+    config: &Config,
+    html: &str,
+    base: &Url,
+    stylesheets: &HashMap<Url, String>,
+    data_urls: &HashMap<Url, String>,
+) -> Result<String, SnapshotError> {
+    let mut style_text = String::new();
+
+    let mut element_content_handlers = script_handlers();
+
+    element_content_handlers.extend([
+        // Every URL is made absolute, so the page's base would only get in the way
+        (
+            Cow::Owned("base".parse::<Selector>().unwrap()),
+            ElementContentHandlers::default().element(|el| {
+                el.remove();
+                Ok(())
+            }),
+        ),
+        (
+            Cow::Owned("link[rel][href]".parse().unwrap()),
+            ElementContentHandlers::default().element(|el| {
+                if has_rel(el, &PRELOAD_RELS) {
+                    el.remove();
+                    return Ok(());
+                }
+
+                let href = el.get_attribute("href").unwrap_or_default();
+                let Some(url) = absolute_url(base, &href) else {
+                    return Ok(());
+                };
+
+                if let (true, Some(css)) = (has_rel(el, &["stylesheet"]), stylesheets.get(&url)) {
+                    let media = el
+                        .get_attribute("media")
+                        .map(|media| format!(r#" media="{}""#, media.replace('"', "&quot;")))
+                        .unwrap_or_default();
+                    let style = format!("<style{media}>{}</style>", escape_style(css));
+
+                    el.replace(&style, ContentType::Html);
+                } else if let Some(data_url) = data_urls.get(&url) {
+                    el.set_attribute("href", data_url)?;
+                }
+
+                Ok(())
+            }),
+        ),
+        (
+            Cow::Owned("img, source".parse().unwrap()),
+            ElementContentHandlers::default().element(|el| {
+                // Any of the candidates could be picked, but only `src` is inlined
+                el.remove_attribute("srcset");
+                el.remove_attribute("sizes");
+
+                Ok(())
+            }),
+        ),
+        (
+            Cow::Owned("picture > source".parse().unwrap()),
+            ElementContentHandlers::default().element(|el| {
+                el.remove();
+                Ok(())
+            }),
+        ),
+        (
+            Cow::Owned("img[src], [poster]".parse().unwrap()),
+            ElementContentHandlers::default().element(|el| {
+                let attribute = if el.has_attribute("src") {
+                    "src"
+                } else {
+                    "poster"
+                };
+                let src = el.get_attribute(attribute).unwrap_or_default();
+
+                if let Some(data_url) = absolute_url(base, &src).and_then(|url| data_urls.get(&url))
+                {
+                    el.set_attribute(attribute, data_url)?;
+                }
+
+                Ok(())
+            }),
+        ),
+        (
+            Cow::Owned("[style]".parse().unwrap()),
+            ElementContentHandlers::default().element(|el| {
+                let style = el.get_attribute("style").unwrap_or_default();
+                let style = inline_css(config, &style, base, data_urls);
+                el.set_attribute("style", &style)?;
+
+                Ok(())
+            }),
+        ),
+        (
+            Cow::Owned("style".parse().unwrap()),
+            ElementContentHandlers::default().text(|chunk| {
+                style_text.push_str(chunk.as_str());
+
+                if chunk.last_in_text_node() {
+                    let css = inline_css(config, &std::mem::take(&mut style_text), base, data_urls);
+                    chunk.replace(&escape_style(&css), ContentType::Html);
+                } else {
+                    chunk.remove();
+                }
+
+                Ok(())
+            }),
+        ),
+    ]);
+
+    element_content_handlers.extend(URL_ATTRIBUTES.iter().map(|attribute| {
+        (
+            Cow::Owned(format!("[{attribute}]").parse().unwrap()),
+            ElementContentHandlers::default().element(move |el| {
+                if el.removed() {
+                    return Ok(());
+                }
+
+                let value = el.get_attribute(attribute).unwrap_or_default();
+
+                if let Some(url) = absolute_url(base, &value) {
+                    el.set_attribute(attribute, &encode_url(config, url.as_str()))?;
+                }
+
+                Ok(())
+            }),
+        )
+    }));
+
+    let mut output = Vec::with_capacity(html.len());
+    let mut rewriter = HtmlRewriter::new(
+        Settings {
+            element_content_handlers,
+            ..Settings::default()
+        },
+        |c: &[u8]| output.extend_from_slice(c),
+    );
+    rewriter.write(html.as_bytes())?;
+    rewriter.end()?;
+
+    Ok(String::from_utf8_lossy(&output).into_owned())
+}
+
+/// Whether a `<link>` has any of the `rels`
+pub(crate) fn has_rel(el: &Element, rels: &[&str]) -> bool {
+    el.get_attribute("rel").is_some_and(|rel| {
+        rel.split_ascii_whitespace()
+            .any(|token| rels.iter().any(|rel| token.eq_ignore_ascii_case(rel)))
+    })
+}
+
+/// `url` resolved against `base`, when it points at something on the web. Fragments within the
+/// page, `data:` URLs and the like are left alone.
+pub(crate) fn absolute_url(base: &Url, url: &str) -> Option<Url> {
+    let url = url.trim();
+
+    if url.is_empty() || url.starts_with('#') || is_javascript_url(url) {
+        return None;
+    }
+
+    base.join(url)
+        .ok()
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+}
+
+/// The URLs in the `url()`s of `css`
+fn css_urls(css: &str, base: &Url) -> Vec<Url> {
+    CSS_URL
+        .captures_iter(css)
+        .filter_map(|captures| absolute_url(base, css_url(&captures)))
+        .collect()
+}
+
+/// `css` with the URLs of its `url()`s replaced by their `data_urls`, or else pointed at the proxy
+fn inline_css(config: &Config, css: &str, base: &Url, data_urls: &HashMap<Url, String>) -> String {
+    CSS_URL
+        .replace_all(css, |captures: &Captures| {
+            let Some(url) = absolute_url(base, css_url(captures)) else {
+                return captures[0].to_string();
+            };
+
+            match data_urls.get(&url) {
+                Some(data_url) => format!(r#"url("{data_url}")"#),
+                None => format!(r#"url("{}")"#, encode_url(config, url.as_str())),
+            }
+        })
+        .into_owned()
+}
+
+fn css_url<'a>(captures: &Captures<'a>) -> &'a str {
+    captures
+        .get(1)
+        .or_else(|| captures.get(2))
+        .or_else(|| captures.get(3))
+        .map(|url| url.as_str())
+        .unwrap_or_default()
+}
+
+/// Keeps CSS from closing the `<style>` element it is put in
+fn escape_style(css: &str) -> String {
+    css.replace("</", "<\\/")
+}
+
+fn data_url(resource: &Fetched) -> String {
+    let content_type = match resource.content_type.as_str() {
+        "" => "application/octet-stream",
+        content_type => content_type,
+    };
+
+    format!(
+        "data:{};base64,{}",
+        content_type.replace(' ', ""),
+        STANDARD.encode(&resource.body)
+    )
+}
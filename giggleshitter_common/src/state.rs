@@ -1,9 +1,33 @@
-use std::{net::SocketAddr, sync::Arc, usize};
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+};
 
+use arc_swap::ArcSwap;
 use base32::Alphabet;
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
 
-use super::rewriting::html::html_rewriter;
+use super::{
+    access::AccessControl,
+    api::keys::RateLimiter,
+    audit::AuditLog,
+    blocking::Blocker,
+    breaker::CircuitBreaker,
+    diagnostics::Diagnostics,
+    hooks::Hooks,
+    listener::ListenAddr,
+    metrics::Metrics,
+    plugins::Plugins,
+    proxy::{favicon::Favicons, prefetch::Prefetcher},
+    recording::Recorder,
+    rewriting::registry::RewriterRegistry,
+    rules::PathPattern,
+    upstream::ConnectionStats,
+    usage::Usage,
+};
 
 const fn default_padding() -> bool {
     false
@@ -55,38 +79,1288 @@ pub enum UrlEncodingAlgorithm {
 pub struct Config {
     /// The algorithm to encode the origin of the proxied host
     pub url_encoding_algorithm: UrlEncodingAlgorithm,
-    /// The listen address for the proxy server, where all proxied hosts will point to
-    pub host: SocketAddr,
+    /// Algorithms that were used before the current one, so that links shared before a rotation
+    /// keep working. Hosts are only decoded with these, in order, when the current algorithm
+    /// fails, and nothing is encoded with them.
+    #[serde(default)]
+    pub legacy_url_encoding_algorithms: Vec<UrlEncodingAlgorithm>,
+    /// XOR keys that were used with the alphabet of [`Config::url_encoding_algorithm`] before its
+    /// current key, newest first. Hosts are decoded with these after the current key and before
+    /// the legacy algorithms, while everything is encoded with the current key.
+    #[serde(default)]
+    pub previous_keys: Vec<Vec<u8>>,
+    /// The listen address for the proxy server, where all proxied hosts will point to. Either a
+    /// socket address, `unix:/path/to.sock` or `systemd` to use a socket passed by systemd.
+    pub host: ListenAddr,
     /// The public root domain to host the proxied hosts on, e.g. `example.com`
     pub public_host: String,
+    /// Where to keep a record of the proxied requests, auditing is disabled when unset
+    #[serde(default)]
+    pub audit: Option<AuditConfig>,
+    /// Per client bandwidth accounting and quotas
+    #[serde(default)]
+    pub bandwidth: BandwidthConfig,
+    /// Responses larger than this are streamed through without being rewritten, rather than
+    /// buffered into memory
+    #[serde(default = "default_max_rewrite_body_bytes")]
+    pub max_rewrite_body_bytes: u64,
+    /// Send bodies that fail to be rewritten as the upstream sent them, marked with an
+    /// `x-gs-rewrite-skipped` header, rather than an error page
+    #[serde(default)]
+    pub serve_unrewritten_on_error: bool,
+    /// Compression of responses that would otherwise be sent uncompressed
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    /// How log messages are printed and where they are written
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    /// Exporting traces of the proxied requests
+    #[serde(default)]
+    pub telemetry: TelemetryConfig,
+    /// Keeping search engines from indexing proxied sites under the public host
+    #[serde(default)]
+    pub crawlers: CrawlerConfig,
+    /// What to send upstream as the `Referer` of proxied requests
+    #[serde(default)]
+    pub referrer_policy: ReferrerPolicy,
+    /// Send upstreams their own origin in the `Origin` header instead of the proxied one, and map
+    /// `Access-Control-Allow-Origin` back to the proxied origin
+    #[serde(default = "default_translate_origin")]
+    pub translate_origin: bool,
+    /// Drop the headers that describe the client's device and settings, such as client hints,
+    /// `Device-Memory` and `DNT`, from upstream requests, so upstreams only see what a first-party
+    /// request carries. Upstreams aren't asked for client hints either.
+    #[serde(default)]
+    pub strip_fingerprinting_headers: bool,
+    /// The upstream hosts that may be proxied, including their subdomains. Every host is allowed
+    /// when unset.
+    #[serde(default)]
+    pub allowed_hosts: Option<Vec<String>>,
+    /// HTML appended to the `<head>` of every rewritten page
+    #[serde(default)]
+    pub inject_html: Option<String>,
+    /// Have the injected script present the upstream origin to page scripts, through
+    /// `document.domain`, `document.URL` and `window.origin`, and accept upstream URLs in
+    /// `history.pushState`. The address bar keeps showing the proxied URL.
+    #[serde(default = "default_spoof_origin")]
+    pub spoof_origin: bool,
+    /// More public hosts served by this instance, each with its own encoding and settings. The
+    /// top level public host keeps being served alongside them.
+    #[serde(default)]
+    pub tenants: Vec<Tenant>,
+    /// Also serve the API under this path on the bare public host, e.g. `/__api`, for DNS setups
+    /// that can't point `api.<public_host>` at the proxy
+    #[serde(default)]
+    pub api_path_prefix: Option<String>,
+    /// A directory of WebAssembly plugins, loaded at startup when built with the `plugins` feature
+    #[serde(default)]
+    pub plugins_dir: Option<PathBuf>,
+    /// Ad and tracker blocking with filter lists
+    #[serde(default)]
+    pub blocking: BlockingConfig,
+    /// Per site tweaks, applied in order
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    /// Headers added to the upstream requests of matching origins, such as credentials for
+    /// private services. Keyed by a glob like the origin of a [`Rule`], e.g. `internal.example.com`
+    /// or `https://*.example.com`, overriding any header of the same name the client sent. An empty
+    /// value removes the client's header.
+    #[serde(default)]
+    pub origin_headers: BTreeMap<String, BTreeMap<String, String>>,
+    /// Headers set on the proxied responses of matching origins, keyed like `origin_headers`, with
+    /// `*` matching every origin. They replace upstream headers of the same name, and an empty
+    /// value removes the header.
+    #[serde(default)]
+    pub response_headers: BTreeMap<String, BTreeMap<String, String>>,
+    /// The browser the upstreams of matching origins are told the client is, keyed like
+    /// `origin_headers` with `*` matching every origin. Every matching fingerprint is applied in
+    /// the order of the globs, so `*` comes first, and `origin_headers` still override them.
+    #[serde(default)]
+    pub fingerprints: BTreeMap<String, Fingerprint>,
+    /// Rewrite absolute URLs in the string values of `application/json` responses, for sites
+    /// whose APIs return media URLs. Only read at startup.
+    #[serde(default)]
+    pub rewrite_json: bool,
+    /// What the HTML rewriter does with `data:` URLs
+    #[serde(default)]
+    pub data_uris: DataUriConfig,
+    /// Also resolve relative URLs in rewritten documents against the page's upstream URL, rather
+    /// than leave them to the browser. Protocol-relative URLs are always resolved.
+    #[serde(default)]
+    pub resolve_relative_urls: bool,
+    /// What the HTML rewriter does with `<iframe>` elements
+    #[serde(default)]
+    pub frames: FrameConfig,
+    /// Downscaling, metadata stripping and format conversion of images, when built with the
+    /// `media` feature
+    #[serde(default)]
+    pub media: MediaConfig,
+    /// Resuming and limiting large downloads
+    #[serde(default)]
+    pub downloads: DownloadConfig,
+    /// Limits on proxied WebSocket connections
+    #[serde(default)]
+    pub websockets: WebSocketConfig,
+    /// How upstream hosts are resolved. Only read at startup.
+    #[serde(default)]
+    pub dns: DnsConfig,
+    /// How connections to upstream hosts are kept open and reused. Only read at startup.
+    #[serde(default)]
+    pub upstream: UpstreamConfig,
+    /// Remembering upstream hosts that only want HTTPS, to link to them over HTTPS right away
+    #[serde(default)]
+    pub hsts: HstsConfig,
+    /// Serving `ftp://` origins through the proxy
+    #[serde(default)]
+    pub ftp: FtpConfig,
+    /// Saving proxied pages as self-contained HTML through the API
+    #[serde(default)]
+    pub snapshots: SnapshotConfig,
+    /// Short links to proxied URLs, made through the API
+    #[serde(default)]
+    pub shortener: ShortenerConfig,
+    /// Recording proxied requests and responses into WARC files
+    #[serde(default)]
+    pub recording: RecordingConfig,
+    /// Serving the last good copy of a response when the upstream can't be reached
+    #[serde(default)]
+    pub offline: OfflineConfig,
+    /// Fetching the stylesheets and fonts of pages ahead of the browser asking for them
+    #[serde(default)]
+    pub prefetch: PrefetchConfig,
+    /// Refusing requests to upstream origins that keep failing, for a while
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+    /// Looking at the start of bodies whose declared content type can't be trusted
+    #[serde(default)]
+    pub sniffing: SniffingConfig,
+    /// Removing tracking parameters from the URLs that are requested and linked to
+    #[serde(default)]
+    pub tracking_params: TrackingParamsConfig,
+    /// How PDFs and office documents are served
+    #[serde(default)]
+    pub documents: DocumentConfig,
+    /// Answering `/favicon.ico` on proxied hosts
+    #[serde(default)]
+    pub favicon: FaviconConfig,
+    /// Keeping track of how long proxied requests take
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    /// Which clients may use the proxy
+    #[serde(default)]
+    pub access: AccessConfig,
+    /// Tie proxied URLs to the browser they were opened in
+    #[serde(default)]
+    pub sessions: SessionConfig,
+    /// Keys that API clients have to present to use the API. With none configured encoding URLs is
+    /// open to everyone, while the admin API is refused until a key with the Admin scope is
+    /// configured.
+    #[serde(default)]
+    pub api_keys: Vec<ApiKey>,
+    /// How to answer requests to hosts under the public host that aren't valid proxied addresses
+    #[serde(default)]
+    pub invalid_address: InvalidAddressBehavior,
+}
+
+fn default_spoof_origin() -> bool {
+    true
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+/// A tweak to the proxied requests matching all of its conditions, unset conditions match
+/// everything
+pub struct Rule {
+    /// A glob matched against the upstream host, or the whole origin if it contains `://`, e.g.
+    /// `*.example.com`
+    #[serde(default)]
+    pub origin: Option<String>,
+    /// A regular expression searched for in the upstream path
+    #[serde(default)]
+    pub path: Option<PathPattern>,
+    /// A glob matched against the MIME type of the response, e.g. `text/*`. Rules with a content
+    /// type are only applied once the upstream responded.
+    #[serde(default)]
+    pub content_type: Option<String>,
+    pub actions: Vec<RuleAction>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum RuleAction {
+    /// Refuse the request with a 403 page
+    Block,
+    /// Redirect to another URL, through the proxy
+    Redirect(String),
+    /// Add a header to the response
+    AddHeader(String, String),
+    /// Remove the HTML elements matching a CSS selector
+    RemoveElement(String),
+    /// Replace every occurrence of a string in the response body
+    ReplaceString(String, String),
+    /// Fit images within a width and height, instead of the limits of the media settings
+    ResizeImage(u32, u32),
+    /// Strip `<script>` elements, inline event handlers and `javascript:` URLs from HTML, and
+    /// forbid scripts with a `Content-Security-Policy` header, for reading untrusted sites safely
+    DisableScripts,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+/// A key for the API, sent in an `Authorization: Bearer <key>` or `x-gs-api-key` header. Keys are
+/// never taken from cookies or query strings, so other sites can't make a browser use one.
+pub struct ApiKey {
+    /// Identifies the key in logs and rate limits
+    pub name: String,
+    /// The SHA-256 hash of the key, hex encoded, so that the configuration doesn't hold the key
+    /// itself
+    pub sha256: String,
+    #[serde(default)]
+    pub scope: ApiScope,
+    /// How many requests the key may make each minute
+    #[serde(default)]
+    pub requests_per_minute: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+/// What an API key may be used for. Each scope includes the ones before it.
+pub enum ApiScope {
+    /// Encoding URLs
+    #[default]
+    Encode,
+    /// Encoding URLs and reading the server's statistics
+    Admin,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+/// Settings for session-scoped browsing. Visitors of the API host are given a signed session
+/// cookie, and proxied URLs are encoded with a key derived from their session, so links can't be
+/// shared, scraped or followed by other visitors.
+pub struct SessionConfig {
+    pub enabled: bool,
+    /// The secret that session cookies are signed with and encoding keys derived from. Changing it
+    /// ends every session.
+    pub secret: String,
+    pub cookie_name: String,
+    /// How long a session lasts, in seconds
+    pub max_age_secs: u64,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            secret: String::new(),
+            cookie_name: "gs_session".to_string(),
+            max_age_secs: 7 * 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+/// Rules for which clients may use the proxy, checked before anything is proxied. Clients on Unix
+/// sockets have no address and are always let in.
+pub struct AccessConfig {
+    /// Only let in clients in these networks, e.g. `"10.0.0.0/8"`, unless the list is empty
+    pub allow: Vec<IpNet>,
+    /// Refuse clients in these networks, even if they are allowed
+    pub deny: Vec<IpNet>,
+    /// A MaxMind GeoIP2 or GeoLite2 country database, needed for the country rules and only
+    /// used when built with the `geoip` feature. Only read at startup.
+    pub geoip_database: Option<PathBuf>,
+    /// Only let in clients located in these countries, as ISO codes such as `"NL"`, unless the
+    /// list is empty
+    pub allow_countries: Vec<String>,
+    /// Refuse clients located in these countries
+    pub deny_countries: Vec<String>,
+    /// Shown to refused clients instead of the default explanation
+    pub denial_message: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+/// Settings for the connections of the upstream clients. Tuning them helps against slow
+/// upstreams, where setting up a connection costs more than the request itself.
+pub struct UpstreamConfig {
+    /// Send small writes right away instead of batching them
+    pub tcp_nodelay: bool,
+    /// Probe idle connections at this interval, so that dead ones are noticed
+    pub tcp_keepalive_secs: Option<u64>,
+    /// Close pooled connections that have been idle this long
+    pub pool_idle_timeout_secs: Option<u64>,
+    /// Keep at most this many idle connections open per host, unlimited when unset
+    pub pool_max_idle_per_host: Option<usize>,
+    /// Ping HTTP/2 connections at this interval to keep them open
+    pub http2_keep_alive_interval_secs: Option<u64>,
+    /// Close HTTP/2 connections whose ping isn't answered within this many seconds
+    pub http2_keep_alive_timeout_secs: u64,
+    /// Also ping HTTP/2 connections that have no requests in flight
+    pub http2_keep_alive_while_idle: bool,
+    /// Send header names to HTTP/1 upstreams in title case, such as `Content-Type`, for servers
+    /// that don't take them lowercase
+    pub title_case_headers: bool,
+    /// Which address family to connect to upstream hosts over
+    pub ip_preference: IpPreference,
+    /// Follow up to this many upstream redirects on the proxy and return where they lead, rather
+    /// than passing each redirect on to the browser. Only redirects within the origin are
+    /// followed, those to other origins are passed on so that the page is served on its own
+    /// proxied host. Redirects are passed on when unset.
+    pub follow_redirects: Option<usize>,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+/// The order upstream addresses are tried in. Whichever family comes first is connected to, and
+/// the other one is raced against it if the connection hasn't been set up within 300ms.
+pub enum IpPreference {
+    /// Try the addresses in the order the resolver returned them
+    #[default]
+    HappyEyeballs,
+    /// Try IPv4 addresses first
+    PreferIpv4,
+    /// Try IPv6 addresses first
+    PreferIpv6,
+    /// Only connect over IPv4, for hosts with broken AAAA records
+    Ipv4Only,
+    /// Only connect over IPv6
+    Ipv6Only,
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        Self {
+            tcp_nodelay: true,
+            tcp_keepalive_secs: None,
+            pool_idle_timeout_secs: Some(90),
+            pool_max_idle_per_host: None,
+            http2_keep_alive_interval_secs: None,
+            http2_keep_alive_timeout_secs: 20,
+            http2_keep_alive_while_idle: false,
+            title_case_headers: false,
+            ip_preference: IpPreference::default(),
+            follow_redirects: None,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+/// Settings for remembering upstream hosts that send `Strict-Transport-Security` or redirect plain
+/// HTTP to HTTPS. Links to `http://` URLs on those hosts are then encoded as `https://`, and
+/// requests for their plain HTTP origin are redirected, instead of bouncing through the upstream's
+/// redirect every time.
+pub struct HstsConfig {
+    pub enabled: bool,
+    /// How long a host that redirected to HTTPS is remembered, as such a redirect doesn't say
+    pub redirect_max_age_secs: u64,
+}
+
+impl Default for HstsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            redirect_max_age_secs: 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+/// Settings for the FTP gateway, which fetches files from `ftp://` origins in passive mode and
+/// renders their directories as HTML listings
+pub struct FtpConfig {
+    pub enabled: bool,
+    pub user: String,
+    /// Anonymous FTP servers conventionally take an email address here
+    pub password: String,
+    /// How long to wait for the server to answer a command or accept a data connection
+    pub timeout_secs: u64,
+}
+
+impl Default for FtpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            user: "anonymous".to_string(),
+            password: "anonymous@".to_string(),
+            timeout_secs: 30,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+/// Settings for snapshots, which save a page with its stylesheets and images inlined as a single
+/// HTML document
+pub struct SnapshotConfig {
+    /// Where snapshots are kept when they are asked to be stored, storing them is refused when
+    /// unset
+    pub dir: Option<PathBuf>,
+    /// The largest snapshot, counting the page and everything inlined into it. Resources that
+    /// would go over it are linked through the proxy instead.
+    pub max_bytes: usize,
+    /// The most stylesheets and images fetched for one snapshot
+    pub max_resources: usize,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            max_bytes: 20 * 1024 * 1024,
+            max_resources: 100,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+/// Settings for short links, `https://<public host>/s/<id>` redirecting to a proxied URL, which
+/// are easier to share than long encoded hosts
+pub struct ShortenerConfig {
+    /// Where the links are kept, making them is refused when unset
+    pub dir: Option<PathBuf>,
+    /// How many characters new IDs have
+    pub id_length: usize,
+}
+
+impl Default for ShortenerConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            id_length: 7,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+/// Settings for recording proxied exchanges into WARC files, which are listed and downloaded
+/// through the admin API
+pub struct RecordingConfig {
+    /// Where the WARC files are written, recording is disabled when unset
+    pub dir: Option<PathBuf>,
+    /// Record every exchange with the origins matching one of these globs, like the keys of
+    /// [`Config::origin_headers`]
+    pub origins: Vec<String>,
+    /// Let clients start sessions that are recorded, with `POST /session?record=true` on the API
+    pub sessions: bool,
+    /// Start a new file once the current one would grow past this many bytes
+    pub max_file_bytes: u64,
+    /// Delete the oldest files beyond this many
+    pub max_files: usize,
+    /// Cut off recorded request and response bodies after this many bytes, marking them as
+    /// truncated. The whole bodies are still sent on, and only this much of a request body is
+    /// held in memory before it is streamed to the upstream.
+    pub max_body_bytes: usize,
+    /// Record the cookies and authorization headers of the users, and the cookies the upstream
+    /// sets for them. They are redacted otherwise, as anyone with an admin key can download the
+    /// recordings.
+    pub credentials: bool,
+}
+
+impl Default for RecordingConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            origins: vec![],
+            sessions: false,
+            max_file_bytes: 1024 * 1024 * 1024,
+            max_files: 10,
+            max_body_bytes: 16 * 1024 * 1024,
+            credentials: false,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+/// Settings for offline replay, which keeps the last good copy of the responses of chosen origins
+/// on disk and serves it, with a banner on pages, while the upstream can't be reached
+pub struct OfflineConfig {
+    /// Where the copies are kept, offline replay is disabled when unset
+    pub dir: Option<PathBuf>,
+    /// The origins whose responses are kept, globs like the keys of [`Config::origin_headers`]
+    pub origins: Vec<String>,
+    /// Responses larger than this aren't kept
+    pub max_body_bytes: usize,
+}
+
+impl Default for OfflineConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            origins: vec![],
+            max_body_bytes: 8 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+/// Settings for subresource prefetching. The first stylesheets and fonts a rewritten page links to
+/// on its own origin are fetched while the page is sent, and the page is answered with `Link`
+/// preload headers so that the browser asks for them before it parsed the page. Pages requested
+/// with cookies or credentials aren't prefetched from, and prefetched responses are only handed to
+/// requests without them.
+pub struct PrefetchConfig {
+    pub enabled: bool,
+    /// How many subresources of a page are prefetched at most
+    pub max_resources: usize,
+    /// Responses larger than this aren't kept
+    pub max_body_bytes: usize,
+    /// How long a prefetched response is kept for the browser to ask for it
+    pub ttl_secs: u64,
+}
+
+impl Default for PrefetchConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_resources: 8,
+            max_body_bytes: 1024 * 1024,
+            ttl_secs: 30,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+/// Settings for the circuit breaker, which answers requests to an upstream origin with a 503 page
+/// after it failed several times in a row, instead of waiting on a dead upstream for every one
+pub struct CircuitBreakerConfig {
+    pub enabled: bool,
+    /// How many requests in a row have to fail before the origin's circuit opens. Requests fail
+    /// when the upstream can't be reached, or answers with a 502, 503 or 504.
+    pub failure_threshold: u32,
+    /// How long requests are refused once the circuit opened, before one is let through to see if
+    /// the origin recovered
+    pub cooldown_secs: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            failure_threshold: 5,
+            cooldown_secs: 30,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+/// Settings for guessing what a body holds from its first chunk, when the content type it was
+/// served with can't be trusted. Only uncompressed `200 OK` responses are looked at.
+pub struct SniffingConfig {
+    /// Rewrite bodies served as `text/plain` or without a content type that start like an HTML
+    /// document, as browsers may render them as HTML
+    pub html: bool,
+    /// Never rewrite bodies that hold control characters only found in binary data, whatever
+    /// content type they were served with
+    pub binary_guard: bool,
+    /// How many bytes of the first chunk are looked at
+    pub bytes: usize,
+}
+
+impl Default for SniffingConfig {
+    fn default() -> Self {
+        Self {
+            html: false,
+            binary_guard: true,
+            bytes: 512,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+/// Settings for stripping the query parameters that sites and ad networks track clicks with from
+/// upstream requests and proxied links
+pub struct TrackingParamsConfig {
+    pub enabled: bool,
+    /// The parameter names that are removed, ignoring case. A name ending in `*` removes every
+    /// parameter that starts with the rest of it.
+    pub params: Vec<String>,
+}
+
+impl Default for TrackingParamsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            params: [
+                "utm_*", "fbclid", "gclid", "dclid", "gbraid", "wbraid", "msclkid", "yclid",
+                "twclid", "ttclid", "igshid", "mc_cid", "mc_eid", "_hsenc", "_hsmi", "mkt_tok",
+            ]
+            .map(String::from)
+            .to_vec(),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+/// Settings for PDFs and office documents. Browsers show them on the proxied origin, where the
+/// scripts and links a document can hold run with the proxy's cookies for that origin.
+pub struct DocumentConfig {
+    pub handling: DocumentHandling,
+    /// Globs of the content types that are documents, ignoring case
+    pub content_types: Vec<String>,
+    /// POST PDFs to this URL and serve what it answers with instead, inline, such as a service
+    /// that flattens them. PDFs it fails on, or larger than `max_rewrite_body_bytes`, are sent as
+    /// attachments.
+    pub pdf_sanitizer: Option<String>,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+/// How documents that aren't sanitized are served
+pub enum DocumentHandling {
+    /// As the upstream sent them
+    #[default]
+    Inline,
+    /// With `Content-Disposition: attachment`, so that they're downloaded rather than shown
+    Attachment,
+}
+
+impl Default for DocumentConfig {
+    fn default() -> Self {
+        Self {
+            handling: DocumentHandling::default(),
+            content_types: [
+                "application/pdf",
+                "application/msword",
+                "application/vnd.ms-excel",
+                "application/vnd.ms-powerpoint",
+                "application/vnd.openxmlformats-officedocument.*",
+                "application/vnd.oasis.opendocument.*",
+                "application/rtf",
+            ]
+            .map(String::from)
+            .to_vec(),
+            pdf_sanitizer: None,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+/// Settings for `/favicon.ico`, which browsers ask every proxied host for whether or not the page
+/// links to an icon. Origins' icons are fetched once and kept, and the default icon is served for
+/// origins without one and for hosts that aren't proxied addresses.
+pub struct FaviconConfig {
+    pub enabled: bool,
+    /// How long an origin's icon, or that it has none, is kept
+    pub ttl_secs: u64,
+    /// Icons larger than this are taken as missing
+    pub max_bytes: usize,
+    /// An `.ico`, `.png`, `.gif` or `.svg` file to serve when there is no icon, a bare 404 is
+    /// served when unset
+    pub default_icon: Option<PathBuf>,
+}
+
+impl Default for FaviconConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ttl_secs: 24 * 60 * 60,
+            max_bytes: 256 * 1024,
+            default_icon: None,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+/// Settings for the request latencies served at `/stats` on the admin API. Latencies are measured
+/// until the response is ready to be sent, so streaming a body to the client isn't counted.
+pub struct MetricsConfig {
+    /// Requests that take at least this many milliseconds are added to the slow request log
+    pub slow_request_ms: u64,
+    /// How many of the most recent slow requests are kept, none when set to 0
+    pub slow_log_size: usize,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            slow_request_ms: 2000,
+            slow_log_size: 100,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+/// Settings for resolving upstream hosts, for operators whose system resolver is censored or
+/// leaks what is being proxied. With nothing set the system resolver is used.
+pub struct DnsConfig {
+    /// The servers to ask instead of the system resolver, in order of preference
+    pub servers: Vec<DnsServer>,
+    /// Fixed addresses for upstream hosts, which are never looked up
+    pub static_records: BTreeMap<String, Vec<IpAddr>>,
+    /// How many answers from the servers to cache, each for as long as its TTL allows
+    pub cache_size: usize,
+    /// Cache answers for at least this many seconds, even if their TTL is shorter
+    pub min_ttl_secs: Option<u64>,
+    /// Cache answers for at most this many seconds, even if their TTL is longer
+    pub max_ttl_secs: Option<u64>,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            servers: vec![],
+            static_records: BTreeMap::new(),
+            cache_size: 1024,
+            min_ttl_secs: None,
+            max_ttl_secs: None,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum DnsServer {
+    /// Plain DNS over UDP, and TCP for large answers, e.g. `Plain("9.9.9.9:53")`
+    Plain(SocketAddr),
+    /// DNS over TLS with the server's certificate name, e.g.
+    /// `Tls("1.1.1.1:853", "cloudflare-dns.com")`
+    Tls(SocketAddr, String),
+    /// DNS over HTTPS with the server's certificate name, e.g.
+    /// `Https("1.1.1.1:443", "cloudflare-dns.com")`
+    Https(SocketAddr, String),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+/// Settings for responses that are streamed through to the client, such as file downloads
+pub struct DownloadConfig {
+    /// How often to ask the upstream for the rest of a response whose body broke off, for
+    /// upstreams that serve byte ranges. The client only sees the connection fail once these run
+    /// out.
+    pub resume_attempts: u32,
+    /// Refuse responses larger than this many bytes, and cut off those that turn out larger than
+    /// they claimed
+    pub max_bytes: Option<u64>,
+    /// Fail a response once its upstream has sent nothing of the body for this many seconds,
+    /// rather than holding on to the client's connection. A stall counts as the body breaking
+    /// off, so downloads that can be resumed are.
+    pub stall_timeout_secs: Option<u64>,
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        Self {
+            resume_attempts: 3,
+            max_bytes: None,
+            stall_timeout_secs: Some(60),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+/// Limits applied to each proxied WebSocket connection, so that a single connection can't tie up
+/// the proxy indefinitely. Connections going over a limit are closed.
+pub struct WebSocketConfig {
+    /// The largest message or frame accepted in either direction
+    pub max_message_bytes: usize,
+    /// How many messages the client may send per second
+    pub max_messages_per_sec: Option<u32>,
+    /// Close connections that have been open this long
+    pub max_lifetime_secs: Option<u64>,
+    /// Close connections that haven't carried a message in either direction for this long
+    pub idle_timeout_secs: Option<u64>,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            max_message_bytes: 16 * 1024 * 1024,
+            max_messages_per_sec: None,
+            max_lifetime_secs: None,
+            idle_timeout_secs: None,
+        }
+    }
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+/// Headers sent upstream in place of the client's, to present the same browser to a site whoever
+/// uses it, or a desktop browser to sites that are unusable on mobile
+pub struct Fingerprint {
+    /// Sent as the `User-Agent`. The client's own `Sec-CH-UA` client hints are dropped along with
+    /// its user agent, as they would give the real browser away.
+    pub user_agent: Option<String>,
+    /// Sent as the `Accept-Language`
+    pub accept_language: Option<String>,
+    /// Client hints sent in place of the client's, by header name, e.g.
+    /// `{"Sec-CH-UA-Mobile": "?0", "Sec-CH-UA-Platform": "\"Windows\""}`
+    pub client_hints: BTreeMap<String, String>,
+    /// Only apply to clients on mobile devices, by their `Sec-CH-UA-Mobile` or a user agent with
+    /// `Mobi` in it
+    pub mobile_only: bool,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+/// Settings for `data:` URLs in rewritten pages. They are never sent through the proxy, and neither
+/// are `blob:` URLs, which belong to the page that created them.
+pub struct DataUriConfig {
+    /// Drop `data:` URLs longer than this from pages, leaving the element without the attribute
+    pub max_bytes: Option<usize>,
+    /// Drop `data:` URLs of HTML documents, which would otherwise run their own scripts in frames
+    /// that aren't rewritten
+    pub block_html: bool,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+/// Settings for `<iframe>` elements in rewritten pages. Their `src` is proxied either way.
+pub struct FrameConfig {
+    pub sandbox: FrameSandbox,
+    /// What to do with frames whose `src` is on another host than the page
+    pub third_party: ThirdPartyFrames,
+}
+
+#[derive(Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+/// What to do with the `sandbox` attribute of frames
+pub enum FrameSandbox {
+    /// Leave it as the page has it
+    #[default]
+    Keep,
+    /// Remove it, for pages whose frames break under the proxy's origin
+    Remove,
+    /// Set it on every frame, e.g. to `allow-scripts allow-forms`. An empty value gives frames no
+    /// permissions at all.
+    Set(String),
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+/// What to do with frames loading another host than their page, such as embedded videos and ads
+pub enum ThirdPartyFrames {
+    #[default]
+    Allow,
+    /// Remove them from the page
+    Block,
+    /// Replace them with a button that loads the frame when clicked
+    ClickToLoad,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+/// Settings for transforming JPEG and PNG images on their way to the client, to save bandwidth
+/// for mobile users. Only applied when built with the `media` feature.
+pub struct MediaConfig {
+    pub enabled: bool,
+    /// Downscale images wider than this many pixels, keeping their aspect ratio
+    pub max_width: Option<u32>,
+    /// Downscale images taller than this many pixels, keeping their aspect ratio
+    pub max_height: Option<u32>,
+    /// Encode every image again, dropping EXIF and other metadata
+    pub strip_metadata: bool,
+    /// Convert images to AVIF or WebP when the client accepts them and the result is smaller
+    pub negotiate_formats: bool,
+    /// The quality of lossy encodings, from 1 to 100
+    pub quality: u8,
+}
+
+impl Default for MediaConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_width: None,
+            max_height: None,
+            strip_metadata: true,
+            negotiate_formats: true,
+            quality: 75,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+/// Settings for blocking ads and trackers with EasyList/uBlock style filter lists. Requests
+/// matching a network filter are refused before reaching the upstream, and element hiding
+/// filters are injected into rewritten pages as CSS.
+pub struct BlockingConfig {
+    pub enabled: bool,
+    /// The filter lists, as HTTP(S) URLs or file paths. They are read again on every update.
+    pub lists: Vec<String>,
+    /// How often to update the filter lists, in seconds
+    pub update_interval_secs: u64,
+    /// Upstream sites, including their subdomains, that nothing is blocked or hidden on
+    pub exempt_hosts: Vec<String>,
+    /// Also inject the element hiding filters that apply to every site, which adds a large
+    /// stylesheet to every page with the usual lists
+    pub generic_hiding: bool,
+}
+
+impl Default for BlockingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            lists: vec![],
+            update_interval_secs: 24 * 60 * 60,
+            exempt_hosts: vec![],
+            generic_hiding: false,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+/// A public host served next to the top level one, for hosting several proxy brands on one
+/// instance. Everything else is shared with the top level configuration.
+pub struct Tenant {
+    /// The public root domain of this tenant
+    pub public_host: String,
+    /// The algorithm to encode origins under this tenant's public host
+    pub url_encoding_algorithm: UrlEncodingAlgorithm,
+    /// Like [`Config::legacy_url_encoding_algorithms`], for this tenant
+    #[serde(default)]
+    pub legacy_url_encoding_algorithms: Vec<UrlEncodingAlgorithm>,
+    /// Like [`Config::previous_keys`], for this tenant
+    #[serde(default)]
+    pub previous_keys: Vec<Vec<u8>>,
+    /// Like [`Config::allowed_hosts`], for this tenant
+    #[serde(default)]
+    pub allowed_hosts: Option<Vec<String>>,
+    /// Like [`Config::inject_html`], for this tenant
+    #[serde(default)]
+    pub inject_html: Option<String>,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+/// How the `Referer` header of a proxied request is presented to the upstream
+pub enum ReferrerPolicy {
+    /// Never send a `Referer`
+    #[default]
+    Strip,
+    /// Send the root of the upstream origin, whatever the client sent
+    OriginRoot,
+    /// Decode a proxied referring URL back into the real URL it was loaded from, dropping referers
+    /// from outside the proxy
+    Translate,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+/// How to answer a request to a host that doesn't decode to an upstream origin
+pub enum InvalidAddressBehavior {
+    /// Show a page explaining that the link is broken, with a 404 status
+    #[default]
+    Page,
+    /// Redirect to the landing page of the API host
+    RedirectToLanding,
+    /// Respond with an empty 404
+    NotFound,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+/// Settings for how proxied sites present themselves to search engine crawlers
+pub struct CrawlerConfig {
+    /// Answer `/robots.txt` on every proxied host with a policy that disallows everything, instead
+    /// of the upstream's
+    pub disallow_all: bool,
+    /// An `X-Robots-Tag` header added to every proxied response, e.g. `noindex, nofollow`
+    pub x_robots_tag: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+/// Settings for logging. They are read once at startup, changes are only picked up on restart.
+pub struct LoggingConfig {
+    pub format: LogFormat,
+    /// Which messages to log, as `tracing` filter directives such as
+    /// `"info,giggleshitter_common::proxy=debug,hyper=warn"`. Overridden by the `RUST_LOG`
+    /// environment variable.
+    pub filter: String,
+    /// Also write log messages to files, rotated as configured
+    pub file: Option<LogFileConfig>,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::default(),
+            filter: "info".to_string(),
+            file: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+/// How log messages are formatted
+pub enum LogFormat {
+    /// One line per message, with its span context
+    #[default]
+    Full,
+    /// One line per message, leaving out most of the span context
+    Compact,
+    /// Messages spread over multiple lines, for reading during development
+    Pretty,
+    /// One JSON object per line, for log collectors
+    Json,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+/// Settings for writing log messages to files
+pub struct LogFileConfig {
+    /// The directory the files are written to, created if it doesn't exist
+    pub directory: PathBuf,
+    /// The start of the file names, which end with the date and time they were started at
+    #[serde(default = "default_log_file_prefix")]
+    pub prefix: String,
+    #[serde(default)]
+    pub rotation: LogRotation,
+    /// Delete the oldest files when there are more than this many
+    #[serde(default)]
+    pub max_files: Option<usize>,
+    /// The format of the files, the same as the console when unset
+    #[serde(default)]
+    pub format: Option<LogFormat>,
+}
+
+impl LogFileConfig {
+    /// Daily rotated files in `directory`, with the default names and format
+    pub fn in_directory(directory: PathBuf) -> Self {
+        Self {
+            directory,
+            prefix: default_log_file_prefix(),
+            rotation: LogRotation::default(),
+            max_files: None,
+            format: None,
+        }
+    }
+}
+
+fn default_log_file_prefix() -> String {
+    "giggleshitter.log".to_string()
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+/// How often to start a new log file
+pub enum LogRotation {
+    Minutely,
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+/// Settings for tracing. Spans are exported over OTLP when built with the `otel` feature.
+pub struct TelemetryConfig {
+    /// The OTLP gRPC endpoint spans are exported to, e.g. `http://localhost:4317`. Spans are
+    /// only printed when unset.
+    pub otlp_endpoint: Option<String>,
+    /// The `service.name` resource attribute of exported spans
+    pub service_name: String,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: None,
+            service_name: "giggleshitter".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+/// Settings for compressing proxied responses, using the best algorithm the client accepts.
+/// Upstream bodies are decompressed when received, so this also applies to pass-through responses.
+pub struct CompressionConfig {
+    pub enabled: bool,
+    pub gzip: bool,
+    pub br: bool,
+    pub zstd: bool,
+    /// The compression level, interpreted by each algorithm and clamped to its maximum. Uses the
+    /// algorithm's default when unset.
+    pub level: Option<i32>,
+    /// Responses smaller than this many bytes are sent uncompressed
+    pub min_size_bytes: u64,
+    /// Forward compressed upstream bodies untouched when they don't need to be rewritten, instead
+    /// of decompressing them on the proxy. The upstream is only offered the encodings the client
+    /// accepts.
+    pub passthrough: bool,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            gzip: true,
+            br: true,
+            zstd: true,
+            level: None,
+            min_size_bytes: 1024,
+            passthrough: false,
+        }
+    }
+}
+
+const fn default_max_rewrite_body_bytes() -> u64 {
+    16 * 1024 * 1024
+}
+
+const fn default_translate_origin() -> bool {
+    true
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+/// Settings for the per client bandwidth accounting, which counts the request and response bodies
+/// of every proxied request
+pub struct BandwidthConfig {
+    /// How many bytes a client may transfer per day (UTC) before further requests are refused,
+    /// unlimited when unset
+    pub daily_quota_bytes: Option<u64>,
+    /// A file the counters are saved to, so that they survive restarts
+    pub persist_path: Option<PathBuf>,
+    /// How often to save the counters, in seconds
+    pub persist_interval_secs: u64,
+}
+
+impl Default for BandwidthConfig {
+    fn default() -> Self {
+        Self {
+            daily_quota_bytes: None,
+            persist_path: None,
+            persist_interval_secs: 60,
+        }
+    }
+}
+
+const fn default_audit_max_bytes() -> u64 {
+    16 * 1024 * 1024
+}
+
+const fn default_audit_max_files() -> usize {
+    8
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+/// Settings for the audit log of proxied requests, written as one JSON record per line
+pub struct AuditConfig {
+    /// The file records are appended to
+    pub path: PathBuf,
+    /// Rotate the file once it grows past this many bytes
+    #[serde(default = "default_audit_max_bytes")]
+    pub max_bytes: u64,
+    /// How many rotated files to keep next to the current one, older files are deleted
+    #[serde(default = "default_audit_max_files")]
+    pub max_files: usize,
+    /// Mixed into the client IP hashes, so that they can't be reversed by hashing every address
+    #[serde(default)]
+    pub ip_salt: String,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
             url_encoding_algorithm: UrlEncodingAlgorithm::Base32(Alphabet::Z),
-            host: SocketAddr::from(([0, 0, 0, 0], 3069)),
+            legacy_url_encoding_algorithms: vec![],
+            previous_keys: vec![],
+            host: SocketAddr::from(([0, 0, 0, 0], 3069)).into(),
             public_host: "changeme.local".to_string(),
+            audit: None,
+            bandwidth: BandwidthConfig::default(),
+            max_rewrite_body_bytes: default_max_rewrite_body_bytes(),
+            serve_unrewritten_on_error: false,
+            compression: CompressionConfig::default(),
+            logging: LoggingConfig::default(),
+            telemetry: TelemetryConfig::default(),
+            crawlers: CrawlerConfig::default(),
+            referrer_policy: ReferrerPolicy::default(),
+            translate_origin: default_translate_origin(),
+            strip_fingerprinting_headers: false,
+            allowed_hosts: None,
+            inject_html: None,
+            spoof_origin: default_spoof_origin(),
+            tenants: vec![],
+            api_path_prefix: None,
+            plugins_dir: None,
+            blocking: BlockingConfig::default(),
+            rules: vec![],
+            origin_headers: BTreeMap::new(),
+            response_headers: BTreeMap::new(),
+            fingerprints: BTreeMap::new(),
+            rewrite_json: false,
+            data_uris: DataUriConfig::default(),
+            resolve_relative_urls: false,
+            frames: FrameConfig::default(),
+            media: MediaConfig::default(),
+            downloads: DownloadConfig::default(),
+            websockets: WebSocketConfig::default(),
+            dns: DnsConfig::default(),
+            upstream: UpstreamConfig::default(),
+            hsts: HstsConfig::default(),
+            ftp: FtpConfig::default(),
+            snapshots: SnapshotConfig::default(),
+            shortener: ShortenerConfig::default(),
+            recording: RecordingConfig::default(),
+            offline: OfflineConfig::default(),
+            prefetch: PrefetchConfig::default(),
+            circuit_breaker: CircuitBreakerConfig::default(),
+            sniffing: SniffingConfig::default(),
+            tracking_params: TrackingParamsConfig::default(),
+            documents: DocumentConfig::default(),
+            favicon: FaviconConfig::default(),
+            metrics: MetricsConfig::default(),
+            access: AccessConfig::default(),
+            sessions: SessionConfig::default(),
+            api_keys: vec![],
+            invalid_address: InvalidAddressBehavior::default(),
         }
     }
 }
 
+/// A configuration that can be swapped out while the server is running, requests pick up the new
+/// configuration as soon as it is stored
+pub type LiveConfig = Arc<ArcSwap<Config>>;
+
 #[derive(Clone)]
 /// The state that is passed to frontend routes
-pub struct APIState {
-    pub config: Arc<Config>,
+pub(crate) struct APIState {
+    /// The live configuration, which the admin API changes the access rules of
+    pub config: LiveConfig,
+    pub usage: Usage,
+    pub access: AccessControl,
+    pub rate_limiter: RateLimiter,
+    pub connections: ConnectionStats,
+    pub breaker: CircuitBreaker,
+    pub metrics: Metrics,
+    pub diagnostics: Diagnostics,
+    /// The connection settings of the upstream clients, as they were built at startup
+    pub upstream: UpstreamConfig,
+    /// The upstream client, for fetching the pages that are saved as snapshots
+    pub client: reqwest::Client,
 }
 
 #[derive(Clone)]
 /// The state that is passed to the proxy handler
-pub struct ProxyState {
-    pub config: Arc<Config>,
+pub(crate) struct ProxyState {
     pub client: reqwest::Client,
-    pub html_rewriter: Arc<html_rewriter::HtmlRewriter>,
+    /// The client used in pass-through mode, which leaves response bodies compressed
+    pub passthrough_client: reqwest::Client,
+    pub rewriters: RewriterRegistry,
+    pub hooks: Hooks,
+    pub plugins: Plugins,
+    pub blocker: Blocker,
+    pub access: AccessControl,
+    pub audit: AuditLog,
+    pub recorder: Recorder,
+    pub prefetcher: Prefetcher,
+    pub favicons: Favicons,
+    pub usage: Usage,
+    pub connections: ConnectionStats,
+    pub breaker: CircuitBreaker,
+    pub metrics: Metrics,
+    pub diagnostics: Diagnostics,
 }
 
 #[derive(Clone)]
 /// The shared state that is passed to the hostname router
-pub struct SharedState {
-    pub config: Arc<Config>,
+pub(crate) struct SharedState {
+    pub config: LiveConfig,
 }
@@ -0,0 +1,89 @@
+use crate::{
+    error::Result,
+    logging::{BoxedLayer, Filter},
+    state::TelemetryConfig,
+};
+
+#[cfg(not(feature = "otel"))]
+use scorched::{logf, LogData, LogImportance};
+
+/// Flushes the spans that haven't been exported yet when dropped, keep it alive for as long as
+/// the process serves requests
+pub struct TelemetryGuard {
+    #[cfg(feature = "otel")]
+    provider: Option<opentelemetry_sdk::trace::TracerProvider>,
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = self.provider.take() {
+            let _ = provider.shutdown();
+        }
+    }
+}
+
+/// The layer exporting spans over OTLP when an endpoint is configured, along with the guard that
+/// flushes them
+pub(crate) fn layer(
+    config: &TelemetryConfig,
+    filter: Filter,
+) -> Result<(Option<BoxedLayer>, TelemetryGuard)> {
+    #[cfg(feature = "otel")]
+    {
+        use opentelemetry::trace::TracerProvider as _;
+        use tracing_subscriber::Layer;
+
+        let provider = config
+            .otlp_endpoint
+            .as_deref()
+            .map(|endpoint| otlp_provider(endpoint, &config.service_name))
+            .transpose()?;
+
+        let otel = provider.as_ref().map(|provider| {
+            tracing_opentelemetry::layer()
+                .with_tracer(provider.tracer("giggleshitter"))
+                .with_filter(filter)
+                .boxed()
+        });
+
+        Ok((otel, TelemetryGuard { provider }))
+    }
+
+    #[cfg(not(feature = "otel"))]
+    {
+        let _ = filter;
+
+        if let Some(endpoint) = &config.otlp_endpoint {
+            logf!(
+                Warning,
+                "Not exporting spans to {}, this build does not include the otel feature",
+                endpoint
+            );
+        }
+
+        Ok((None, TelemetryGuard {}))
+    }
+}
+
+#[cfg(feature = "otel")]
+fn otlp_provider(
+    endpoint: &str,
+    service_name: &str,
+) -> Result<opentelemetry_sdk::trace::TracerProvider> {
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{runtime, trace, Resource};
+
+    Ok(opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(trace::Config::default().with_resource(Resource::new(vec![
+            KeyValue::new("service.name", service_name.to_string()),
+        ])))
+        .install_batch(runtime::Tokio)?)
+}
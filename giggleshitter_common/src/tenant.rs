@@ -0,0 +1,67 @@
+use std::sync::Arc;
+
+use crate::state::Config;
+
+#[derive(Clone)]
+/// The configuration of the tenant serving a request, inserted into the request extensions by
+/// the hostname router. Handlers should read the public host, encoding and per tenant settings
+/// from here rather than from the live configuration.
+pub struct TenantConfig(pub Arc<Config>);
+
+impl Config {
+    /// The configuration as seen by the tenant whose public host `host` falls under, preferring
+    /// the longest matching public host. The top level public host is the default tenant, and is
+    /// also used when no tenant matches.
+    pub fn for_host(self: &Arc<Self>, host: &str) -> Arc<Config> {
+        let host = match host.rfind(':') {
+            Some(index) => &host[..index],
+            None => host,
+        };
+
+        let serves = |public_host: &str| {
+            host == public_host
+                || host
+                    .strip_suffix(public_host)
+                    .is_some_and(|subdomain| subdomain.ends_with('.'))
+        };
+
+        let tenant = self
+            .tenants
+            .iter()
+            .filter(|tenant| serves(&tenant.public_host))
+            .max_by_key(|tenant| tenant.public_host.len());
+
+        match tenant {
+            Some(tenant)
+                if !serves(&self.public_host)
+                    || tenant.public_host.len() > self.public_host.len() =>
+            {
+                Arc::new(Config {
+                    public_host: tenant.public_host.clone(),
+                    url_encoding_algorithm: tenant.url_encoding_algorithm.clone(),
+                    legacy_url_encoding_algorithms: tenant.legacy_url_encoding_algorithms.clone(),
+                    previous_keys: tenant.previous_keys.clone(),
+                    allowed_hosts: tenant.allowed_hosts.clone(),
+                    inject_html: tenant.inject_html.clone(),
+                    tenants: vec![],
+                    ..(**self).clone()
+                })
+            }
+            _ => self.clone(),
+        }
+    }
+
+    /// Whether the allow-list lets `upstream_host` be proxied, either by naming it or one of its
+    /// parent domains
+    pub fn allows(&self, upstream_host: &str) -> bool {
+        match &self.allowed_hosts {
+            Some(allowed_hosts) => allowed_hosts.iter().any(|allowed| {
+                upstream_host == allowed
+                    || upstream_host
+                        .strip_suffix(allowed.as_str())
+                        .is_some_and(|subdomain| subdomain.ends_with('.'))
+            }),
+            None => true,
+        }
+    }
+}
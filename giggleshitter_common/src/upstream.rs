@@ -0,0 +1,88 @@
+use std::{
+    cmp::Reverse,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+};
+
+use lru::LruCache;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+/// How many upstream hosts statistics are kept for, the least recently used are dropped first
+const TRACKED_HOSTS: NonZeroUsize = NonZeroUsize::new(512).unwrap();
+
+#[derive(Clone, Copy, Default)]
+struct HostCounters {
+    requests: u64,
+    connections: u64,
+}
+
+#[derive(Serialize, ToSchema)]
+/// How often requests to an upstream host reused a pooled connection
+pub struct HostConnectionStats {
+    pub host: String,
+    pub requests: u64,
+    /// Connections opened to the host, counted by the lookups of its address made for them. Hosts
+    /// that are IP addresses aren't looked up, so their connections aren't counted.
+    pub new_connections: u64,
+    /// The share of requests that were sent over an already open connection, from 0 to 1
+    pub reuse_rate: f64,
+}
+
+#[derive(Clone)]
+/// Counts the requests sent to each upstream host and the connections opened for them, to tell how
+/// well the upstream clients' connection pools are working
+pub struct ConnectionStats(Arc<Mutex<LruCache<String, HostCounters>>>);
+
+impl Default for ConnectionStats {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(LruCache::new(TRACKED_HOSTS))))
+    }
+}
+
+impl ConnectionStats {
+    pub fn record_request(&self, host: &str) {
+        self.update(host, |counters| counters.requests += 1);
+    }
+
+    pub fn record_connection(&self, host: &str) {
+        self.update(host, |counters| counters.connections += 1);
+    }
+
+    fn update(&self, host: &str, update: impl FnOnce(&mut HostCounters)) {
+        let mut hosts = self.0.lock().unwrap();
+
+        match hosts.get_mut(host) {
+            Some(counters) => update(counters),
+            None => {
+                let mut counters = HostCounters::default();
+                update(&mut counters);
+                hosts.put(host.to_string(), counters);
+            }
+        }
+    }
+
+    /// The statistics of every tracked host, busiest first
+    pub fn snapshot(&self) -> Vec<HostConnectionStats> {
+        let mut stats = self
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(host, counters)| HostConnectionStats {
+                host: host.clone(),
+                requests: counters.requests,
+                new_connections: counters.connections,
+                reuse_rate: match counters.requests {
+                    0 => 0.0,
+                    requests => {
+                        requests.saturating_sub(counters.connections) as f64 / requests as f64
+                    }
+                },
+            })
+            .collect::<Vec<_>>();
+
+        stats.sort_by_key(|stats| Reverse(stats.requests));
+        stats
+    }
+}
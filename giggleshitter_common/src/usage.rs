@@ -0,0 +1,204 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::IpAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{NaiveDate, Utc};
+use scorched::{logf, LogData, LogImportance};
+use serde::{Deserialize, Serialize};
+use tokio::task::AbortHandle;
+
+use crate::state::LiveConfig;
+
+#[derive(Serialize, Deserialize)]
+/// Bytes transferred by each client on a single day
+struct UsageTable {
+    day: NaiveDate,
+    clients: HashMap<IpAddr, u64>,
+}
+
+impl UsageTable {
+    fn today() -> Self {
+        Self {
+            day: Utc::now().date_naive(),
+            clients: HashMap::new(),
+        }
+    }
+
+    /// Start counting from zero once the day is over
+    fn roll_over(&mut self) {
+        if self.day != Utc::now().date_naive() {
+            *self = Self::today();
+        }
+    }
+}
+
+#[derive(Clone)]
+/// Per client bandwidth accounting, reset every day at midnight UTC. Clients connected over a
+/// Unix socket have no address and are not accounted.
+pub struct Usage {
+    config: LiveConfig,
+    table: Arc<Mutex<UsageTable>>,
+    _saver: Arc<Saver>,
+}
+
+/// The task saving the counters, stopped once the last clone of the usage is dropped along with
+/// the server it was built for. The counters are saved one last time then, for the next server to
+/// pick up.
+struct Saver {
+    task: AbortHandle,
+    config: LiveConfig,
+    table: Arc<Mutex<UsageTable>>,
+    /// Held while the file is written, so that the last save can't interleave with one the task
+    /// already started
+    writing: Arc<Mutex<()>>,
+}
+
+impl Drop for Saver {
+    fn drop(&mut self) {
+        self.task.abort();
+
+        if let Some(path) = &self.config.load().bandwidth.persist_path {
+            if let Err(e) = save(path, &self.table, &self.writing) {
+                logf!(Error, "Error saving usage to {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+impl Usage {
+    /// Load the counters saved by a previous run and start saving them periodically, if a file is
+    /// configured
+    pub fn spawn(config: LiveConfig) -> Self {
+        let table = config
+            .load()
+            .bandwidth
+            .persist_path
+            .as_deref()
+            .and_then(load)
+            .unwrap_or_else(UsageTable::today);
+        let table = Arc::new(Mutex::new(table));
+        let writing = Arc::new(Mutex::new(()));
+
+        let task = tokio::spawn(persist(config.clone(), table.clone(), writing.clone()));
+
+        Self {
+            config: config.clone(),
+            table: table.clone(),
+            _saver: Arc::new(Saver {
+                task: task.abort_handle(),
+                config,
+                table,
+                writing,
+            }),
+        }
+    }
+
+    pub fn add(&self, client: IpAddr, bytes: u64) {
+        let mut table = self.table.lock().unwrap();
+        table.roll_over();
+
+        *table.clients.entry(client).or_default() += bytes;
+    }
+
+    /// The bytes transferred by `client` today
+    pub fn get(&self, client: IpAddr) -> u64 {
+        let mut table = self.table.lock().unwrap();
+        table.roll_over();
+
+        table.clients.get(&client).copied().unwrap_or_default()
+    }
+
+    /// The configured daily quota, if any
+    pub fn quota(&self) -> Option<u64> {
+        self.config.load().bandwidth.daily_quota_bytes
+    }
+
+    /// Whether `client` used up its daily quota
+    pub fn is_exceeded(&self, client: IpAddr) -> bool {
+        self.quota().is_some_and(|quota| self.get(client) >= quota)
+    }
+}
+
+fn load(path: &Path) -> Option<UsageTable> {
+    let contents = std::fs::read_to_string(path).ok()?;
+
+    match serde_json::from_str::<UsageTable>(&contents) {
+        Ok(mut table) => {
+            table.roll_over();
+            Some(table)
+        }
+        Err(e) => {
+            logf!(Error, "Ignoring usage file {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+fn save(path: &Path, table: &Mutex<UsageTable>, writing: &Mutex<()>) -> io::Result<()> {
+    let _writing = writing.lock().unwrap();
+    let contents = serde_json::to_vec(&*table.lock().unwrap())?;
+
+    // Write to a temporary file first so a crash can't leave a truncated file behind
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+
+    std::fs::write(&temporary, contents)?;
+    std::fs::rename(&temporary, path)
+}
+
+async fn persist(config: LiveConfig, table: Arc<Mutex<UsageTable>>, writing: Arc<Mutex<()>>) {
+    loop {
+        let bandwidth = config.load().bandwidth.clone();
+        tokio::time::sleep(Duration::from_secs(bandwidth.persist_interval_secs.max(1))).await;
+
+        let Some(path) = bandwidth.persist_path else {
+            continue;
+        };
+
+        let (table, writing) = (table.clone(), writing.clone());
+        let saving = tokio::task::spawn_blocking(move || {
+            save(&path, &table, &writing).map_err(|e| (path, e))
+        });
+
+        if let Ok(Err((path, e))) = saving.await {
+            logf!(Error, "Error saving usage to {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use arc_swap::ArcSwap;
+
+    use crate::state::Config;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn saves_the_counters_once_dropped() {
+        let path = std::env::temp_dir().join(format!("gs-usage-{}.json", std::process::id()));
+        let mut config = Config::default();
+        config.bandwidth.persist_path = Some(path.clone());
+        config.bandwidth.persist_interval_secs = 3600;
+        let config: LiveConfig = Arc::new(ArcSwap::from_pointee(config));
+
+        let usage = Usage::spawn(config.clone());
+        let client = IpAddr::from([192, 0, 2, 1]);
+        usage.add(client, 42);
+
+        drop(usage);
+        tokio::task::yield_now().await;
+        // The task saving the counters stopped along with it
+        assert_eq!(Arc::strong_count(&config), 1);
+
+        let usage = Usage::spawn(config);
+        assert_eq!(usage.get(client), 42);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
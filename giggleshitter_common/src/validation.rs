@@ -0,0 +1,341 @@
+use std::{collections::HashSet, fmt};
+
+use base32::Alphabet;
+use hyper::header::{HeaderName, HeaderValue, ACCEPT_LANGUAGE, USER_AGENT};
+use thiserror::Error;
+
+use crate::state::{Config, RuleAction, UrlEncodingAlgorithm};
+
+/// XOR keys shorter than this are accepted, but reported as a warning
+const MIN_RECOMMENDED_KEY_LENGTH: usize = 8;
+
+/// Session secrets shorter than this could be brute forced from a signed cookie
+const MIN_SESSION_SECRET_LENGTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A problem found while validating a [`Config`]
+pub enum ConfigProblem {
+    /// The listen address could not be parsed
+    InvalidHost(String),
+    EmptyPublicHost,
+    /// The public host contains a scheme, path, port or whitespace
+    InvalidPublicHost(String),
+    /// The API path prefix doesn't start with a slash, ends with one, or is the root
+    InvalidApiPathPrefix(String),
+    /// A `RemoveElement` rule action has a CSS selector that can't be parsed
+    InvalidRuleSelector(String),
+    /// Two tenants share a public host, only one of them could ever be served
+    DuplicatePublicHost(String),
+    /// Padded alphabets produce `=`, which is not allowed in subdomains
+    PaddedAlphabet,
+    /// Uppercase alphabets can't be decoded once browsers lowercase the host
+    UppercaseAlphabet,
+    /// An empty XOR key encodes every origin as an empty string
+    EmptyXorKey,
+    /// A short XOR key works, but makes encoded origins easy to guess
+    ShortXorKey(usize),
+    /// Sessions are enabled with a secret that is short enough to guess
+    WeakSessionSecret,
+    /// The session cookie name is empty or contains characters cookies can't have
+    InvalidSessionCookieName(String),
+    /// An API key's hash isn't a hex encoded SHA-256 hash, so no key can match it
+    InvalidApiKeyHash(String),
+    /// Two API keys share a name, and with it their rate limit
+    DuplicateApiKeyName(String),
+    /// A circuit breaker threshold of zero would open every circuit before any request is sent
+    ZeroFailureThreshold,
+    /// A header of `origin_headers`, `response_headers`, `fingerprints`, a rule's `AddHeader` or
+    /// `crawlers.x_robots_tag` has a name or value that can't be sent
+    InvalidConfiguredHeader(String),
+    /// Without a salt, hashed client IPs in the audit log can be reversed by hashing every address
+    UnsaltedAuditLog,
+}
+
+impl ConfigProblem {
+    /// Whether the problem stops the proxy from working, as opposed to being a warning
+    pub fn is_error(&self) -> bool {
+        !matches!(
+            self,
+            ConfigProblem::ShortXorKey(_) | ConfigProblem::UnsaltedAuditLog
+        )
+    }
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigProblem::InvalidHost(host) => write!(
+                f,
+                "host `{}` is not `<ip>:<port>`, `unix:<path>` or `systemd[:<index>]`",
+                host
+            ),
+            ConfigProblem::EmptyPublicHost => write!(f, "public_host must not be empty"),
+            ConfigProblem::InvalidPublicHost(host) => write!(
+                f,
+                "public_host `{}` must be a bare domain without a scheme, path or port",
+                host
+            ),
+            ConfigProblem::InvalidApiPathPrefix(prefix) => write!(
+                f,
+                "api_path_prefix `{}` must start with `/` and not end with one, e.g. `/__api`",
+                prefix
+            ),
+            ConfigProblem::InvalidRuleSelector(selector) => {
+                write!(f, "rule selector `{}` is not a supported CSS selector", selector)
+            }
+            ConfigProblem::DuplicatePublicHost(host) => {
+                write!(f, "public_host `{}` is used by more than one tenant", host)
+            }
+            ConfigProblem::PaddedAlphabet => write!(
+                f,
+                "padded alphabets produce `=`, which is not allowed in subdomains"
+            ),
+            ConfigProblem::UppercaseAlphabet => write!(
+                f,
+                "uppercase alphabets can't be decoded after browsers lowercase the host, use the Lower variant"
+            ),
+            ConfigProblem::EmptyXorKey => write!(
+                f,
+                "the XOR key must not be empty, it would encode every origin as an empty string"
+            ),
+            ConfigProblem::ShortXorKey(length) => write!(
+                f,
+                "the XOR key is only {} bytes long, which makes encoded origins easy to guess",
+                length
+            ),
+            ConfigProblem::WeakSessionSecret => write!(
+                f,
+                "sessions.secret must be at least {} characters long when sessions are enabled",
+                MIN_SESSION_SECRET_LENGTH
+            ),
+            ConfigProblem::InvalidSessionCookieName(name) => {
+                write!(f, "sessions.cookie_name `{}` is not a valid cookie name", name)
+            }
+            ConfigProblem::InvalidApiKeyHash(name) => write!(
+                f,
+                "the sha256 of API key `{}` must be 64 hexadecimal characters",
+                name
+            ),
+            ConfigProblem::DuplicateApiKeyName(name) => {
+                write!(f, "more than one API key is named `{}`", name)
+            }
+            ConfigProblem::ZeroFailureThreshold => write!(
+                f,
+                "circuit_breaker.failure_threshold must be at least 1, disable the circuit breaker instead"
+            ),
+            ConfigProblem::InvalidConfiguredHeader(name) => write!(
+                f,
+                "header `{}` in origin_headers, response_headers, fingerprints, rules or crawlers isn't a valid header name and value",
+                name
+            ),
+            ConfigProblem::UnsaltedAuditLog => write!(
+                f,
+                "audit.ip_salt is empty, so client IP hashes in the audit log can be reversed"
+            ),
+        }
+    }
+}
+
+#[derive(Error, Debug, Clone)]
+/// The problems that make a [`Config`] unusable, along with any warnings
+pub struct ConfigError(pub Vec<ConfigProblem>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid configuration")?;
+
+        for problem in self.0.iter().filter(|problem| problem.is_error()) {
+            write!(f, "\n  - {}", problem)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Config {
+    /// Every problem with the configuration, including warnings
+    pub fn problems(&self) -> Vec<ConfigProblem> {
+        let mut problems = vec![];
+
+        check_public_host(
+            &self.public_host,
+            &self.url_encoding_algorithm,
+            &mut problems,
+        );
+
+        let mut public_hosts = vec![self.public_host.as_str()];
+
+        for tenant in &self.tenants {
+            check_public_host(
+                &tenant.public_host,
+                &tenant.url_encoding_algorithm,
+                &mut problems,
+            );
+
+            if public_hosts.contains(&tenant.public_host.as_str()) {
+                problems.push(ConfigProblem::DuplicatePublicHost(
+                    tenant.public_host.clone(),
+                ));
+            }
+            public_hosts.push(&tenant.public_host);
+        }
+
+        if let Some(prefix) = &self.api_path_prefix {
+            if !prefix.starts_with('/') || prefix.ends_with('/') {
+                problems.push(ConfigProblem::InvalidApiPathPrefix(prefix.clone()));
+            }
+        }
+
+        for action in self.rules.iter().flat_map(|rule| &rule.actions) {
+            match action {
+                RuleAction::RemoveElement(selector)
+                    if selector.parse::<lol_html::Selector>().is_err() =>
+                {
+                    problems.push(ConfigProblem::InvalidRuleSelector(selector.clone()));
+                }
+                RuleAction::AddHeader(name, value)
+                    if HeaderName::from_bytes(name.as_bytes()).is_err()
+                        || HeaderValue::from_str(value).is_err() =>
+                {
+                    problems.push(ConfigProblem::InvalidConfiguredHeader(name.clone()));
+                }
+                _ => {}
+            }
+        }
+
+        if self.sessions.enabled && self.sessions.secret.len() < MIN_SESSION_SECRET_LENGTH {
+            problems.push(ConfigProblem::WeakSessionSecret);
+        }
+
+        let cookie_name = &self.sessions.cookie_name;
+        if cookie_name.is_empty()
+            || !cookie_name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+        {
+            problems.push(ConfigProblem::InvalidSessionCookieName(cookie_name.clone()));
+        }
+
+        let mut key_names = HashSet::new();
+        for key in &self.api_keys {
+            if key.sha256.len() != 64 || !key.sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+                problems.push(ConfigProblem::InvalidApiKeyHash(key.name.clone()));
+            }
+
+            if !key_names.insert(&key.name) {
+                problems.push(ConfigProblem::DuplicateApiKeyName(key.name.clone()));
+            }
+        }
+
+        if self.circuit_breaker.enabled && self.circuit_breaker.failure_threshold == 0 {
+            problems.push(ConfigProblem::ZeroFailureThreshold);
+        }
+
+        for (name, value) in self
+            .origin_headers
+            .values()
+            .chain(self.response_headers.values())
+            .chain(
+                self.fingerprints
+                    .values()
+                    .map(|fingerprint| &fingerprint.client_hints),
+            )
+            .flatten()
+        {
+            if HeaderName::from_bytes(name.as_bytes()).is_err()
+                || HeaderValue::from_str(value).is_err()
+            {
+                problems.push(ConfigProblem::InvalidConfiguredHeader(name.clone()));
+            }
+        }
+
+        if self
+            .crawlers
+            .x_robots_tag
+            .as_ref()
+            .is_some_and(|tag| HeaderValue::from_str(tag).is_err())
+        {
+            problems.push(ConfigProblem::InvalidConfiguredHeader(
+                "X-Robots-Tag".to_string(),
+            ));
+        }
+
+        for fingerprint in self.fingerprints.values() {
+            for (name, value) in [
+                (USER_AGENT, &fingerprint.user_agent),
+                (ACCEPT_LANGUAGE, &fingerprint.accept_language),
+            ] {
+                if value
+                    .as_ref()
+                    .is_some_and(|value| HeaderValue::from_str(value).is_err())
+                {
+                    problems.push(ConfigProblem::InvalidConfiguredHeader(name.to_string()));
+                }
+            }
+        }
+
+        if self
+            .audit
+            .as_ref()
+            .is_some_and(|audit| audit.ip_salt.is_empty())
+        {
+            problems.push(ConfigProblem::UnsaltedAuditLog);
+        }
+
+        problems
+    }
+
+    /// Check the configuration before serving with it, failing with all the problems found if any
+    /// of them is an error. Warnings alone don't fail validation, use [`Config::problems`] to see
+    /// them.
+    pub fn validate(&self) -> std::result::Result<(), ConfigError> {
+        let problems = self.problems();
+
+        if problems.iter().any(ConfigProblem::is_error) {
+            return Err(ConfigError(problems));
+        }
+
+        Ok(())
+    }
+}
+
+/// The checks shared by the top level public host and every tenant
+fn check_public_host(
+    public_host: &str,
+    url_encoding_algorithm: &UrlEncodingAlgorithm,
+    problems: &mut Vec<ConfigProblem>,
+) {
+    if public_host.is_empty() {
+        problems.push(ConfigProblem::EmptyPublicHost);
+    } else if public_host.contains("://")
+        || public_host.contains(|c: char| c == '/' || c == ':' || c.is_whitespace())
+    {
+        problems.push(ConfigProblem::InvalidPublicHost(public_host.to_string()));
+    }
+
+    let (alphabet, key) = match url_encoding_algorithm {
+        UrlEncodingAlgorithm::Base32(alphabet) => (alphabet, None),
+        UrlEncodingAlgorithm::Base32Xor(alphabet, key) => (alphabet, Some(key)),
+    };
+
+    match alphabet {
+        Alphabet::Rfc4648 { padding: true }
+        | Alphabet::Rfc4648Lower { padding: true }
+        | Alphabet::Rfc4648Hex { padding: true }
+        | Alphabet::Rfc4648HexLower { padding: true } => {
+            problems.push(ConfigProblem::PaddedAlphabet)
+        }
+        Alphabet::Rfc4648 { .. } | Alphabet::Rfc4648Hex { .. } => {
+            problems.push(ConfigProblem::UppercaseAlphabet)
+        }
+        _ => {}
+    }
+
+    match key {
+        Some(key) if key.is_empty() => problems.push(ConfigProblem::EmptyXorKey),
+        Some(key) if key.len() < MIN_RECOMMENDED_KEY_LENGTH => {
+            problems.push(ConfigProblem::ShortXorKey(key.len()))
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,155 @@
+//! An in-process upstream ("origin") server and a proxy in front of it, both on ephemeral ports
+
+use std::{future::IntoFuture, net::SocketAddr, sync::Arc};
+
+use arc_swap::ArcSwap;
+use axum::{
+    http::{header::HOST, Method},
+    Router,
+};
+use base32::Alphabet;
+use giggleshitter_common::{
+    proxy::util::encode_url,
+    server::ServerBuilder,
+    state::{ApiKey, ApiScope, Config, UrlEncodingAlgorithm},
+};
+use sha2::{Digest, Sha256};
+use tokio::{net::TcpListener, task::JoinHandle};
+
+pub const PUBLIC_HOST: &str = "proxy.test";
+
+/// The admin API key every harness is configured with, which [`Harness::api`] presents
+pub const ADMIN_KEY: &str = "harness admin key";
+
+pub struct Harness {
+    pub config: Config,
+    /// Where the origin server listens
+    pub origin: SocketAddr,
+    /// Where the proxy listens
+    pub proxy: SocketAddr,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Harness {
+    /// Serve `origin` and a proxy with the default configuration
+    pub async fn start(origin: Router) -> Self {
+        Self::start_with(origin, |_| {}).await
+    }
+
+    /// Serve `origin` and a proxy with the configuration changed by `configure`
+    pub async fn start_with(origin: Router, configure: impl FnOnce(&mut Config)) -> Self {
+        Self::start_with_builder(origin, configure, |builder| builder).await
+    }
+
+    /// Serve `origin` and a proxy with the configuration changed by `configure`, and the server
+    /// changed by `customize`, such as to register extra rewriters
+    pub async fn start_with_builder(
+        origin: Router,
+        configure: impl FnOnce(&mut Config),
+        customize: impl FnOnce(ServerBuilder) -> ServerBuilder,
+    ) -> Self {
+        let origin_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_addr = origin_listener.local_addr().unwrap();
+        let origin_task = tokio::spawn(async move {
+            axum::serve(origin_listener, origin)
+                .into_future()
+                .await
+                .unwrap();
+        });
+
+        let mut config = Config {
+            url_encoding_algorithm: UrlEncodingAlgorithm::Base32(Alphabet::Z),
+            public_host: PUBLIC_HOST.to_string(),
+            api_keys: vec![ApiKey {
+                name: "harness".to_string(),
+                sha256: format!("{:x}", Sha256::digest(ADMIN_KEY)),
+                scope: ApiScope::Admin,
+                requests_per_minute: None,
+            }],
+            ..Default::default()
+        };
+        configure(&mut config);
+
+        let proxy_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = proxy_listener.local_addr().unwrap();
+        let live_config = Arc::new(ArcSwap::from_pointee(config.clone()));
+        let builder = customize(ServerBuilder::with_live_config(live_config));
+        let proxy_task = tokio::spawn(async move {
+            builder
+                .serve_with_listener(proxy_listener, std::future::pending())
+                .await
+                .unwrap();
+        });
+
+        Self {
+            config,
+            origin: origin_addr,
+            proxy: proxy_addr,
+            tasks: vec![origin_task, proxy_task],
+        }
+    }
+
+    /// The URL of `path` on the origin server
+    pub fn origin_url(&self, path: &str) -> String {
+        format!("http://{}{}", self.origin, path)
+    }
+
+    /// The proxied URL of `path` on the origin server, as the proxy itself writes it
+    pub fn proxied_url(&self, path: &str) -> String {
+        encode_url(&self.config, &self.origin_url(path))
+    }
+
+    /// The host the origin server is proxied on
+    pub fn proxied_host(&self) -> String {
+        let url = self.proxied_url("/");
+
+        url.trim_start_matches("https://")
+            .trim_end_matches('/')
+            .to_string()
+    }
+
+    /// The proxied URL of `path`, pointing at the proxy's plain HTTP listener
+    pub fn url(&self, path: &str) -> String {
+        format!(
+            "http://{}:{}{}",
+            self.proxied_host(),
+            self.proxy.port(),
+            path
+        )
+    }
+
+    /// A request to `path` on the API with the [`ADMIN_KEY`]
+    pub fn api(&self, method: Method, path: &str) -> reqwest::RequestBuilder {
+        self.anonymous_api(method, path).bearer_auth(ADMIN_KEY)
+    }
+
+    /// A request to `path` on the API without a key. The host is sent without the listener's
+    /// port, which the API is only served without.
+    pub fn anonymous_api(&self, method: Method, path: &str) -> reqwest::RequestBuilder {
+        self.client()
+            .request(method, format!("http://{}{}", self.proxy, path))
+            .header(HOST, format!("api.{}", PUBLIC_HOST))
+    }
+
+    /// A client that resolves the proxied host to the proxy, doesn't follow redirects and doesn't
+    /// decompress bodies
+    pub fn client(&self) -> reqwest::Client {
+        reqwest::Client::builder()
+            .resolve(&self.proxied_host(), self.proxy)
+            .redirect(reqwest::redirect::Policy::none())
+            .no_gzip()
+            .no_brotli()
+            .no_zstd()
+            .no_deflate()
+            .build()
+            .unwrap()
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
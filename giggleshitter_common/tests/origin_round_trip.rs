@@ -0,0 +1,161 @@
+use base32::Alphabet;
+use giggleshitter_common::{
+    proxy::util::{decode_url, encode_url, proxied_origin},
+    state::{Config, UrlEncodingAlgorithm},
+};
+use proptest::prelude::*;
+
+const ALPHABETS: [Alphabet; 4] = [
+    Alphabet::Z,
+    Alphabet::Crockford,
+    Alphabet::Rfc4648Lower { padding: false },
+    Alphabet::Rfc4648Hex { padding: false },
+];
+
+/// An alphabet from [`ALPHABETS`] and an optional XOR key, `UrlEncodingAlgorithm` itself isn't
+/// `Debug` so proptest can't print it
+fn algorithm() -> impl Strategy<Value = (usize, Option<Vec<u8>>)> {
+    (
+        0..ALPHABETS.len(),
+        prop::option::of(prop::collection::vec(any::<u8>(), 1..16)),
+    )
+}
+
+fn config((alphabet, key): (usize, Option<Vec<u8>>)) -> Config {
+    let alphabet = ALPHABETS[alphabet];
+
+    Config {
+        url_encoding_algorithm: match key {
+            Some(key) => UrlEncodingAlgorithm::Base32Xor(alphabet, key),
+            None => UrlEncodingAlgorithm::Base32(alphabet),
+        },
+        public_host: "proxy.local".to_string(),
+        ..Default::default()
+    }
+}
+
+fn host() -> impl Strategy<Value = String> {
+    prop_oneof![
+        "[a-zA-Z0-9]([a-zA-Z0-9-]{0,20}[a-zA-Z0-9])?(\\.[a-zA-Z]{2,6}){0,3}",
+        any::<[u8; 4]>().prop_map(|ip| std::net::Ipv4Addr::from(ip).to_string()),
+        any::<[u16; 8]>().prop_map(|ip| format!("[{}]", std::net::Ipv6Addr::from(ip))),
+    ]
+    // URLs pointing at the proxy itself aren't encoded again
+    .prop_filter("hosts of the proxy", |host| {
+        !host.to_ascii_lowercase().ends_with("proxy.local")
+    })
+}
+
+proptest! {
+    #[test]
+    fn encoded_urls_decode_to_the_same_url(
+        algorithm in algorithm(),
+        scheme in prop_oneof![Just("http"), Just("https")],
+        host in host(),
+        port in prop::option::of(1..u16::MAX),
+        path in "(/[a-zA-Z0-9._~-]{0,10}){1,4}(\\?[a-z]=[0-9]{1,4})?",
+    ) {
+        let config = config(algorithm);
+        let port_suffix = port.map(|port| format!(":{}", port)).unwrap_or_default();
+        let url = format!("{}://{}{}{}", scheme, host, port_suffix, path);
+
+        let decoded = decode_url(&config, &encode_url(&config, &url)).unwrap();
+
+        let default_port = match scheme {
+            "http" => 80,
+            _ => 443,
+        };
+        let expected = format!(
+            "{}://{}:{}{}",
+            scheme,
+            host.to_ascii_lowercase(),
+            port.unwrap_or(default_port),
+            path
+        );
+        prop_assert_eq!(decoded.to_ascii_lowercase(), expected.to_ascii_lowercase());
+    }
+
+    #[test]
+    fn labels_decode_in_any_case(
+        algorithm in algorithm(),
+        host in host(),
+        uppercase in any::<bool>(),
+    ) {
+        let config = config(algorithm);
+        let encoded = encode_url(&config, &format!("https://{}/", host));
+        let label = encoded
+            .strip_prefix("https://")
+            .and_then(|encoded| encoded.strip_suffix(".proxy.local/"))
+            .unwrap();
+
+        let label = match uppercase {
+            true => label.to_ascii_uppercase(),
+            false => label.to_ascii_lowercase(),
+        };
+        let proxied_host = format!("{}.proxy.local", label);
+
+        let origin = proxied_origin(&config, &proxied_host).unwrap();
+        prop_assert!(origin.host().eq_ignore_ascii_case(&host));
+        prop_assert_eq!(origin.port(), 443);
+    }
+
+    #[test]
+    fn proxied_urls_are_not_encoded_again(
+        algorithm in algorithm(),
+        host in host(),
+        path in "(/[a-zA-Z0-9._~-]{0,10}){1,4}",
+    ) {
+        let config = config(algorithm);
+        let proxied = encode_url(&config, &format!("https://{}{}", host, path));
+
+        prop_assert_eq!(encode_url(&config, &proxied), proxied.clone());
+        prop_assert_eq!(
+            encode_url(&config, &proxied.to_ascii_uppercase()),
+            proxied.to_ascii_uppercase()
+        );
+    }
+
+    #[test]
+    fn arbitrary_hosts_dont_panic(algorithm in algorithm(), host in any::<String>()) {
+        let config = config(algorithm);
+
+        let _ = proxied_origin(&config, &host);
+        let _ = proxied_origin(&config, &format!("{}.proxy.local", host));
+        let _ = proxied_origin(&config, &format!("{}.proxy.local:8080", host));
+    }
+
+    #[test]
+    fn arbitrary_urls_dont_panic(algorithm in algorithm(), url in any::<String>()) {
+        let config = config(algorithm);
+
+        let _ = decode_url(&config, &encode_url(&config, &url));
+        let _ = decode_url(&config, &url);
+    }
+}
+
+#[test]
+fn legacy_labels_decode_after_a_rotation() {
+    let old = config((0, Some(vec![0x11, 0x22])));
+    // Every byte differs in its top bit, so labels of one never decode with the other
+    let new = config((0, Some(vec![0x91, 0xa2])));
+    let label = |config: &Config| {
+        encode_url(config, "https://example.com/")
+            .strip_prefix("https://")
+            .and_then(|encoded| encoded.strip_suffix("/"))
+            .unwrap()
+            .to_string()
+    };
+    let (old_host, new_host) = (label(&old), label(&new));
+
+    assert!(proxied_origin(&new, &old_host).is_err());
+
+    let rotated = Config {
+        legacy_url_encoding_algorithms: vec![old.url_encoding_algorithm.clone()],
+        ..new
+    };
+    for host in [old_host, new_host] {
+        let origin = proxied_origin(&rotated, &host).unwrap();
+        assert_eq!(origin.host(), "example.com");
+        assert_eq!(origin.port(), 443);
+    }
+}
@@ -0,0 +1,2518 @@
+mod common;
+
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    future::IntoFuture,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use async_compression::tokio::bufread::GzipEncoder;
+use axum::{
+    body::{Body, Bytes},
+    extract::{ws::WebSocketUpgrade, Host},
+    http::{
+        header::{
+            ACCEPT, ACCEPT_ENCODING, ACCESS_CONTROL_ALLOW_ORIGIN, AUTHORIZATION,
+            CONTENT_DISPOSITION, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_SECURITY_POLICY,
+            CONTENT_TYPE, COOKIE, ETAG, EXPECT, HOST, LINK, LOCATION, ORIGIN, RETRY_AFTER,
+            SET_COOKIE, TRAILER,
+        },
+        HeaderMap, HeaderName, Method, StatusCode, Uri,
+    },
+    response::{Html, IntoResponse, Redirect},
+    routing::{get, post},
+    Router,
+};
+use base32::Alphabet;
+use common::{Harness, PUBLIC_HOST};
+use futures_util::{stream, SinkExt, StreamExt};
+use giggleshitter_common::{
+    error::AppError,
+    hooks::{ProxyHook, ResponseEvent},
+    proxy::util::encode_url,
+    rewriting::rewriter::{RewriteContext, Rewriter},
+    rules::PathPattern,
+    state::{
+        ApiKey, ApiScope, Config, DocumentHandling, FrameSandbox, Rule, RuleAction,
+        ThirdPartyFrames, UrlEncodingAlgorithm,
+    },
+};
+use hyper::body::{Body as HttpBody, Frame};
+use regex::Regex;
+use reqwest_websocket::{Message, RequestBuilderExt};
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+
+const PAYLOAD: &[u8] = b"a payload that is passed through without being decompressed";
+
+async fn gzipped(data: &[u8]) -> Vec<u8> {
+    let mut gzipped = vec![];
+    GzipEncoder::new(data)
+        .read_to_end(&mut gzipped)
+        .await
+        .unwrap();
+
+    gzipped
+}
+
+/// The body of `response` as JSON
+async fn json(response: reqwest::Response) -> serde_json::Value {
+    serde_json::from_str(&response.text().await.unwrap()).unwrap()
+}
+
+fn origin() -> Router {
+    Router::new()
+        .route(
+            "/page",
+            get(|Host(host): Host| async move {
+                Html(format!(
+                    r#"<html><head><title>Page</title></head><body><a href="http://{}/other">Other</a><img src="/relative.png"></body></html>"#,
+                    host
+                ))
+            }),
+        )
+        .route(
+            "/redirect",
+            get(|Host(host): Host| async move {
+                Redirect::temporary(&format!("http://{}/target", host))
+            }),
+        )
+        .route("/target", get(|| async { "target" }))
+        .route(
+            "/article",
+            get(|Host(host): Host| async move {
+                Html(format!(
+                    r#"<html><head><title>An article</title><script>alert(1)</script></head><body><nav><a href="/">Home</a><a href="/about">About</a></nav><div class="post-body"><p>The first paragraph of the article, which goes on for a while, with commas, to look like prose.</p><p onclick="alert(2)">The second paragraph links to <a href="http://{host}/other">another page</a> and shows <img src="/picture.png" alt="A picture">.</p><script>alert(3)</script></div><footer>Copyright</footer></body></html>"#
+                ))
+            }),
+        )
+        .route(
+            "/saved",
+            get(|| async {
+                Html(
+                    r#"<html><head><link rel="stylesheet" href="/saved.css"><script>alert(1)</script></head><body><img src="images/dot.png" srcset="images/dot-2x.png 2x"><a href="/other">Other</a></body></html>"#,
+                )
+            }),
+        )
+        .route(
+            "/saved.css",
+            get(|| async {
+                (
+                    [(CONTENT_TYPE, "text/css")],
+                    "body { background: url('images/texture.png') }",
+                )
+            }),
+        )
+        .route(
+            "/images/:name",
+            get(|| async { ([(CONTENT_TYPE, "image/png")], "PNG") }),
+        )
+        .route(
+            "/scripted",
+            get(|| async {
+                Html(
+                    r#"<html><head><script>alert(1)</script></head><body onload="alert(2)"><a href=" JavaScript:alert(3)" title="Link">Link</a><noscript><p>No scripts</p></noscript></body></html>"#,
+                )
+            }),
+        )
+        .route(
+            "/frames",
+            get(|Host(host): Host| async move {
+                Html(format!(
+                    r#"<html><body><iframe src="http://{host}/target"></iframe><iframe src="https://video.example/embed" width="560"></iframe></body></html>"#
+                ))
+            }),
+        )
+        .route(
+            "/image.svg",
+            get(|Host(host): Host| async move {
+                (
+                    [(CONTENT_TYPE, "image/svg+xml")],
+                    format!(
+                        r##"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink"><style>rect {{ fill: url("http://{host}/pattern.svg#p"); }}</style><image xlink:href="http://{host}/photo.png"/><script href="http://{host}/script.js"/><use href="#shape"/></svg>"##
+                    ),
+                )
+            }),
+        )
+        .route(
+            "/secure",
+            get(|| async { Redirect::permanent("https://127.0.0.1/secure") }),
+        )
+        .route("/loop", get(|| async { Redirect::temporary("/loop") }))
+        .route(
+            "/cookie",
+            get(|headers: HeaderMap| async move {
+                let cookie = headers
+                    .get(COOKIE)
+                    .and_then(|cookie| cookie.to_str().ok())
+                    .unwrap_or_default()
+                    .to_string();
+
+                (
+                    [(SET_COOKIE, "id=1; Domain=127.0.0.1; Path=/; HttpOnly")],
+                    cookie,
+                )
+            }),
+        )
+        .route(
+            "/authorization",
+            get(|headers: HeaderMap| async move {
+                headers
+                    .get(AUTHORIZATION)
+                    .and_then(|authorization| authorization.to_str().ok())
+                    .unwrap_or_default()
+                    .to_string()
+            }),
+        )
+        .route(
+            "/unavailable",
+            get(|| async { (StatusCode::SERVICE_UNAVAILABLE, "upstream is down") }),
+        )
+        .route(
+            "/data.bin",
+            get(|| async {
+                (
+                    [
+                        (CONTENT_TYPE, "application/octet-stream"),
+                        (CONTENT_ENCODING, "gzip"),
+                    ],
+                    gzipped(PAYLOAD).await,
+                )
+            }),
+        )
+        .route(
+            "/ws",
+            get(|ws: WebSocketUpgrade| async {
+                ws.on_upgrade(|mut socket| async move {
+                    while let Some(Ok(message)) = socket.recv().await {
+                        if socket.send(message).await.is_err() {
+                            break;
+                        }
+                    }
+                })
+                .into_response()
+            }),
+        )
+}
+
+#[tokio::test]
+async fn rewrites_html() {
+    let harness = Harness::start(origin()).await;
+
+    let response = harness
+        .client()
+        .get(harness.url("/page"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = response.text().await.unwrap();
+    assert!(body.contains(&format!(r#"href="{}""#, harness.proxied_url("/other"))));
+    assert!(body.contains(r#"src="/relative.png""#));
+    assert!(!body.contains(&format!("http://{}/other", harness.origin)));
+    assert!(body.contains(&format!(r#"({{"origin":"http://{}"}})"#, harness.origin)));
+}
+
+#[tokio::test]
+async fn resolves_relative_urls_against_the_page() {
+    let relative = Router::new().route(
+        "/relative",
+        get(|Host(host): Host| async move {
+            Html(format!(
+                r#"<html><body><img src="//{host}/cdn.png"><a href="/root">Root</a><a href="sibling">Sibling</a><base href="/base/"><a href="/after-base">After</a></body></html>"#
+            ))
+        }),
+    );
+    let harness = Harness::start_with(relative, |config| config.resolve_relative_urls = true).await;
+
+    let response = harness
+        .client()
+        .get(harness.url("/relative"))
+        .send()
+        .await
+        .unwrap();
+    let body = response.text().await.unwrap();
+
+    assert!(body.contains(&format!(r#"src="{}""#, harness.proxied_url("/cdn.png"))));
+    assert!(body.contains(&format!(r#"href="{}""#, harness.proxied_url("/root"))));
+    assert!(body.contains(&format!(r#"href="{}""#, harness.proxied_url("/sibling"))));
+    assert!(body.contains(&format!(r#"href="{}""#, harness.proxied_url("/base/"))));
+    assert!(body.contains(r#"href="/after-base""#));
+}
+
+#[tokio::test]
+async fn strips_tracking_parameters() {
+    let tracked = Router::new()
+        .route(
+            "/tracked",
+            get(|Host(host): Host| async move {
+                Html(format!(
+                    r#"<html><body><a href="http://{host}/other?fbclid=abc">Other</a></body></html>"#
+                ))
+            }),
+        )
+        .route("/query", get(|uri: Uri| async move { uri.to_string() }));
+    let harness =
+        Harness::start_with(tracked, |config| config.tracking_params.enabled = true).await;
+    let client = harness.client();
+
+    let body = client
+        .get(harness.url("/tracked"))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(body.contains(&format!(r#"href="{}""#, harness.proxied_url("/other"))));
+
+    let requested = client
+        .get(harness.url("/query?q=1&utm_campaign=launch&gclid=x"))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert_eq!(requested, "/query?q=1");
+}
+
+#[tokio::test]
+async fn serves_bodies_that_fail_to_be_rewritten_unmodified() {
+    struct Failing;
+
+    impl Rewriter for Failing {
+        fn rewrite(&self, _: &Config, _: &RewriteContext, _: Vec<u8>) -> Result<Vec<u8>, AppError> {
+            Err(anyhow!("unbalanced document").into())
+        }
+    }
+
+    let harness = Harness::start_with_builder(
+        origin(),
+        |config| config.serve_unrewritten_on_error = true,
+        |builder| builder.with_rewriter("text/html", Box::new(Failing)),
+    )
+    .await;
+
+    let response = harness
+        .client()
+        .get(harness.url("/page"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["x-gs-rewrite-skipped"], "rewrite-failed");
+
+    let body = response.text().await.unwrap();
+    assert!(body.contains(&format!(r#"href="http://{}/other""#, harness.origin)));
+    assert!(!body.contains("<script"));
+}
+
+#[tokio::test]
+async fn rewrites_svg() {
+    let harness = Harness::start(origin()).await;
+
+    let response = harness
+        .client()
+        .get(harness.url("/image.svg"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = response.text().await.unwrap();
+    assert!(body.contains(&format!(
+        r#"xlink:href="{}""#,
+        harness.proxied_url("/photo.png")
+    )));
+    assert!(body.contains(&format!(r#"href="{}""#, harness.proxied_url("/script.js"))));
+    assert!(body.contains(&format!(
+        r#"url(&quot;{}"#,
+        harness.proxied_url("/pattern.svg#p")
+    )));
+    assert!(body.contains(r##"href="#shape""##));
+    assert!(!body.contains(&harness.origin.to_string()));
+}
+
+#[tokio::test]
+async fn sniffs_mislabeled_bodies() {
+    let mislabeled = Router::new()
+        .route(
+            "/plain",
+            get(|Host(host): Host| async move {
+                (
+                    [(CONTENT_TYPE, "text/plain")],
+                    format!(r#"<!DOCTYPE html><a href="http://{host}/other">Other</a>"#),
+                )
+            }),
+        )
+        .route(
+            "/image",
+            get(|Host(host): Host| async move {
+                let mut body = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".to_vec();
+                body.extend(format!(r#"<a href="http://{host}/other">"#).as_bytes());
+                ([(CONTENT_TYPE, "text/html")], body)
+            }),
+        );
+    let harness = Harness::start_with(mislabeled, |config| config.sniffing.html = true).await;
+
+    let response = harness
+        .client()
+        .get(harness.url("/plain"))
+        .send()
+        .await
+        .unwrap();
+    let body = response.text().await.unwrap();
+    assert!(body.contains(&format!(r#"href="{}""#, harness.proxied_url("/other"))));
+
+    let response = harness
+        .client()
+        .get(harness.url("/image"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["x-gs-rewrite-skipped"], "binary-content");
+    let body = response.bytes().await.unwrap();
+    assert!(body.starts_with(b"\x89PNG"));
+    assert!(body.ends_with(format!(r#"href="http://{}/other">"#, harness.origin).as_bytes()));
+}
+
+#[tokio::test]
+async fn applies_the_frame_policy() {
+    let harness = Harness::start_with(origin(), |config| {
+        config.frames.sandbox = FrameSandbox::Set("allow-scripts".to_string());
+        config.frames.third_party = ThirdPartyFrames::ClickToLoad;
+    })
+    .await;
+
+    let response = harness
+        .client()
+        .get(harness.url("/frames"))
+        .send()
+        .await
+        .unwrap();
+
+    let body = response.text().await.unwrap();
+    assert!(body.contains(&format!(
+        r#"<iframe src="{}" sandbox="allow-scripts">"#,
+        harness.proxied_url("/target")
+    )));
+    assert!(body.contains("Load content from video.example"));
+    assert!(body.contains("width:560px;"));
+    assert!(!body.contains(r#"<iframe src="https://video.example"#));
+}
+
+#[tokio::test]
+async fn routes_runtime_urls_through_the_proxy() {
+    let harness = Harness::start(origin()).await;
+    let client = harness.client();
+
+    let response = client
+        .get(harness.url("/__gs_open?url=http%3A%2F%2Fexample.com%2Fpopup%3Fa%3D1"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    assert_eq!(
+        response.headers()[LOCATION],
+        encode_url(&harness.config, "http://example.com/popup?a=1").as_str()
+    );
+
+    let response = client
+        .get(harness.url("/__gs_open?url=javascript%3Aalert(1)"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let proxied = client
+        .get(harness.url("/__gs_origin?encode=https%3A%2F%2Fexample.com"))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert_eq!(
+        format!("{proxied}/"),
+        encode_url(&harness.config, "https://example.com/")
+    );
+
+    let decoded = client
+        .get(harness.url(&format!("/__gs_origin?decode={proxied}")))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert_eq!(decoded, "https://example.com");
+}
+
+#[tokio::test]
+async fn disables_scripts() {
+    let harness = Harness::start_with(origin(), |config| {
+        config.rules.push(Rule {
+            origin: Some("127.0.0.1".to_string()),
+            path: None,
+            content_type: None,
+            actions: vec![RuleAction::DisableScripts],
+        });
+    })
+    .await;
+
+    let response = harness
+        .client()
+        .get(harness.url("/scripted"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(
+        response.headers()[CONTENT_SECURITY_POLICY],
+        "script-src 'none'"
+    );
+
+    let body = response.text().await.unwrap();
+    assert!(!body.contains("<script"));
+    assert!(!body.contains("alert"));
+    assert!(body.contains(r#"<a title="Link">Link</a>"#));
+    assert!(body.contains("<p>No scripts</p>"));
+    assert!(!body.contains("noscript"));
+}
+
+#[tokio::test]
+async fn shows_pages_as_articles() {
+    let harness = Harness::start(origin()).await;
+
+    let response = harness
+        .client()
+        .get(harness.url("/article?__gs_reader=1"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()[CONTENT_SECURITY_POLICY],
+        "script-src 'none'"
+    );
+
+    let body = response.text().await.unwrap();
+    assert!(body.contains("<title>An article</title>"));
+    assert!(body.contains("<p>The first paragraph of the article"));
+    assert!(body.contains(&format!(
+        r#"<a href="{}">another page</a>"#,
+        harness.proxied_url("/other")
+    )));
+    assert!(body.contains(r#"<img src="/picture.png" alt="A picture">"#));
+    assert!(!body.contains("alert"));
+    assert!(!body.contains("About"));
+    assert!(!body.contains("Copyright"));
+}
+
+#[tokio::test]
+async fn saves_pages_as_snapshots() {
+    let dir = std::env::temp_dir().join(format!("gs-snapshots-{}", std::process::id()));
+    let harness = Harness::start_with(origin(), |config| {
+        config.snapshots.dir = Some(dir.clone());
+    })
+    .await;
+
+    let response = harness
+        .api(Method::POST, "/snapshot")
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::json!({ "url": harness.origin_url("/saved") }).to_string())
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()[CONTENT_SECURITY_POLICY],
+        "sandbox; script-src 'none'"
+    );
+
+    let body = response.text().await.unwrap();
+    assert!(body.starts_with(&format!(
+        "<!-- saved from {} on ",
+        harness.origin_url("/saved")
+    )));
+    assert!(
+        body.contains(r#"<style>body { background: url("data:image/png;base64,UE5H") }</style>"#)
+    );
+    assert!(body.contains(r#"<img src="data:image/png;base64,UE5H">"#));
+    assert!(body.contains(&format!(
+        r#"<a href="{}">Other</a>"#,
+        harness.proxied_url("/other")
+    )));
+    assert!(!body.contains("alert"));
+
+    let stored = harness
+        .api(Method::POST, "/snapshot")
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::json!({ "url": harness.origin_url("/saved"), "store": true }).to_string())
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    let stored: serde_json::Value = serde_json::from_str(&stored).unwrap();
+
+    let id = stored["id"].as_str().unwrap();
+    let response = harness
+        .api(Method::GET, &format!("/snapshot/{id}"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("data:image/png;base64,UE5H"));
+
+    let response = harness
+        .api(Method::GET, "/snapshot/..%2Fsecret")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn draws_qr_codes_of_proxied_urls() {
+    let harness = Harness::start(origin()).await;
+
+    let response = harness
+        .api(Method::GET, "/encode/qr")
+        .query(&[("url", harness.origin_url("/page"))])
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[CONTENT_TYPE], "image/svg+xml");
+    assert!(response.text().await.unwrap().contains("<svg"));
+
+    let response = harness
+        .api(Method::GET, "/encode/qr")
+        .query(&[("url", "a".repeat(8000))])
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn serves_a_bookmarklet_and_an_extension() {
+    let harness = Harness::start(origin()).await;
+
+    let page = harness
+        .api(Method::GET, "/bookmarklet")
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(page.contains(r#"<a href="javascript:"#));
+
+    let manifest = json(
+        harness
+            .api(Method::GET, "/extension/manifest.json")
+            .send()
+            .await
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(manifest["manifest_version"], 3);
+    assert_eq!(
+        manifest["host_permissions"][0],
+        format!("https://api.{}/*", PUBLIC_HOST)
+    );
+
+    let script = harness
+        .api(Method::GET, "/extension/background.js")
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(script.contains(&format!("\"https://api.{}/encode?url=\"", PUBLIC_HOST)));
+
+    // What the bookmarklet submits
+    let response = harness
+        .api(Method::POST, "/open")
+        .form(&[("url", harness.origin_url("/page"))])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FOUND);
+    assert_eq!(
+        response.headers()[LOCATION],
+        harness.proxied_url("/page").as_str()
+    );
+}
+
+#[tokio::test]
+async fn closes_the_admin_api_without_keys() {
+    let harness = Harness::start_with(origin(), |config| config.api_keys.clear()).await;
+
+    // Encoding stays open to everyone
+    let response = harness
+        .anonymous_api(Method::GET, "/encode/qr")
+        .query(&[("url", "https://example.com/")])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    for path in [
+        "/stats",
+        "/encoding-keys",
+        "/upstream",
+        "/cache",
+        "/access",
+        "/diagnostics/unknown",
+        "/recordings",
+        "/recordings/any.warc",
+    ] {
+        let response = harness
+            .anonymous_api(Method::GET, path)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN, "{} is open", path);
+    }
+
+    let response = harness
+        .anonymous_api(Method::POST, "/access/rules")
+        .header(CONTENT_TYPE, "application/json")
+        .body(r#"{"list": "deny", "network": "127.0.0.1/32", "enabled": true}"#)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn describes_the_api_with_openapi() {
+    let harness = Harness::start(origin()).await;
+
+    let spec = json(
+        harness
+            .api(Method::GET, "/openapi.json")
+            .send()
+            .await
+            .unwrap(),
+    )
+    .await;
+    assert!(spec["openapi"].as_str().unwrap().starts_with("3."));
+    for path in [
+        "/encode",
+        "/encode/qr",
+        "/s/{id}",
+        "/stats",
+        "/access/rules",
+    ] {
+        assert!(spec["paths"][path].is_object(), "{} is missing", path);
+    }
+    assert_eq!(
+        spec["paths"]["/stats"]["get"]["security"][0]["api_key"],
+        serde_json::json!([])
+    );
+
+    let docs = harness.api(Method::GET, "/docs/").send().await.unwrap();
+    assert_eq!(docs.status(), StatusCode::OK);
+    assert!(docs.text().await.unwrap().contains("swagger"));
+}
+
+#[tokio::test]
+async fn redirects_short_links_to_proxied_urls() {
+    let dir = std::env::temp_dir().join(format!("gs-short-links-{}", std::process::id()));
+    let harness = Harness::start_with(origin(), |config| {
+        config.shortener.dir = Some(dir.clone());
+    })
+    .await;
+
+    let shortened = json(
+        harness
+            .api(Method::POST, "/shorten")
+            .header(CONTENT_TYPE, "application/json")
+            .body(serde_json::json!({ "url": harness.origin_url("/page?q=1") }).to_string())
+            .send()
+            .await
+            .unwrap(),
+    )
+    .await;
+
+    let id = shortened["id"].as_str().unwrap();
+    assert_eq!(id.len(), 7);
+    assert_eq!(
+        shortened["short_url"],
+        format!("https://{}/s/{}", PUBLIC_HOST, id)
+    );
+
+    // Served on the bare public host, like the short URL says
+    let response = harness
+        .client()
+        .get(format!("http://{}/s/{}", harness.proxy, id))
+        .header(HOST, PUBLIC_HOST)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FOUND);
+    assert_eq!(
+        response.headers()[LOCATION],
+        harness.proxied_url("/page?q=1").as_str()
+    );
+
+    let response = harness
+        .client()
+        .get(format!("http://{}/s/..%2Fsecret", harness.proxy))
+        .header(HOST, PUBLIC_HOST)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn records_exchanges_into_warc_files() {
+    let dir = std::env::temp_dir().join(format!("gs-recordings-{}", std::process::id()));
+    let harness = Harness::start_with(origin(), |config| {
+        config.recording.dir = Some(dir.clone());
+        config.recording.origins = vec!["127.0.0.1".to_string()];
+    })
+    .await;
+
+    let response = harness
+        .client()
+        .get(harness.url("/page"))
+        .header(COOKIE, "session=secret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    response.text().await.unwrap();
+
+    // The exchange is written in the background
+    let mut recordings = serde_json::Value::Null;
+    for _ in 0..50 {
+        let listed = harness
+            .api(Method::GET, "/recordings")
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        recordings = serde_json::from_str(&listed).unwrap();
+
+        if recordings["recordings"][0]["bytes"].as_u64() > Some(500) {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+
+    let name = recordings["recordings"][0]["name"].as_str().unwrap();
+
+    // The recordings hold what users browsed, only admins may download them
+    let response = harness
+        .anonymous_api(Method::GET, &format!("/recordings/{name}"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = harness
+        .api(Method::GET, &format!("/recordings/{name}"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[CONTENT_TYPE], "application/warc");
+
+    let warc = response.text().await.unwrap();
+    assert!(warc.starts_with("WARC/1.1\r\nWARC-Type: warcinfo\r\n"));
+    assert!(warc.contains("WARC-Type: response\r\nWARC-Record-ID: <urn:uuid:"));
+    assert!(warc.contains(&format!(
+        "WARC-Target-URI: {}\r\n",
+        harness.origin_url("/page")
+    )));
+    assert!(warc.contains("GET /page HTTP/1.1\r\n"));
+    assert!(warc.contains("HTTP/1.1 200 OK\r\n"));
+    // The upstream's body, before it was rewritten
+    assert!(warc.contains("<img src=\"/relative.png\"></body></html>"));
+    // Recordings are shared with every admin, who don't get to see the users' credentials
+    assert!(warc.contains("cookie: [redacted]\r\n"));
+    assert!(!warc.contains("secret"));
+
+    let response = harness
+        .api(Method::GET, "/recordings/..%2Fsecret.warc")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn streams_recorded_uploads_past_the_recorded_size() {
+    // Told when the origin got the start of the upload, which the rest of it waits for
+    let (started_tx, started_rx) = tokio::sync::oneshot::channel::<()>();
+    let started_tx = Arc::new(std::sync::Mutex::new(Some(started_tx)));
+
+    let dir = std::env::temp_dir().join(format!("gs-recorded-uploads-{}", std::process::id()));
+    let harness = Harness::start_with(
+        origin().route(
+            "/upload",
+            post(move |body: Body| async move {
+                let mut body = body.into_data_stream();
+                let mut received = 0;
+                while let Some(chunk) = body.next().await {
+                    received += chunk.unwrap().len();
+                    if let Some(started) = started_tx.lock().unwrap().take() {
+                        started.send(()).unwrap();
+                    }
+                }
+
+                received.to_string()
+            }),
+        ),
+        |config| {
+            config.recording.dir = Some(dir.clone());
+            config.recording.origins = vec!["127.0.0.1".to_string()];
+            config.recording.max_body_bytes = 1024;
+        },
+    )
+    .await;
+
+    // The rest is only sent once the origin received the start, which it never would if the proxy
+    // buffered the whole body to record it
+    let body = futures_util::stream::once(async {
+        Ok::<_, std::io::Error>(Bytes::from(vec![b'a'; 4096]))
+    })
+    .chain(futures_util::stream::once(async move {
+        started_rx.await.unwrap();
+        Ok(Bytes::from(vec![b'b'; 4096]))
+    }));
+
+    let response = tokio::time::timeout(
+        std::time::Duration::from_secs(30),
+        harness
+            .client()
+            .post(harness.url("/upload"))
+            .body(reqwest::Body::wrap_stream(body))
+            .send(),
+    )
+    .await
+    .expect("the upload was buffered by the proxy")
+    .unwrap();
+
+    assert_eq!(response.text().await.unwrap(), "8192");
+
+    // The exchange is written in the background
+    let mut warc = String::new();
+    for _ in 0..50 {
+        let listed = harness
+            .api(Method::GET, "/recordings")
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        let listed: serde_json::Value = serde_json::from_str(&listed).unwrap();
+
+        if let Some(name) = listed["recordings"][0]["name"].as_str() {
+            warc = harness
+                .api(Method::GET, &format!("/recordings/{name}"))
+                .send()
+                .await
+                .unwrap()
+                .text()
+                .await
+                .unwrap();
+            if warc.contains("POST /upload HTTP/1.1\r\n") {
+                break;
+            }
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+
+    let request = &warc[warc.find("WARC-Type: request\r\n").unwrap()..];
+    assert!(request.contains("WARC-Truncated: length\r\n"));
+    assert!(request.contains(&"a".repeat(1024)));
+    assert!(!request.contains(&"a".repeat(1025)));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn prefetches_the_stylesheets_of_pages() {
+    let fetches = Arc::new(AtomicUsize::new(0));
+    let counted = fetches.clone();
+    let harness = Harness::start_with(
+        origin()
+            .route(
+                "/styled",
+                get(|| async {
+                    Html(
+                        r#"<html><head><link rel="stylesheet" href="/styled.css"><link rel="stylesheet" href="https://elsewhere.test/other.css"></head></html>"#,
+                    )
+                }),
+            )
+            .route(
+                "/styled.css",
+                get(move || async move {
+                    counted.fetch_add(1, Ordering::SeqCst);
+                    ([(CONTENT_TYPE, "text/css")], "body { color: red }")
+                }),
+            ),
+        |config| config.prefetch.enabled = true,
+    )
+    .await;
+    let client = harness.client();
+
+    let response = client.get(harness.url("/styled")).send().await.unwrap();
+
+    // Only stylesheets on the page's own origin are preloaded
+    let links = response
+        .headers()
+        .get_all(LINK)
+        .iter()
+        .map(|link| link.to_str().unwrap().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(links, ["</styled.css>; rel=preload; as=style"]);
+    response.text().await.unwrap();
+
+    let response = client.get(harness.url("/styled.css")).send().await.unwrap();
+    assert_eq!(response.text().await.unwrap(), "body { color: red }");
+
+    // The browser's request was answered with the prefetched stylesheet
+    assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+    // Which is only handed out once
+    client.get(harness.url("/styled.css")).send().await.unwrap();
+    assert_eq!(fetches.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn rewrites_redirects() {
+    let harness = Harness::start(origin()).await;
+
+    let response = harness
+        .client()
+        .get(harness.url("/redirect"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+    assert_eq!(
+        response.headers()[LOCATION],
+        harness.proxied_url("/target").as_str()
+    );
+}
+
+#[tokio::test]
+async fn follows_redirects() {
+    let harness = Harness::start_with(
+        origin()
+            .route("/moved", get(|| async { Redirect::temporary("/dir/page") }))
+            .route(
+                "/dir/page",
+                get(|| async { Html(r#"<a href="sibling">Sibling</a>"#) }),
+            )
+            .route(
+                "/away",
+                get(|Host(host): Host| async move {
+                    let host = host.replace("127.0.0.1", "localhost");
+                    Redirect::temporary(&format!("http://{}/target", host))
+                }),
+            ),
+        |config| {
+            config.upstream.follow_redirects = Some(3);
+            config.resolve_relative_urls = true;
+        },
+    )
+    .await;
+    let client = harness.client();
+
+    let response = client.get(harness.url("/redirect")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.text().await.unwrap(), "target");
+
+    // Links on the page are relative to where the redirects led
+    let response = client.get(harness.url("/moved")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.text().await.unwrap();
+    assert!(body.contains(&harness.proxied_url("/dir/sibling")));
+
+    // Redirects to other origins are left to the browser, to be served on their own host
+    let response = client.get(harness.url("/away")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+    assert_eq!(
+        response.headers()[LOCATION],
+        encode_url(
+            &harness.config,
+            &format!("http://localhost:{}/target", harness.origin.port())
+        )
+    );
+
+    let response = client.get(harness.url("/loop")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+
+    let response = client
+        .get(harness.url("/loop"))
+        .header(ACCEPT, "text/html")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("Too many redirects"));
+}
+
+#[tokio::test]
+async fn remembers_https_redirects() {
+    let harness = Harness::start(origin()).await;
+
+    let response = harness
+        .client()
+        .get(harness.url("/secure"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+
+    // Links to the host without a port now go straight to HTTPS
+    assert_eq!(
+        encode_url(&harness.config, "http://127.0.0.1/secure"),
+        encode_url(&harness.config, "https://127.0.0.1/secure")
+    );
+}
+
+#[tokio::test]
+async fn keeps_duplicate_headers_in_order() {
+    let harness = Harness::start(origin().route(
+        "/headers",
+        get(|| async {
+            let mut response = "headers".into_response();
+            let headers = response.headers_mut();
+            for (name, value) in [
+                ("set-cookie", "first=1; Path=/"),
+                ("x-first", "1"),
+                ("connection", "x-hop"),
+                ("x-hop", "1"),
+                ("set-cookie", "second=2; Path=/"),
+                ("x-second", "2"),
+                ("x-third", "3"),
+            ] {
+                headers.append(name, value.parse().unwrap());
+            }
+
+            response
+        }),
+    ))
+    .await;
+
+    let names = |response: &reqwest::Response| {
+        response
+            .headers()
+            .keys()
+            .map(|name| name.to_string())
+            .filter(|name| name != "connection" && name != "x-hop")
+            .collect::<Vec<_>>()
+    };
+
+    let direct = reqwest::get(harness.origin_url("/headers")).await.unwrap();
+    let proxied = harness
+        .client()
+        .get(harness.url("/headers"))
+        .send()
+        .await
+        .unwrap();
+
+    let cookies = proxied
+        .headers()
+        .get_all(SET_COOKIE)
+        .iter()
+        .map(|cookie| cookie.to_str().unwrap().split(';').next().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(cookies, ["first=1", "second=2"]);
+
+    // Headers the proxy adds itself come after the upstream's
+    let proxied = names(&proxied);
+    assert_eq!(proxied[..names(&direct).len()], names(&direct));
+}
+
+#[tokio::test]
+async fn forwards_cookies() {
+    let harness = Harness::start(origin()).await;
+
+    let response = harness
+        .client()
+        .get(harness.url("/cookie"))
+        .header(COOKIE, "theme=dark")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(
+        response.headers()[SET_COOKIE],
+        format!("id=1; Domain={}; Path=/; HttpOnly=", PUBLIC_HOST).as_str()
+    );
+    assert_eq!(response.text().await.unwrap(), "theme=dark");
+}
+
+#[tokio::test]
+async fn adds_origin_headers() {
+    let harness = Harness::start_with(origin(), |config| {
+        config.origin_headers.insert(
+            "127.0.0.1".to_string(),
+            [("Authorization".to_string(), "Bearer upstream".to_string())].into(),
+        );
+    })
+    .await;
+
+    let response = harness
+        .client()
+        .get(harness.url("/authorization"))
+        .header(AUTHORIZATION, "Bearer client")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.text().await.unwrap(), "Bearer upstream");
+}
+
+#[tokio::test]
+async fn strips_fingerprinting_headers() {
+    let harness = Harness::start_with(
+        origin().route(
+            "/hints",
+            get(|headers: HeaderMap| async move {
+                let mut names = headers.keys().map(HeaderName::as_str).collect::<Vec<_>>();
+                names.sort();
+
+                ([("accept-ch", "Sec-CH-UA-Model")], names.join(","))
+            }),
+        ),
+        |config| config.strip_fingerprinting_headers = true,
+    )
+    .await;
+
+    let response = harness
+        .client()
+        .get(harness.url("/hints"))
+        .header("sec-ch-ua-platform", "\"Linux\"")
+        .header("device-memory", "8")
+        .header("dnt", "1")
+        .header("x-requested-with", "XMLHttpRequest")
+        .send()
+        .await
+        .unwrap();
+
+    assert!(!response.headers().contains_key("accept-ch"));
+    let names = response.text().await.unwrap();
+    assert!(names.contains("x-requested-with"));
+    for name in ["sec-ch-ua-platform", "device-memory", "dnt"] {
+        assert!(!names.contains(name), "{} was sent upstream", name);
+    }
+}
+
+#[tokio::test]
+async fn sets_response_headers() {
+    let harness = Harness::start_with(origin(), |config| {
+        config.response_headers.insert(
+            "*".to_string(),
+            [
+                ("X-Proxied-By".to_string(), "giggleshitter".to_string()),
+                ("Content-Type".to_string(), "text/plain".to_string()),
+            ]
+            .into(),
+        );
+        config.response_headers.insert(
+            "http://127.0.0.1:*".to_string(),
+            [("X-Proxied-By".to_string(), String::new())].into(),
+        );
+    })
+    .await;
+
+    let response = harness
+        .client()
+        .get(harness.url("/authorization"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.headers()[CONTENT_TYPE], "text/plain");
+    assert!(!response.headers().contains_key("x-proxied-by"));
+}
+
+#[tokio::test]
+async fn opens_the_circuit_of_failing_origins() {
+    let harness = Harness::start_with(origin(), |config| {
+        config.circuit_breaker.failure_threshold = 2;
+    })
+    .await;
+    let client = harness.client();
+
+    for _ in 0..2 {
+        let response = client
+            .get(harness.url("/unavailable"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.text().await.unwrap(), "upstream is down");
+    }
+
+    // Every path of the origin is refused until the cooldown ends
+    let response = client.get(harness.url("/page")).send().await.unwrap();
+
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(response.headers().contains_key("retry-after"));
+    assert!(response.text().await.unwrap().contains("Site unavailable"));
+}
+
+#[tokio::test]
+async fn explains_refusals_by_correlation_id() {
+    let harness = Harness::start_with(origin(), |config| {
+        config.allowed_hosts = Some(vec!["elsewhere.test".to_string()]);
+        config.api_keys = vec![ApiKey {
+            name: "support".to_string(),
+            sha256: format!("{:x}", Sha256::digest(b"support key")),
+            scope: ApiScope::Admin,
+            requests_per_minute: None,
+        }];
+    })
+    .await;
+
+    let response = harness
+        .client()
+        .get(harness.url("/page?secret=1"))
+        .header(ACCEPT, "text/html")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let id = response.headers()["x-gs-correlation-id"]
+        .to_str()
+        .unwrap()
+        .to_string();
+    assert!(response.text().await.unwrap().contains(&id));
+
+    let path = format!("/diagnostics/{}", id);
+    let response = harness
+        .anonymous_api(Method::GET, &path)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let refusal = json(
+        harness
+            .anonymous_api(Method::GET, &path)
+            .bearer_auth("support key")
+            .send()
+            .await
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(refusal["reason"], "origin_not_allowed");
+    assert_eq!(refusal["status"], 403);
+    assert_eq!(refusal["path"], "/page");
+    assert!(refusal["detail"].as_str().unwrap().contains("127.0.0.1"));
+    assert!(refusal["total_ms"].is_u64());
+
+    let response = harness
+        .anonymous_api(Method::GET, "/diagnostics/unknown")
+        .bearer_auth("support key")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn replays_offline_copies_of_unavailable_origins() {
+    let dir = std::env::temp_dir().join(format!("gs-offline-{}", std::process::id()));
+    let harness = Harness::start_with(origin(), |config| {
+        config.circuit_breaker.failure_threshold = 1;
+        config.offline.dir = Some(dir.clone());
+        config.offline.origins = vec!["127.0.0.1".to_string()];
+    })
+    .await;
+    let client = harness.client();
+
+    let response = client.get(harness.url("/page")).send().await.unwrap();
+    assert!(!response.headers().contains_key("x-gs-offline-copy"));
+    response.text().await.unwrap();
+
+    // The copy is saved in the background
+    for _ in 0..50 {
+        let saved = std::fs::read_dir(&dir).map_or(0, |entries| {
+            entries
+                .filter(|entry| {
+                    entry
+                        .as_ref()
+                        .is_ok_and(|entry| entry.path().extension().is_some_and(|e| e == "json"))
+                })
+                .count()
+        });
+        if saved > 0 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+
+    // Opens the origin's circuit
+    client
+        .get(harness.url("/unavailable"))
+        .send()
+        .await
+        .unwrap();
+
+    let response = client.get(harness.url("/page")).send().await.unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().contains_key("x-gs-offline-copy"));
+
+    let body = response.text().await.unwrap();
+    assert!(body.contains("You are seeing a copy saved on"));
+    assert!(body.contains(&format!(
+        r#"<a href="{}">Other</a>"#,
+        harness.proxied_url("/other")
+    )));
+
+    // Nothing was saved of the path that failed
+    let response = client
+        .get(harness.url("/unavailable"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn keeps_no_offline_copies_of_personal_pages() {
+    let dir = std::env::temp_dir().join(format!("gs-offline-personal-{}", std::process::id()));
+    let harness = Harness::start_with(
+        origin().route(
+            "/account",
+            get(|headers: HeaderMap| async move {
+                Html(format!(
+                    "<html><body>Signed in with {}</body></html>",
+                    headers
+                        .get(COOKIE)
+                        .map_or("nothing", |cookie| cookie.to_str().unwrap())
+                ))
+            }),
+        ),
+        |config| {
+            config.circuit_breaker.failure_threshold = 1;
+            config.offline.dir = Some(dir.clone());
+            config.offline.origins = vec!["127.0.0.1".to_string()];
+        },
+    )
+    .await;
+    let client = harness.client();
+
+    let response = client
+        .get(harness.url("/account"))
+        .header(COOKIE, "user=alice")
+        .send()
+        .await
+        .unwrap();
+    assert!(response.text().await.unwrap().contains("user=alice"));
+
+    // Would have been saved in the background by now
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+    // Opens the origin's circuit
+    client
+        .get(harness.url("/unavailable"))
+        .send()
+        .await
+        .unwrap();
+
+    // Someone else asking for the page while the origin is down doesn't get the first user's
+    let response = client.get(harness.url("/account")).send().await.unwrap();
+
+    assert_ne!(response.status(), StatusCode::OK);
+    assert!(!response.headers().contains_key("x-gs-offline-copy"));
+    assert!(!response.text().await.unwrap().contains("alice"));
+
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[tokio::test]
+async fn shows_browsers_a_retry_page_for_unreachable_upstreams() {
+    let harness = Harness::start(origin()).await;
+
+    // Nothing listens on the port of a listener that was dropped
+    let port = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let upstream = format!("http://127.0.0.1:{port}");
+    let proxied = encode_url(&harness.config, &format!("{upstream}/"));
+    let host = proxied.trim_start_matches("https://").trim_end_matches('/');
+    let client = reqwest::Client::builder()
+        .resolve(host, harness.proxy)
+        .build()
+        .unwrap();
+    let url = format!("http://{}:{}/", host, harness.proxy.port());
+
+    let response = client
+        .get(&url)
+        .header(ACCEPT, "text/html,application/xhtml+xml,*/*;q=0.8")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    assert_eq!(response.headers()[RETRY_AFTER], "5");
+
+    let body = response.text().await.unwrap();
+    assert!(body.contains("be reached"));
+    assert!(body.contains(&format!("<code>{upstream}</code>")));
+    assert!(body.contains("location.reload()"));
+
+    // Other clients keep getting the error as JSON
+    let response = client.get(&url).send().await.unwrap();
+
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+}
+
+#[tokio::test]
+async fn protects_against_documents() {
+    // Flattens PDFs, and fails on those it can't
+    let sanitizer = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let sanitizer_addr = sanitizer.local_addr().unwrap();
+    tokio::spawn(
+        axum::serve(
+            sanitizer,
+            Router::new().route(
+                "/",
+                post(|body: String| async move {
+                    match body.contains("broken") {
+                        true => Err(StatusCode::UNPROCESSABLE_ENTITY),
+                        false => Ok(format!("flattened {}", body)),
+                    }
+                }),
+            ),
+        )
+        .into_future(),
+    );
+
+    let document = |content_type: &'static str, body: &'static str| {
+        get(move || async move {
+            (
+                [
+                    (CONTENT_TYPE, content_type),
+                    (CONTENT_DISPOSITION, r#"inline; filename="file""#),
+                ],
+                body,
+            )
+        })
+    };
+    let harness = Harness::start_with(
+        origin()
+            .route("/file.pdf", document("application/pdf", "%PDF"))
+            .route("/broken.pdf", document("application/pdf", "%PDF broken"))
+            .route(
+                "/file.docx",
+                document(
+                    "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+                    "PK",
+                ),
+            ),
+        |config| {
+            config.documents.handling = DocumentHandling::Attachment;
+            config.documents.pdf_sanitizer = Some(format!("http://{}/", sanitizer_addr));
+        },
+    )
+    .await;
+    let client = harness.client();
+
+    let get = |path: &'static str| {
+        let response = client.get(harness.url(path)).send();
+        async move {
+            let response = response.await.unwrap();
+            let disposition = response.headers()[CONTENT_DISPOSITION].clone();
+            (disposition, response.text().await.unwrap())
+        }
+    };
+
+    // Sanitized PDFs are safe to show
+    let (disposition, body) = get("/file.pdf").await;
+    assert_eq!(disposition, r#"inline; filename="file""#);
+    assert_eq!(body, "flattened %PDF");
+
+    let (disposition, body) = get("/broken.pdf").await;
+    assert_eq!(disposition, r#"attachment; filename="file""#);
+    assert_eq!(body, "%PDF broken");
+
+    let (disposition, body) = get("/file.docx").await;
+    assert_eq!(disposition, r#"attachment; filename="file""#);
+    assert_eq!(body, "PK");
+}
+
+#[tokio::test]
+async fn passes_gzip_through() {
+    let harness = Harness::start_with(origin(), |config| {
+        config.compression.passthrough = true;
+    })
+    .await;
+
+    let response = harness
+        .client()
+        .get(harness.url("/data.bin"))
+        .header("accept-encoding", "gzip")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+    assert_eq!(
+        response.bytes().await.unwrap().as_ref(),
+        gzipped(PAYLOAD).await.as_slice()
+    );
+}
+
+#[tokio::test]
+async fn streams_multipart_uploads() {
+    // Told when the origin got the start of the upload, which the rest of it waits for
+    let (started_tx, started_rx) = tokio::sync::oneshot::channel::<()>();
+    let started_tx = Arc::new(std::sync::Mutex::new(Some(started_tx)));
+
+    let harness = Harness::start(origin().route(
+        "/upload",
+        post(move |headers: HeaderMap, body: Body| async move {
+            let mut body = body.into_data_stream();
+            let mut received = vec![];
+            while let Some(chunk) = body.next().await {
+                received.extend_from_slice(&chunk.unwrap());
+                if let Some(started) = started_tx.lock().unwrap().take() {
+                    started.send(()).unwrap();
+                }
+            }
+
+            (
+                [
+                    (CONTENT_TYPE, "application/octet-stream".to_string()),
+                    (
+                        HeaderName::from_static("x-content-type"),
+                        headers[CONTENT_TYPE].to_str().unwrap().to_string(),
+                    ),
+                    (
+                        HeaderName::from_static("x-content-length"),
+                        headers[CONTENT_LENGTH].to_str().unwrap().to_string(),
+                    ),
+                ],
+                received,
+            )
+        }),
+    ))
+    .await;
+
+    let boundary = "----gs-Boundary7MA4YWxkTrZu0gW";
+    let file = (0..16 * 1024 * 1024)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<_>>();
+    let head = format!(
+        "--{boundary}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"large.bin\"\r\nContent-Type: application/octet-stream\r\n\r\n"
+    );
+    let tail = format!("\r\n--{boundary}--\r\n");
+    let mut upload = head.clone().into_bytes();
+    upload.extend_from_slice(&file);
+    upload.extend_from_slice(tail.as_bytes());
+
+    // The file is only sent once the origin received the part before it, which it never would if
+    // the proxy waited for the whole body
+    let rest = Bytes::from([file, tail.into_bytes()].concat());
+    let body =
+        futures_util::stream::once(async move { Ok::<_, std::io::Error>(Bytes::from(head)) })
+            .chain(futures_util::stream::once(async move {
+                started_rx.await.unwrap();
+                Ok(rest)
+            }));
+
+    let response = tokio::time::timeout(
+        std::time::Duration::from_secs(30),
+        harness
+            .client()
+            .post(harness.url("/upload"))
+            .header(
+                CONTENT_TYPE,
+                format!("multipart/form-data; boundary={boundary}"),
+            )
+            .header(CONTENT_LENGTH, upload.len())
+            .body(reqwest::Body::wrap_stream(body))
+            .send(),
+    )
+    .await
+    .expect("the upload was buffered by the proxy")
+    .unwrap();
+
+    assert_eq!(
+        response.headers()["x-content-type"],
+        format!("multipart/form-data; boundary={boundary}").as_str()
+    );
+    assert_eq!(
+        response.headers()["x-content-length"],
+        upload.len().to_string().as_str()
+    );
+    assert!(response.bytes().await.unwrap() == upload);
+}
+
+#[tokio::test]
+async fn passes_grpc_web_calls_through() {
+    let harness = Harness::start(origin().route(
+        "/service.Echo/Say",
+        post(|headers: HeaderMap, body: Bytes| async move {
+            (
+                [
+                    (CONTENT_TYPE, "application/grpc-web+proto".to_string()),
+                    (CONTENT_ENCODING, "gzip".to_string()),
+                    (
+                        HeaderName::from_static("x-accept-encoding"),
+                        headers[ACCEPT_ENCODING].to_str().unwrap().to_string(),
+                    ),
+                ],
+                gzipped(&body).await,
+            )
+        }),
+    ))
+    .await;
+
+    // A message frame of five bytes: no flags, then the length
+    let message = b"\x00\x00\x00\x00\x05hello";
+    let response = harness
+        .client()
+        .post(harness.url("/service.Echo/Say"))
+        .header(CONTENT_TYPE, "application/grpc-web+proto")
+        .header(ACCEPT_ENCODING, "identity, gzip;q=0.5")
+        .body(message.as_slice())
+        .send()
+        .await
+        .unwrap();
+
+    // Sent upstream and back exactly as they were, even though compression isn't passed through
+    assert_eq!(
+        response.headers()["x-accept-encoding"],
+        "identity, gzip;q=0.5"
+    );
+    assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+    let body = gzipped(message).await;
+    assert_eq!(response.content_length(), Some(body.len() as u64));
+    assert_eq!(response.bytes().await.unwrap().as_ref(), body.as_slice());
+}
+
+/// A body sent as the given frames
+struct Frames(VecDeque<Frame<Bytes>>);
+
+impl HttpBody for Frames {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+        Poll::Ready(self.0.pop_front().map(Ok))
+    }
+}
+
+#[tokio::test]
+async fn forwards_trailers_of_streamed_responses() {
+    let harness = Harness::start(origin().route(
+        "/call",
+        get(|| async {
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", "0".parse().unwrap());
+
+            (
+                [(TRAILER, "grpc-status")],
+                Body::new(Frames(VecDeque::from([
+                    Frame::data(Bytes::from_static(b"message")),
+                    Frame::trailers(trailers),
+                ]))),
+            )
+        }),
+    ))
+    .await;
+
+    // Trailers are only sent to clients that ask for them
+    let mut stream = TcpStream::connect(harness.proxy).await.unwrap();
+    stream
+        .write_all(
+            format!(
+                "GET /call HTTP/1.1\r\nHost: {}:{}\r\nTE: trailers\r\nConnection: close\r\n\r\n",
+                harness.proxied_host(),
+                harness.proxy.port()
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    let response = response.to_ascii_lowercase();
+
+    assert!(response.starts_with("http/1.1 200 ok\r\n"));
+    assert!(response.contains("\r\ntrailer: grpc-status\r\n"));
+    assert!(response.ends_with("7\r\nmessage\r\n0\r\ngrpc-status: 0\r\n\r\n"));
+}
+
+#[tokio::test]
+async fn continues_uploads_that_expect_it() {
+    let harness = Harness::start(origin().route(
+        "/upload",
+        post(|headers: HeaderMap, body: String| async move {
+            format!("{} {}", headers.contains_key(EXPECT), body)
+        }),
+    ))
+    .await;
+    let request = |expect: &str| {
+        format!(
+            "POST /upload HTTP/1.1\r\nHost: {}:{}\r\nExpect: {}\r\nContent-Length: 6\r\nConnection: close\r\n\r\n",
+            harness.proxied_host(),
+            harness.proxy.port(),
+            expect
+        )
+    };
+
+    // The body is only sent once the proxy asks for it
+    let mut stream = BufReader::new(TcpStream::connect(harness.proxy).await.unwrap());
+    stream
+        .write_all(request("100-continue").as_bytes())
+        .await
+        .unwrap();
+
+    let mut line = String::new();
+    timeout(Duration::from_secs(5), stream.read_line(&mut line))
+        .await
+        .expect("the proxy never asked for the body")
+        .unwrap();
+    assert_eq!(line.to_ascii_lowercase(), "http/1.1 100 continue\r\n");
+
+    stream.write_all(b"upload").await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+
+    assert!(response.contains("200 OK\r\n"));
+    // The expectation was met by the proxy, and isn't passed on
+    assert!(response.ends_with("false upload"));
+
+    // Expectations other than `100-continue` can't be met
+    let mut stream = TcpStream::connect(harness.proxy).await.unwrap();
+    stream
+        .write_all(request("something-else").as_bytes())
+        .await
+        .unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 417 Expectation Failed\r\n"));
+}
+
+#[tokio::test]
+async fn echoes_websocket_messages() {
+    let harness = Harness::start(origin()).await;
+
+    let mut socket = harness
+        .client()
+        .get(harness.url("/ws"))
+        .upgrade()
+        .send()
+        .await
+        .unwrap()
+        .into_websocket()
+        .await
+        .unwrap();
+
+    socket.send(Message::Text("hello".into())).await.unwrap();
+
+    match socket.next().await {
+        Some(Ok(Message::Text(text))) => assert_eq!(text, "hello"),
+        message => panic!("expected the echoed message, got {:?}", message),
+    }
+}
+
+#[tokio::test]
+async fn blocks_websockets_by_filter_lists() {
+    let list = std::env::temp_dir().join(format!("gs-ws-filters-{}.txt", std::process::id()));
+    std::fs::write(&list, "||127.0.0.1^$websocket\n").unwrap();
+
+    let harness = Harness::start_with(origin(), |config| {
+        config.blocking.enabled = true;
+        config.blocking.lists = vec![list.to_string_lossy().into_owned()];
+    })
+    .await;
+
+    // The lists are loaded in the background
+    let mut response = None;
+    for _ in 0..50 {
+        let upgrade = harness
+            .client()
+            .get(harness.url("/ws"))
+            .upgrade()
+            .send()
+            .await
+            .unwrap();
+        if upgrade.headers().contains_key("x-gs-blocked") {
+            response = Some(upgrade.into_inner());
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+
+    let response = response.expect("the WebSocket wasn't blocked");
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // Only WebSockets are blocked by the rule
+    let response = harness
+        .client()
+        .get(harness.url("/page"))
+        .header("sec-fetch-dest", "document")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    std::fs::remove_file(list).unwrap();
+}
+
+#[tokio::test]
+async fn blocks_websockets_by_rules() {
+    let harness = Harness::start_with(origin(), |config| {
+        config.rules.push(Rule {
+            origin: Some("127.0.0.1".to_string()),
+            path: Some(PathPattern(Regex::new("^/ws$").unwrap())),
+            content_type: None,
+            actions: vec![RuleAction::Block],
+        });
+    })
+    .await;
+
+    let response = harness
+        .client()
+        .get(harness.url("/ws"))
+        .upgrade()
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert!(response.headers().contains_key("x-gs-correlation-id"));
+}
+
+#[tokio::test]
+async fn isolates_requests_that_panic() {
+    struct Panicking;
+
+    impl ProxyHook for Panicking {
+        fn on_response(&self, event: &ResponseEvent) {
+            if event.url.ends_with("/target") {
+                panic!("a hook bug");
+            }
+        }
+    }
+
+    let harness = Harness::start_with_builder(
+        origin(),
+        |_| {},
+        |builder| builder.with_request_hook(Arc::new(Panicking)),
+    )
+    .await;
+    let client = harness.client();
+
+    let response = client.get(harness.url("/target")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+    // The connection the panic happened on keeps serving requests
+    let response = client.get(harness.url("/page")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn passes_bodiless_and_unusual_responses_through() {
+    let statuses = origin()
+        .route("/no-content", get(|| async { StatusCode::NO_CONTENT }))
+        .route(
+            "/not-modified",
+            get(|| async { (StatusCode::NOT_MODIFIED, [(ETAG, r#""v1""#)]) }),
+        )
+        .route(
+            "/unusual",
+            get(|| async { (StatusCode::from_u16(599).unwrap(), Html("<p>odd</p>")) }),
+        );
+    let harness = Harness::start(statuses).await;
+    let client = harness.client();
+
+    let page_length = client
+        .get(harness.origin_url("/page"))
+        .send()
+        .await
+        .unwrap()
+        .content_length();
+    let response = client.head(harness.url("/page")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()[CONTENT_LENGTH].to_str().unwrap(),
+        page_length.unwrap().to_string()
+    );
+    assert!(response.bytes().await.unwrap().is_empty());
+
+    let response = client.get(harness.url("/no-content")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert!(response.headers().get(CONTENT_LENGTH).is_none());
+    assert!(response.bytes().await.unwrap().is_empty());
+
+    let response = client
+        .get(harness.url("/not-modified"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(response.headers()[ETAG], r#""v1""#);
+    assert!(response.bytes().await.unwrap().is_empty());
+
+    let response = client.get(harness.url("/unusual")).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 599);
+    assert!(response.text().await.unwrap().contains("<p>odd</p>"));
+}
+
+#[tokio::test]
+async fn answers_invalid_addresses() {
+    let harness = Harness::start(origin()).await;
+
+    let client = reqwest::Client::builder()
+        .resolve(&format!("not-base32!.{}", PUBLIC_HOST), harness.proxy)
+        .build()
+        .unwrap();
+
+    let response = client
+        .get(format!(
+            "http://not-base32!.{}:{}/",
+            PUBLIC_HOST,
+            harness.proxy.port()
+        ))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn serves_favicons() {
+    let icon = std::env::temp_dir().join(format!("gs-favicon-{}.png", std::process::id()));
+    std::fs::write(&icon, "default icon").unwrap();
+
+    let fetches = Arc::new(AtomicUsize::new(0));
+    let counted = fetches.clone();
+    let default_icon = icon.clone();
+    let harness = Harness::start_with(
+        origin().route(
+            "/favicon.ico",
+            get(move || async move {
+                counted.fetch_add(1, Ordering::SeqCst);
+                ([(CONTENT_TYPE, "image/x-icon")], "origin icon")
+            }),
+        ),
+        |config| {
+            config.favicon.enabled = true;
+            config.favicon.default_icon = Some(default_icon);
+        },
+    )
+    .await;
+    let client = harness.client();
+
+    // The origin's icon is only fetched once
+    for _ in 0..2 {
+        let response = client
+            .get(harness.url("/favicon.ico"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[CONTENT_TYPE], "image/x-icon");
+        assert_eq!(response.text().await.unwrap(), "origin icon");
+    }
+    assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+    let client = reqwest::Client::builder()
+        .resolve(&format!("not-base32!.{}", PUBLIC_HOST), harness.proxy)
+        .build()
+        .unwrap();
+    let response = client
+        .get(format!(
+            "http://not-base32!.{}:{}/favicon.ico",
+            PUBLIC_HOST,
+            harness.proxy.port()
+        ))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[CONTENT_TYPE], "image/png");
+    assert_eq!(response.text().await.unwrap(), "default icon");
+
+    std::fs::remove_file(icon).unwrap();
+}
+
+#[tokio::test]
+async fn accepts_hosts_encoded_with_previous_keys() {
+    let harness = Harness::start_with(origin(), |config| {
+        config.url_encoding_algorithm = UrlEncodingAlgorithm::Base32Xor(Alphabet::Z, vec![0x91]);
+        config.previous_keys = vec![vec![0x11]];
+    })
+    .await;
+
+    // A link shared before the key was rotated
+    let old = Config {
+        url_encoding_algorithm: UrlEncodingAlgorithm::Base32Xor(Alphabet::Z, vec![0x11]),
+        ..harness.config.clone()
+    };
+    let old_url = encode_url(&old, &harness.origin_url("/target"));
+    let old_host = old_url
+        .trim_start_matches("https://")
+        .trim_end_matches("/target")
+        .to_string();
+    assert_ne!(old_host, harness.proxied_host());
+
+    let client = reqwest::Client::builder()
+        .resolve(&old_host, harness.proxy)
+        .build()
+        .unwrap();
+    let response = client
+        .get(format!(
+            "http://{}:{}/target",
+            old_host,
+            harness.proxy.port()
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let keys: serde_json::Value = serde_json::from_str(
+        &harness
+            .api(Method::GET, "/encoding-keys")
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(keys[0]["role"], "current");
+    assert_eq!(keys[1]["role"], "previous");
+    assert_eq!(keys[1]["matches"], 1);
+    assert!(keys[1]["last_matched"].is_string());
+}
+
+#[tokio::test]
+async fn logs_slow_requests_with_their_timings() {
+    let slow = Router::new()
+        .route("/fast", get(|| async { "fast" }))
+        .route(
+            "/slow",
+            get(|| async {
+                tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+                "slow"
+            }),
+        );
+    let harness = Harness::start_with(slow, |config| {
+        config.metrics.slow_request_ms = 200;
+    })
+    .await;
+
+    for path in ["/fast", "/slow"] {
+        let response = harness
+            .client()
+            .get(harness.url(path))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    let stats: serde_json::Value = serde_json::from_str(
+        &harness
+            .api(Method::GET, "/stats")
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap(),
+    )
+    .unwrap();
+
+    let origin = &stats["origins"][0];
+    assert_eq!(origin["origin"], harness.origin_url(""));
+    assert_eq!(origin["requests"], 2);
+    assert!(origin["max_ms"].as_u64().unwrap() >= 300);
+
+    let slow_requests = stats["slow_requests"].as_array().unwrap();
+    assert_eq!(slow_requests.len(), 1);
+    assert_eq!(slow_requests[0]["path"], "/slow");
+    assert_eq!(slow_requests[0]["status"], 200);
+    assert!(slow_requests[0]["upstream_ms"].as_u64().unwrap() >= 300);
+}
+
+#[tokio::test]
+async fn toggles_access_rules_from_the_admin_api() {
+    let harness = Harness::start(origin()).await;
+
+    let page = harness.api(Method::GET, "/admin").send().await.unwrap();
+    assert_eq!(page.status(), StatusCode::OK);
+    assert!(page.text().await.unwrap().contains("Admin dashboard"));
+
+    // Changing the rules needs an admin key, and isn't opened to other sites
+    let response = harness
+        .anonymous_api(Method::POST, "/access/rules")
+        .header(ORIGIN, "https://elsewhere.test")
+        .header(CONTENT_TYPE, "application/json")
+        .body(r#"{"list": "deny", "network": "127.0.0.1/32", "enabled": true}"#)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert!(!response.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+
+    let toggle = |enabled: bool| {
+        harness
+            .api(Method::POST, "/access/rules")
+            .header(CONTENT_TYPE, "application/json")
+            .body(format!(
+                r#"{{"list": "deny", "network": "127.0.0.1/32", "enabled": {}}}"#,
+                enabled
+            ))
+            .send()
+    };
+
+    let rules = json(toggle(true).await.unwrap()).await;
+    assert_eq!(rules["deny"][0], "127.0.0.1/32");
+
+    let response = harness
+        .client()
+        .get(harness.url("/page"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let rules = json(toggle(false).await.unwrap()).await;
+    assert_eq!(rules["deny"].as_array().unwrap().len(), 0);
+
+    let response = harness
+        .client()
+        .get(harness.url("/page"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let dashboard = json(harness.api(Method::GET, "/dashboard").send().await.unwrap()).await;
+    assert_eq!(dashboard["traffic"]["requests"], 1);
+    assert_eq!(dashboard["access"]["denied_address"], 1);
+    assert_eq!(
+        dashboard["top_origins"][0]["origin"],
+        harness.origin_url("")
+    );
+}
+
+/// Set once the body it is kept in is dropped
+struct Dropped(Arc<AtomicBool>);
+
+impl Drop for Dropped {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// A body that never ends, setting `dropped` once it's no longer read
+fn endless(dropped: &Arc<AtomicBool>) -> Body {
+    Body::from_stream(stream::unfold(
+        Dropped(dropped.clone()),
+        |guard| async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            Some((
+                Ok::<_, Infallible>(Bytes::from_static(b"<p>more</p>")),
+                guard,
+            ))
+        },
+    ))
+}
+
+#[tokio::test]
+async fn cancels_upstream_transfers_of_aborted_requests() {
+    let streamed = Arc::new(AtomicBool::new(false));
+    let rewritten = Arc::new(AtomicBool::new(false));
+    let harness = Harness::start(
+        origin()
+            .route(
+                "/download",
+                get({
+                    let streamed = streamed.clone();
+                    move || async move { endless(&streamed) }
+                }),
+            )
+            .route(
+                "/endless-page",
+                get({
+                    let rewritten = rewritten.clone();
+                    move || async move { ([(CONTENT_TYPE, "text/html")], endless(&rewritten)) }
+                }),
+            ),
+    )
+    .await;
+    let client = harness.client();
+
+    // The client goes away in the middle of a download
+    let mut response = client.get(harness.url("/download")).send().await.unwrap();
+    response.chunk().await.unwrap();
+    drop(response);
+
+    // And before a page that is read whole to be rewritten is ready
+    let page = client.get(harness.url("/endless-page")).send();
+    assert!(timeout(Duration::from_millis(200), page).await.is_err());
+
+    for dropped in [&streamed, &rewritten] {
+        timeout(Duration::from_secs(5), async {
+            while !dropped.load(Ordering::SeqCst) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the upstream body was still being read");
+    }
+
+    // Requests that were answered in full aren't counted
+    for method in [Method::GET, Method::HEAD] {
+        let response = client
+            .request(method, harness.url("/page"))
+            .send()
+            .await
+            .unwrap();
+        response.bytes().await.unwrap();
+    }
+
+    let dashboard = json(harness.api(Method::GET, "/dashboard").send().await.unwrap()).await;
+    assert_eq!(dashboard["traffic"]["cancelled"], 2);
+}
+
+#[tokio::test]
+async fn fails_responses_the_upstream_stops_sending() {
+    let harness = Harness::start_with(
+        origin().route(
+            "/stalling",
+            get(|| async {
+                Body::from_stream(
+                    stream::once(async { Ok::<_, Infallible>(Bytes::from_static(b"start")) })
+                        .chain(stream::pending()),
+                )
+            }),
+        ),
+        |config| config.downloads.stall_timeout_secs = Some(1),
+    )
+    .await;
+
+    let response = harness
+        .client()
+        .get(harness.url("/stalling"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = timeout(Duration::from_secs(5), response.bytes())
+        .await
+        .expect("the stalled response was kept open");
+    assert!(body.is_err());
+}
+
+#[tokio::test]
+async fn closes_websockets_over_the_message_rate() {
+    let harness = Harness::start_with(origin(), |config| {
+        config.websockets.max_messages_per_sec = Some(2);
+    })
+    .await;
+
+    let mut socket = harness
+        .client()
+        .get(harness.url("/ws"))
+        .upgrade()
+        .send()
+        .await
+        .unwrap()
+        .into_websocket()
+        .await
+        .unwrap();
+
+    for _ in 0..3 {
+        socket.send(Message::Text("hello".into())).await.unwrap();
+    }
+
+    // Some of the first messages may be echoed before the connection is closed
+    loop {
+        match socket.next().await {
+            Some(Ok(Message::Text(_))) => {}
+            Some(Ok(Message::Close { code, .. })) => {
+                assert_eq!(u16::from(code), 1008);
+                break;
+            }
+            message => panic!("expected the connection to be closed, got {:?}", message),
+        }
+    }
+}
+
+/// An FTP server with `/pub/hello.txt` and an empty `/pub/sub/`, for one session at a time
+async fn ftp_server() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    tokio::spawn(async move {
+        loop {
+            let (control, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = control.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            let mut data = None;
+
+            writer.write_all(b"220 ready\r\n").await.unwrap();
+
+            while let Ok(Some(line)) = lines.next_line().await {
+                let (command, argument) = line.split_once(' ').unwrap_or((&line, ""));
+
+                let reply = match (command, argument) {
+                    ("USER", _) => "331 password please",
+                    ("PASS", _) => "230 logged in",
+                    ("TYPE", _) => "200 binary",
+                    ("CWD", "/pub" | "/pub/" | "/pub/sub" | "/pub/sub/") => "250 ok",
+                    ("CWD", _) => "550 no such directory",
+                    ("SIZE", "/pub/hello.txt") => "213 5",
+                    ("EPSV", _) => {
+                        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                        let port = listener.local_addr().unwrap().port();
+                        data = Some(listener);
+
+                        writer
+                            .write_all(format!("229 passive (|||{port}|)\r\n").as_bytes())
+                            .await
+                            .unwrap();
+                        continue;
+                    }
+                    ("MLSD", _) | ("RETR", "/pub/hello.txt") => {
+                        let content: &[u8] = match command {
+                            "MLSD" => {
+                                b"type=cdir; .\r\ntype=file;size=5; hello.txt\r\ntype=dir; sub\r\n"
+                            }
+                            _ => b"hello",
+                        };
+
+                        writer.write_all(b"150 sending\r\n").await.unwrap();
+                        let (mut stream, _) = data.take().unwrap().accept().await.unwrap();
+                        stream.write_all(content).await.unwrap();
+                        drop(stream);
+                        "226 done"
+                    }
+                    _ => "550 not found",
+                };
+
+                writer
+                    .write_all(format!("{reply}\r\n").as_bytes())
+                    .await
+                    .unwrap();
+            }
+        }
+    });
+
+    port
+}
+
+#[tokio::test]
+async fn serves_ftp_through_the_gateway() {
+    let ftp_port = ftp_server().await;
+    let harness = Harness::start_with(origin(), |config| config.ftp.enabled = true).await;
+
+    let proxied = encode_url(&harness.config, &format!("ftp://127.0.0.1:{ftp_port}/"));
+    let host = proxied
+        .trim_start_matches("https://")
+        .trim_end_matches('/')
+        .to_string();
+    let client = reqwest::Client::builder()
+        .resolve(&host, harness.proxy)
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+    let url = |path: &str| format!("http://{}:{}{}", host, harness.proxy.port(), path);
+
+    let response = client.get(url("/pub")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(response.headers()[LOCATION], "/pub/");
+
+    let response = client.get(url("/pub/")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let listing = response.text().await.unwrap();
+    assert!(listing.contains(r#"href="sub/""#));
+    assert!(listing.contains(r#"href="hello.txt""#));
+    assert!(listing.find("sub/") < listing.find("hello.txt"));
+
+    let response = client.get(url("/pub/hello.txt")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()[CONTENT_TYPE],
+        "text/plain; charset=utf-8"
+    );
+    assert_eq!(response.text().await.unwrap(), "hello");
+
+    let response = client.get(url("/pub/missing.txt")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,235 @@
+use std::{collections::HashMap, sync::RwLock};
+
+use axum::{
+    body::Body,
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::Response,
+};
+use futures_util::future::BoxFuture;
+use giggleshitter_common::hooks::{
+    ErrorEvent, HookVerdict, ProxyHook, RequestEvent, ResponseEvent, WebSocketEvent,
+};
+use napi::{
+    bindgen_prelude::*,
+    threadsafe_function::{ErrorStrategy, ThreadsafeFunction, ThreadsafeFunctionCallMode},
+    JsObject, JsUnknown, ValueType,
+};
+use napi_derive::napi;
+
+#[napi(object)]
+/// A request that is about to be sent upstream
+pub struct ProxyRequestEvent {
+    pub method: String,
+    pub url: String,
+    pub headers: HashMap<String, String>,
+    /// The address of the client that made the request, unknown on Unix sockets
+    pub client: Option<String>,
+}
+
+#[napi(object)]
+/// A response received from upstream
+pub struct ProxyResponseEvent {
+    pub method: String,
+    pub url: String,
+    pub status: u32,
+    pub headers: HashMap<String, String>,
+    /// The address of the client that made the request, unknown on Unix sockets
+    pub client: Option<String>,
+}
+
+#[napi(object)]
+/// A request that failed before a response could be sent
+pub struct ProxyErrorEvent {
+    pub host: String,
+    pub message: String,
+    /// The address of the client that made the request, unknown on Unix sockets
+    pub client: Option<String>,
+}
+
+#[napi(object)]
+/// A proxied WebSocket connection
+pub struct ProxyWebSocketEvent {
+    pub url: String,
+}
+
+#[napi(object)]
+/// What an `onRequest` callback may return. Any field may be omitted, and returning nothing lets the
+/// request through unchanged.
+pub struct ProxyRequestVerdict {
+    /// Don't contact the upstream and respond with `status` and `body` instead
+    pub cancel: Option<bool>,
+    /// The status of the cancelled response, defaults to 403 when missing or not a valid status
+    pub status: Option<u32>,
+    pub body: Option<String>,
+    /// Headers to set on the upstream request
+    pub headers: Option<HashMap<String, String>>,
+}
+
+impl ProxyRequestVerdict {
+    // Read the verdict leniently, a malformed return value lets the request through rather than
+    // aborting the process
+    fn from_js(value: JsUnknown) -> Option<Self> {
+        if value.get_type().ok()? != ValueType::Object {
+            return None;
+        }
+
+        let object: JsObject = unsafe { value.cast() };
+
+        Some(Self {
+            cancel: object.get("cancel").ok().flatten(),
+            status: object.get("status").ok().flatten(),
+            body: object.get("body").ok().flatten(),
+            headers: object.get("headers").ok().flatten(),
+        })
+    }
+}
+
+type Callback<T> = RwLock<Option<ThreadsafeFunction<T, ErrorStrategy::Fatal>>>;
+
+#[derive(Default)]
+/// Forwards proxy lifecycle events to JavaScript callbacks
+pub struct NapiHooks {
+    pub on_request: Callback<ProxyRequestEvent>,
+    pub on_response: Callback<ProxyResponseEvent>,
+    pub on_error: Callback<ProxyErrorEvent>,
+    pub on_websocket_open: Callback<ProxyWebSocketEvent>,
+    pub on_websocket_close: Callback<ProxyWebSocketEvent>,
+}
+
+fn headers_to_js(headers: &HeaderMap) -> HashMap<String, String> {
+    let mut map: HashMap<String, String> = HashMap::with_capacity(headers.keys_len());
+
+    for (name, value) in headers {
+        let value = String::from_utf8_lossy(value.as_bytes());
+
+        map.entry(name.to_string())
+            .and_modify(|existing| {
+                existing.push_str(", ");
+                existing.push_str(&value);
+            })
+            .or_insert_with(|| value.into_owned());
+    }
+
+    map
+}
+
+fn notify<T: 'static>(callback: &Callback<T>, event: T) {
+    if let Some(callback) = callback.read().unwrap().as_ref() {
+        callback.call(event, ThreadsafeFunctionCallMode::NonBlocking);
+    }
+}
+
+impl ProxyHook for NapiHooks {
+    fn on_request<'a>(&'a self, event: &'a mut RequestEvent) -> BoxFuture<'a, HookVerdict> {
+        Box::pin(async move {
+            let rx = {
+                let callback = self.on_request.read().unwrap();
+                let Some(callback) = callback.as_ref() else {
+                    return HookVerdict::Continue;
+                };
+
+                let (tx, rx) = tokio::sync::oneshot::channel();
+
+                callback.call_with_return_value(
+                    ProxyRequestEvent {
+                        method: event.method.to_string(),
+                        url: event.url.clone(),
+                        headers: headers_to_js(&event.headers),
+                        client: event.client.map(|ip| ip.to_string()),
+                    },
+                    ThreadsafeFunctionCallMode::NonBlocking,
+                    move |value: JsUnknown| {
+                        let _ = tx.send(ProxyRequestVerdict::from_js(value));
+                        Ok(())
+                    },
+                );
+
+                rx
+            };
+
+            let Ok(Some(verdict)) = rx.await else {
+                return HookVerdict::Continue;
+            };
+
+            if verdict.cancel.unwrap_or(false) {
+                let status = verdict
+                    .status
+                    .and_then(|status| u16::try_from(status).ok())
+                    .and_then(|status| StatusCode::from_u16(status).ok())
+                    .unwrap_or(StatusCode::FORBIDDEN);
+
+                return HookVerdict::Respond(
+                    Response::builder()
+                        .status(status)
+                        .body(Body::from(verdict.body.unwrap_or_default()))
+                        .unwrap(),
+                );
+            }
+
+            for (name, value) in verdict.headers.unwrap_or_default() {
+                if let (Ok(name), Ok(value)) = (
+                    HeaderName::from_bytes(name.as_bytes()),
+                    HeaderValue::from_str(&value),
+                ) {
+                    event.headers.insert(name, value);
+                }
+            }
+
+            HookVerdict::Continue
+        })
+    }
+
+    fn on_response(&self, event: &ResponseEvent) {
+        notify(
+            &self.on_response,
+            ProxyResponseEvent {
+                method: event.method.to_string(),
+                url: event.url.clone(),
+                status: event.status.as_u16().into(),
+                headers: headers_to_js(&event.headers),
+                client: event.client.map(|ip| ip.to_string()),
+            },
+        );
+    }
+
+    fn on_error(&self, event: &ErrorEvent) {
+        notify(
+            &self.on_error,
+            ProxyErrorEvent {
+                host: event.host.clone(),
+                message: event.message.clone(),
+                client: event.client.map(|ip| ip.to_string()),
+            },
+        );
+    }
+
+    fn on_websocket_open(&self, event: &WebSocketEvent) {
+        notify(
+            &self.on_websocket_open,
+            ProxyWebSocketEvent {
+                url: event.url.clone(),
+            },
+        );
+    }
+
+    fn on_websocket_close(&self, event: &WebSocketEvent) {
+        notify(
+            &self.on_websocket_close,
+            ProxyWebSocketEvent {
+                url: event.url.clone(),
+            },
+        );
+    }
+}
+
+/// Store a callback, unreferencing it so a registered hook doesn't keep Node alive on its own
+pub fn set_callback<T: 'static>(
+    env: &Env,
+    slot: &Callback<T>,
+    mut callback: ThreadsafeFunction<T, ErrorStrategy::Fatal>,
+) -> Result<()> {
+    callback.unref(env)?;
+    *slot.write().unwrap() = Some(callback);
+
+    Ok(())
+}
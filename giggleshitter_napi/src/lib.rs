@@ -1,11 +1,35 @@
-use std::sync::Arc;
+mod hooks;
 
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use arc_swap::ArcSwap;
 use base32::Alphabet;
-use giggleshitter_common::state::{Config, UrlEncodingAlgorithm};
-use napi::bindgen_prelude::*;
+use giggleshitter_common::{
+    listener::{ListenAddr, Listener},
+    logging::{self, LoggingGuard},
+    openapi_json,
+    proxy::util,
+    server::ServerBuilder,
+    state::{
+        Config, LiveConfig, LogFileConfig, LogFormat, LoggingConfig, TelemetryConfig,
+        UrlEncodingAlgorithm,
+    },
+    validation::{ConfigError, ConfigProblem},
+};
+use hooks::{
+    set_callback, NapiHooks, ProxyErrorEvent, ProxyRequestEvent, ProxyResponseEvent,
+    ProxyWebSocketEvent,
+};
+use napi::{
+    bindgen_prelude::*,
+    threadsafe_function::{ErrorStrategy, ThreadsafeFunction},
+};
 use napi_derive::napi;
-use scorched::{LogExpect, LogImportance};
-use tokio::sync::oneshot::{Receiver, Sender};
+use scorched::{logf, LogData, LogImportance};
+use tokio::{sync::oneshot::Sender, task::JoinHandle};
 
 #[napi]
 #[derive(Debug)]
@@ -18,6 +42,26 @@ pub enum AlphabetNapi {
     Z,
 }
 
+#[napi]
+#[derive(Debug)]
+pub enum LogFormatNapi {
+    Full,
+    Compact,
+    Pretty,
+    Json,
+}
+
+impl From<LogFormatNapi> for LogFormat {
+    fn from(format: LogFormatNapi) -> Self {
+        match format {
+            LogFormatNapi::Full => LogFormat::Full,
+            LogFormatNapi::Compact => LogFormat::Compact,
+            LogFormatNapi::Pretty => LogFormat::Pretty,
+            LogFormatNapi::Json => LogFormat::Json,
+        }
+    }
+}
+
 #[napi(object)]
 #[derive(Debug, Clone)]
 pub struct EncoderOptions {
@@ -37,9 +81,19 @@ impl Default for EncoderOptions {
 #[napi(object)]
 #[derive(Debug, Clone)]
 pub struct ServeConfig {
+    /// The listen address, either `<ip>:<port>` or `unix:/path/to.sock`. Use port 0 to let the OS
+    /// pick a free port and read it back from `App.port` once started.
     pub host: Option<String>,
     pub public_host: Option<String>,
     pub encoder: Option<EncoderOptions>,
+    /// The OTLP gRPC endpoint to export spans to, requires a build with the `otel` feature
+    pub otlp_endpoint: Option<String>,
+    pub log_format: Option<LogFormatNapi>,
+    /// Which messages to log, as `tracing` filter directives such as `info,hyper=warn`.
+    /// Overridden by the `RUST_LOG` environment variable.
+    pub log_filter: Option<String>,
+    /// Also write log messages to daily rotated files in this directory
+    pub log_directory: Option<String>,
 }
 
 impl Default for ServeConfig {
@@ -48,11 +102,57 @@ impl Default for ServeConfig {
             host: Some("0.0.0.0:3069".to_string()),
             public_host: Some("changeme.local".to_string()),
             encoder: Some(EncoderOptions::default()),
+            otlp_endpoint: None,
+            log_format: None,
+            log_filter: None,
+            log_directory: None,
         }
     }
 }
 
 impl ServeConfig {
+    // Overwrite the fields that are set in the partial config
+    fn merge(&mut self, partial: ServeConfig) {
+        if partial.host.is_some() {
+            self.host = partial.host;
+        }
+
+        if partial.public_host.is_some() {
+            self.public_host = partial.public_host;
+        }
+
+        if partial.otlp_endpoint.is_some() {
+            self.otlp_endpoint = partial.otlp_endpoint;
+        }
+
+        if partial.log_format.is_some() {
+            self.log_format = partial.log_format;
+        }
+
+        if partial.log_filter.is_some() {
+            self.log_filter = partial.log_filter;
+        }
+
+        if partial.log_directory.is_some() {
+            self.log_directory = partial.log_directory;
+        }
+
+        if let Some(partial_encoder) = partial.encoder {
+            match self.encoder.as_mut() {
+                Some(encoder) => {
+                    if partial_encoder.alphabet.is_some() {
+                        encoder.alphabet = partial_encoder.alphabet;
+                    }
+
+                    if partial_encoder.key.is_some() {
+                        encoder.key = partial_encoder.key;
+                    }
+                }
+                None => self.encoder = Some(partial_encoder),
+            }
+        }
+    }
+
     // Set all none values to default values
     fn set_defaults(&mut self) {
         let default = ServeConfig::default();
@@ -65,16 +165,22 @@ impl ServeConfig {
             self.public_host = default.public_host;
         }
 
-        if self.encoder.is_none() {
-            self.encoder = default.encoder;
-        } else if self.encoder.as_ref().unwrap().alphabet.is_none() {
-            self.encoder.as_mut().unwrap().alphabet = default.encoder.unwrap().alphabet;
+        match self.encoder.as_mut() {
+            None => self.encoder = default.encoder,
+            Some(encoder) if encoder.alphabet.is_none() => {
+                encoder.alphabet = default.encoder.unwrap().alphabet;
+            }
+            Some(_) => {}
         }
     }
 }
 
-impl From<ServeConfig> for Config {
-    fn from(config: ServeConfig) -> Self {
+impl TryFrom<ServeConfig> for Config {
+    type Error = Error;
+
+    /// Convert a config with its defaults set, failing with every problem
+    /// [`Config::validate`] finds
+    fn try_from(config: ServeConfig) -> Result<Self> {
         let encoder = config.encoder.unwrap();
         // KMS
         let url_encoding_algorithm = match (encoder.alphabet.unwrap(), encoder.key) {
@@ -111,44 +217,132 @@ impl From<ServeConfig> for Config {
             (AlphabetNapi::Z, Some(key)) => UrlEncodingAlgorithm::Base32Xor(Alphabet::Z, key),
         };
 
-        Config {
+        let host = config.host.unwrap();
+        let host = host
+            .parse()
+            .map_err(|_| invalid_config(ConfigError(vec![ConfigProblem::InvalidHost(host)])))?;
+
+        let default_logging = LoggingConfig::default();
+        let config = Config {
             url_encoding_algorithm,
-            host: config.host.unwrap().parse().unwrap(),
+            host,
             public_host: config.public_host.unwrap(),
-        }
+            logging: LoggingConfig {
+                format: config.log_format.map(Into::into).unwrap_or_default(),
+                filter: config.log_filter.unwrap_or(default_logging.filter),
+                file: config
+                    .log_directory
+                    .map(|directory| LogFileConfig::in_directory(directory.into())),
+            },
+            telemetry: TelemetryConfig {
+                otlp_endpoint: config.otlp_endpoint,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        config.validate().map_err(invalid_config)?;
+
+        Ok(config)
     }
 }
 
+fn invalid_config(e: ConfigError) -> Error {
+    Error::new(Status::InvalidArg, e.to_string())
+}
+
+#[napi(object)]
+/// The upstream origin a proxied host points to
+pub struct DecodedOrigin {
+    /// Either `http` or `https`
+    pub scheme: String,
+    pub host: String,
+    pub port: u32,
+    /// The full origin, e.g. `https://example.com:443`
+    pub origin: String,
+}
+
+fn resolve_config(config: ServeConfig) -> Result<Config> {
+    let mut config = config;
+    config.set_defaults();
+    config.try_into()
+}
+
+#[napi]
+/// Encode a URL so that it is fetched through the proxy
+pub fn encode_url(config: ServeConfig, url: String) -> Result<String> {
+    Ok(util::encode_url(&resolve_config(config)?, &url))
+}
+
+#[napi]
+/// Decode the upstream origin from a proxied host, e.g. `<encoded>.changeme.local`
+pub fn decode_host(config: ServeConfig, host: String) -> Result<DecodedOrigin> {
+    let origin = util::proxied_origin(&resolve_config(config)?, &host)
+        .map_err(|e| Error::new(Status::InvalidArg, e.to_string()))?;
+
+    Ok(DecodedOrigin {
+        scheme: origin.scheme().as_str().to_string(),
+        host: origin.host().to_string(),
+        port: origin.port().into(),
+        origin: origin.into(),
+    })
+}
+
+#[napi]
+/// Decode a proxied URL back into the URL of the upstream resource
+pub fn decode_url(config: ServeConfig, url: String) -> Result<String> {
+    util::decode_url(&resolve_config(config)?, &url)
+        .map_err(|e| Error::new(Status::InvalidArg, e.to_string()))
+}
+
+#[napi]
+/// The OpenAPI document of the API host, as JSON
+pub fn openapi_spec() -> String {
+    openapi_json()
+}
+
 #[napi]
 pub struct App {
     pub config: ServeConfig,
-    channel: (Option<Sender<()>>, Option<Receiver<()>>),
+    live_config: LiveConfig,
+    shutdown: Option<Sender<()>>,
+    running: Arc<AtomicBool>,
+    server_handle: Option<JoinHandle<()>>,
+    address: Option<ListenAddr>,
+    hooks: Arc<NapiHooks>,
+    logging: Option<LoggingGuard>,
 }
 
 #[napi]
 impl App {
     #[napi(constructor)]
     pub fn new(config: Option<ServeConfig>) -> Result<Self> {
-        let channel = {
-            let channel = tokio::sync::oneshot::channel::<()>();
-            (Some(channel.0), Some(channel.1))
-        };
-
         let mut config = config.unwrap_or_default();
 
         config.set_defaults();
 
-        Ok(Self { config, channel })
+        let live_config = Arc::new(ArcSwap::from_pointee(Config::try_from(config.clone())?));
+
+        Ok(Self {
+            config,
+            live_config,
+            shutdown: None,
+            running: Arc::new(AtomicBool::new(false)),
+            server_handle: None,
+            address: None,
+            hooks: Arc::new(NapiHooks::default()),
+            logging: None,
+        })
     }
 
     #[napi]
-    /// Close the server
+    /// Close the server, after which it can be started again
     /// # Safety
     /// This function is marked as unsafe because of a limitation in the napi crate.
     pub async unsafe fn close(&mut self) -> Result<()> {
-        match self.channel.0.take() {
-            Some(rx) => {
-                let _ = rx.send(());
+        match self.shutdown.take() {
+            Some(tx) => {
+                let _ = tx.send(());
             }
             None => {
                 return Err(Error::new(
@@ -158,37 +352,177 @@ impl App {
             }
         }
 
+        // Wait for the server to wind down so that a subsequent start can bind the same address
+        if let Some(server_handle) = self.server_handle.take() {
+            let _ = server_handle.await;
+        }
+
         Ok(())
     }
 
     #[napi]
-    /// Start the server
+    /// Update the configuration, only the fields set in `partial` are changed. The new
+    /// configuration applies to all requests made after the update, apart from the listen address
+    /// which only changes when the server is restarted. An invalid update is rejected and leaves
+    /// the configuration unchanged.
+    pub fn update_config(&mut self, partial: ServeConfig) -> Result<()> {
+        let mut config = self.config.clone();
+        config.merge(partial);
+        config.set_defaults();
+
+        self.live_config
+            .store(Arc::new(Config::try_from(config.clone())?));
+        self.config = config;
+
+        Ok(())
+    }
+
+    #[napi(
+        ts_args_type = "callback: (event: ProxyRequestEvent) => ProxyRequestVerdict | undefined | void"
+    )]
+    /// Register a callback invoked before each request is sent upstream. The callback may return a
+    /// `ProxyRequestVerdict` to cancel the request or set upstream request headers.
+    pub fn on_request(
+        &self,
+        env: Env,
+        callback: ThreadsafeFunction<ProxyRequestEvent, ErrorStrategy::Fatal>,
+    ) -> Result<()> {
+        set_callback(&env, &self.hooks.on_request, callback)
+    }
+
+    #[napi(ts_args_type = "callback: (event: ProxyResponseEvent) => void")]
+    /// Register a callback invoked when a response is received from upstream
+    pub fn on_response(
+        &self,
+        env: Env,
+        callback: ThreadsafeFunction<ProxyResponseEvent, ErrorStrategy::Fatal>,
+    ) -> Result<()> {
+        set_callback(&env, &self.hooks.on_response, callback)
+    }
+
+    #[napi(ts_args_type = "callback: (event: ProxyErrorEvent) => void")]
+    /// Register a callback invoked when a request fails
+    pub fn on_error(
+        &self,
+        env: Env,
+        callback: ThreadsafeFunction<ProxyErrorEvent, ErrorStrategy::Fatal>,
+    ) -> Result<()> {
+        set_callback(&env, &self.hooks.on_error, callback)
+    }
+
+    #[napi(ts_args_type = "callback: (event: ProxyWebSocketEvent) => void")]
+    /// Register a callback invoked when a proxied WebSocket connection is opened
+    pub fn on_web_socket_open(
+        &self,
+        env: Env,
+        callback: ThreadsafeFunction<ProxyWebSocketEvent, ErrorStrategy::Fatal>,
+    ) -> Result<()> {
+        set_callback(&env, &self.hooks.on_websocket_open, callback)
+    }
+
+    #[napi(ts_args_type = "callback: (event: ProxyWebSocketEvent) => void")]
+    /// Register a callback invoked when a proxied WebSocket connection is closed
+    pub fn on_web_socket_close(
+        &self,
+        env: Env,
+        callback: ThreadsafeFunction<ProxyWebSocketEvent, ErrorStrategy::Fatal>,
+    ) -> Result<()> {
+        set_callback(&env, &self.hooks.on_websocket_close, callback)
+    }
+
+    #[napi]
+    /// Whether the server is currently accepting connections
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    #[napi(getter)]
+    /// The address the server is bound to, including the kernel-assigned port when the configured
+    /// host uses port 0
+    pub fn address(&self) -> Option<String> {
+        self.address.as_ref().map(|address| address.to_string())
+    }
+
+    #[napi(getter)]
+    /// The port the server is bound to, if it is listening on TCP
+    pub fn port(&self) -> Option<u32> {
+        match self.address {
+            Some(ListenAddr::Tcp(address)) => Some(address.port().into()),
+            _ => None,
+        }
+    }
+
+    #[napi]
+    /// Start the server in the background, resolving with the bound address once the listener is
+    /// ready to accept connections
     /// # Safety
     /// This function is marked as unsafe because of a limitation in the napi crate.
-    pub async unsafe fn serve(&mut self) -> Result<()> {
-        let _ = tracing_subscriber::fmt::try_init();
+    pub async unsafe fn start(&mut self) -> Result<String> {
+        if self.is_running() {
+            return Err(Error::new(
+                Status::GenericFailure,
+                "Server is already running",
+            ));
+        }
 
-        let config = self.config.clone();
+        // Pick up any changes made to the `config` property since the last update
+        self.config.set_defaults();
+        let config = Config::try_from(self.config.clone())?;
 
-        let rx = match self.channel.1.take() {
-            Some(rx) => rx,
-            None => {
-                return Err(Error::new(
-                    Status::GenericFailure,
-                    "Server is already running",
-                ));
+        // The host process or another `App` may have installed a subscriber already
+        if self.logging.is_none() {
+            self.logging = logging::init(&config).ok();
+        }
+        let host = config.host.clone();
+        self.live_config.store(Arc::new(config));
+
+        let listener = Listener::bind(&host).await.map_err(|e| {
+            Error::new(
+                Status::GenericFailure,
+                format!("Failed to bind {}: {}", host, e),
+            )
+        })?;
+
+        let address = listener.local_addr()?;
+        self.address = Some(address.clone());
+
+        let running = self.running.clone();
+        running.store(true, Ordering::SeqCst);
+
+        let hooks = self.hooks.clone();
+
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        self.shutdown = Some(tx);
+
+        let live_config = self.live_config.clone();
+
+        self.server_handle = Some(tokio::spawn(async move {
+            if let Err(e) = ServerBuilder::with_live_config(live_config)
+                .with_request_hook(hooks)
+                .serve_with_listener(listener, async {
+                    rx.await.ok();
+                })
+                .await
+            {
+                logf!(Error, "Server stopped with an error: {}", e);
             }
-        };
 
-        let server_handle = tokio::spawn(async move {
-            giggleshitter_common::serve(Arc::new(config.into()), async {
-                rx.await.ok();
-            })
-            .await
-            .log_expect(LogImportance::Error, "Failed to start server")
-        });
+            running.store(false, Ordering::SeqCst);
+        }));
+
+        Ok(address.to_string())
+    }
 
-        let _ = server_handle.await;
+    #[napi]
+    /// Start the server, resolving once it has been closed
+    /// # Safety
+    /// This function is marked as unsafe because of a limitation in the napi crate.
+    pub async unsafe fn serve(&mut self) -> Result<()> {
+        self.start().await?;
+
+        if let Some(server_handle) = self.server_handle.take() {
+            let _ = server_handle.await;
+        }
 
         Ok(())
     }
@@ -0,0 +1,139 @@
+use std::path::PathBuf;
+
+use base32::Alphabet;
+use clap::{Parser, Subcommand, ValueEnum};
+use giggleshitter_common::{
+    listener::ListenAddr,
+    state::{Config, LogFormat, UrlEncodingAlgorithm},
+};
+use tracing_subscriber::filter::LevelFilter;
+
+#[derive(Parser)]
+#[command(version, about)]
+/// The giggleshitter proxy server. Every option can also be set with its `GS_*` environment
+/// variable, and takes precedence over the configuration file.
+pub struct Cli {
+    /// Path to the configuration file, defaults to the platform configuration directory
+    #[arg(short, long, env = "GS_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// The listen address, either `<ip>:<port>`, `unix:<path>` or `systemd`
+    #[arg(long, env = "GS_HOST")]
+    pub host: Option<ListenAddr>,
+
+    /// The public root domain the proxied hosts are served on
+    #[arg(long, env = "GS_PUBLIC_HOST")]
+    pub public_host: Option<String>,
+
+    /// The base32 alphabet used to encode proxied origins, any configured XOR key is kept
+    #[arg(long, env = "GS_ALPHABET", value_enum)]
+    pub alphabet: Option<AlphabetArg>,
+
+    /// The maximum level of log messages to print, replacing the configured filter
+    #[arg(long, env = "GS_LOG_LEVEL")]
+    pub log_level: Option<LevelFilter>,
+
+    /// How log messages are printed
+    #[arg(long, env = "GS_LOG_FORMAT", value_enum)]
+    pub log_format: Option<LogFormatArg>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Inspect and manage the configuration file
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Print the path of the configuration file
+    Path,
+    /// Print a commented configuration file with the default values
+    Generate {
+        /// Write the configuration to this file instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Check the configuration file for problems, exiting with an error if there are any
+    Validate,
+    /// Print the configuration after applying the command line and environment overrides
+    Show,
+}
+
+#[derive(Copy, Clone, ValueEnum)]
+pub enum AlphabetArg {
+    Crockford,
+    Rfc4648,
+    Rfc4648Lower,
+    Rfc4648Hex,
+    Rfc4648HexLower,
+    Z,
+}
+
+impl From<AlphabetArg> for Alphabet {
+    fn from(alphabet: AlphabetArg) -> Self {
+        match alphabet {
+            AlphabetArg::Crockford => Alphabet::Crockford,
+            AlphabetArg::Rfc4648 => Alphabet::Rfc4648 { padding: false },
+            AlphabetArg::Rfc4648Lower => Alphabet::Rfc4648Lower { padding: false },
+            AlphabetArg::Rfc4648Hex => Alphabet::Rfc4648Hex { padding: false },
+            AlphabetArg::Rfc4648HexLower => Alphabet::Rfc4648HexLower { padding: false },
+            AlphabetArg::Z => Alphabet::Z,
+        }
+    }
+}
+
+#[derive(Copy, Clone, ValueEnum)]
+pub enum LogFormatArg {
+    Full,
+    Compact,
+    Pretty,
+    Json,
+}
+
+impl From<LogFormatArg> for LogFormat {
+    fn from(format: LogFormatArg) -> Self {
+        match format {
+            LogFormatArg::Full => LogFormat::Full,
+            LogFormatArg::Compact => LogFormat::Compact,
+            LogFormatArg::Pretty => LogFormat::Pretty,
+            LogFormatArg::Json => LogFormat::Json,
+        }
+    }
+}
+
+impl Cli {
+    /// Layer the overrides on top of the configuration loaded from the file
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(host) = &self.host {
+            config.host = host.clone();
+        }
+
+        if let Some(public_host) = &self.public_host {
+            config.public_host = public_host.clone();
+        }
+
+        if let Some(alphabet) = self.alphabet {
+            config.url_encoding_algorithm = match &config.url_encoding_algorithm {
+                UrlEncodingAlgorithm::Base32(_) => UrlEncodingAlgorithm::Base32(alphabet.into()),
+                UrlEncodingAlgorithm::Base32Xor(_, key) => {
+                    UrlEncodingAlgorithm::Base32Xor(alphabet.into(), key.clone())
+                }
+            };
+        }
+
+        if let Some(level) = self.log_level {
+            config.logging.filter = level.to_string();
+        }
+
+        if let Some(format) = self.log_format {
+            config.logging.format = format.into();
+        }
+    }
+}
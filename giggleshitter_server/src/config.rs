@@ -0,0 +1,330 @@
+use std::path::PathBuf;
+
+use giggleshitter_common::{error::Result, state::Config};
+use scorched::{logf, LogData, LogImportance};
+
+use crate::cli::{Cli, ConfigCommand};
+
+const APP_NAME: &str = "weirdproxy";
+
+/// Comments written above each top level field by `config generate`
+const FIELD_COMMENTS: &[(&str, &str)] = &[
+    (
+        "url_encoding_algorithm",
+        "How the origin of a proxied site is encoded into its subdomain, either Base32(<alphabet>) or Base32Xor(alphabet: <alphabet>, key: [<bytes>])",
+    ),
+    (
+        "legacy_url_encoding_algorithms",
+        "Encodings used before url_encoding_algorithm was changed, tried in order on subdomains the current one can't decode so that old links keep working",
+    ),
+    (
+        "previous_keys",
+        "XOR keys used with the url_encoding_algorithm alphabet before its current key, newest first, still accepted when decoding. GET /encoding-keys on the API shows which are still matched",
+    ),
+    (
+        "host",
+        "The listen address, either \"<ip>:<port>\", \"unix:<path>\" or \"systemd\"",
+    ),
+    (
+        "public_host",
+        "The public root domain, proxied sites are served on <encoded>.<public_host> and the API on api.<public_host>",
+    ),
+    (
+        "audit",
+        "Record proxied requests as JSON lines, e.g. Some((path: \"audit.jsonl\", max_bytes: 16777216, max_files: 8, ip_salt: \"<random>\"))",
+    ),
+    (
+        "bandwidth",
+        "Per client bandwidth accounting, optionally with a daily_quota_bytes limit and a persist_path to keep the counters across restarts",
+    ),
+    (
+        "max_rewrite_body_bytes",
+        "Responses larger than this many bytes are passed through without rewriting, and marked with an x-gs-rewrite-skipped header",
+    ),
+    (
+        "serve_unrewritten_on_error",
+        "Send responses that fail to be rewritten unmodified, marked with an x-gs-rewrite-skipped header, instead of an error page",
+    ),
+    (
+        "compression",
+        "Compress responses with the best of gzip, br and zstd the client accepts, the algorithms and level apply after a restart. Set passthrough to forward compressed upstream bodies untouched when they aren't rewritten",
+    ),
+    (
+        "logging",
+        "Log output: format is Full, Compact, Pretty or Json, filter takes tracing directives such as \"info,hyper=warn\" (overridden by RUST_LOG), and file optionally writes rotated log files. Only read at startup",
+    ),
+    (
+        "telemetry",
+        "Export spans of proxied requests to an OTLP gRPC otlp_endpoint, when built with the otel feature",
+    ),
+    (
+        "crawlers",
+        "Set disallow_all to answer robots.txt on proxied hosts with a disallow-all policy, and x_robots_tag to add an X-Robots-Tag header to proxied responses",
+    ),
+    (
+        "referrer_policy",
+        "The Referer sent upstream: Strip drops it, OriginRoot sends the root of the upstream origin, Translate decodes proxied referers back to their real URL",
+    ),
+    (
+        "translate_origin",
+        "Whether to send upstreams their real origin in the Origin header and map Access-Control-Allow-Origin back to the proxied host",
+    ),
+    (
+        "strip_fingerprinting_headers",
+        "Drop client hints (Sec-CH-*), Device-Memory, Save-Data, DNT and similar headers from upstream requests, and Accept-CH from responses",
+    ),
+    (
+        "allowed_hosts",
+        "Only proxy these upstream hosts and their subdomains, e.g. Some([\"example.com\"]), every host is allowed when None",
+    ),
+    (
+        "inject_html",
+        "HTML appended to the <head> of every rewritten page",
+    ),
+    (
+        "spoof_origin",
+        "Have page scripts see the upstream origin in document.domain, document.URL and window.origin, and accept upstream URLs in history.pushState",
+    ),
+    (
+        "api_path_prefix",
+        "Also serve the API under this path on the bare public host, e.g. Some(\"/__api\"), for when api.<public_host> can't be pointed at the proxy",
+    ),
+    (
+        "plugins_dir",
+        "A directory of .wasm plugins loaded at startup, only when built with the plugins feature",
+    ),
+    (
+        "blocking",
+        "Ad and tracker blocking: set enabled and list filter list URLs or paths in lists, exempt_hosts lists sites to leave alone",
+    ),
+    (
+        "rules",
+        "Per site tweaks, e.g. (origin: Some(\"*.example.com\"), path: Some(\"^/news\"), content_type: None, actions: [RemoveElement(\".cookie-banner\")]). Actions are Block, Redirect(url), AddHeader(name, value), RemoveElement(selector), ReplaceString(from, to), ResizeImage(width, height) and DisableScripts",
+    ),
+    (
+        "origin_headers",
+        "Headers added to upstream requests, keyed by an origin glob like the rules use, e.g. {\"internal.example.com\": {\"Authorization\": \"Bearer <token>\"}}",
+    ),
+    (
+        "response_headers",
+        "Headers set on proxied responses, keyed like origin_headers with \"*\" matching every origin, e.g. {\"*\": {\"X-Proxied-By\": \"giggleshitter\"}}. An empty value removes the header",
+    ),
+    (
+        "fingerprints",
+        "The browser upstreams are told the client is, keyed like origin_headers, e.g. {\"*\": {\"user_agent\": \"Mozilla/5.0 ...\", \"accept_language\": \"en-US\", \"client_hints\": {}, \"mobile_only\": true}}",
+    ),
+    (
+        "rewrite_json",
+        "Rewrite absolute URLs in JSON API responses so they go through the proxy, takes effect on restart",
+    ),
+    (
+        "data_uris",
+        "data: URLs in pages: max_bytes optionally drops longer ones, block_html drops data:text/html documents",
+    ),
+    (
+        "resolve_relative_urls",
+        "Send relative URLs in pages through the proxy as absolute URLs, resolved against the page's upstream URL. Protocol-relative URLs always are",
+    ),
+    (
+        "frames",
+        "iframes in pages: sandbox is Keep, Remove or Set(\"allow-scripts\"), third_party is Allow, Block or ClickToLoad for frames from other hosts than the page",
+    ),
+    (
+        "media",
+        "Image downscaling, metadata stripping and AVIF/WebP conversion, needs a build with the media feature",
+    ),
+    (
+        "downloads",
+        "Large downloads: resume_attempts is how often to resume a broken off upstream transfer with a range request, max_bytes optionally caps response sizes, stall_timeout_secs fails bodies the upstream stops sending",
+    ),
+    (
+        "websockets",
+        "Limits on each proxied WebSocket connection: max_message_bytes, max_messages_per_sec sent by the client, max_lifetime_secs and idle_timeout_secs. Connections over a limit are closed",
+    ),
+    (
+        "dns",
+        "Upstream DNS: servers lists Plain(\"9.9.9.9:53\"), Tls(addr, name) or Https(\"1.1.1.1:443\", \"cloudflare-dns.com\") resolvers to use instead of the system's, static_records maps hosts to fixed IPs. Takes effect on restart",
+    ),
+    (
+        "upstream",
+        "Upstream connection tuning: tcp_nodelay, tcp_keepalive_secs, the idle pool's timeout and size per host, HTTP/2 keepalive pings, title_case_headers for HTTP/1 upstreams that need them, ip_preference (HappyEyeballs, PreferIpv4, PreferIpv6, Ipv4Only or Ipv6Only), and follow_redirects to follow up to that many redirects on the proxy. Takes effect on restart",
+    ),
+    (
+        "hsts",
+        "Remember upstream hosts that send Strict-Transport-Security or redirect to HTTPS, and link to them over HTTPS. redirect_max_age_secs is how long a redirect is remembered",
+    ),
+    (
+        "ftp",
+        "FTP gateway: with enabled set, ftp:// links are fetched anonymously (or as user with password) and directories are shown as listings. timeout_secs bounds each step",
+    ),
+    (
+        "snapshots",
+        "Self-contained HTML snapshots from POST /snapshot on the API: stored in dir when asked (storing is refused when unset), with at most max_resources stylesheets and images inlined up to max_bytes in total",
+    ),
+    (
+        "shortener",
+        "Short links from POST /shorten on the API, served as https://<public_host>/s/<id>: kept in dir (making them is refused when unset), with IDs of id_length characters",
+    ),
+    (
+        "recording",
+        "WARC recording into dir of the origins matching a glob in origins, and of sessions started with POST /session?record=true when sessions is set. Files roll over at max_file_bytes, the newest max_files are kept, and bodies are cut off at max_body_bytes. Users' cookies and authorization headers are redacted unless credentials is set. Listed and downloaded with GET /recordings on the admin API",
+    ),
+    (
+        "prefetch",
+        "Subresource prefetching: when enabled, the first max_resources stylesheets and fonts on a page's own origin are fetched while the page is sent and advertised with Link preload headers. Responses of up to max_body_bytes are kept for ttl_secs, and only for requests without cookies or credentials",
+    ),
+    (
+        "offline",
+        "Offline replay: the last good copy of GET responses of up to max_body_bytes from the origins matching a glob in origins is kept in dir, and served with a banner while the upstream can't be reached. Responses marked no-store or private aren't kept",
+    ),
+    (
+        "circuit_breaker",
+        "After failure_threshold requests in a row to an origin fail (unreachable, or a 502, 503 or 504), answer requests to it with a 503 page for cooldown_secs",
+    ),
+    (
+        "tracking_params",
+        "With enabled set, query parameters named in params (utm_*, fbclid, gclid and other click trackers by default, a trailing * matches any suffix) are removed from upstream requests and proxied links",
+    ),
+    (
+        "documents",
+        "PDFs and office documents, matched by content_types: handling is Inline or Attachment to have them downloaded rather than shown on the proxy's origin. pdf_sanitizer is a URL PDFs are POSTed to, whose answer is served instead",
+    ),
+    (
+        "favicon",
+        "With enabled set, /favicon.ico on proxied hosts is answered from the origin's icon, kept for ttl_secs, or default_icon, a file path, when the origin has none or the host isn't a proxied address",
+    ),
+    (
+        "sniffing",
+        "Content sniffing: with html set, bodies served as text/plain or without a content type that start like an HTML document are rewritten. With binary_guard set, bodies whose first bytes hold binary data are never rewritten, whatever they were served as",
+    ),
+    (
+        "metrics",
+        "Request latencies by origin, served with GET /stats on the admin API. The last slow_log_size requests that took at least slow_request_ms are listed with the time spent decoding, waiting on the upstream and rewriting",
+    ),
+    (
+        "access",
+        "Client access rules: allow and deny take CIDR networks, allow_countries and deny_countries take ISO country codes looked up in geoip_database (needs the geoip feature)",
+    ),
+    (
+        "sessions",
+        "Session-scoped browsing: with enabled set, proxied links only work in the browser they were opened in. Set secret to a long random string",
+    ),
+    (
+        "api_keys",
+        "Keys required to use the API, each with a name, the sha256 of the key, a scope (Encode or Admin) and an optional requests_per_minute. Leave empty to keep encoding open to everyone, the admin API is refused until an Admin key is configured",
+    ),
+    (
+        "invalid_address",
+        "How to answer hosts that aren't valid proxied addresses: Page shows an explanation, RedirectToLanding redirects to the API landing page, NotFound returns an empty 404",
+    ),
+    (
+        "tenants",
+        "More public hosts to serve, each a (public_host, url_encoding_algorithm, allowed_hosts, inject_html) with the last two optional",
+    ),
+];
+
+pub fn config_path(cli: &Cli) -> Result<PathBuf> {
+    match &cli.config {
+        Some(path) => Ok(path.clone()),
+        None => Ok(confy::get_configuration_file_path(APP_NAME, None)?),
+    }
+}
+
+/// Load the configuration file, creating it with the defaults if it doesn't exist, and apply the
+/// command line overrides on top of it
+pub fn load_config(cli: &Cli) -> Result<Config> {
+    let path = config_path(cli)?;
+
+    logf!(Info, "Loading config from file: {}", path.display());
+
+    let mut config: Config = confy::load_path(path)?;
+
+    cli.apply(&mut config);
+
+    for problem in config
+        .problems()
+        .iter()
+        .filter(|problem| !problem.is_error())
+    {
+        logf!(Warning, "{}", problem);
+    }
+
+    config.validate()?;
+
+    Ok(config)
+}
+
+pub fn run(cli: &Cli, command: &ConfigCommand) -> Result<()> {
+    match command {
+        ConfigCommand::Path => println!("{}", config_path(cli)?.display()),
+        ConfigCommand::Generate { output } => {
+            let generated = generate()?;
+
+            match output {
+                Some(output) => std::fs::write(output, generated)?,
+                None => print!("{}", generated),
+            }
+        }
+        ConfigCommand::Validate => {
+            let path = config_path(cli)?;
+
+            let contents = std::fs::read_to_string(&path)?;
+            let mut config: Config = ron::from_str(&contents)
+                .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+
+            cli.apply(&mut config);
+
+            let problems = config.problems();
+
+            for problem in &problems {
+                if problem.is_error() {
+                    println!("error: {}", problem);
+                } else {
+                    println!("warning: {}", problem);
+                }
+            }
+
+            if problems.iter().any(|problem| problem.is_error()) {
+                println!("{} is not valid", path.display());
+                std::process::exit(1);
+            }
+
+            println!("{} is valid", path.display());
+        }
+        ConfigCommand::Show => print!("{}", to_ron(&load_config(cli)?)?),
+    }
+
+    Ok(())
+}
+
+fn to_ron(config: &Config) -> Result<String> {
+    let mut ron = ron::ser::to_string_pretty(config, ron::ser::PrettyConfig::default())?;
+    ron.push('\n');
+
+    Ok(ron)
+}
+
+/// Serialize the default configuration with a comment above each top level field
+fn generate() -> Result<String> {
+    let mut generated = String::new();
+
+    for line in to_ron(&Config::default())?.lines() {
+        let comment = line
+            .strip_prefix("    ")
+            .filter(|field| !field.starts_with(' '))
+            .and_then(|field| {
+                FIELD_COMMENTS
+                    .iter()
+                    .find(|(name, _)| field.starts_with(&format!("{}:", name)))
+            });
+
+        if let Some((_, comment)) = comment {
+            generated.push_str(&format!("    // {}\n", comment));
+        }
+
+        generated.push_str(line);
+        generated.push('\n');
+    }
+
+    Ok(generated)
+}
@@ -1,22 +1,42 @@
+mod cli;
+mod config;
+mod reload;
+
 use std::sync::Arc;
 
-use giggleshitter_common::{error::Result, serve, state::Config};
+use arc_swap::ArcSwap;
+use clap::Parser;
+use cli::{Cli, Command};
+use giggleshitter_common::{error::Result, listener::Listener, logging, serve_with_listener};
 use scorched::{logf, LogData, LogImportance};
 use tokio::signal;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+    let cli = Arc::new(Cli::parse());
+
+    if let Some(Command::Config { command }) = &cli.command {
+        return config::run(&cli, command);
+    }
+
+    let config = match config::load_config(&cli) {
+        Ok(config) => config,
+        Err(e) => {
+            logf!(Error, "{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let _logging = logging::init(&config)?;
+
+    let listener = Listener::bind(&config.host).await?;
+    logf!(Info, "Listening on {}", listener.local_addr()?);
 
-    logf!(
-        Info,
-        "Loading config from file: {}",
-        confy::get_configuration_file_path("weirdproxy", None)?.display()
-    );
+    let live_config = Arc::new(ArcSwap::from_pointee(config));
 
-    let config: Arc<Config> = Arc::new(confy::load("weirdproxy", None)?);
+    reload::spawn(cli, live_config.clone())?;
 
-    serve(config, shutdown_signal()).await?;
+    serve_with_listener(listener, live_config, shutdown_signal()).await?;
 
     Ok(())
 }
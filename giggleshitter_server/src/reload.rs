@@ -0,0 +1,104 @@
+use std::{sync::Arc, time::Duration};
+
+use giggleshitter_common::{error::Result, state::LiveConfig};
+use notify::{EventKind, RecursiveMode, Watcher};
+use scorched::{logf, LogData, LogImportance};
+use tokio::sync::mpsc;
+
+use crate::{cli::Cli, config};
+
+/// Editors often write a file in several steps, wait for them to settle before reloading
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Reload the configuration into `live` whenever the configuration file changes or the process
+/// receives SIGHUP. Invalid configurations are logged and leave the running one in place.
+pub fn spawn(cli: Arc<Cli>, live: LiveConfig) -> Result<()> {
+    let path = config::config_path(&cli)?;
+    let (tx, mut rx) = mpsc::unbounded_channel();
+
+    let file_tx = tx.clone();
+    let watched = path.clone();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let Ok(event) = event else {
+            return;
+        };
+
+        if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+            && event.paths.iter().any(|path| path.ends_with(&watched))
+        {
+            let _ = file_tx.send(());
+        }
+    })?;
+
+    // Watch the directory rather than the file, so that replacing the file by renaming over it is
+    // picked up as well
+    let directory = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(std::path::Path::new("."));
+    watcher.watch(directory, RecursiveMode::NonRecursive)?;
+
+    #[cfg(unix)]
+    {
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                if tx.send(()).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    tokio::spawn(async move {
+        // The watcher stops when dropped, keep it alive for as long as the task runs
+        let _watcher = watcher;
+
+        while rx.recv().await.is_some() {
+            tokio::time::sleep(DEBOUNCE).await;
+            while rx.try_recv().is_ok() {}
+
+            reload(&cli, &live);
+        }
+    });
+
+    Ok(())
+}
+
+fn reload(cli: &Cli, live: &LiveConfig) {
+    match config::config_path(cli) {
+        Ok(path) if !path.exists() => {
+            logf!(
+                Warning,
+                "Not reloading config, {} is missing",
+                path.display()
+            );
+            return;
+        }
+        Err(e) => {
+            logf!(Error, "Not reloading config: {}", e);
+            return;
+        }
+        Ok(_) => {}
+    }
+
+    let config = match config::load_config(cli) {
+        Ok(config) => config,
+        Err(e) => {
+            logf!(Error, "Not reloading config: {}", e);
+            return;
+        }
+    };
+
+    if config.host != live.load().host {
+        logf!(
+            Warning,
+            "The listen address changed to {}, restart the server to apply it",
+            config.host
+        );
+    }
+
+    live.store(Arc::new(config));
+
+    logf!(Info, "Reloaded config");
+}